@@ -0,0 +1,197 @@
+//! Address parsing and resolution for IPv4, IPv6 and dual-stack sockets
+//!
+//! Besides host names and literal addresses, IPv6 link-local addresses may
+//! carry a zone as either a numeric scope ID or an interface name, as in
+//! `[fe80::1%eth0]:3000`. Multicast interfaces are named the same way, or
+//! by one of their IPv4 addresses.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AddressError {
+    #[error("Could not resolve {0}: {1}")]
+    Resolve(String, std::io::Error),
+    #[error("No {1} address for {0}")]
+    NoAddress(String, AddressFamily),
+    #[error("Unknown network interface: {0}")]
+    UnknownInterface(String),
+}
+
+/// Address family restriction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressFamily {
+    /// IPv4 or IPv6, listening on both where possible
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    /// Whether `addr` belongs to this family
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::V4 => addr.is_ipv4(),
+            AddressFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AddressFamily::Any => "IP",
+            AddressFamily::V4 => "IPv4",
+            AddressFamily::V6 => "IPv6",
+        })
+    }
+}
+
+/// Network interface used for multicast
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Interface {
+    /// Chosen by the routing table
+    #[default]
+    Default,
+    /// The interface with this IPv4 address
+    Addr(Ipv4Addr),
+    /// The interface with this index
+    Index(u32),
+}
+
+/// Parse an interface given as IPv4 address, index or name
+pub fn parse_interface(s: &str) -> Result<Interface, AddressError> {
+    if let Ok(addr) = s.parse() {
+        return Ok(Interface::Addr(addr));
+    }
+    s.parse()
+        .ok()
+        .or_else(|| interface_index(s))
+        .map(Interface::Index)
+        .ok_or_else(|| AddressError::UnknownInterface(s.to_string()))
+}
+
+/// Parse a literal socket address, accepting interface names as IPv6 zones
+pub fn parse_socket_addr(s: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = s.parse() {
+        return Some(addr);
+    }
+
+    let (host, port) = s.strip_prefix('[')?.split_once("]:")?;
+    let (ip, zone) = host.split_once('%')?;
+    let scope_id = interface_index(zone)?;
+    Some(SocketAddr::V6(SocketAddrV6::new(ip.parse().ok()?, port.parse().ok()?, 0, scope_id)))
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: `name` is a valid NUL-terminated string
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Resolve `target` (`host:port` or a literal address) to an address of
+/// the requested family
+pub async fn resolve(target: &str, family: AddressFamily) -> Result<SocketAddr, AddressError> {
+    let candidates: Vec<SocketAddr> = match parse_socket_addr(target) {
+        Some(addr) => vec![addr],
+        None => tokio::net::lookup_host(target)
+            .await
+            .map_err(|e| AddressError::Resolve(target.to_string(), e))?
+            .collect(),
+    };
+
+    candidates
+        .into_iter()
+        .find(|addr| family.matches(addr))
+        .ok_or_else(|| AddressError::NoAddress(target.to_string(), family))
+}
+
+/// Address a receiver listens on: without `ip`, every local address of
+/// the family, IPv6 and IPv4 alike for `AddressFamily::Any`
+pub fn listen_address(ip: Option<IpAddr>, port: u16, family: AddressFamily) -> Result<SocketAddr, AddressError> {
+    let ip = ip.unwrap_or(match family {
+        AddressFamily::V4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        AddressFamily::Any | AddressFamily::V6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    });
+    let addr = SocketAddr::new(ip, port);
+    if !family.matches(&addr) {
+        return Err(AddressError::NoAddress(ip.to_string(), family));
+    }
+    Ok(addr)
+}
+
+/// Wildcard address with an ephemeral port in the family of `target`
+pub fn unspecified_for(target: &SocketAddr) -> SocketAddr {
+    match target {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scoped() {
+        assert_eq!(parse_socket_addr("10.0.0.1:3000"), Some("10.0.0.1:3000".parse().unwrap()));
+
+        let numeric = parse_socket_addr("[fe80::1%2]:3000").unwrap();
+        let SocketAddr::V6(numeric) = numeric else { panic!("not IPv6") };
+        assert_eq!(numeric.scope_id(), 2);
+
+        #[cfg(target_os = "linux")]
+        {
+            let named = parse_socket_addr("[fe80::1%lo]:3000").unwrap();
+            let SocketAddr::V6(named) = named else { panic!("not IPv6") };
+            assert_eq!(named.scope_id(), 1);
+            assert_eq!(named.port(), 3000);
+        }
+
+        assert_eq!(parse_socket_addr("[fe80::1%no-such-interface]:3000"), None);
+        assert_eq!(parse_socket_addr("example.com:3000"), None);
+    }
+
+    #[test]
+    fn test_parse_interface() {
+        assert_eq!(parse_interface("10.0.0.1").unwrap(), Interface::Addr(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(parse_interface("3").unwrap(), Interface::Index(3));
+        #[cfg(target_os = "linux")]
+        assert_eq!(parse_interface("lo").unwrap(), Interface::Index(1));
+        assert!(matches!(parse_interface("no-such-interface"), Err(AddressError::UnknownInterface(_))));
+    }
+
+    #[test]
+    fn test_listen_address() {
+        let localhost = Some("::1".parse().unwrap());
+        assert_eq!(listen_address(None, 3000, AddressFamily::Any).unwrap(), "[::]:3000".parse().unwrap());
+        assert_eq!(listen_address(None, 3000, AddressFamily::V4).unwrap(), "0.0.0.0:3000".parse().unwrap());
+        assert_eq!(listen_address(localhost, 3000, AddressFamily::V6).unwrap(), "[::1]:3000".parse().unwrap());
+        assert!(matches!(
+            listen_address(Some("127.0.0.1".parse().unwrap()), 3000, AddressFamily::V6),
+            Err(AddressError::NoAddress(_, AddressFamily::V6))
+        ));
+    }
+
+    #[test]
+    fn test_resolve_family() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let addr = resolve("[::1]:3000", AddressFamily::Any).await.unwrap();
+            assert_eq!(unspecified_for(&addr), "[::]:0".parse().unwrap());
+            assert!(resolve("[::1]:3000", AddressFamily::V4).await.is_err());
+            let addr = resolve("127.0.0.1:3000", AddressFamily::V4).await.unwrap();
+            assert_eq!(unspecified_for(&addr), "0.0.0.0:0".parse().unwrap());
+        });
+    }
+}
@@ -0,0 +1,486 @@
+//! Directory and multi-file transfers packed into a single object
+//!
+//! The files selected by a directory or glob are sent as one object laid
+//! out as
+//!
+//! ```text
+//! magic (4) | version (1) | manifest length (4) | manifest | file contents
+//! ```
+//!
+//! The manifest lists every entry with its relative path, size, mode and
+//! BLAKE3 hash, and the contents of the files follow back to back in
+//! manifest order. Being part of the object, the manifest is protected by
+//! the fountain code like the data, and small files share source blocks
+//! instead of each paying for blocks of their own. Entries are sorted, so
+//! mirrors packing the same tree produce the same object and session ID.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+/// Magic bytes identifying an archive object
+pub const MAGIC: [u8; 4] = *b"FFAR";
+/// Current archive format version
+pub const VERSION: u8 = 1;
+
+/// Magic, version and manifest length
+const HEADER_SIZE: usize = 9;
+
+/// Suffix of files being written until they are complete
+const PART_SUFFIX: &str = ".part";
+
+const KIND_FILE: u8 = 0;
+const KIND_DIRECTORY: u8 = 1;
+
+#[derive(Debug, Error)]
+pub enum ArchiveError {
+    #[error("I/O error on {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Invalid glob pattern: {0}")]
+    Pattern(#[from] glob::PatternError),
+    #[error("Nothing matches {0}")]
+    NoMatch(String),
+    #[error("Path is not valid UTF-8: {0}")]
+    NonUtf8Path(PathBuf),
+    #[error("Unsafe path in manifest: {0}")]
+    UnsafePath(String),
+    #[error("Malformed manifest")]
+    Malformed,
+    #[error("Hash mismatch for {0}")]
+    HashMismatch(String),
+}
+
+/// Kind of a manifest entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// One file or directory of an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Path relative to the destination, with `/` separators
+    pub path: String,
+    pub kind: EntryKind,
+    /// File size in bytes, zero for directories
+    pub size: u64,
+    /// Unix permission bits
+    pub mode: u32,
+    /// BLAKE3 hash of the file contents
+    pub hash: [u8; 32],
+}
+
+/// List of the entries of an archive
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+}
+
+impl Manifest {
+    /// Number of regular files
+    pub fn files(&self) -> usize {
+        self.entries.iter().filter(|e| e.kind == EntryKind::File).count()
+    }
+
+    /// Total size of the files in bytes
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().filter(|e| e.kind == EntryKind::File).map(|e| e.size).sum()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for entry in &self.entries {
+            buffer.extend_from_slice(&(entry.path.len() as u16).to_be_bytes());
+            buffer.extend_from_slice(entry.path.as_bytes());
+            buffer.push(match entry.kind {
+                EntryKind::File => KIND_FILE,
+                EntryKind::Directory => KIND_DIRECTORY,
+            });
+            buffer.extend_from_slice(&entry.size.to_be_bytes());
+            buffer.extend_from_slice(&entry.mode.to_be_bytes());
+            buffer.extend_from_slice(&entry.hash);
+        }
+        buffer
+    }
+
+    fn decode(mut buf: &[u8]) -> Result<Self, ArchiveError> {
+        let count = u32::from_be_bytes(take(&mut buf, 4)?.try_into().unwrap());
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let path_len = u16::from_be_bytes(take(&mut buf, 2)?.try_into().unwrap()) as usize;
+            let path = String::from_utf8(take(&mut buf, path_len)?.to_vec()).map_err(|_| ArchiveError::Malformed)?;
+            let kind = match take(&mut buf, 1)?[0] {
+                KIND_FILE => EntryKind::File,
+                KIND_DIRECTORY => EntryKind::Directory,
+                _ => return Err(ArchiveError::Malformed),
+            };
+            let size = u64::from_be_bytes(take(&mut buf, 8)?.try_into().unwrap());
+            let mode = u32::from_be_bytes(take(&mut buf, 4)?.try_into().unwrap());
+            let hash = take(&mut buf, 32)?.try_into().unwrap();
+            entries.push(Entry {
+                path,
+                kind,
+                size,
+                mode,
+                hash,
+            });
+        }
+        if !buf.is_empty() {
+            return Err(ArchiveError::Malformed);
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// Split `n` bytes off the front of `buf`
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], ArchiveError> {
+    if buf.len() < n {
+        return Err(ArchiveError::Malformed);
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Ok(head)
+}
+
+/// Whether `spec` names several files, as a directory or glob pattern
+pub fn is_multi_file(spec: &str) -> bool {
+    spec.contains(['*', '?', '[']) || Path::new(spec).is_dir()
+}
+
+/// Pack the directory or files matching the glob `spec` into an archive
+///
+/// A directory is stored under its own name; files matched by a glob are
+/// stored relative to the directory the pattern starts in. Symbolic links
+/// are followed only where `spec` names them, and skipped with a warning
+/// below it, as are entries that are neither files nor directories.
+pub fn pack(spec: &str) -> Result<(Manifest, Vec<u8>), ArchiveError> {
+    let mut sources = Vec::new();
+    if spec.contains(['*', '?', '[']) {
+        let base = glob_base(spec);
+        for path in glob::glob(spec)? {
+            let path = path.map_err(|e| ArchiveError::Io(e.path().to_path_buf(), e.into()))?;
+            let name = path.strip_prefix(&base).unwrap_or(&path).to_path_buf();
+            collect(&path, &name, true, &mut sources)?;
+        }
+    } else {
+        let path = Path::new(spec);
+        // `.` and `..` are stored under the name of the directory they are
+        let name = match path.file_name() {
+            Some(name) => PathBuf::from(name),
+            None => fs::canonicalize(path)
+                .map_err(|e| ArchiveError::Io(path.to_path_buf(), e))?
+                .file_name()
+                .map(PathBuf::from)
+                .ok_or_else(|| ArchiveError::NoMatch(spec.to_string()))?,
+        };
+        collect(path, &name, true, &mut sources)?;
+    }
+    if sources.is_empty() {
+        return Err(ArchiveError::NoMatch(spec.to_string()));
+    }
+    sources.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+    sources.dedup_by(|a, b| a.1.path == b.1.path);
+
+    let mut contents = Vec::new();
+    let mut manifest = Manifest::default();
+    for (path, mut entry) in sources {
+        if entry.kind == EntryKind::File {
+            let data = fs::read(&path).map_err(|e| ArchiveError::Io(path.clone(), e))?;
+            entry.size = data.len() as u64;
+            entry.hash = *blake3::hash(&data).as_bytes();
+            contents.extend_from_slice(&data);
+        }
+        manifest.entries.push(entry);
+    }
+
+    let encoded = manifest.encode();
+    let mut object = Vec::with_capacity(HEADER_SIZE + encoded.len() + contents.len());
+    object.extend_from_slice(&MAGIC);
+    object.push(VERSION);
+    object.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+    object.extend_from_slice(&encoded);
+    object.extend_from_slice(&contents);
+    Ok((manifest, object))
+}
+
+/// Leading directories of a glob pattern that contain no wildcards
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+        .collect()
+}
+
+/// Add `path`, stored as `name`, and everything below it to `sources`,
+/// following `path` itself if it is a symbolic link and `follow` is set
+fn collect(path: &Path, name: &Path, follow: bool, sources: &mut Vec<(PathBuf, Entry)>) -> Result<(), ArchiveError> {
+    let metadata = match follow {
+        true => fs::metadata(path),
+        false => fs::symlink_metadata(path),
+    }
+    .map_err(|e| ArchiveError::Io(path.to_path_buf(), e))?;
+    if metadata.is_symlink() {
+        log::warn!("Skipping symbolic link {}", path.display());
+        return Ok(());
+    }
+    if !metadata.is_file() && !metadata.is_dir() {
+        log::warn!("Skipping {}, which is neither a file nor a directory", path.display());
+        return Ok(());
+    }
+    let entry_path = name
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| ArchiveError::NonUtf8Path(path.to_path_buf()))?
+        .join("/");
+    let kind = if metadata.is_dir() {
+        EntryKind::Directory
+    } else {
+        EntryKind::File
+    };
+    sources.push((
+        path.to_path_buf(),
+        Entry {
+            path: entry_path,
+            kind,
+            size: 0,
+            mode: mode(&metadata, kind),
+            hash: [0; 32],
+        },
+    ));
+
+    if kind == EntryKind::Directory {
+        let children = fs::read_dir(path).map_err(|e| ArchiveError::Io(path.to_path_buf(), e))?;
+        for child in children {
+            let child = child.map_err(|e| ArchiveError::Io(path.to_path_buf(), e))?;
+            collect(&child.path(), &name.join(child.file_name()), false, sources)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata, _kind: EntryKind) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &fs::Metadata, kind: EntryKind) -> u32 {
+    // Without permission bits, record the usual defaults
+    match kind {
+        EntryKind::File => 0o644,
+        EntryKind::Directory => 0o755,
+    }
+}
+
+/// Parse an archive object into its manifest and file contents, or `None`
+/// if `object` is not a well-formed archive
+pub fn parse(object: &[u8]) -> Option<(Manifest, &[u8])> {
+    let mut buf = object;
+    if take(&mut buf, 4).ok()? != MAGIC || take(&mut buf, 1).ok()?[0] != VERSION {
+        return None;
+    }
+    let manifest_len = u32::from_be_bytes(take(&mut buf, 4).ok()?.try_into().unwrap()) as usize;
+    let manifest = Manifest::decode(take(&mut buf, manifest_len).ok()?).ok()?;
+    (manifest.total_size() == buf.len() as u64).then_some((manifest, buf))
+}
+
+/// Recreate the tree of an archive below `destination`, verifying every file
+///
+/// Each file is written under its `.part` name and renamed once complete,
+/// so a file under its final name is always whole.
+pub fn unpack(manifest: &Manifest, contents: &[u8], destination: &Path) -> Result<(), ArchiveError> {
+    fs::create_dir_all(destination).map_err(|e| ArchiveError::Io(destination.to_path_buf(), e))?;
+
+    let mut offset = 0usize;
+    let mut directories = Vec::new();
+    for entry in &manifest.entries {
+        let path = destination.join(safe_path(&entry.path)?);
+        match entry.kind {
+            EntryKind::Directory => {
+                fs::create_dir_all(&path).map_err(|e| ArchiveError::Io(path.clone(), e))?;
+                directories.push((path, entry.mode));
+            }
+            EntryKind::File => {
+                let data = usize::try_from(entry.size)
+                    .ok()
+                    .and_then(|size| contents.get(offset..offset.checked_add(size)?))
+                    .ok_or(ArchiveError::Malformed)?;
+                offset += data.len();
+                if *blake3::hash(data).as_bytes() != entry.hash {
+                    return Err(ArchiveError::HashMismatch(entry.path.clone()));
+                }
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).map_err(|e| ArchiveError::Io(parent.to_path_buf(), e))?;
+                }
+                write_whole(&path, data, entry.mode)?;
+            }
+        }
+    }
+
+    // Directory permissions last, in case they forbid writing
+    for (path, mode) in directories.into_iter().rev() {
+        set_mode(&path, mode)?;
+    }
+    Ok(())
+}
+
+/// Name `path` is written under until it is complete
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(PART_SUFFIX);
+    PathBuf::from(part)
+}
+
+/// Write `data` with `mode` to the `.part` name of `path` and rename it
+/// into place, removing the partial file on failure
+fn write_whole(path: &Path, data: &[u8], mode: u32) -> Result<(), ArchiveError> {
+    let part = part_path(path);
+    let written = fs::File::create(&part)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .map_err(|e| ArchiveError::Io(part.clone(), e))
+        .and_then(|_| set_mode(&part, mode))
+        .and_then(|_| fs::rename(&part, path).map_err(|e| ArchiveError::Io(path.to_path_buf(), e)));
+    if written.is_err() {
+        let _ = fs::remove_file(&part);
+    }
+    written
+}
+
+/// Relative path of a manifest entry, refusing anything that could
+/// escape the destination
+fn safe_path(path: &str) -> Result<PathBuf, ArchiveError> {
+    let relative = PathBuf::from(path);
+    let safe = !path.is_empty()
+        && !path.contains('\\')
+        && relative.components().all(|c| matches!(c, Component::Normal(_)));
+    safe.then_some(relative)
+        .ok_or_else(|| ArchiveError::UnsafePath(path.to_string()))
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), ArchiveError> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(|e| ArchiveError::Io(path.to_path_buf(), e))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<(), ArchiveError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("photos");
+        fs::create_dir_all(root.join("2024/empty")).unwrap();
+        fs::write(root.join("a.txt"), b"first").unwrap();
+        fs::write(root.join("2024/b.bin"), vec![7u8; 10_000]).unwrap();
+        fs::write(root.join("2024/c.txt"), b"").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_directory_roundtrip() {
+        let source = tree();
+        let (manifest, object) = pack(source.path().join("photos").to_str().unwrap()).unwrap();
+        let paths: Vec<&str> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec!["photos", "photos/2024", "photos/2024/b.bin", "photos/2024/c.txt", "photos/2024/empty", "photos/a.txt"]
+        );
+        assert_eq!(manifest.files(), 3);
+
+        let (parsed, contents) = parse(&object).unwrap();
+        assert_eq!(parsed, manifest);
+
+        let destination = tempfile::tempdir().unwrap();
+        unpack(&parsed, contents, destination.path()).unwrap();
+        let out = destination.path().join("photos");
+        assert_eq!(fs::read(out.join("a.txt")).unwrap(), b"first");
+        assert_eq!(fs::read(out.join("2024/b.bin")).unwrap(), vec![7u8; 10_000]);
+        assert!(out.join("2024/empty").is_dir());
+        assert!(!part_path(&out.join("a.txt")).exists());
+    }
+
+    #[test]
+    fn test_dot_paths() {
+        let source = tree();
+        let root = source.path().join("photos");
+        // Paths ending in `..` have no file name, like `.` itself
+        for spec in [root.join("."), root.join("2024/..")] {
+            let (manifest, _) = pack(spec.to_str().unwrap()).unwrap();
+            assert_eq!(manifest.entries[0].path, "photos");
+            assert!(manifest.entries.iter().any(|e| e.path == "photos/a.txt"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks() {
+        let source = tree();
+        let root = source.path().join("photos");
+        std::os::unix::fs::symlink("..", root.join("2024/loop")).unwrap();
+        std::os::unix::fs::symlink("a.txt", root.join("link.txt")).unwrap();
+
+        // Links below the named directory are skipped, loops included
+        let (manifest, _) = pack(root.to_str().unwrap()).unwrap();
+        let paths: Vec<&str> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+        assert!(!paths.iter().any(|path| path.ends_with("loop") || path.ends_with("link.txt")));
+        assert_eq!(manifest.files(), 3);
+
+        // A link named by the spec itself is followed
+        let link = source.path().join("album");
+        std::os::unix::fs::symlink(&root, &link).unwrap();
+        let (manifest, _) = pack(link.to_str().unwrap()).unwrap();
+        assert_eq!(manifest.entries[0].path, "album");
+        assert_eq!(manifest.files(), 3);
+    }
+
+    #[test]
+    fn test_glob() {
+        let source = tree();
+        let pattern = source.path().join("photos/2024/*");
+        let (manifest, _) = pack(pattern.to_str().unwrap()).unwrap();
+        let paths: Vec<&str> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["b.bin", "c.txt", "empty"]);
+
+        let pattern = source.path().join("photos/*.none");
+        assert!(matches!(pack(pattern.to_str().unwrap()), Err(ArchiveError::NoMatch(_))));
+    }
+
+    #[test]
+    fn test_rejects_unsafe_and_corrupt() {
+        assert!(parse(b"plain file contents").is_none());
+
+        let source = tree();
+        let (mut manifest, object) = pack(source.path().join("photos/a.txt").to_str().unwrap()).unwrap();
+        let (_, contents) = parse(&object).unwrap();
+        let destination = tempfile::tempdir().unwrap();
+
+        manifest.entries[0].path = "../escape".to_string();
+        assert!(matches!(
+            unpack(&manifest, contents, destination.path()),
+            Err(ArchiveError::UnsafePath(_))
+        ));
+
+        manifest.entries[0].path = "a.txt".to_string();
+        assert!(matches!(
+            unpack(&manifest, b"fir5t", destination.path()),
+            Err(ArchiveError::HashMismatch(_))
+        ));
+
+        // Truncated objects do not parse
+        assert!(parse(&object[..object.len() - 1]).is_none());
+    }
+}
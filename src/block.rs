@@ -1,6 +1,13 @@
 use crate::systematic::KMAX;
 use thiserror::Error;
 use std::cmp::min;
+use std::ops::Range;
+
+/// Minimum number of source symbols per source block accepted by the encoder
+pub const MIN_BLOCK_SYMBOLS: usize = 4;
+
+/// Symbol alignment in bytes (Section 4.2 recommends 4)
+pub const SYMBOL_ALIGNMENT: usize = 4;
 
 #[derive(Debug, Error)]
 pub enum BlockError {
@@ -35,7 +42,7 @@ impl BlockParameters {
         max_symbols_per_packet: usize
     ) -> Result<Self, BlockError> {
         // Validate input parameters
-        if alignment == 0 || max_payload_size % alignment != 0 || target_subblock_size == 0 {
+        if alignment == 0 || !max_payload_size.is_multiple_of(alignment) || target_subblock_size == 0 {
             return Err(BlockError::InvalidParameters);
         }
 
@@ -94,6 +101,137 @@ impl BlockParameters {
     }
 }
 
+/// Largest symbol size for one symbol per packet with payloads of at most
+/// `max_payload_size` bytes
+///
+/// The transfer layout shrinks the symbol further for objects too small to
+/// fill `MIN_BLOCK_SYMBOLS` symbols.
+pub fn symbol_size_for_payload(max_payload_size: usize) -> Result<usize, BlockError> {
+    let payload = max_payload_size - max_payload_size % SYMBOL_ALIGNMENT;
+    let params = BlockParameters::new(payload as u64, payload, payload, SYMBOL_ALIGNMENT, 1)?;
+    Ok(params.symbol_size)
+}
+
+/// Partition `i` items into `j` blocks of nearly equal size (Section 5.3.1.2)
+///
+/// Returns `(il, is, jl, js)`: `jl` blocks of `il` items followed by `js`
+/// blocks of `is` items.
+pub fn partition(i: usize, j: usize) -> (usize, usize, usize, usize) {
+    let il = i.div_ceil(j);
+    let is = i / j;
+    let jl = i - is * j;
+    let js = j - jl;
+    (il, is, jl, js)
+}
+
+/// Division of a transfer object into source blocks of at most `KMAX` symbols
+///
+/// Both ends derive the same layout from the transfer length and symbol size
+/// carried in every data packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferLayout {
+    /// Transfer length in bytes
+    pub transfer_length: u64,
+    /// Symbol size in bytes
+    pub symbol_size: usize,
+    /// Number of source blocks
+    pub num_blocks: usize,
+    /// Symbols in each of the first `large_blocks` source blocks
+    pub large_block_symbols: usize,
+    /// Symbols in each of the remaining source blocks
+    pub small_block_symbols: usize,
+    /// Number of source blocks with `large_block_symbols` symbols
+    pub large_blocks: usize,
+}
+
+impl TransferLayout {
+    /// Lay out `transfer_length` bytes using symbols of at most `max_symbol_size` bytes
+    ///
+    /// Objects too small to fill `MIN_BLOCK_SYMBOLS` symbols get a smaller
+    /// symbol size and are zero padded, so every source block is encodable.
+    pub fn new(transfer_length: u64, max_symbol_size: usize) -> Result<Self, BlockError> {
+        if max_symbol_size == 0 {
+            return Err(BlockError::InvalidParameters);
+        }
+
+        let mut symbol_size = max_symbol_size;
+        if transfer_length.div_ceil(symbol_size as u64) < MIN_BLOCK_SYMBOLS as u64 {
+            symbol_size = (transfer_length.div_ceil(MIN_BLOCK_SYMBOLS as u64) as usize).clamp(1, max_symbol_size);
+        }
+
+        let kt = usize::try_from(transfer_length.div_ceil(symbol_size as u64))
+            .map_err(|_| BlockError::TransferTooLarge)?
+            .max(MIN_BLOCK_SYMBOLS);
+        let num_blocks = kt.div_ceil(KMAX);
+        if u32::try_from(num_blocks).is_err() {
+            return Err(BlockError::TransferTooLarge);
+        }
+        let (large_block_symbols, small_block_symbols, large_blocks, _) = partition(kt, num_blocks);
+
+        Ok(Self {
+            transfer_length,
+            symbol_size,
+            num_blocks,
+            large_block_symbols,
+            small_block_symbols,
+            large_blocks,
+        })
+    }
+
+    /// Lay out a stream of `transfer_length` bytes in source blocks of
+    /// `block_symbols` symbols of `symbol_size` bytes, but for the last
+    ///
+    /// Unlike `new`, the layout of the leading blocks does not depend on
+    /// the length, so a stream can be sent before its length is known.
+    /// The last block has just enough symbols for the rest, zero padded,
+    /// and even an empty stream has one.
+    pub fn stream(transfer_length: u64, symbol_size: usize, block_symbols: usize) -> Result<Self, BlockError> {
+        if symbol_size == 0 || !(MIN_BLOCK_SYMBOLS..=KMAX).contains(&block_symbols) {
+            return Err(BlockError::InvalidParameters);
+        }
+        let block_bytes = (block_symbols * symbol_size) as u64;
+        let num_blocks = transfer_length.div_ceil(block_bytes).max(1);
+        let rest = transfer_length - (num_blocks - 1) * block_bytes;
+        let num_blocks = u32::try_from(num_blocks).map_err(|_| BlockError::TransferTooLarge)? as usize;
+
+        Ok(Self {
+            transfer_length,
+            symbol_size,
+            num_blocks,
+            large_block_symbols: block_symbols,
+            small_block_symbols: (rest.div_ceil(symbol_size as u64) as usize).max(MIN_BLOCK_SYMBOLS),
+            large_blocks: num_blocks - 1,
+        })
+    }
+
+    /// Number of source symbols (K) in source block `sbn`
+    pub fn block_symbols(&self, sbn: usize) -> usize {
+        if sbn < self.large_blocks {
+            self.large_block_symbols
+        } else {
+            self.small_block_symbols
+        }
+    }
+
+    /// Total number of source symbols, the least a receiver needs to
+    /// recover the object
+    pub fn source_symbols(&self) -> usize {
+        self.large_blocks * self.large_block_symbols + (self.num_blocks - self.large_blocks) * self.small_block_symbols
+    }
+
+    /// Byte range of source block `sbn` within the transfer object
+    ///
+    /// The range is clipped to the transfer length, so it may be shorter than
+    /// `block_symbols(sbn) * symbol_size` (or empty) for trailing blocks.
+    pub fn block_range(&self, sbn: usize) -> Range<u64> {
+        let large = sbn.min(self.large_blocks) * self.large_block_symbols;
+        let small = sbn.saturating_sub(self.large_blocks) * self.small_block_symbols;
+        let start = ((large + small) * self.symbol_size) as u64;
+        let end = start + (self.block_symbols(sbn) * self.symbol_size) as u64;
+        start.min(self.transfer_length)..end.min(self.transfer_length)
+    }
+}
+
 /// Represents a source block with its sub-blocks
 #[derive(Debug)]
 pub struct SourceBlock {
@@ -123,7 +261,7 @@ impl SourceBlock {
         }
 
         // Add padding to last symbol if needed
-        if data.len() % params.symbol_size != 0 {
+        if !data.len().is_multiple_of(params.symbol_size) {
             let mut last_symbol = data[block_size..].to_vec();
             last_symbol.resize(params.symbol_size, 0);
             symbols.push(last_symbol);
@@ -192,7 +330,7 @@ mod tests {
         assert!(block.is_ok());
         let block = block.unwrap();
         assert_eq!(block.sub_blocks.len(), 2);
-        assert!(block.sub_blocks[0].len() > 0);
+        assert!(!block.sub_blocks[0].is_empty());
     }
 
     #[test]
@@ -211,4 +349,63 @@ mod tests {
         assert!(block.sub_symbol(0, 0).is_some());
         assert!(block.sub_symbol(0, 2).is_none());
     }
+
+    #[test]
+    fn test_symbol_size_for_payload() {
+        assert_eq!(symbol_size_for_payload(1432).unwrap(), 1432);
+        assert_eq!(symbol_size_for_payload(1434).unwrap(), 1432);
+        assert_eq!(symbol_size_for_payload(8932).unwrap(), 8932);
+        assert!(symbol_size_for_payload(3).is_err());
+    }
+
+    #[test]
+    fn test_partition() {
+        assert_eq!(partition(10, 3), (4, 3, 1, 2));
+        assert_eq!(partition(512, 2), (256, 256, 0, 2));
+    }
+
+    #[test]
+    fn test_transfer_layout() {
+        // Large transfer spans several source blocks of at most KMAX symbols
+        let layout = TransferLayout::new(1_000_000, 1400).unwrap();
+        assert_eq!(layout.symbol_size, 1400);
+        assert_eq!(layout.num_blocks, 3);
+        assert!(layout.large_block_symbols <= KMAX);
+        let total: usize = (0..layout.num_blocks).map(|sbn| layout.block_symbols(sbn)).sum();
+        assert_eq!(total, 715);
+        assert_eq!(layout.source_symbols(), total);
+        assert_eq!(layout.block_range(0).start, 0);
+        assert_eq!(layout.block_range(1).start, layout.block_range(0).end);
+        assert_eq!(layout.block_range(2).end, 1_000_000);
+
+        // Small transfers shrink the symbol size to keep at least four symbols
+        let layout = TransferLayout::new(10, 1400).unwrap();
+        assert_eq!(layout.symbol_size, 3);
+        assert_eq!(layout.num_blocks, 1);
+        assert_eq!(layout.block_symbols(0), MIN_BLOCK_SYMBOLS);
+        assert_eq!(layout.block_range(0), 0..10);
+
+        // The receiver re-derives the same layout from the chosen symbol size
+        assert_eq!(TransferLayout::new(10, layout.symbol_size).unwrap(), layout);
+        assert!(TransferLayout::new(0, 1400).is_ok());
+        assert!(TransferLayout::new(100, 0).is_err());
+    }
+
+    #[test]
+    fn test_stream_layout() {
+        let layout = TransferLayout::stream(1000, 10, 16).unwrap();
+        assert_eq!(layout.num_blocks, 7);
+        assert_eq!(layout.block_symbols(5), 16);
+        assert_eq!(layout.block_symbols(6), MIN_BLOCK_SYMBOLS);
+        assert_eq!(layout.block_range(1), 160..320);
+        assert_eq!(layout.block_range(6), 960..1000);
+        assert_eq!(layout.source_symbols(), 6 * 16 + MIN_BLOCK_SYMBOLS);
+
+        // Empty streams still have a block, and exact multiples no extra one
+        let layout = TransferLayout::stream(0, 10, 16).unwrap();
+        assert_eq!((layout.num_blocks, layout.block_symbols(0)), (1, MIN_BLOCK_SYMBOLS));
+        let layout = TransferLayout::stream(320, 10, 16).unwrap();
+        assert_eq!((layout.num_blocks, layout.block_symbols(1)), (2, 16));
+        assert!(TransferLayout::stream(100, 10, KMAX + 1).is_err());
+    }
 }
@@ -0,0 +1,314 @@
+//! Receive checkpoints that let an interrupted transfer resume
+//!
+//! The receiver journals its decoding state to a sidecar file next to the
+//! output: a header identifying the session, then one record per symbol
+//! received for a still-incomplete source block and one per source block
+//! as it is recovered. Records are only ever appended, so a crash loses at
+//! most the unflushed tail, and a torn final record is ignored on load.
+//!
+//! ```text
+//! magic (4) | version (1) | session id (8) | transfer length (8) | symbol size (2)
+//! symbol record: 0 | sbn (4) | esi (4) | degree (2) | payload (symbol size)
+//! block record:  1 | sbn (4) | length (4) | data
+//! ```
+//!
+//! On resume the journal is replayed and rewritten compacted, keeping the
+//! recovered blocks and the symbols of the blocks still incomplete.
+
+use crate::protocol::DataPacket;
+use crate::session::ReceiveSession;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Magic bytes identifying a checkpoint journal
+pub const MAGIC: [u8; 4] = *b"FFCP";
+/// Current journal format version
+pub const VERSION: u8 = 1;
+
+/// Suffix appended to the output path to name the sidecar
+const SIDECAR_SUFFIX: &str = ".ffpart";
+
+const HEADER_SIZE: usize = 23;
+
+const RECORD_SYMBOL: u8 = 0;
+const RECORD_BLOCK: u8 = 1;
+
+#[derive(Debug, Error)]
+pub enum CheckpointError {
+    #[error("Checkpoint I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Not a checkpoint file")]
+    BadHeader,
+}
+
+/// Sidecar checkpoint path for the output file `output`
+pub fn sidecar_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(SIDECAR_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Symbol kept for a source block that was still incomplete
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedSymbol {
+    pub sbn: u32,
+    pub esi: u32,
+    pub degree: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Decoding state read back from a checkpoint
+#[derive(Debug, Default)]
+pub struct Saved {
+    pub session_id: u64,
+    pub transfer_length: u64,
+    pub symbol_size: u16,
+    /// Recovered source blocks by source block number
+    pub blocks: HashMap<u32, Vec<u8>>,
+    /// Symbols of source blocks not recovered
+    pub symbols: Vec<SavedSymbol>,
+}
+
+impl Saved {
+    /// Whether the checkpoint belongs to the session `packet` is part of
+    pub fn matches(&self, packet: &DataPacket) -> bool {
+        self.session_id == packet.session_id
+            && self.transfer_length == packet.transfer_length
+            && self.symbol_size == packet.symbol_size
+    }
+
+    /// Feed the saved state into a fresh session, returning how many
+    /// symbols and blocks it accepted
+    pub fn restore(&self, session: &mut ReceiveSession) -> (usize, usize) {
+        let mut blocks = 0;
+        for (&sbn, data) in &self.blocks {
+            match session.restore_block(sbn as usize, data.clone()) {
+                Ok(()) => blocks += 1,
+                Err(e) => log::warn!("Discarding checkpointed source block {}: {}", sbn, e),
+            }
+        }
+        let mut symbols = 0;
+        for symbol in &self.symbols {
+            match session.restore_symbol(symbol.sbn as usize, symbol.esi, symbol.degree, &symbol.payload) {
+                Ok(_) => symbols += 1,
+                Err(e) => log::warn!("Discarding checkpointed symbol {} of block {}: {}", symbol.esi, symbol.sbn, e),
+            }
+        }
+        (symbols, blocks)
+    }
+}
+
+/// Read the checkpoint at `path`, `None` if there is none
+pub fn load(path: &Path) -> Result<Option<Saved>, CheckpointError> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut data)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if data.len() < HEADER_SIZE || data[..4] != MAGIC || data[4] != VERSION {
+        return Err(CheckpointError::BadHeader);
+    }
+    let mut saved = Saved {
+        session_id: u64::from_be_bytes(data[5..13].try_into().unwrap()),
+        transfer_length: u64::from_be_bytes(data[13..21].try_into().unwrap()),
+        symbol_size: u16::from_be_bytes(data[21..23].try_into().unwrap()),
+        ..Saved::default()
+    };
+
+    let symbol_size = saved.symbol_size as usize;
+    let mut buf = &data[HEADER_SIZE..];
+    // A record cut short by a crash ends the journal
+    while let Some((&tag, rest)) = buf.split_first() {
+        match tag {
+            RECORD_SYMBOL if rest.len() >= 10 + symbol_size => {
+                saved.symbols.push(SavedSymbol {
+                    sbn: u32::from_be_bytes(rest[0..4].try_into().unwrap()),
+                    esi: u32::from_be_bytes(rest[4..8].try_into().unwrap()),
+                    degree: u16::from_be_bytes(rest[8..10].try_into().unwrap()),
+                    payload: rest[10..10 + symbol_size].to_vec(),
+                });
+                buf = &rest[10 + symbol_size..];
+            }
+            RECORD_BLOCK if rest.len() >= 8 => {
+                let sbn = u32::from_be_bytes(rest[0..4].try_into().unwrap());
+                let len = u32::from_be_bytes(rest[4..8].try_into().unwrap()) as usize;
+                let Some(block) = rest.get(8..8 + len) else { break };
+                saved.blocks.insert(sbn, block.to_vec());
+                buf = &rest[8 + len..];
+            }
+            RECORD_SYMBOL | RECORD_BLOCK => break,
+            _ => return Err(CheckpointError::BadHeader),
+        }
+    }
+
+    saved.symbols.retain(|symbol| !saved.blocks.contains_key(&symbol.sbn));
+    Ok(Some(saved))
+}
+
+/// Append-only journal of a receive session
+pub struct Checkpoint {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl Checkpoint {
+    /// Start a journal at `path` for the session of `packet`, replacing any
+    /// existing file, and write `saved` state into it
+    pub fn create(path: &Path, packet: &DataPacket, saved: Option<&Saved>) -> Result<Self, CheckpointError> {
+        // Write to a temporary file first so the old journal survives a
+        // crash during compaction
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+
+        let mut checkpoint = Self {
+            path: temporary.clone(),
+            file: BufWriter::new(File::create(&temporary)?),
+        };
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(&MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(&packet.session_id.to_be_bytes());
+        header.extend_from_slice(&packet.transfer_length.to_be_bytes());
+        header.extend_from_slice(&packet.symbol_size.to_be_bytes());
+        checkpoint.file.write_all(&header)?;
+
+        if let Some(saved) = saved {
+            for (&sbn, data) in &saved.blocks {
+                checkpoint.record_block(sbn, data)?;
+            }
+            for symbol in &saved.symbols {
+                checkpoint.write_symbol(symbol.sbn, symbol.esi, symbol.degree, &symbol.payload)?;
+            }
+        }
+        checkpoint.file.flush()?;
+        fs::rename(&temporary, path)?;
+        checkpoint.path = path.to_path_buf();
+        Ok(checkpoint)
+    }
+
+    /// Journal a symbol of a source block that is still incomplete
+    pub fn record_symbol(&mut self, packet: &DataPacket) -> Result<(), CheckpointError> {
+        self.write_symbol(packet.sbn, packet.esi, packet.degree, &packet.payload)
+    }
+
+    fn write_symbol(&mut self, sbn: u32, esi: u32, degree: u16, payload: &[u8]) -> Result<(), CheckpointError> {
+        self.file.write_all(&[RECORD_SYMBOL])?;
+        self.file.write_all(&sbn.to_be_bytes())?;
+        self.file.write_all(&esi.to_be_bytes())?;
+        self.file.write_all(&degree.to_be_bytes())?;
+        self.file.write_all(payload)?;
+        Ok(())
+    }
+
+    /// Journal a recovered source block
+    pub fn record_block(&mut self, sbn: u32, data: &[u8]) -> Result<(), CheckpointError> {
+        self.file.write_all(&[RECORD_BLOCK])?;
+        self.file.write_all(&sbn.to_be_bytes())?;
+        self.file.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    /// Hand buffered records to the operating system
+    pub fn flush(&mut self) -> Result<(), CheckpointError> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Delete the journal once the output is safely written
+    pub fn remove(self) -> Result<(), CheckpointError> {
+        drop(self.file);
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{ReceiveLimits, SendSession};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_resume_after_interruption() {
+        let data: Vec<u8> = (0..600 * 16u32).map(|i| (i * 13 % 256) as u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = sidecar_path(&dir.path().join("out.bin"));
+        let from: SocketAddr = "10.0.0.1:3000".parse().unwrap();
+
+        // First run: receive part of the transfer, journaling as we go
+        let mut sender = SendSession::new(&data, 16).unwrap();
+        let first = sender.next_packet().unwrap();
+        let mut receiver = ReceiveSession::with_limits(&first, &ReceiveLimits::unlimited()).unwrap();
+        let mut checkpoint = Checkpoint::create(&path, &first, None).unwrap();
+        let mut packet = first.clone();
+        let mut sent = 0;
+        while receiver.blocks_decoded() < 2 {
+            let sbn = packet.sbn as usize;
+            if !receiver.is_block_decoded(sbn) {
+                if receiver.add_packet(from, &packet).unwrap() {
+                    checkpoint.record_block(packet.sbn, receiver.block(sbn).unwrap()).unwrap();
+                } else {
+                    checkpoint.record_symbol(&packet).unwrap();
+                }
+            }
+            packet = sender.next_packet().unwrap();
+            sent += 1;
+        }
+        checkpoint.flush().unwrap();
+        drop(checkpoint);
+
+        // Second run: restore, then finish with fresh symbols only
+        let saved = load(&path).unwrap().unwrap();
+        assert!(saved.matches(&first));
+        assert_eq!(saved.blocks.len(), 2);
+        let mut resumed = ReceiveSession::with_limits(&first, &ReceiveLimits::unlimited()).unwrap();
+        saved.restore(&mut resumed);
+        assert_eq!(resumed.blocks_decoded(), 2);
+
+        let mut fresh = 0;
+        while !resumed.is_complete() {
+            resumed.add_packet(from, &sender.next_packet().unwrap()).unwrap();
+            fresh += 1;
+        }
+        assert!(fresh < sent, "resume needed {} symbols after {}", fresh, sent);
+        assert_eq!(resumed.into_data().unwrap(), data);
+
+        // Compaction keeps the same state in a smaller file
+        let before = fs::metadata(&path).unwrap().len();
+        Checkpoint::create(&path, &first, Some(&saved)).unwrap().flush().unwrap();
+        let compacted = load(&path).unwrap().unwrap();
+        assert_eq!(compacted.blocks, saved.blocks);
+        assert_eq!(compacted.symbols, saved.symbols);
+        assert!(fs::metadata(&path).unwrap().len() <= before);
+    }
+
+    #[test]
+    fn test_torn_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let mut sender = SendSession::new(&[5u8; 1000], 10).unwrap();
+        let first = sender.next_packet().unwrap();
+
+        let mut checkpoint = Checkpoint::create(&path, &first, None).unwrap();
+        checkpoint.record_symbol(&first).unwrap();
+        checkpoint.record_symbol(&sender.next_packet().unwrap()).unwrap();
+        checkpoint.flush().unwrap();
+        drop(checkpoint);
+
+        // Cut the last record short
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+        assert_eq!(load(&path).unwrap().unwrap().symbols.len(), 1);
+
+        assert!(load(&dir.path().join("missing")).unwrap().is_none());
+        fs::write(&path, b"garbage").unwrap();
+        assert!(matches!(load(&path), Err(CheckpointError::BadHeader)));
+    }
+}
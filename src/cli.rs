@@ -1,61 +1,996 @@
 //! Command-line interface
+//!
+//! Each command takes its own typed arguments; addresses, sizes and
+//! durations are checked while parsing, before anything is opened.
 
-use clap::Parser;
+use crate::addr::{parse_interface, parse_socket_addr, AddressError, AddressFamily, Interface};
+use crate::daemon::{DEFAULT_MAX_SESSIONS, DEFAULT_MAX_SESSION_MEMORY};
+use crate::pacer::{PacerConfig, DEFAULT_BURST};
+use crate::pmtu::{DEFAULT_MTU, MAX_PROBE_MTU};
+use crate::session::{ReceiveLimits, DEFAULT_MAX_TRANSFER_LENGTH, DEFAULT_MIN_SYMBOL_SIZE};
+use crate::simulator::{Impairments, LossModel};
+use crate::transfer::DEFAULT_PORT;
+use clap::{Args, Parser, Subcommand};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// File name standing for standard input when sending and standard output
+/// when receiving
+pub const STDIO: &str = "-";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
-    /// Operation mode: 'send' or 'receive'
+    #[command(subcommand)]
+    pub command: Command,
+
+    /// Enable verbose output
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+
+    /// Print the events and results of send, receive, serve and simulate,
+    /// and any error, as newline-delimited JSON
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// Configuration file read after the system and user ones
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Profile of the configuration to take settings from
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Send a file, directory or glob to one or more receivers
+    Send(SendArgs),
+    /// Receive one transfer
+    Receive(ReceiveArgs),
+    /// Receive many transfers at once into an inbox directory
+    Serve(ServeArgs),
+    /// Submit, list, pause and cancel the transfers of a running serve
+    Ctl(CtlArgs),
+    /// Describe a file, archive, checkpoint, chunk file or parity volume
+    Inspect(InspectArgs),
+    /// Run a transfer across a simulated network with impairments
+    Simulate(SimulateArgs),
+    /// Measure encoding and decoding throughput
+    Bench(BenchArgs),
+    /// Write the encoded packet stream to chunk files
+    EncodeToDir(EncodeToDirArgs),
+    /// Rebuild a transfer from chunk files
+    DecodeFromDir(DecodeFromDirArgs),
+    /// Write parity volumes protecting a file
+    Create(CreateArgs),
+    /// Check a file against its parity volumes
+    Verify(ParityArgs),
+    /// Repair a file from its parity volumes
+    Repair(ParityArgs),
+}
+
+/// Address family restriction
+#[derive(Args, Debug, Clone, Default)]
+pub struct FamilyArgs {
+    /// Use IPv4 only
+    #[arg(short = '4', long, conflicts_with = "ipv6")]
+    pub ipv4: bool,
+
+    /// Use IPv6 only; a receiver listening on all addresses otherwise also
+    /// accepts IPv4
+    #[arg(short = '6', long)]
+    pub ipv6: bool,
+}
+
+impl FamilyArgs {
+    /// Address family selected by `--ipv4`/`--ipv6`
+    pub fn family(&self) -> AddressFamily {
+        match (self.ipv4, self.ipv6) {
+            (true, _) => AddressFamily::V4,
+            (_, true) => AddressFamily::V6,
+            _ => AddressFamily::Any,
+        }
+    }
+}
+
+/// Rate limits
+#[derive(Args, Debug, Clone)]
+pub struct PacingArgs {
+    /// Maximum transfer rate in Mbps (10^6 bits per second, 0 for unlimited)
+    #[arg(short, long, default_value = "1000")]
+    pub rate_limit: u32,
+
+    /// Maximum packets per second, in addition to the byte rate limit
+    #[arg(long)]
+    pub packet_rate: Option<u32>,
+
+    /// Largest burst the pacer lets out back-to-back (e.g. 15000, 64KiB)
+    #[arg(long, default_value_t = DEFAULT_BURST as u64, value_parser = parse_size)]
+    pub burst: u64,
+}
+
+impl PacingArgs {
+    /// Pacing settings from the rate limit options
+    pub fn pacing(&self) -> PacerConfig {
+        PacerConfig {
+            packets_per_second: self.packet_rate.map(f64::from),
+            burst: self.burst as usize,
+            ..PacerConfig::from_mbps(self.rate_limit)
+        }
+    }
+}
+
+/// Limits on how long a receive goes on once its first packet arrived
+#[derive(Args, Debug, Clone)]
+pub struct TimeoutArgs {
+    /// Give up after this long without packets of the transfer (e.g. 30s,
+    /// 5m)
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    pub idle_timeout: Duration,
+
+    /// Give up unless the transfer completes this long after its first
+    /// packet; no limit by default
+    #[arg(long, value_parser = parse_duration)]
+    pub deadline: Option<Duration>,
+}
+
+/// Bounds on the objects a receive accepts
+#[derive(Args, Debug, Clone)]
+pub struct LimitArgs {
+    /// Largest object accepted (e.g. 512MiB, 8GiB)
+    #[arg(long, default_value_t = DEFAULT_MAX_TRANSFER_LENGTH, value_parser = parse_size)]
+    pub max_size: u64,
+
+    /// Smallest symbol size accepted for objects of more than one source
+    /// block, in bytes
+    #[arg(long, default_value_t = DEFAULT_MIN_SYMBOL_SIZE)]
+    pub min_symbol_size: usize,
+}
+
+impl LimitArgs {
+    pub fn limits(&self) -> ReceiveLimits {
+        ReceiveLimits {
+            max_transfer_length: self.max_size,
+            min_symbol_size: self.min_symbol_size,
+        }
+    }
+}
+
+/// Completion acknowledgement signing
+#[derive(Args, Debug, Clone, Default)]
+pub struct KeyArgs {
+    /// Pre-shared key used to sign and verify completion acknowledgements
+    #[arg(long)]
+    pub key: Option<String>,
+}
+
+impl KeyArgs {
+    /// Key material for completion acknowledgements (empty if none was given)
+    pub fn ack_key(&self) -> &[u8] {
+        self.key.as_deref().unwrap_or_default().as_bytes()
+    }
+}
+
+/// Control socket of a daemon
+#[derive(Args, Debug, Clone)]
+pub struct SocketArgs {
+    /// Unix socket of the daemon's control interface
+    #[arg(long, default_value_os_t = default_control_socket())]
+    pub socket: PathBuf,
+}
+
+/// `fountainflow.sock` in the user's runtime directory, or in a directory
+/// of the user's own in the temporary directory if there is none
+pub fn default_control_socket() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(private_temp_dir)
+        .join("fountainflow.sock")
+}
+
+#[cfg(unix)]
+fn private_temp_dir() -> PathBuf {
+    // SAFETY: getuid has no preconditions and cannot fail
+    std::env::temp_dir().join(format!("fountainflow-{}", unsafe { libc::getuid() }))
+}
+
+#[cfg(not(unix))]
+fn private_temp_dir() -> PathBuf {
+    std::env::temp_dir()
+}
+
+/// Multicast interface selection
+#[derive(Args, Debug, Clone, Default)]
+pub struct InterfaceArgs {
+    /// Network interface for multicast: IPv4 address, or name or index
+    #[arg(long)]
+    pub interface: Option<String>,
+}
+
+impl InterfaceArgs {
+    /// Multicast interface selected by `--interface`
+    pub fn multicast_interface(&self) -> Result<Interface, AddressError> {
+        self.interface
+            .as_deref()
+            .map_or(Ok(Interface::Default), parse_interface)
+    }
+}
+
+/// Where a receiver listens
+#[derive(Args, Debug, Clone)]
+pub struct ListenArgs {
+    /// UDP port to listen on
+    #[arg(short, long, default_value_t = DEFAULT_PORT)]
+    pub port: u16,
+
+    /// Local address to listen on instead of all addresses
+    #[arg(long)]
+    pub bind: Option<IpAddr>,
+
+    #[command(flatten)]
+    pub family: FamilyArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct SendArgs {
+    /// File to send; a directory or glob pattern sends several files,
+    /// recreated below the receiver's destination, and "-" streams
+    /// standard input as it is read
+    #[arg(short, long)]
+    pub file: String,
+
+    /// Receiver address (e.g. "192.168.1.100:3000", "[fe80::1%eth0]:3000",
+    /// "host.example:3000"), repeated to send to several destinations;
+    /// defaults to the targets of the profile
+    #[arg(short, long, value_parser = parse_target)]
+    pub target: Vec<String>,
+
+    #[command(flatten)]
+    pub family: FamilyArgs,
+
+    #[command(flatten)]
+    pub pacing: PacingArgs,
+
+    #[command(flatten)]
+    pub interface: InterfaceArgs,
+
+    /// Multicast TTL (IPv4) or hop limit (IPv6)
+    #[arg(long, default_value = "1")]
+    pub ttl: u32,
+
+    /// Completion acknowledgements to wait for from a multicast target
+    #[arg(long, default_value = "1")]
+    pub receivers: usize,
+
+    /// First encoding symbol ID sent; mirrors serving the same file use
+    /// distinct offsets below the stride
+    #[arg(long, default_value = "0")]
+    pub esi_offset: u32,
+
+    /// Step between encoding symbol IDs sent, usually the number of mirrors
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    pub esi_stride: u32,
+
+    /// Path MTU in bytes; skips path MTU discovery
+    #[arg(long, value_parser = parse_mtu)]
+    pub mtu: Option<usize>,
+
+    /// Largest MTU tried by path MTU discovery
+    #[arg(long, default_value_t = MAX_PROBE_MTU, value_parser = parse_mtu)]
+    pub max_mtu: usize,
+
+    /// Send at the full rate limit instead of adapting to receiver feedback
+    #[arg(long)]
+    pub no_congestion_control: bool,
+
+    /// Accept completion acknowledgements reporting a different hash
+    #[arg(long)]
+    pub no_checksum: bool,
+
+    /// Send this percentage of repair symbols on top of the source symbols
+    /// and stop, instead of sending until acknowledged
+    #[arg(long)]
+    pub overhead: Option<u32>,
+
+    /// How long to keep generating repair symbols while waiting for
+    /// completion acknowledgements (e.g. 120, 90s, 5m)
+    #[arg(long, default_value = "120s", value_parser = parse_duration)]
+    pub deadline: Duration,
+
+    #[command(flatten)]
+    pub key: KeyArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ReceiveArgs {
+    /// Destination file, or directory an archive of several files is
+    /// recreated below; "-" writes to standard output
     #[arg(short, long)]
-    pub mode: String,
+    pub file: PathBuf,
 
-    /// File path (source for send, destination for receive)
+    #[command(flatten)]
+    pub listen: ListenArgs,
+
+    /// Multicast group to join
+    #[arg(long)]
+    pub group: Option<IpAddr>,
+
+    #[command(flatten)]
+    pub interface: InterfaceArgs,
+
+    /// Do not keep a checkpoint to resume an interrupted receive from
+    #[arg(long)]
+    pub no_checkpoint: bool,
+
+    /// Remove the checkpoint or partly written stream of a cancelled or
+    /// failed receive instead of keeping it
+    #[arg(long)]
+    pub discard_partial: bool,
+
+    /// How long to wait for the first packet (e.g. 30s, 5m)
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    pub first_packet_timeout: Duration,
+
+    /// Wait for a transfer, and between its packets, indefinitely
+    #[arg(long, conflicts_with_all = ["first_packet_timeout", "idle_timeout"])]
+    pub wait: bool,
+
+    #[command(flatten)]
+    pub timeouts: TimeoutArgs,
+
+    #[command(flatten)]
+    pub limits: LimitArgs,
+
+    #[command(flatten)]
+    pub key: KeyArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// Directory completed transfers are written to
+    #[arg(short, long)]
+    pub inbox: PathBuf,
+
+    #[command(flatten)]
+    pub listen: ListenArgs,
+
+    /// Most transfers received at once; more are turned away
+    #[arg(long, default_value_t = DEFAULT_MAX_SESSIONS)]
+    pub max_sessions: usize,
+
+    /// Most memory a transfer may take while it is received (e.g. 512MiB);
+    /// larger objects are turned away
+    #[arg(long, default_value_t = DEFAULT_MAX_SESSION_MEMORY, value_parser = parse_size)]
+    pub max_session_memory: u64,
+
+    /// Remove partly written streams of cancelled or failed transfers
+    /// instead of keeping them
+    #[arg(long)]
+    pub discard_partial: bool,
+
+    #[command(flatten)]
+    pub timeouts: TimeoutArgs,
+
+    #[command(flatten)]
+    pub limits: LimitArgs,
+
+    #[command(flatten)]
+    pub key: KeyArgs,
+
+    /// Accept `ctl` requests on the control socket
+    #[arg(long)]
+    pub control: bool,
+
+    #[command(flatten)]
+    pub socket: SocketArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct CtlArgs {
+    #[command(flatten)]
+    pub socket: SocketArgs,
+
+    #[command(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CtlCommand {
+    /// Start sending a file, directory or glob through the daemon's socket
+    Submit(SubmitArgs),
+    /// List the transfers running and recently finished
+    List,
+    /// Show the progress of a transfer
+    Status { transfer: u64 },
+    /// Hold off sending an outgoing transfer
+    Pause { transfer: u64 },
+    /// Go on sending a paused transfer
+    Resume { transfer: u64 },
+    /// Cancel a transfer, telling its peers
+    Cancel { transfer: u64 },
+}
+
+#[derive(Args, Debug)]
+pub struct SubmitArgs {
+    /// File to send, or a directory or glob pattern sending several files;
+    /// read by the daemon
     #[arg(short, long)]
     pub file: String,
 
-    /// Target address for send mode (e.g., "192.168.1.100:3000")
-    /// or port for receive mode (e.g., "3000")
+    /// Receiver address, repeated to send to several destinations
+    #[arg(short, long, required = true, value_parser = parse_target)]
+    pub target: Vec<String>,
+
+    /// Maximum transfer rate in Mbps (0 for unlimited); 1000 unless given
     #[arg(short, long)]
-    pub target: String,
+    pub rate_limit: Option<u32>,
 
-    /// Maximum transfer rate in Mbps
-    #[arg(short, long, default_value = "1000")]
-    pub rate_limit: u32,
+    /// Path MTU in bytes; skips path MTU discovery
+    #[arg(long, value_parser = parse_mtu)]
+    pub mtu: Option<usize>,
+}
 
-    /// Enable verbose output
-    #[arg(short, long, default_value = "false")]
-    pub verbose: bool,
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    /// File or directory to describe
+    pub path: PathBuf,
 
-    /// Disable checksum verification
-    #[arg(long, default_value = "false")]
-    pub no_checksum: bool,
+    /// MTU to lay out a plain file for
+    #[arg(long, default_value_t = DEFAULT_MTU, value_parser = parse_mtu)]
+    pub mtu: usize,
+}
+
+#[derive(Args, Debug)]
+pub struct SimulateArgs {
+    /// File to transfer; random data of --size bytes if not given
+    #[arg(short, long)]
+    pub file: Option<String>,
+
+    /// Size of the random data transferred (e.g. 10MB, 64MiB)
+    #[arg(long, default_value = "10MB", value_parser = parse_size)]
+    pub size: u64,
+
+    /// Fraction of packets lost
+    #[arg(long, default_value = "0", value_parser = parse_probability)]
+    pub loss: f64,
+
+    /// Mean length of loss bursts in packets; 1 loses packets independently
+    #[arg(long, default_value = "1", value_parser = parse_burst_length)]
+    pub burst_length: f64,
+
+    /// One-way delay (e.g. 20ms)
+    #[arg(long, default_value = "0ms", value_parser = parse_duration)]
+    pub delay: Duration,
+
+    /// Most additional random delay per packet
+    #[arg(long, default_value = "0ms", value_parser = parse_duration)]
+    pub jitter: Duration,
+
+    /// Fraction of packets held back behind later ones
+    #[arg(long, default_value = "0", value_parser = parse_probability)]
+    pub reorder: f64,
+
+    /// Fraction of packets delivered twice
+    #[arg(long, default_value = "0", value_parser = parse_probability)]
+    pub duplicate: f64,
+
+    /// Fraction of packets with a flipped bit
+    #[arg(long, default_value = "0", value_parser = parse_probability)]
+    pub corrupt: f64,
+
+    /// Seed of the simulated network's random choices
+    #[arg(long, default_value = "1")]
+    pub seed: u64,
+
+    /// MTU of the simulated path
+    #[arg(long, default_value_t = DEFAULT_MTU, value_parser = parse_mtu)]
+    pub mtu: usize,
+
+    #[command(flatten)]
+    pub pacing: PacingArgs,
+
+    /// Send at the full rate limit instead of adapting to receiver feedback
+    #[arg(long)]
+    pub no_congestion_control: bool,
+
+    /// Longest the simulated transfer may take
+    #[arg(long, default_value = "120s", value_parser = parse_duration)]
+    pub deadline: Duration,
+}
+
+impl SimulateArgs {
+    /// Impairments of the simulated path
+    ///
+    /// Bursty loss uses a Gilbert-Elliott model that loses every packet in
+    /// the bad state and spends the `--loss` fraction of the time there.
+    pub fn impairments(&self) -> Impairments {
+        let loss = match (self.loss, self.burst_length) {
+            (0.0, _) => LossModel::None,
+            (loss, length) if length <= 1.0 => LossModel::bernoulli(loss),
+            (loss, length) if loss >= 1.0 => LossModel::bursts(1.0, 1.0 / length),
+            (loss, length) => LossModel::bursts(loss / (1.0 - loss) / length, 1.0 / length),
+        };
+        Impairments {
+            loss,
+            delay: self.delay,
+            jitter: self.jitter,
+            reorder: self.reorder,
+            reorder_delay: self.jitter.max(self.delay / 2),
+            duplicate: self.duplicate,
+            corrupt: self.corrupt,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Amount of random data to encode and decode (e.g. 64MiB)
+    #[arg(long, default_value = "64MiB", value_parser = parse_size)]
+    pub size: u64,
+
+    /// Symbol size in bytes
+    #[arg(long, default_value = "1400", value_parser = parse_size)]
+    pub symbol_size: u64,
+
+    /// Fraction of encoded symbols dropped before decoding
+    #[arg(long, default_value = "0", value_parser = parse_probability)]
+    pub loss: f64,
+}
+
+#[derive(Args, Debug)]
+pub struct EncodeToDirArgs {
+    /// File to encode; a directory or glob pattern encodes several files
+    #[arg(short, long)]
+    pub file: String,
+
+    /// Directory the chunk files are written to
+    #[arg(short, long)]
+    pub dir: PathBuf,
+
+    /// Number of chunk files the packets are spread over
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..))]
+    pub chunks: u32,
+
+    /// Repair symbols written, as a percentage of the source symbols
+    #[arg(long, default_value = "200")]
+    pub overhead: u32,
+}
+
+#[derive(Args, Debug)]
+pub struct DecodeFromDirArgs {
+    /// Directory holding the chunk files
+    #[arg(short, long)]
+    pub dir: PathBuf,
+
+    /// Destination file, or directory an archive is recreated below
+    #[arg(short, long)]
+    pub file: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct CreateArgs {
+    /// File to protect
+    #[arg(short, long)]
+    pub file: PathBuf,
+
+    /// Number of parity volumes written
+    #[arg(long, default_value = "2", value_parser = clap::value_parser!(u32).range(1..))]
+    pub volumes: u32,
+
+    /// Repair symbols written, as a percentage of the source symbols
+    #[arg(long, default_value = "10")]
+    pub redundancy: u32,
+}
+
+#[derive(Args, Debug)]
+pub struct ParityArgs {
+    /// File protected by parity volumes
+    #[arg(short, long)]
+    pub file: PathBuf,
+}
+
+/// Parse a byte count with an optional decimal (kB, MB, GB) or binary
+/// (KiB, MiB, GiB) unit
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "ki" | "kib" => 1 << 10,
+        "mi" | "mib" => 1 << 20,
+        "gi" | "gib" => 1 << 30,
+        "ti" | "tib" => 1 << 40,
+        _ => return Err(format!("unknown size unit '{}'", unit.trim())),
+    };
+    let number: f64 = number.parse().map_err(|_| format!("invalid size '{}'", s))?;
+    let bytes = number * multiplier as f64;
+    if !(0.0..=u64::MAX as f64).contains(&bytes) {
+        return Err(format!("size '{}' out of range", s));
+    }
+    Ok(bytes.round() as u64)
+}
+
+/// Parse a duration with an optional unit (ms, s, m, h); plain numbers are
+/// seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let seconds: f64 = match unit.trim() {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" | "min" => 60.0,
+        "h" => 3600.0,
+        unit => return Err(format!("unknown duration unit '{}'", unit)),
+    };
+    let number: f64 = number.parse().map_err(|_| format!("invalid duration '{}'", s))?;
+    Duration::try_from_secs_f64(number * seconds).map_err(|_| format!("duration '{}' out of range", s))
+}
+
+/// Parse a send target: a literal socket address or `host:port`
+pub fn parse_target(s: &str) -> Result<String, String> {
+    if parse_socket_addr(s).is_some() {
+        return Ok(s.to_string());
+    }
+    let valid = match s.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty() && !host.contains(':') && !host.contains(['[', ']']) && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    };
+    match valid {
+        true => Ok(s.to_string()),
+        false => Err(format!(
+            "'{}' is not an address with a port, like 192.168.1.100:3000",
+            s
+        )),
+    }
+}
+
+/// Parse an MTU in bytes, as a size between 576 and 65535
+pub fn parse_mtu(s: &str) -> Result<usize, String> {
+    let mtu = parse_size(s)?;
+    match mtu {
+        576..=65_535 => Ok(mtu as usize),
+        _ => Err(format!("MTU of {} bytes outside 576..=65535", mtu)),
+    }
+}
+
+fn parse_probability(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err(format!("'{}' is not a fraction between 0 and 1", s)),
+    }
+}
+
+fn parse_burst_length(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(length) if length >= 1.0 => Ok(length),
+        _ => Err(format!("'{}' is not a burst length of at least 1", s)),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn send(args: &[&str]) -> SendArgs {
+        let cli = Cli::parse_from(["fountainflow", "send"].iter().chain(args));
+        match cli.command {
+            Command::Send(args) => args,
+            command => panic!("parsed {:?}", command),
+        }
+    }
+
+    fn receive(args: &[&str]) -> ReceiveArgs {
+        let cli = Cli::parse_from(["fountainflow", "receive"].iter().chain(args));
+        match cli.command {
+            Command::Receive(args) => args,
+            command => panic!("parsed {:?}", command),
+        }
+    }
+
     #[test]
     fn verify_cli() {
         use clap::CommandFactory;
         Cli::command().debug_assert()
     }
 
+    fn serve(args: &[&str]) -> ServeArgs {
+        let cli = Cli::parse_from(["fountainflow", "serve"].iter().chain(args));
+        match cli.command {
+            Command::Serve(args) => args,
+            command => panic!("parsed {:?}", command),
+        }
+    }
+
+    fn ctl(args: &[&str]) -> CtlArgs {
+        let cli = Cli::parse_from(["fountainflow", "ctl"].iter().chain(args));
+        match cli.command {
+            Command::Ctl(args) => args,
+            command => panic!("parsed {:?}", command),
+        }
+    }
+
     #[test]
     fn cli_parse_send() {
+        let args = send(&[
+            "--file",
+            "test.txt",
+            "--target",
+            "192.168.1.100:3000",
+            "--rate-limit",
+            "500",
+        ]);
+
+        assert_eq!(args.file, "test.txt");
+        assert_eq!(args.target, vec!["192.168.1.100:3000"]);
+        assert_eq!(args.pacing.rate_limit, 500);
+        assert_eq!(args.pacing.pacing().bytes_per_second, Some(62_500_000.0));
+        assert_eq!(args.pacing.pacing().packets_per_second, None);
+        assert_eq!(args.pacing.burst, DEFAULT_BURST as u64);
+        assert_eq!(args.mtu, None);
+        assert_eq!(args.max_mtu, MAX_PROBE_MTU);
+        assert!(!args.no_checksum);
+        assert_eq!(args.deadline, Duration::from_secs(120));
+        assert_eq!((args.esi_offset, args.esi_stride), (0, 1));
+        assert_eq!(args.key.ack_key(), b"");
+
+        let cli = Cli::parse_from(["fountainflow", "-v", "send", "-f", "a", "-t", "10.0.0.1:3000"]);
+        assert!(cli.verbose);
+    }
+
+    #[test]
+    fn cli_parse_receive() {
+        let args = receive(&["--file", "out.bin"]);
+        assert_eq!(args.file, PathBuf::from("out.bin"));
+        assert_eq!((args.listen.port, args.listen.bind), (DEFAULT_PORT, None));
+        assert!(!args.no_checkpoint);
+        assert!(!args.discard_partial);
+        assert_eq!(args.limits.limits(), ReceiveLimits::default());
+
+        let args = receive(&["-f", "out.bin", "-p", "4000", "--bind", "::1", "--no-checkpoint"]);
+        assert_eq!(args.listen.port, 4000);
+        assert_eq!(args.listen.bind, Some("::1".parse().unwrap()));
+        assert!(args.no_checkpoint);
+        assert!(receive(&["-f", "out.bin", "--discard-partial"]).discard_partial);
+
+        let args = receive(&["-f", "out.bin", "--idle-timeout", "2m", "--deadline", "1h"]);
+        assert_eq!(args.first_packet_timeout, Duration::from_secs(30));
+        assert_eq!(args.timeouts.idle_timeout, Duration::from_secs(120));
+        assert_eq!(args.timeouts.deadline, Some(Duration::from_secs(3600)));
+        assert!(receive(&["-f", "out.bin", "--wait"]).wait);
+        let args = receive(&["-f", "out.bin", "--max-size", "8GiB", "--min-symbol-size", "512"]);
+        assert_eq!(args.limits.max_size, 8 << 30);
+        assert_eq!(args.limits.min_symbol_size, 512);
+        let waiting = ["fountainflow", "receive", "-f", "out", "--wait", "--idle-timeout", "5s"];
+        assert!(Cli::try_parse_from(waiting).is_err());
+
+        assert!(Cli::try_parse_from(["fountainflow", "receive", "-f", "out", "-p", "70000"]).is_err());
+        assert!(Cli::try_parse_from(["fountainflow", "receive", "-f", "out", "--bind", "nonsense"]).is_err());
+    }
+
+    #[test]
+    fn cli_parse_serve() {
+        let args = serve(&["--inbox", "in"]);
+        assert_eq!(args.max_sessions, DEFAULT_MAX_SESSIONS);
+        assert_eq!(args.max_session_memory, DEFAULT_MAX_SESSION_MEMORY);
+        assert!(!args.control);
+        assert_eq!(args.socket.socket, default_control_socket());
+
+        let args = serve(&["-i", "in", "--max-sessions", "4", "--max-session-memory", "64MiB"]);
+        assert_eq!(args.max_sessions, 4);
+        assert_eq!(args.max_session_memory, 64 << 20);
+    }
+
+    #[test]
+    fn cli_parse_ctl() {
+        let args = ctl(&["--socket", "/run/ff.sock", "submit", "-f", "a.bin", "-t", "10.0.0.1:3000"]);
+        assert_eq!(args.socket.socket, PathBuf::from("/run/ff.sock"));
+        let CtlCommand::Submit(submit) = args.command else {
+            panic!("parsed {:?}", args.command);
+        };
+        assert_eq!(submit.target, vec!["10.0.0.1:3000"]);
+        assert_eq!((submit.rate_limit, submit.mtu), (None, None));
+        assert!(matches!(ctl(&["pause", "3"]).command, CtlCommand::Pause { transfer: 3 }));
+
+        assert!(Cli::try_parse_from(["fountainflow", "ctl", "submit", "-f", "a.bin"]).is_err());
+        assert!(Cli::try_parse_from(["fountainflow", "ctl", "cancel", "last"]).is_err());
+    }
+
+    #[test]
+    fn cli_parse_address_family() {
+        let args = send(&["-f", "test.txt", "-t", "[fe80::1%2]:3000", "-6"]);
+        assert_eq!(args.family.family(), AddressFamily::V6);
+
+        let args = receive(&["-f", "out", "-4"]);
+        assert_eq!(args.listen.family.family(), AddressFamily::V4);
+        assert_eq!(receive(&["-f", "out"]).listen.family.family(), AddressFamily::Any);
+
+        assert!(Cli::try_parse_from(["fountainflow", "receive", "-f", "out", "-4", "-6"]).is_err());
+    }
+
+    #[test]
+    fn cli_parse_multicast() {
+        let args = send(&[
+            "-f",
+            "test.txt",
+            "-t",
+            "239.1.2.3:3000",
+            "--interface",
+            "10.0.0.1",
+            "--ttl",
+            "4",
+            "--receivers",
+            "3",
+        ]);
+        assert_eq!(args.ttl, 4);
+        assert_eq!(args.receivers, 3);
+        assert_eq!(
+            args.interface.multicast_interface().unwrap(),
+            Interface::Addr("10.0.0.1".parse().unwrap())
+        );
+
+        let args = receive(&["-f", "out", "--group", "ff02::1234", "--interface", "2"]);
+        assert_eq!(args.group, Some("ff02::1234".parse().unwrap()));
+        assert_eq!(args.interface.multicast_interface().unwrap(), Interface::Index(2));
+    }
+
+    #[test]
+    fn cli_parse_esi_schedule() {
+        let args = send(&[
+            "-f",
+            "test.txt",
+            "-t",
+            "10.0.0.1:3000",
+            "--esi-offset",
+            "1",
+            "--esi-stride",
+            "3",
+        ]);
+        assert_eq!((args.esi_offset, args.esi_stride), (1, 3));
+
+        assert!(Cli::try_parse_from([
+            "fountainflow",
+            "send",
+            "-f",
+            "test.txt",
+            "-t",
+            "10.0.0.1:3000",
+            "--esi-stride",
+            "0",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn cli_parse_targets() {
+        let args = send(&[
+            "-f",
+            "test.txt",
+            "-t",
+            "10.0.0.1:3000",
+            "-t",
+            "[2001:db8::1]:3000",
+            "-t",
+            "host:3000",
+        ]);
+        assert_eq!(args.target, vec!["10.0.0.1:3000", "[2001:db8::1]:3000", "host:3000"]);
+
+        // Targets may come from a profile instead; sending without any fails
+        assert!(send(&["-f", "test.txt"]).target.is_empty());
+        for target in ["10.0.0.1", "host", "host:port", "2001:db8::1:3000", "10.0.0.1:70000"] {
+            assert!(
+                Cli::try_parse_from(["fountainflow", "send", "-f", "test.txt", "-t", target]).is_err(),
+                "accepted {}",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn cli_parse_ack_options() {
+        let args = send(&[
+            "-f",
+            "test.txt",
+            "-t",
+            "192.168.1.100:3000",
+            "--deadline",
+            "10",
+            "--key",
+            "secret",
+        ]);
+        assert_eq!(args.deadline, Duration::from_secs(10));
+        assert_eq!(args.key.ack_key(), b"secret");
+    }
+
+    #[test]
+    fn cli_parse_offline() {
+        let cli = Cli::parse_from([
+            "fountainflow",
+            "encode-to-dir",
+            "-f",
+            "test.txt",
+            "-d",
+            "/media/usb",
+            "--chunks",
+            "4",
+        ]);
+        let Command::EncodeToDir(args) = cli.command else {
+            panic!("not encode-to-dir")
+        };
+        assert_eq!(args.dir, PathBuf::from("/media/usb"));
+        assert_eq!((args.chunks, args.overhead), (4, 200));
+
+        let cli = Cli::parse_from(["fountainflow", "decode-from-dir", "-d", "/media/usb", "-f", "out"]);
+        assert!(matches!(cli.command, Command::DecodeFromDir(_)));
+    }
+
+    #[test]
+    fn cli_parse_parity() {
+        let cli = Cli::parse_from(["fountainflow", "create", "-f", "archive.tar", "--redundancy", "5"]);
+        let Command::Create(args) = cli.command else {
+            panic!("not create")
+        };
+        assert_eq!((args.volumes, args.redundancy), (2, 5));
+        assert!(matches!(
+            Cli::parse_from(["fountainflow", "repair", "-f", "archive.tar"]).command,
+            Command::Repair(_)
+        ));
+    }
+
+    #[test]
+    fn cli_parse_simulate() {
         let cli = Cli::parse_from([
             "fountainflow",
-            "--mode", "send",
-            "--file", "test.txt",
-            "--target", "192.168.1.100:3000",
-            "--rate-limit", "500",
+            "simulate",
+            "--size",
+            "1MiB",
+            "--loss",
+            "0.1",
+            "--burst-length",
+            "4",
+            "--delay",
+            "20ms",
         ]);
+        let Command::Simulate(args) = cli.command else {
+            panic!("not simulate")
+        };
+        assert_eq!(args.size, 1 << 20);
+        assert_eq!(args.delay, Duration::from_millis(20));
+        let impairments = args.impairments();
+        assert!((impairments.loss.mean_loss() - 0.1).abs() < 1e-9);
+        assert!(matches!(impairments.loss, LossModel::GilbertElliott { .. }));
 
-        assert_eq!(cli.mode, "send");
-        assert_eq!(cli.file, "test.txt");
-        assert_eq!(cli.target, "192.168.1.100:3000");
-        assert_eq!(cli.rate_limit, 500);
-        assert!(!cli.verbose);
-        assert!(!cli.no_checksum);
+        assert!(Cli::try_parse_from(["fountainflow", "simulate", "--loss", "1.5"]).is_err());
+        assert!(Cli::try_parse_from(["fountainflow", "simulate", "--mtu", "100"]).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1500"), Ok(1500));
+        assert_eq!(parse_size("10MB"), Ok(10_000_000));
+        assert_eq!(parse_size("64 MiB"), Ok(64 << 20));
+        assert_eq!(parse_size("1.5k"), Ok(1500));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert!(parse_size("ten").is_err());
+        assert!(parse_size("5 parsecs").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("3 fortnights").is_err());
+    }
+}
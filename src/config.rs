@@ -0,0 +1,358 @@
+//! Configuration files with named profiles
+//!
+//! Settings are read from the system file, then the user's, then a file
+//! given with `--config`, each overriding the ones before. A `[defaults]`
+//! table applies to every run and `[profiles.<name>]` tables to runs with
+//! `--profile <name>`:
+//!
+//! ```toml
+//! [defaults]
+//! rate-limit = 500
+//!
+//! [profiles.backup-link]
+//! targets = ["10.1.0.5:3000"]
+//! rate-limit = 100
+//! mtu = 1400
+//! key = "shared secret"
+//! overhead = 25
+//! ```
+//!
+//! Options given on the command line win over every file.
+
+use crate::cli::{parse_duration, parse_mtu, parse_size, parse_target, Command};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Configuration file read by every user of the machine
+pub const SYSTEM_CONFIG: &str = "/etc/fountainflow/config.toml";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read {}: {source}", path.display())]
+    Io { path: PathBuf, source: std::io::Error },
+    #[error("Invalid configuration in {}: {source}", path.display())]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[error("No profile named '{0}' in the configuration")]
+    UnknownProfile(String),
+}
+
+/// Settings of a profile; anything left out falls back to the profile
+/// below it, and then to the built-in default
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    /// Destinations a send goes to when no `--target` is given
+    #[serde(deserialize_with = "targets")]
+    pub targets: Option<Vec<String>>,
+    /// Port receivers listen on
+    pub port: Option<u16>,
+    /// Rate limit in Mbps
+    pub rate_limit: Option<u32>,
+    /// Packets per second limit
+    pub packet_rate: Option<u32>,
+    /// Largest burst in bytes, as a number or with a unit
+    #[serde(deserialize_with = "size")]
+    pub burst: Option<u64>,
+    /// Path MTU, skipping discovery
+    #[serde(deserialize_with = "mtu")]
+    pub mtu: Option<usize>,
+    /// Largest MTU path MTU discovery tries
+    #[serde(deserialize_with = "mtu")]
+    pub max_mtu: Option<usize>,
+    /// Pre-shared key for completion acknowledgements
+    pub key: Option<String>,
+    /// Percentage of repair symbols to send before stopping, instead of
+    /// sending until acknowledged
+    pub overhead: Option<u32>,
+    /// How long a sender waits for acknowledgements, in seconds or with a unit
+    #[serde(deserialize_with = "duration")]
+    pub deadline: Option<Duration>,
+    /// How long a receiver goes without packets of its transfer
+    #[serde(deserialize_with = "duration")]
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Profile {
+    /// This profile with the settings of `over` taking precedence
+    pub fn overridden_by(self, over: Profile) -> Profile {
+        Profile {
+            targets: over.targets.or(self.targets),
+            port: over.port.or(self.port),
+            rate_limit: over.rate_limit.or(self.rate_limit),
+            packet_rate: over.packet_rate.or(self.packet_rate),
+            burst: over.burst.or(self.burst),
+            mtu: over.mtu.or(self.mtu),
+            max_mtu: over.max_mtu.or(self.max_mtu),
+            key: over.key.or(self.key),
+            overhead: over.overhead.or(self.overhead),
+            deadline: over.deadline.or(self.deadline),
+            idle_timeout: over.idle_timeout.or(self.idle_timeout),
+        }
+    }
+
+    /// Fill in the options of `command` not given on the command line,
+    /// whose arguments were parsed into `matches`
+    pub fn apply(&self, command: &mut Command, matches: &ArgMatches) {
+        // Only options given explicitly win over the profile
+        let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+        match command {
+            Command::Send(args) => {
+                set(&mut args.target, self.targets.clone(), unset("target"));
+                set(&mut args.pacing.rate_limit, self.rate_limit, unset("rate_limit"));
+                set(
+                    &mut args.pacing.packet_rate,
+                    self.packet_rate.map(Some),
+                    unset("packet_rate"),
+                );
+                set(&mut args.pacing.burst, self.burst, unset("burst"));
+                set(&mut args.mtu, self.mtu.map(Some), unset("mtu"));
+                set(&mut args.max_mtu, self.max_mtu, unset("max_mtu"));
+                set(&mut args.key.key, self.key.clone().map(Some), unset("key"));
+                set(&mut args.overhead, self.overhead.map(Some), unset("overhead"));
+                set(&mut args.deadline, self.deadline, unset("deadline"));
+            }
+            Command::Receive(args) => {
+                set(&mut args.listen.port, self.port, unset("port"));
+                set(&mut args.key.key, self.key.clone().map(Some), unset("key"));
+                set(&mut args.timeouts.idle_timeout, self.idle_timeout, unset("idle_timeout"));
+            }
+            Command::Serve(args) => {
+                set(&mut args.listen.port, self.port, unset("port"));
+                set(&mut args.key.key, self.key.clone().map(Some), unset("key"));
+                set(&mut args.timeouts.idle_timeout, self.idle_timeout, unset("idle_timeout"));
+            }
+            Command::Simulate(args) => {
+                set(&mut args.pacing.rate_limit, self.rate_limit, unset("rate_limit"));
+                set(
+                    &mut args.pacing.packet_rate,
+                    self.packet_rate.map(Some),
+                    unset("packet_rate"),
+                );
+                set(&mut args.pacing.burst, self.burst, unset("burst"));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn set<T>(option: &mut T, value: Option<T>, unset: bool) {
+    if let (Some(value), true) = (value, unset) {
+        *option = value;
+    }
+}
+
+/// A setting written as a number or as a string with a unit
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Setting {
+    Number(u64),
+    Text(String),
+}
+
+/// Deserialize a setting with the parser of the matching option
+fn parsed<'de, D, T>(deserializer: D, parse: fn(&str) -> Result<T, String>) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let text = match Setting::deserialize(deserializer)? {
+        Setting::Number(number) => number.to_string(),
+        Setting::Text(text) => text,
+    };
+    parse(&text).map(Some).map_err(serde::de::Error::custom)
+}
+
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    parsed(deserializer, parse_size)
+}
+
+fn mtu<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+    parsed(deserializer, parse_mtu)
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    parsed(deserializer, parse_duration)
+}
+
+fn targets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|target| parse_target(target))
+        .collect::<Result<_, _>>()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Contents of one or more configuration files
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Settings for every run
+    pub defaults: Profile,
+    /// Settings selected with `--profile`
+    pub profiles: HashMap<String, Profile>,
+}
+
+impl Config {
+    /// Parse the configuration file at `path`
+    pub fn read(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Read the system and user configuration files that exist, then
+    /// `explicit`, which must exist
+    pub fn load(explicit: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut config = Config::default();
+        let found = [Some(PathBuf::from(SYSTEM_CONFIG)), user_config()];
+        for path in found.iter().flatten().filter(|path| path.is_file()) {
+            config = config.overridden_by(Config::read(path)?);
+        }
+        if let Some(path) = explicit {
+            config = config.overridden_by(Config::read(path)?);
+        }
+        Ok(config)
+    }
+
+    /// This configuration with the settings of `over` taking precedence
+    pub fn overridden_by(mut self, over: Config) -> Config {
+        self.defaults = self.defaults.overridden_by(over.defaults);
+        for (name, profile) in over.profiles {
+            let merged = self.profiles.remove(&name).unwrap_or_default().overridden_by(profile);
+            self.profiles.insert(name, merged);
+        }
+        self
+    }
+
+    /// Settings of the profile `name` over the defaults, or the defaults
+    /// alone
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile, ConfigError> {
+        let Some(name) = name else {
+            return Ok(self.defaults.clone());
+        };
+        match self.profiles.get(name) {
+            Some(profile) => Ok(self.defaults.clone().overridden_by(profile.clone())),
+            None => Err(ConfigError::UnknownProfile(name.to_string())),
+        }
+    }
+}
+
+/// The user's configuration file, in `$XDG_CONFIG_HOME` or `~/.config`
+pub fn user_config() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("fountainflow").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+    use clap::{CommandFactory, FromArgMatches};
+
+    const CONFIG: &str = r#"
+        [defaults]
+        rate-limit = 500
+        key = "default key"
+
+        [profiles.slow]
+        targets = ["10.1.0.5:3000", "backup.example:3001"]
+        rate-limit = 100
+        burst = "64KiB"
+        mtu = 1400
+        overhead = 25
+        deadline = "5m"
+        idle-timeout = "2m"
+        port = 3001
+    "#;
+
+    /// Parse `args` like the command line and apply `profile`
+    fn parse(args: &[&str], profile: &Profile) -> Command {
+        let matches = Cli::command().get_matches_from(args);
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+        profile.apply(&mut cli.command, matches);
+        cli.command
+    }
+
+    #[test]
+    fn test_profiles() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let defaults = config.profile(None).unwrap();
+        assert_eq!(defaults.rate_limit, Some(500));
+        assert_eq!(defaults.targets, None);
+
+        let slow = config.profile(Some("slow")).unwrap();
+        assert_eq!(slow.rate_limit, Some(100));
+        assert_eq!(slow.key.as_deref(), Some("default key"));
+        assert_eq!(slow.burst, Some(64 << 10));
+        assert_eq!(slow.deadline, Some(Duration::from_secs(300)));
+        assert!(matches!(
+            config.profile(Some("fast")),
+            Err(ConfigError::UnknownProfile(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(toml::from_str::<Config>("[defaults]\nmtu = 100").is_err());
+        assert!(toml::from_str::<Config>("[defaults]\ntargets = [\"nowhere\"]").is_err());
+        assert!(toml::from_str::<Config>("[defaults]\nrate = 100").is_err());
+    }
+
+    #[test]
+    fn test_layers() {
+        let system: Config = toml::from_str(CONFIG).unwrap();
+        let user: Config = toml::from_str("[profiles.slow]\nrate-limit = 50\n[profiles.other]\nport = 4000").unwrap();
+        let config = system.overridden_by(user);
+        let slow = config.profile(Some("slow")).unwrap();
+        assert_eq!(slow.rate_limit, Some(50));
+        assert_eq!(slow.mtu, Some(1400));
+        assert_eq!(config.profile(Some("other")).unwrap().port, Some(4000));
+    }
+
+    #[test]
+    fn test_flags_win() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let slow = config.profile(Some("slow")).unwrap();
+
+        let Command::Send(args) = parse(&["fountainflow", "send", "-f", "a", "--rate-limit", "200"], &slow) else {
+            panic!("not a send");
+        };
+        assert_eq!(args.target, vec!["10.1.0.5:3000", "backup.example:3001"]);
+        assert_eq!(args.pacing.rate_limit, 200);
+        assert_eq!(args.pacing.burst, 64 << 10);
+        assert_eq!(args.mtu, Some(1400));
+        assert_eq!(args.overhead, Some(25));
+        assert_eq!(args.key.key.as_deref(), Some("default key"));
+
+        let Command::Send(args) = parse(&["fountainflow", "send", "-f", "a", "-t", "10.0.0.1:3000"], &slow) else {
+            panic!("not a send");
+        };
+        assert_eq!(args.target, vec!["10.0.0.1:3000"]);
+
+        let Command::Receive(args) = parse(&["fountainflow", "receive", "-f", "a"], &slow) else {
+            panic!("not a receive");
+        };
+        assert_eq!(args.listen.port, 3001);
+        assert_eq!(args.timeouts.idle_timeout, Duration::from_secs(120));
+        let receive = ["fountainflow", "receive", "-f", "a", "-p", "3000", "--idle-timeout", "10s"];
+        let Command::Receive(args) = parse(&receive, &slow) else {
+            panic!("not a receive");
+        };
+        assert_eq!(args.listen.port, 3000);
+        assert_eq!(args.timeouts.idle_timeout, Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,319 @@
+//! Delay- and loss-based congestion control for the sender
+//!
+//! A rate-based variant of LEDBAT (RFC 6817): the sender aims to keep the
+//! queuing delay reported by the receiver near `TARGET_DELAY`, ramps up
+//! while the path is idle and backs off multiplicatively on loss. The rate
+//! never exceeds the configured ceiling.
+
+use crate::protocol::ProgressReport;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Queuing delay the controller tries to maintain (RFC 6817 Section 2.5)
+pub const TARGET_DELAY: Duration = Duration::from_millis(100);
+
+/// Lowest rate the controller will fall back to, in bytes per second
+pub const MIN_RATE: f64 = 64.0 * 1024.0;
+
+/// Highest rate the controller will ramp up to when no ceiling is configured
+/// (100 Gbit/s), in bytes per second
+pub const MAX_RATE: f64 = 12.5e9;
+
+/// Rate the controller starts slow start from, in bytes per second
+const INITIAL_RATE: f64 = 1024.0 * 1024.0;
+
+/// Fraction of the current rate gained or lost per report at full off-target
+const GAIN: f64 = 0.25;
+
+/// Interval loss rate above which the rate is cut
+const LOSS_THRESHOLD: f64 = 0.02;
+
+/// Reports missing for this long are treated as a congestion signal
+const REPORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Width and number of the base delay history buckets (RFC 6817 Section 3.4.2)
+const BASE_HISTORY_INTERVAL: Duration = Duration::from_secs(60);
+const BASE_HISTORY_LEN: usize = 10;
+
+/// Microseconds elapsed since `epoch`, wrapping at `u32::MAX`
+pub fn clock_us(epoch: Instant) -> u32 {
+    epoch.elapsed().as_micros() as u32
+}
+
+/// Whether wrapping clock value `a` is earlier than `b`
+fn wrapping_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Receiver-side tracker for the one-way delay samples in progress reports
+#[derive(Debug)]
+pub struct DelaySampler {
+    epoch: Instant,
+    min_delay: Option<u32>,
+}
+
+impl DelaySampler {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            min_delay: None,
+        }
+    }
+
+    /// Record the arrival of a packet stamped with the sender clock
+    pub fn observe(&mut self, timestamp_us: u32) {
+        let delay = clock_us(self.epoch).wrapping_sub(timestamp_us);
+        if self.min_delay.is_none_or(|min| wrapping_lt(delay, min)) {
+            self.min_delay = Some(delay);
+        }
+    }
+
+    /// Smallest delay since the previous call, or `None` if nothing arrived
+    pub fn take(&mut self) -> Option<u32> {
+        self.min_delay.take()
+    }
+}
+
+impl Default for DelaySampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sender-side rate controller fed by receiver progress reports
+#[derive(Debug)]
+pub struct CongestionController {
+    /// Maximum rate in bytes per second
+    ceiling: f64,
+    /// Current rate in bytes per second
+    rate: f64,
+    /// Doubling the rate each report until a congestion signal
+    slow_start: bool,
+    /// Minimum delay per history bucket, newest last
+    base_history: VecDeque<(Instant, u32)>,
+    /// Counters from the previous report
+    last_received: Option<(u64, u32)>,
+    last_report: Instant,
+}
+
+impl CongestionController {
+    /// Create a controller limited to `ceiling` bytes per second
+    pub fn new(ceiling: f64) -> Self {
+        let ceiling = ceiling.min(MAX_RATE);
+        Self {
+            ceiling,
+            rate: INITIAL_RATE.min(ceiling),
+            slow_start: true,
+            base_history: VecDeque::with_capacity(BASE_HISTORY_LEN),
+            last_received: None,
+            last_report: Instant::now(),
+        }
+    }
+
+    /// Current send rate in bytes per second
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Adjust the rate for a new progress report and return it
+    pub fn on_report(&mut self, report: &ProgressReport, now: Instant) -> f64 {
+        self.last_report = now;
+
+        let loss = self.interval_loss(report);
+        // A report without a delay sample must not become the base delay
+        let queuing_delay = report.delay_us.map(|delay_us| self.queuing_delay(delay_us, now));
+
+        if loss > LOSS_THRESHOLD {
+            // Multiplicative decrease proportional to the loss, at most half
+            self.slow_start = false;
+            self.rate *= 1.0 - loss.min(1.0) / 2.0;
+        } else if let Some(queuing_delay) = queuing_delay {
+            let target = TARGET_DELAY.as_secs_f64();
+            let off_target = ((target - queuing_delay.as_secs_f64()) / target).clamp(-1.0, 1.0);
+            if self.slow_start && off_target > 0.5 {
+                self.rate *= 2.0;
+            } else {
+                self.slow_start = false;
+                self.rate *= 1.0 + GAIN * off_target;
+            }
+        }
+
+        self.rate = self.rate.clamp(MIN_RATE.min(self.ceiling), self.ceiling);
+        self.rate
+    }
+
+    /// Halve the rate if the receiver has gone quiet, returning the new rate
+    /// when it changed
+    pub fn on_tick(&mut self, now: Instant) -> Option<f64> {
+        if now.duration_since(self.last_report) < REPORT_TIMEOUT {
+            return None;
+        }
+        // Like a TCP retransmission timeout: back off, then probe again
+        self.last_report = now;
+        self.slow_start = true;
+        self.rate = (self.rate / 2.0).max(MIN_RATE.min(self.ceiling));
+        Some(self.rate)
+    }
+
+    /// Fraction of packets sent since the previous report that were lost
+    fn interval_loss(&mut self, report: &ProgressReport) -> f64 {
+        let current = (report.symbols_received, report.highest_seq);
+        let Some((received, highest)) = self.last_received.replace(current) else {
+            return 0.0;
+        };
+
+        let sent = report.highest_seq.wrapping_sub(highest) as f64;
+        let arrived = report.symbols_received.saturating_sub(received) as f64;
+        if sent <= 0.0 {
+            return 0.0;
+        }
+        (1.0 - arrived / sent).max(0.0)
+    }
+
+    /// Reported delay above the base (minimum) delay seen recently
+    fn queuing_delay(&mut self, delay_us: u32, now: Instant) -> Duration {
+        match self.base_history.back_mut() {
+            Some((start, min)) if now.duration_since(*start) < BASE_HISTORY_INTERVAL => {
+                if wrapping_lt(delay_us, *min) {
+                    *min = delay_us;
+                }
+            }
+            _ => {
+                if self.base_history.len() == BASE_HISTORY_LEN {
+                    self.base_history.pop_front();
+                }
+                self.base_history.push_back((now, delay_us));
+            }
+        }
+
+        let base = self
+            .base_history
+            .iter()
+            .map(|&(_, min)| min)
+            .reduce(|a, b| if wrapping_lt(b, a) { b } else { a })
+            .unwrap_or(delay_us);
+        Duration::from_micros(delay_us.wrapping_sub(base) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(symbols_received: u64, highest_seq: u32, delay_us: u32) -> ProgressReport {
+        ProgressReport {
+            session_id: 1,
+            symbols_received,
+            blocks_decoded: 0,
+            num_blocks: 1,
+            highest_seq,
+            delay_us: Some(delay_us),
+        }
+    }
+
+    #[test]
+    fn test_ramps_up_to_ceiling() {
+        let ceiling = 100.0 * 1024.0 * 1024.0;
+        let mut controller = CongestionController::new(ceiling);
+        let now = Instant::now();
+
+        let mut previous = controller.rate();
+        for i in 1..40u32 {
+            let rate = controller.on_report(&report(i as u64 * 1000, i * 1000 - 1, 5_000), now);
+            assert!(rate >= previous);
+            previous = rate;
+        }
+        assert_eq!(controller.rate(), ceiling);
+    }
+
+    #[test]
+    fn test_unlimited_ceiling_is_bounded() {
+        let mut controller = CongestionController::new(f64::INFINITY);
+        let now = Instant::now();
+        for i in 1..100u32 {
+            controller.on_report(&report(i as u64 * 1000, i * 1000 - 1, 5_000), now);
+        }
+        assert_eq!(controller.rate(), MAX_RATE);
+    }
+
+    #[test]
+    fn test_backs_off_on_loss() {
+        let mut controller = CongestionController::new(1e9);
+        let now = Instant::now();
+        controller.on_report(&report(1000, 999, 5_000), now);
+        let before = controller.rate();
+
+        // Only half of the next 1000 packets arrived
+        let after = controller.on_report(&report(1500, 1999, 5_000), now);
+        assert!(after < before);
+        assert!(after >= before * 0.5);
+    }
+
+    #[test]
+    fn test_backs_off_on_queuing_delay() {
+        let mut controller = CongestionController::new(1e9);
+        let now = Instant::now();
+        controller.on_report(&report(1000, 999, 5_000), now);
+        let before = controller.rate();
+
+        // Delay 200ms above the base: twice the target
+        let after = controller.on_report(&report(2000, 1999, 205_000), now);
+        assert!(after < before);
+    }
+
+    #[test]
+    fn test_clock_offset_is_irrelevant() {
+        // Delays straddling the u32 wrap still yield zero queuing delay
+        let mut controller = CongestionController::new(1e9);
+        let now = Instant::now();
+        controller.on_report(&report(1000, 999, u32::MAX - 10), now);
+        let before = controller.rate();
+        let after = controller.on_report(&report(2000, 1999, 5), now);
+        assert!(after >= before);
+    }
+
+    #[test]
+    fn test_reports_without_delay_samples() {
+        let mut controller = CongestionController::new(1e9);
+        let now = Instant::now();
+        let idle = |symbols_received, highest_seq| ProgressReport {
+            delay_us: None,
+            ..report(symbols_received, highest_seq, 0)
+        };
+
+        // Intervals without samples in between leave the rate and base alone,
+        // with clocks far enough apart that a base of 0 would mean queuing
+        controller.on_report(&report(1000, 999, 500_000), now);
+        let ramped = controller.on_report(&report(2000, 1999, 500_000), now);
+        assert_eq!(controller.on_report(&idle(2000, 1999), now), ramped);
+        controller.on_report(&report(3000, 2999, 500_000), now);
+        assert_eq!(controller.on_report(&idle(3000, 2999), now), controller.rate());
+
+        // Samples at the real base are no queuing delay
+        let before = controller.rate();
+        let after = controller.on_report(&report(4000, 3999, 500_100), now);
+        assert!(after >= before);
+    }
+
+    #[test]
+    fn test_silence_halves_rate() {
+        let mut controller = CongestionController::new(1e9);
+        let now = Instant::now();
+        let rate = controller.rate();
+        assert_eq!(controller.on_tick(now), None);
+        assert_eq!(controller.on_tick(now + REPORT_TIMEOUT), Some(rate / 2.0));
+    }
+
+    #[test]
+    fn test_delay_sampler() {
+        let mut sampler = DelaySampler::new();
+        assert_eq!(sampler.take(), None);
+
+        let now = clock_us(sampler.epoch);
+        sampler.observe(now.wrapping_sub(1_000_000));
+        sampler.observe(now.wrapping_sub(10));
+        let delay = sampler.take().unwrap();
+        assert!((10..1_000_000).contains(&delay));
+        assert_eq!(sampler.take(), None);
+    }
+}
@@ -0,0 +1,553 @@
+//! Local control interface of a daemon
+//!
+//! A daemon started with a control socket answers JSON-RPC 2.0 requests on
+//! a Unix domain socket, one JSON object per line each way, by handing them
+//! to its `SessionManager`:
+//!
+//! - `submit` with `{file, targets, rate_limit?, mtu?}` starts sending a
+//!   file, directory or glob and returns `{transfer}`
+//! - `list` returns every transfer running or recently finished
+//! - `status`, `pause`, `resume` and `cancel` with `{transfer}` act on one
+//!   transfer and return it
+//!
+//! Whoever can reach the socket can make the daemon send any file it can
+//! read, so the socket is only accessible to its owner. It is bound in a
+//! private directory and only then moved into place, and never in a
+//! directory others can write to or that belongs to another user.
+
+use crate::daemon::{DaemonError, SessionManager, TransferInfo};
+use crate::transfer::{CancellationToken, Sender, Source};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinSet;
+
+/// Error codes defined by JSON-RPC
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+/// Error codes of the daemon
+pub const UNKNOWN_TRANSFER: i64 = -32000;
+pub const NOT_PAUSABLE: i64 = -32001;
+pub const TRANSFER_FINISHED: i64 = -32002;
+
+#[derive(Debug, Error)]
+pub enum ControlError {
+    #[error("Control socket error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed control message: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("A daemon is already listening on {0}")]
+    InUse(PathBuf),
+    #[error("{0} exists and is not a socket")]
+    NotASocket(PathBuf),
+    #[error("{0} is writable by other users or belongs to one")]
+    InsecureDirectory(PathBuf),
+    #[error("The daemon closed the connection")]
+    Closed,
+    #[error("{message}")]
+    Remote { code: i64, message: String },
+}
+
+/// Transfer for a daemon to send
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Submission {
+    /// File, directory or glob, as the daemon sees it
+    pub file: String,
+    pub targets: Vec<String>,
+    /// Rate limit in Mbps, 0 for unlimited; 1000 unless given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<u32>,
+    /// Path MTU; discovered unless given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct TransferParams {
+    transfer: u64,
+}
+
+#[derive(Deserialize)]
+struct Submitted {
+    transfer: u64,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    #[serde(default)]
+    result: Value,
+    #[serde(default)]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<DaemonError> for RpcError {
+    fn from(error: DaemonError) -> Self {
+        let code = match error {
+            DaemonError::UnknownTransfer(_) => UNKNOWN_TRANSFER,
+            DaemonError::NotPausable(_) => NOT_PAUSABLE,
+            DaemonError::Finished(_) => TRANSFER_FINISHED,
+            DaemonError::Transport(_) => INTERNAL_ERROR,
+        };
+        Self::new(code, error)
+    }
+}
+
+/// Control socket of a daemon
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    handler: Handler,
+}
+
+/// Answers requests to a daemon
+#[derive(Clone)]
+struct Handler {
+    manager: SessionManager,
+    key: Vec<u8>,
+}
+
+/// Make sure `dir` exists and only the user, or root, can write to it
+fn check_directory(dir: &Path) -> Result<(), ControlError> {
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+        _ => {}
+    }
+    let metadata = std::fs::metadata(dir)?;
+    // SAFETY: getuid has no preconditions and cannot fail
+    let uid = unsafe { libc::getuid() };
+    if metadata.mode() & 0o022 != 0 || (metadata.uid() != uid && metadata.uid() != 0) {
+        return Err(ControlError::InsecureDirectory(dir.to_path_buf()));
+    }
+    Ok(())
+}
+
+/// Bind a socket in a directory of `parent` only the user can enter, make
+/// it accessible to the user alone and only then move it to `path`, so no
+/// one can connect in between
+fn bind_private(parent: &Path, path: &Path) -> Result<UnixListener, ControlError> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let staging = parent.join(format!(".{}.{}", name, std::process::id()));
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&staging);
+    Ok(bound?)
+}
+
+impl ControlServer {
+    /// Listen on `path` for requests to `manager`, replacing a socket left
+    /// behind by a daemon that is gone
+    ///
+    /// A missing parent directory is created accessible only to the user;
+    /// one that others can write to or that belongs to another user is
+    /// refused.
+    pub async fn bind(path: impl Into<PathBuf>, manager: SessionManager) -> Result<Self, ControlError> {
+        let path = path.into();
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        check_directory(&parent)?;
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if !metadata.file_type().is_socket() => return Err(ControlError::NotASocket(path)),
+            Ok(_) if UnixStream::connect(&path).await.is_ok() => return Err(ControlError::InUse(path)),
+            Ok(_) => std::fs::remove_file(&path)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let listener = bind_private(&parent, &path)?;
+        Ok(Self {
+            listener,
+            path,
+            handler: Handler {
+                manager,
+                key: Vec::new(),
+            },
+        })
+    }
+
+    /// Pre-shared key submitted transfers check acknowledgements with
+    pub fn key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.handler.key = key.into();
+        self
+    }
+
+    /// Answer requests until `cancel` is cancelled, then remove the socket
+    pub async fn serve(self, cancel: &CancellationToken) -> Result<(), ControlError> {
+        let mut connections = JoinSet::new();
+        let result = loop {
+            let stream = tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => break Err(e.into()),
+                },
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                _ = cancel.cancelled() => break Ok(()),
+            };
+            let handler = self.handler.clone();
+            connections.spawn(async move {
+                if let Err(e) = handler.connection(stream).await {
+                    log::debug!("Control connection failed: {}", e);
+                }
+            });
+        };
+
+        // Connections hold on to the daemon, so they end with it
+        connections.shutdown().await;
+        let _ = std::fs::remove_file(&self.path);
+        result
+    }
+}
+
+impl Handler {
+    async fn connection(&self, stream: UnixStream) -> Result<(), ControlError> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.answer(&line) {
+                writer.write_all(format!("{}\n", response).as_bytes()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Response to the request on `line`, none for a notification
+    fn answer(&self, line: &str) -> Option<Value> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e))),
+        };
+        let id = request.get("id").cloned().unwrap_or_default();
+        let request: Request = match serde_json::from_value(request) {
+            Ok(request) => request,
+            Err(e) => return Some(error_response(id, RpcError::new(INVALID_REQUEST, e))),
+        };
+        let result = self.call(&request.method, request.params);
+        let id = request.id?;
+        Some(match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(error) => error_response(id, error),
+        })
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let manager = &self.manager;
+        let info = match method {
+            "submit" => {
+                let submission: Submission = params_of(params)?;
+                if submission.targets.is_empty() {
+                    return Err(RpcError::new(INVALID_PARAMS, "No targets to send to"));
+                }
+                let transfer = manager.send(self.sender(submission));
+                return Ok(json!({ "transfer": transfer }));
+            }
+            "list" => return Ok(to_value(manager.list())),
+            "status" => manager.get(params_of::<TransferParams>(params)?.transfer)?,
+            "pause" => manager.pause(params_of::<TransferParams>(params)?.transfer)?,
+            "resume" => manager.resume(params_of::<TransferParams>(params)?.transfer)?,
+            "cancel" => {
+                let transfer = params_of::<TransferParams>(params)?.transfer;
+                manager.cancel(transfer)?;
+                manager.get(transfer)?
+            }
+            _ => return Err(RpcError::new(METHOD_NOT_FOUND, format!("No method {}", method))),
+        };
+        Ok(to_value(info))
+    }
+
+    fn sender(&self, submission: Submission) -> Sender {
+        let mut sender = Sender::new(Source::Path(submission.file)).key(self.key.clone());
+        for target in submission.targets {
+            sender = sender.target(target);
+        }
+        if let Some(mbps) = submission.rate_limit {
+            sender = sender.rate_limit(mbps);
+        }
+        if let Some(mtu) = submission.mtu {
+            sender = sender.mtu(mtu);
+        }
+        sender
+    }
+}
+
+fn params_of<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+fn to_value(value: impl Serialize) -> Value {
+    serde_json::to_value(value).expect("transfer infos serialize")
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": error})
+}
+
+/// Connection to the control socket of a daemon
+pub struct ControlClient {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+}
+
+impl ControlClient {
+    /// Connect to the daemon listening on `path`
+    pub async fn connect(path: &Path) -> Result<Self, ControlError> {
+        let (reader, writer) = UnixStream::connect(path).await?.into_split();
+        Ok(Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            next_id: 0,
+        })
+    }
+
+    /// Call `method` with `params`, returning its result
+    pub async fn call<T: DeserializeOwned>(&mut self, method: &str, params: Value) -> Result<T, ControlError> {
+        self.next_id += 1;
+        let request = json!({"jsonrpc": "2.0", "id": self.next_id, "method": method, "params": params});
+        self.writer.write_all(format!("{}\n", request).as_bytes()).await?;
+        let line = self.lines.next_line().await?.ok_or(ControlError::Closed)?;
+        let response: Response = serde_json::from_str(&line)?;
+        match response.error {
+            Some(RpcError { code, message }) => Err(ControlError::Remote { code, message }),
+            None => Ok(serde_json::from_value(response.result)?),
+        }
+    }
+
+    /// Start sending `submission`, returning the number of its transfer
+    pub async fn submit(&mut self, submission: &Submission) -> Result<u64, ControlError> {
+        let submitted: Submitted = self.call("submit", serde_json::to_value(submission)?).await?;
+        Ok(submitted.transfer)
+    }
+
+    /// Transfers running and recently finished, oldest first
+    pub async fn list(&mut self) -> Result<Vec<TransferInfo>, ControlError> {
+        self.call("list", json!({})).await
+    }
+
+    /// Transfer `id`
+    pub async fn status(&mut self, id: u64) -> Result<TransferInfo, ControlError> {
+        self.call("status", json!({ "transfer": id })).await
+    }
+
+    /// Hold off sending outgoing transfer `id`
+    pub async fn pause(&mut self, id: u64) -> Result<TransferInfo, ControlError> {
+        self.call("pause", json!({ "transfer": id })).await
+    }
+
+    /// Go on sending outgoing transfer `id`
+    pub async fn resume(&mut self, id: u64) -> Result<TransferInfo, ControlError> {
+        self.call("resume", json!({ "transfer": id })).await
+    }
+
+    /// Cancel transfer `id`, telling its peers
+    pub async fn cancel(&mut self, id: u64) -> Result<TransferInfo, ControlError> {
+        self.call("cancel", json!({ "transfer": id })).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::{Daemon, TransferState};
+    use crate::simulator::{Impairments, Network};
+    use crate::transfer::{Output, Receiver};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_control() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("control.sock");
+        let file = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::write(&file, &data).unwrap();
+
+        let network = Network::new(1, Impairments::default());
+        let transport = network.bind("10.0.0.2:3000".parse().unwrap()).unwrap();
+        let receiver = Receiver::new(Output::Memory)
+            .transport(Arc::new(network.bind("10.0.0.4:3000".parse().unwrap()).unwrap()))
+            .deadline(Some(Duration::from_secs(20)));
+        let cancel = CancellationToken::new();
+        let manager = Daemon::new(Arc::new(transport), dir.path())
+            .cancel(cancel.clone())
+            .build();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let received = runtime.block_on(async {
+            let server = ControlServer::bind(&socket, manager.clone()).await.unwrap();
+            assert!(matches!(
+                ControlServer::bind(&socket, manager.clone()).await,
+                Err(ControlError::InUse(_))
+            ));
+            let control = async {
+                let mut client = ControlClient::connect(&socket).await.unwrap();
+                assert!(client.list().await.unwrap().is_empty());
+                let unknown = client.status(1).await;
+                assert!(matches!(
+                    unknown,
+                    Err(ControlError::Remote {
+                        code: UNKNOWN_TRANSFER,
+                        ..
+                    })
+                ));
+                let unknown = client.call::<Value>("frobnicate", json!({})).await;
+                assert!(matches!(
+                    unknown,
+                    Err(ControlError::Remote {
+                        code: METHOD_NOT_FOUND,
+                        ..
+                    })
+                ));
+
+                let submission = Submission {
+                    file: file.display().to_string(),
+                    targets: vec!["10.0.0.4:3000".into()],
+                    rate_limit: Some(0),
+                    mtu: Some(1500),
+                };
+                let id = client.submit(&submission).await.unwrap();
+                let received = receiver.receive().await;
+                loop {
+                    let info = client.status(id).await.unwrap();
+                    if info.state.is_finished() {
+                        assert_eq!(info.state, TransferState::Complete);
+                        assert_eq!((info.bytes, info.done), (Some(100_000), 100_000));
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                let finished = client.pause(id).await;
+                assert!(matches!(
+                    finished,
+                    Err(ControlError::Remote {
+                        code: TRANSFER_FINISHED,
+                        ..
+                    })
+                ));
+                assert_eq!(client.list().await.unwrap().len(), 1);
+                cancel.cancel();
+                received
+            };
+            let serving = async {
+                let (served, controlled) = tokio::join!(manager.serve(), server.serve(&cancel));
+                served.unwrap();
+                controlled.unwrap();
+            };
+            let (received, ()) = tokio::join!(control, serving);
+            received.unwrap()
+        });
+        assert_eq!(received.data, Some(data));
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn test_socket_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let network = Network::new(2, Impairments::default());
+        let transport = network.bind("10.0.0.2:3000".parse().unwrap()).unwrap();
+        let manager = Daemon::new(Arc::new(transport), dir.path()).build();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // A missing directory is created private, the socket is the user's alone
+        let socket = dir.path().join("run/control.sock");
+        runtime.block_on(async {
+            let _server = ControlServer::bind(&socket, manager.clone()).await.unwrap();
+        });
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().mode() & 0o777;
+        assert_eq!(mode(&dir.path().join("run")), 0o700);
+        assert_eq!(mode(&socket), 0o600);
+        assert_eq!(std::fs::read_dir(dir.path().join("run")).unwrap().count(), 1);
+
+        // Nor is it bound where others could swap it
+        let shared = dir.path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let bound = runtime.block_on(ControlServer::bind(shared.join("control.sock"), manager));
+        assert!(matches!(bound, Err(ControlError::InsecureDirectory(_))));
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        let manager = Daemon::new(
+            Arc::new(
+                Network::new(1, Impairments::default())
+                    .bind("10.0.0.2:3000".parse().unwrap())
+                    .unwrap(),
+            ),
+            "inbox",
+        )
+        .build();
+        let handler = Handler {
+            manager,
+            key: Vec::new(),
+        };
+        let error_code = |line: &str| handler.answer(line).unwrap()["error"]["code"].as_i64();
+        assert_eq!(error_code("{"), Some(PARSE_ERROR));
+        assert_eq!(error_code(r#"{"id": 1}"#), Some(INVALID_REQUEST));
+        assert_eq!(error_code(r#"{"id": 1, "method": "status"}"#), Some(INVALID_PARAMS));
+        let submit = r#"{"id": 1, "method": "submit", "params": {"file": "a", "targets": []}}"#;
+        assert_eq!(error_code(submit), Some(INVALID_PARAMS));
+        assert_eq!(
+            error_code(r#"{"id": 1, "method": "pause", "params": {"transfer": 9}}"#),
+            Some(UNKNOWN_TRANSFER)
+        );
+
+        // Notifications get no response
+        assert!(handler.answer(r#"{"method": "list"}"#).is_none());
+        let response = handler
+            .answer(r#"{"jsonrpc": "2.0", "id": "a", "method": "list"}"#)
+            .unwrap();
+        assert_eq!(response["id"], "a");
+        assert_eq!(response["result"], json!([]));
+    }
+}
@@ -0,0 +1,1023 @@
+//! Long-running server for many transfers at once
+//!
+//! A daemon listens on one socket and runs every transfer through it
+//! concurrently. The dispatcher reads each packet once and hands it to the
+//! session it belongs to by session ID; each session runs a `Receiver` or
+//! `Sender` over a `SessionTransport` that sees only its own packets.
+//!
+//! Data packets of an unknown session start an incoming transfer, saved to
+//! the inbox under its session ID, unless that would exceed the number of
+//! sessions received at once or the memory allowed per session; the sender
+//! is then told with an abort notice. Outgoing transfers are started
+//! through the `SessionManager`, which also pauses them and cancels any
+//! transfer, as the control socket asks. Until an outgoing session
+//! announces its session ID, packets of no known session, such as replies
+//! to path MTU probes, reach every such session, which passes over what is
+//! not for it.
+//!
+//! Sessions that ended are remembered for a while: stragglers of a complete
+//! one are acknowledged again, those of a failed one passed over.
+
+use crate::block::TransferLayout;
+use crate::protocol::{AbortNotice, CompletionAck, DataPacket, Packet};
+use crate::session::{ReceiveLimits, ReceiveSession, SessionError, StreamReceiveSession};
+use crate::transfer::{
+    CancellationToken, Event, Output, PartialOutput, Receiver, Sender, TransferError, DEFAULT_IDLE_TIMEOUT,
+};
+use crate::transport::PacketTransport;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::{mpsc, watch};
+use tokio_util::task::TaskTracker;
+
+/// Incoming sessions received at once unless told otherwise
+pub const DEFAULT_MAX_SESSIONS: usize = 16;
+
+/// Memory an incoming session may take unless told otherwise
+pub const DEFAULT_MAX_SESSION_MEMORY: u64 = 1 << 30;
+
+/// Packets queued for a session before more are dropped
+const SESSION_QUEUE: usize = 4096;
+
+/// Ended sessions and finished transfers remembered
+const HISTORY: usize = 1024;
+
+/// How long packets of a session that failed are passed over before they
+/// may start it again
+const STRAGGLER_WINDOW: Duration = Duration::from_secs(5);
+
+/// Minimum interval between replies to packets of a session not received
+const REPLY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Error)]
+pub enum DaemonError {
+    #[error("Transport error: {0:#}")]
+    Transport(#[from] anyhow::Error),
+    #[error("No transfer {0}")]
+    UnknownTransfer(u64),
+    #[error("Transfer {0} is incoming; only outgoing transfers can be paused")]
+    NotPausable(u64),
+    #[error("Transfer {0} has finished")]
+    Finished(u64),
+}
+
+/// Which way a transfer goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// Where a transfer stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferState {
+    Running,
+    Paused,
+    Complete,
+    Failed,
+    Cancelled,
+}
+
+impl TransferState {
+    /// Whether the transfer ended, one way or another
+    pub fn is_finished(self) -> bool {
+        !matches!(self, TransferState::Running | TransferState::Paused)
+    }
+}
+
+/// What the daemon knows about one of its transfers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransferInfo {
+    /// Number of the transfer within the daemon
+    pub id: u64,
+    pub direction: Direction,
+    /// Session ID, once known
+    #[serde(serialize_with = "serialize_session_id", deserialize_with = "deserialize_session_id")]
+    pub session_id: Option<u64>,
+    /// Sender of an incoming transfer, targets of an outgoing one
+    pub peers: Vec<String>,
+    pub state: TransferState,
+    /// Size of the object, once known
+    pub bytes: Option<u64>,
+    /// Bytes recovered at the receiving end, as far as is known
+    pub done: u64,
+    /// Where an incoming transfer was saved
+    pub path: Option<PathBuf>,
+    /// Why the transfer failed
+    pub error: Option<String>,
+}
+
+fn serialize_session_id<S: Serializer>(session_id: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match session_id {
+        Some(session_id) => serializer.collect_str(&format_args!("{:016x}", session_id)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_session_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    let Some(hex) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    u64::from_str_radix(&hex, 16).map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Something that happened in the daemon
+#[derive(Debug, Clone, PartialEq)]
+pub enum DaemonEvent {
+    /// Transfer `id` reported `event`
+    Transfer(u64, Event),
+    /// Transfer `id` was paused
+    Paused(u64),
+    /// Transfer `id` was resumed
+    Resumed(u64),
+    /// A transfer ended
+    Finished(TransferInfo),
+    /// An incoming session was turned away
+    Refused {
+        session_id: u64,
+        from: SocketAddr,
+        reason: String,
+    },
+}
+
+/// Serves transfers through one transport until cancelled
+pub struct Daemon {
+    transport: Arc<dyn PacketTransport>,
+    options: DaemonOptions,
+}
+
+struct DaemonOptions {
+    inbox: PathBuf,
+    max_sessions: usize,
+    max_session_memory: u64,
+    limits: ReceiveLimits,
+    idle_timeout: Option<Duration>,
+    deadline: Option<Duration>,
+    key: Vec<u8>,
+    partial: PartialOutput,
+    events: Option<mpsc::UnboundedSender<DaemonEvent>>,
+    cancel: CancellationToken,
+}
+
+impl Daemon {
+    /// Daemon saving what it receives through `transport` in `inbox`,
+    /// receiving at most 16 sessions of at most 1 GiB at once
+    pub fn new(transport: Arc<dyn PacketTransport>, inbox: impl Into<PathBuf>) -> Self {
+        Self {
+            transport,
+            options: DaemonOptions {
+                inbox: inbox.into(),
+                max_sessions: DEFAULT_MAX_SESSIONS,
+                max_session_memory: DEFAULT_MAX_SESSION_MEMORY,
+                limits: ReceiveLimits::default(),
+                idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+                deadline: None,
+                key: Vec::new(),
+                partial: PartialOutput::default(),
+                events: None,
+                cancel: CancellationToken::new(),
+            },
+        }
+    }
+
+    /// Most incoming sessions received at once
+    pub fn max_sessions(mut self, max_sessions: usize) -> Self {
+        self.options.max_sessions = max_sessions;
+        self
+    }
+
+    /// Most memory in bytes an incoming session may take, as estimated from
+    /// its layout; a whole object is held until it is saved, a stream only
+    /// a window of source blocks, along with a decoder for each open block
+    pub fn max_session_memory(mut self, bytes: u64) -> Self {
+        self.options.max_session_memory = bytes;
+        self
+    }
+
+    /// Bounds on the layout of incoming objects; sessions beyond them are
+    /// turned away
+    pub fn limits(mut self, limits: ReceiveLimits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+
+    /// How long an incoming transfer may go without a packet, `None` for no
+    /// limit
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.idle_timeout = timeout;
+        self
+    }
+
+    /// Time allowed for each incoming transfer, `None` for no limit
+    pub fn deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.options.deadline = deadline;
+        self
+    }
+
+    /// Pre-shared key acknowledgements and abort notices are signed with
+    pub fn key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.options.key = key.into();
+        self
+    }
+
+    /// What to do with what was received of incoming transfers that do not
+    /// finish
+    pub fn partial(mut self, partial: PartialOutput) -> Self {
+        self.options.partial = partial;
+        self
+    }
+
+    /// Report events on `tx`
+    pub fn events(mut self, tx: mpsc::UnboundedSender<DaemonEvent>) -> Self {
+        self.options.events = Some(tx);
+        self
+    }
+
+    /// Stop, cancelling every transfer, once `token` is cancelled
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.options.cancel = token;
+        self
+    }
+
+    /// Manager of the daemon's transfers, serving them once `serve` runs
+    pub fn build(self) -> SessionManager {
+        SessionManager {
+            inner: Arc::new(Inner {
+                ipv6: self.transport.local_addr().is_ok_and(|addr| addr.is_ipv6()),
+                transport: self.transport,
+                options: self.options,
+                state: Mutex::new(State::default()),
+                tasks: TaskTracker::new(),
+            }),
+        }
+    }
+}
+
+/// Starts, follows and cancels the transfers of a daemon
+#[derive(Clone)]
+pub struct SessionManager {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    transport: Arc<dyn PacketTransport>,
+    /// Whether the transport is an IPv6 socket, which may also carry IPv4
+    ipv6: bool,
+    options: DaemonOptions,
+    state: Mutex<State>,
+    tasks: TaskTracker,
+}
+
+/// Queue of packets for a session
+type SessionQueue = mpsc::Sender<(Packet, SocketAddr)>;
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    transfers: BTreeMap<u64, Entry>,
+    /// Queues of the sessions running, by session ID
+    routes: HashMap<u64, Route>,
+    /// Queues of outgoing transfers that have not announced their session
+    pending: HashMap<u64, SessionQueue>,
+    /// Incoming sessions that ended
+    ended: HashMap<u64, Ended>,
+    /// When the sender of each session turned away was last told
+    refused: HashMap<u64, Instant>,
+}
+
+struct Entry {
+    info: TransferInfo,
+    cancel: CancellationToken,
+    /// Pauses an outgoing transfer
+    pause: Option<watch::Sender<bool>>,
+}
+
+struct Route {
+    id: u64,
+    queue: SessionQueue,
+}
+
+/// Incoming session that ended
+struct Ended {
+    at: Instant,
+    /// Hash of the object received, if it was
+    hash: Option<blake3::Hash>,
+    last_reply: Option<Instant>,
+}
+
+impl State {
+    fn add(
+        &mut self,
+        direction: Direction,
+        peers: Vec<String>,
+        cancel: CancellationToken,
+        pause: Option<watch::Sender<bool>>,
+    ) -> u64 {
+        self.next_id += 1;
+        let info = TransferInfo {
+            id: self.next_id,
+            direction,
+            session_id: None,
+            peers,
+            state: TransferState::Running,
+            bytes: None,
+            done: 0,
+            path: None,
+            error: None,
+        };
+        self.transfers.insert(self.next_id, Entry { info, cancel, pause });
+        self.next_id
+    }
+
+    /// Remember that the incoming session of `session_id` ended, forgetting
+    /// the oldest beyond the history kept
+    fn remember(&mut self, session_id: u64, hash: Option<blake3::Hash>) {
+        if self.ended.len() >= HISTORY {
+            let oldest = self.ended.iter().min_by_key(|(_, ended)| ended.at).map(|(&id, _)| id);
+            self.ended.retain(|&id, _| Some(id) != oldest);
+        }
+        self.ended.insert(
+            session_id,
+            Ended {
+                at: Instant::now(),
+                hash,
+                last_reply: None,
+            },
+        );
+    }
+
+    /// Forget the oldest finished transfers beyond the history kept
+    fn trim(&mut self) {
+        while self.transfers.len() > HISTORY {
+            let oldest = self
+                .transfers
+                .values()
+                .find(|entry| entry.info.state.is_finished())
+                .map(|entry| entry.info.id);
+            match oldest {
+                Some(id) => self.transfers.remove(&id),
+                None => return,
+            };
+        }
+    }
+
+    /// Why the session of `packet` cannot be received, if it cannot
+    fn refusal(&self, packet: &DataPacket, options: &DaemonOptions) -> Option<String> {
+        let receiving = self
+            .transfers
+            .values()
+            .filter(|entry| entry.info.direction == Direction::Incoming && !entry.info.state.is_finished())
+            .count();
+        if receiving >= options.max_sessions {
+            return Some(format!("{} sessions are being received already", receiving));
+        }
+        let needed = match packet.is_stream() {
+            true => StreamReceiveSession::memory(usize::from(packet.symbol_size)),
+            false => {
+                let checked = TransferLayout::new(packet.transfer_length, usize::from(packet.symbol_size))
+                    .map_err(SessionError::from)
+                    .and_then(|layout| options.limits.check(&layout).map(|_| layout));
+                match checked {
+                    Ok(layout) => ReceiveSession::memory(&layout),
+                    Err(e) => return Some(e.to_string()),
+                }
+            }
+        };
+        if needed > options.max_session_memory {
+            return Some(format!(
+                "it needs {} bytes of memory, more than the {} allowed",
+                needed, options.max_session_memory
+            ));
+        }
+        None
+    }
+}
+
+impl SessionManager {
+    /// Transfers running and recently finished, oldest first
+    pub fn list(&self) -> Vec<TransferInfo> {
+        let state = self.inner.state.lock().unwrap();
+        state.transfers.values().map(|entry| entry.info.clone()).collect()
+    }
+
+    /// Transfer `id`
+    pub fn get(&self, id: u64) -> Result<TransferInfo, DaemonError> {
+        let state = self.inner.state.lock().unwrap();
+        let entry = state.transfers.get(&id).ok_or(DaemonError::UnknownTransfer(id))?;
+        Ok(entry.info.clone())
+    }
+
+    /// Cancel transfer `id`, telling its peers
+    pub fn cancel(&self, id: u64) -> Result<(), DaemonError> {
+        let state = self.inner.state.lock().unwrap();
+        let entry = state.transfers.get(&id).ok_or(DaemonError::UnknownTransfer(id))?;
+        entry.cancel.cancel();
+        Ok(())
+    }
+
+    /// Hold off sending outgoing transfer `id` until it is resumed
+    pub fn pause(&self, id: u64) -> Result<TransferInfo, DaemonError> {
+        self.set_paused(id, true)
+    }
+
+    /// Go on sending outgoing transfer `id`
+    pub fn resume(&self, id: u64) -> Result<TransferInfo, DaemonError> {
+        self.set_paused(id, false)
+    }
+
+    fn set_paused(&self, id: u64, paused: bool) -> Result<TransferInfo, DaemonError> {
+        let info = {
+            let mut state = self.inner.state.lock().unwrap();
+            let entry = state.transfers.get_mut(&id).ok_or(DaemonError::UnknownTransfer(id))?;
+            let pause = entry.pause.as_ref().ok_or(DaemonError::NotPausable(id))?;
+            if entry.info.state.is_finished() {
+                return Err(DaemonError::Finished(id));
+            }
+            if *pause.borrow() == paused {
+                return Ok(entry.info.clone());
+            }
+            pause.send_replace(paused);
+            entry.info.state = match paused {
+                true => TransferState::Paused,
+                false => TransferState::Running,
+            };
+            entry.info.clone()
+        };
+        self.emit(match paused {
+            true => DaemonEvent::Paused(id),
+            false => DaemonEvent::Resumed(id),
+        });
+        Ok(info)
+    }
+
+    /// Start sending with `sender` through the daemon's transport,
+    /// returning the number of the transfer
+    pub fn send(&self, sender: Sender) -> u64 {
+        let (queue, transport) = self.session_transport();
+        let cancel = self.inner.options.cancel.child_token();
+        let (pause, paused) = watch::channel(false);
+        let id = {
+            let mut state = self.inner.state.lock().unwrap();
+            let id = state.add(Direction::Outgoing, sender.targets().to_vec(), cancel.clone(), Some(pause));
+            state.pending.insert(id, queue);
+            id
+        };
+        let sender = sender.transport(transport).cancel(cancel).pause(paused);
+        self.spawn(
+            id,
+            |events| async move { sender.events(events).send().await.map(|_| None) },
+        );
+        id
+    }
+
+    /// Serve transfers until the daemon is cancelled, then wait for them
+    /// all to stop
+    pub async fn serve(&self) -> Result<(), DaemonError> {
+        let result = loop {
+            let received = tokio::select! {
+                received = self.inner.transport.receive_packet() => received,
+                _ = self.inner.options.cancel.cancelled() => break Ok(()),
+            };
+            match received {
+                // IPv4 peers of a dual-stack socket are known by their
+                // IPv4 address, as they are given as targets
+                Ok((packet, addr)) => {
+                    let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                    self.dispatch(packet, addr).await
+                }
+                Err(e) => break Err(DaemonError::Transport(e)),
+            }
+        };
+
+        // Transfers do not outlive the daemon
+        for entry in self.inner.state.lock().unwrap().transfers.values() {
+            entry.cancel.cancel();
+        }
+        self.inner.tasks.close();
+        self.inner.tasks.wait().await;
+        result
+    }
+
+    /// Hand `packet` to the session it belongs to, or start one for it
+    async fn dispatch(&self, packet: Packet, addr: SocketAddr) {
+        let route = {
+            let state = self.inner.state.lock().unwrap();
+            state.routes.get(&packet.session_id()).map(|route| route.queue.clone())
+        };
+        if let Some(queue) = route {
+            // A full queue drops the packet as a full socket buffer would
+            let _ = queue.try_send((packet, addr));
+            return;
+        }
+        match packet {
+            Packet::Data(packet) => self.accept(packet, addr).await,
+            Packet::Probe(probe) => self.reply(addr, Packet::ProbeAck(probe.ack())).await,
+            packet => {
+                let pending: Vec<SessionQueue> = self.inner.state.lock().unwrap().pending.values().cloned().collect();
+                for queue in pending {
+                    let _ = queue.try_send((packet.clone(), addr));
+                }
+            }
+        }
+    }
+
+    /// Start receiving the session of `packet` from `from`, unless it ended
+    /// already or has to be turned away
+    async fn accept(&self, packet: DataPacket, from: SocketAddr) {
+        let session_id = packet.session_id;
+        let options = &self.inner.options;
+        let (reply, refused) = {
+            let mut state = self.inner.state.lock().unwrap();
+            // A failed session may start over once its stragglers are gone
+            let expired = |ended: &Ended| ended.hash.is_none() && ended.at.elapsed() >= STRAGGLER_WINDOW;
+            if state.ended.get(&session_id).is_some_and(expired) {
+                state.ended.remove(&session_id);
+            }
+            if let Some(ended) = state.ended.get_mut(&session_id) {
+                match ended.hash {
+                    Some(_) if ended.last_reply.is_some_and(|sent| sent.elapsed() < REPLY_INTERVAL) => return,
+                    Some(hash) => {
+                        ended.last_reply = Some(Instant::now());
+                        // Signed for the straggler's run, which may be a later
+                        // send of the same object
+                        let ack = CompletionAck::new(session_id, packet.nonce, *hash.as_bytes(), &options.key);
+                        (Packet::Complete(ack), None)
+                    }
+                    None => return,
+                }
+            } else {
+                let Some(reason) = state.refusal(&packet, options) else {
+                    self.receive(&mut state, packet, from);
+                    return;
+                };
+                if state
+                    .refused
+                    .get(&session_id)
+                    .is_some_and(|sent| sent.elapsed() < REPLY_INTERVAL)
+                {
+                    return;
+                }
+                // Forgetting only costs an extra notice
+                if state.refused.len() >= HISTORY {
+                    state.refused.clear();
+                }
+                let first = state.refused.insert(session_id, Instant::now()).is_none();
+                let abort = Packet::Abort(AbortNotice::new(session_id, packet.nonce, &options.key));
+                (abort, first.then_some(reason))
+            }
+        };
+
+        if let Some(reason) = refused {
+            log::warn!("Refusing session {:016x} from {}: {}", session_id, from, reason);
+            self.emit(DaemonEvent::Refused {
+                session_id,
+                from,
+                reason,
+            });
+        }
+        self.reply(from, reply).await;
+    }
+
+    /// Start an incoming transfer for the session of `first`
+    fn receive(&self, state: &mut State, first: DataPacket, from: SocketAddr) {
+        let options = &self.inner.options;
+        let (queue, transport) = self.session_transport();
+        let cancel = options.cancel.child_token();
+        let id = state.add(Direction::Incoming, vec![from.to_string()], cancel.clone(), None);
+        let session_id = first.session_id;
+        if let Some(entry) = state.transfers.get_mut(&id) {
+            entry.info.session_id = Some(session_id);
+        }
+        let _ = queue.try_send((Packet::Data(first), from));
+        state.routes.insert(session_id, Route { id, queue });
+
+        let receiver = Receiver::new(Output::Inbox(options.inbox.clone()))
+            .transport(transport)
+            .idle_timeout(options.idle_timeout)
+            .deadline(options.deadline)
+            .limits(options.limits)
+            .key(options.key.clone())
+            .partial(options.partial)
+            .cancel(cancel);
+        self.spawn(id, |events| async move {
+            let report = receiver.events(events).receive().await?;
+            Ok(Some(report.hash))
+        });
+    }
+
+    /// Run transfer `id` in a task of its own, following its events
+    ///
+    /// The transfer resolves to the hash of the object received, if any.
+    fn spawn<F>(&self, id: u64, transfer: impl FnOnce(mpsc::UnboundedSender<Event>) -> F)
+    where
+        F: Future<Output = Result<Option<blake3::Hash>, TransferError>> + Send + 'static,
+    {
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let transfer = transfer(events_tx);
+        let manager = self.clone();
+        self.inner.tasks.spawn(async move {
+            let following = async {
+                while let Some(event) = events_rx.recv().await {
+                    manager.on_event(id, event);
+                }
+            };
+            // The channel closes once the transfer is done with its sender
+            let (result, ()) = tokio::join!(transfer, following);
+            manager.finish(id, result);
+        });
+    }
+
+    /// Follow `event` of transfer `id`
+    fn on_event(&self, id: u64, event: Event) {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if let Some(entry) = state.transfers.get_mut(&id) {
+                update(&mut entry.info, &event);
+            }
+            // Feedback goes to an outgoing session alone once its ID is known
+            if let Event::Sending { session_id, .. } | Event::Streaming { session_id, .. } = event {
+                if let Some(queue) = state.pending.remove(&id) {
+                    state.routes.insert(session_id, Route { id, queue });
+                }
+            }
+        }
+        // Every session listens on the daemon's socket
+        if !matches!(event, Event::Listening { .. }) {
+            self.emit(DaemonEvent::Transfer(id, event));
+        }
+    }
+
+    /// Record how transfer `id` ended
+    fn finish(&self, id: u64, result: Result<Option<blake3::Hash>, TransferError>) {
+        let info = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.routes.retain(|_, route| route.id != id);
+            state.pending.remove(&id);
+            let Some(entry) = state.transfers.get_mut(&id) else {
+                return;
+            };
+            let (outcome, hash) = match result {
+                Ok(hash) => (TransferState::Complete, hash),
+                Err(TransferError::Cancelled) => (TransferState::Cancelled, None),
+                Err(e) => {
+                    entry.info.error = Some(e.to_string());
+                    (TransferState::Failed, None)
+                }
+            };
+            entry.info.state = outcome;
+            let info = entry.info.clone();
+            if let (Direction::Incoming, Some(session_id)) = (info.direction, info.session_id) {
+                state.remember(session_id, hash);
+            }
+            state.trim();
+            info
+        };
+        self.emit(DaemonEvent::Finished(info));
+    }
+
+    /// Queue for a new session and the transport it reads from
+    fn session_transport(&self) -> (SessionQueue, Arc<dyn PacketTransport>) {
+        let (queue, packets) = mpsc::channel(SESSION_QUEUE);
+        let transport = SessionTransport {
+            shared: self.inner.transport.clone(),
+            ipv6: self.inner.ipv6,
+            packets: tokio::sync::Mutex::new(packets),
+        };
+        (queue, Arc::new(transport))
+    }
+
+    async fn reply(&self, addr: SocketAddr, packet: Packet) {
+        let peer = reachable(self.inner.ipv6, addr);
+        if let Err(e) = self.inner.transport.send_packet(peer, &packet).await {
+            log::debug!("Failed to reply to {}: {:#}", addr, e);
+        }
+    }
+
+    fn emit(&self, event: DaemonEvent) {
+        if let Some(tx) = &self.inner.options.events {
+            let _ = tx.send(event);
+        }
+    }
+}
+
+/// Apply `event` to what is known about its transfer
+fn update(info: &mut TransferInfo, event: &Event) {
+    match *event {
+        Event::Sending { session_id, bytes, .. } => {
+            info.session_id = Some(session_id);
+            info.bytes = Some(bytes);
+        }
+        Event::Streaming { session_id, .. } => info.session_id = Some(session_id),
+        Event::InputEnded { bytes, .. } | Event::Receiving { bytes, .. } => info.bytes = Some(bytes),
+        Event::Progress {
+            blocks_decoded,
+            num_blocks,
+            ..
+        } => {
+            if let Some(bytes) = info.bytes {
+                info.done = bytes * u64::from(blocks_decoded) / u64::from(num_blocks.max(1));
+            }
+        }
+        Event::Confirmed { .. } => info.done = info.bytes.unwrap_or(info.done),
+        Event::Received {
+            symbols_useful,
+            symbols_needed,
+            ..
+        } => {
+            if let Some(bytes) = info.bytes {
+                info.done = bytes * symbols_useful.min(symbols_needed) as u64 / symbols_needed.max(1) as u64;
+            }
+        }
+        Event::Written { bytes, .. } => info.done = bytes,
+        Event::Saved { ref path, bytes, .. } => {
+            info.bytes = Some(bytes);
+            info.done = bytes;
+            info.path = path.clone();
+        }
+        _ => {}
+    }
+}
+
+/// Address `peer` is reached at through an IPv6 socket if `ipv6`
+fn reachable(ipv6: bool, peer: SocketAddr) -> SocketAddr {
+    match (ipv6, peer.ip()) {
+        (true, IpAddr::V4(ip)) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), peer.port()),
+        _ => peer,
+    }
+}
+
+/// Transport of one session of a daemon: sends through the daemon's
+/// transport and receives what the dispatcher routes to the session
+struct SessionTransport {
+    shared: Arc<dyn PacketTransport>,
+    ipv6: bool,
+    packets: tokio::sync::Mutex<mpsc::Receiver<(Packet, SocketAddr)>>,
+}
+
+#[async_trait]
+impl PacketTransport for SessionTransport {
+    async fn send_packet(&self, peer: SocketAddr, packet: &Packet) -> Result<()> {
+        self.shared.send_packet(reachable(self.ipv6, peer), packet).await
+    }
+
+    async fn send_packets(&self, peer: SocketAddr, packets: &[Packet]) -> Result<()> {
+        self.shared.send_packets(reachable(self.ipv6, peer), packets).await
+    }
+
+    async fn receive_packet(&self) -> Result<(Packet, SocketAddr)> {
+        match self.packets.lock().await.recv().await {
+            Some(received) => Ok(received),
+            None => anyhow::bail!("The daemon stopped serving the session"),
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.shared.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{Impairments, LossModel, Network};
+    use crate::transfer::Source;
+    use rand::{Rng, SeedableRng};
+
+    fn contents(seed: u64, len: usize) -> Vec<u8> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    /// Sender of `data` from `from` on `network` to the daemon
+    fn sender(network: &Network, from: &str, data: Vec<u8>) -> Sender {
+        Sender::new(Source::Bytes(data))
+            .target("10.0.0.2:3000")
+            .transport(Arc::new(network.bind(from.parse().unwrap()).unwrap()))
+            .mtu(1500)
+            .rate_limit(0)
+            .deadline(Duration::from_secs(20))
+    }
+
+    #[test]
+    fn test_concurrent_transfers() {
+        let inbox = tempfile::tempdir().unwrap();
+        let network = Network::new(
+            1,
+            Impairments {
+                loss: LossModel::bernoulli(0.02),
+                ..Impairments::default()
+            },
+        );
+        let transport = network.bind("10.0.0.2:3000".parse().unwrap()).unwrap();
+        let cancel = CancellationToken::new();
+        let manager = Daemon::new(Arc::new(transport), inbox.path())
+            .cancel(cancel.clone())
+            .build();
+
+        // Two objects and a stream come in while one goes out through the
+        // same socket
+        let (first, second, streamed, outgoing) = (
+            contents(1, 300_000),
+            contents(2, 200_000),
+            contents(3, 400_000),
+            contents(4, 250_000),
+        );
+        let stream = Sender::new(Source::Stream(Box::new(std::io::Cursor::new(streamed.clone()))))
+            .target("10.0.0.2:3000")
+            .transport(Arc::new(network.bind("10.0.0.5:0".parse().unwrap()).unwrap()))
+            .mtu(1500)
+            .rate_limit(0);
+        let receiver = Receiver::new(Output::Memory)
+            .transport(Arc::new(network.bind("10.0.0.4:3000".parse().unwrap()).unwrap()))
+            .deadline(Some(Duration::from_secs(20)));
+
+        let (sent, received) = runtime().block_on(async {
+            let serving = manager.serve();
+            let transfers = async {
+                let id = manager.send(
+                    Sender::new(Source::Bytes(outgoing.clone()))
+                        .target("10.0.0.4:3000")
+                        .mtu(1500)
+                        .rate_limit(0),
+                );
+                let results = tokio::join!(
+                    sender(&network, "10.0.0.1:0", first.clone()).send(),
+                    sender(&network, "10.0.0.3:0", second.clone()).send(),
+                    stream.send(),
+                    receiver.receive(),
+                );
+                // Let the outgoing transfer see its acknowledgement
+                while manager.get(id).unwrap().state == TransferState::Running {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                cancel.cancel();
+                results
+            };
+            let (served, (first, second, stream, received)) = tokio::join!(serving, transfers);
+            served.unwrap();
+            (
+                vec![first.unwrap(), second.unwrap(), stream.unwrap()],
+                received.unwrap(),
+            )
+        });
+
+        for (report, data) in sent.iter().zip([&first, &second, &streamed]) {
+            let saved = inbox.path().join(format!("{:016x}", report.session_id));
+            assert_eq!(std::fs::read(saved).unwrap(), *data);
+        }
+        assert_eq!(received.data, Some(outgoing));
+
+        let transfers = manager.list();
+        assert_eq!(transfers.len(), 4);
+        assert!(transfers.iter().all(|info| info.state == TransferState::Complete));
+        let outgoing = transfers
+            .iter()
+            .find(|info| info.direction == Direction::Outgoing)
+            .unwrap();
+        assert_eq!(outgoing.session_id, Some(received.session_id));
+        assert_eq!(outgoing.peers, vec!["10.0.0.4:3000"]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let inbox = tempfile::tempdir().unwrap();
+        let network = Network::new(2, Impairments::default());
+        let transport = network.bind("10.0.0.2:3000".parse().unwrap()).unwrap();
+        let cancel = CancellationToken::new();
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let manager = Daemon::new(Arc::new(transport), inbox.path())
+            .max_session_memory(400_000)
+            .events(events_tx)
+            .cancel(cancel.clone())
+            .build();
+
+        let small = contents(5, 100_000);
+        let (large, small, again) = runtime().block_on(async {
+            let transfers = async {
+                let large = sender(&network, "10.0.0.1:0", contents(6, 200_000)).send().await;
+                let small_sent = sender(&network, "10.0.0.1:0", small.clone()).send().await;
+                // Sending what was received already is acknowledged at once
+                let again = sender(&network, "10.0.0.3:0", small.clone()).send().await;
+                cancel.cancel();
+                (large, small_sent, again)
+            };
+            let (served, sent) = tokio::join!(manager.serve(), transfers);
+            served.unwrap();
+            sent
+        });
+
+        let daemon: SocketAddr = "10.0.0.2:3000".parse().unwrap();
+        assert!(matches!(large, Err(TransferError::Aborted(addr)) if addr == daemon));
+        let small = small.unwrap();
+        let again = again.unwrap();
+        assert_eq!(again.confirmed, vec![daemon]);
+        assert_eq!(again.session_id, small.session_id);
+
+        // Only the small object was received, once
+        assert_eq!(manager.list().len(), 1);
+        let mut refused = 0;
+        while let Ok(event) = events_rx.try_recv() {
+            if let DaemonEvent::Refused { reason, .. } = event {
+                assert!(reason.contains("400000"), "{}", reason);
+                refused += 1;
+            }
+        }
+        assert_eq!(refused, 1);
+    }
+
+    #[test]
+    fn test_tiny_symbols_refused() {
+        let inbox = tempfile::tempdir().unwrap();
+        let network = Network::new(4, Impairments::default());
+        let transport = network.bind("10.0.0.2:3000".parse().unwrap()).unwrap();
+        let cancel = CancellationToken::new();
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let manager = Daemon::new(Arc::new(transport), inbox.path())
+            .limits(ReceiveLimits::unlimited())
+            .max_session_memory(4 << 20)
+            .events(events_tx)
+            .cancel(cancel.clone())
+            .build();
+
+        // A small object in tiny symbols needs a decoder for each of many
+        // source blocks, far more memory than the object itself
+        let sent = runtime().block_on(async {
+            let sending = async {
+                let sent = sender(&network, "10.0.0.1:0", contents(8, 100_000))
+                    .mtu(100)
+                    .send()
+                    .await;
+                cancel.cancel();
+                sent
+            };
+            let (served, sent) = tokio::join!(manager.serve(), sending);
+            served.unwrap();
+            sent
+        });
+        assert!(matches!(sent, Err(TransferError::Aborted(_))), "{:?}", sent.map(|report| report.symbol_size));
+        assert!(manager.list().is_empty());
+        match events_rx.try_recv() {
+            Ok(DaemonEvent::Refused { reason, .. }) => assert!(reason.contains("memory"), "{}", reason),
+            _ => panic!("session not refused"),
+        }
+    }
+
+    #[test]
+    fn test_pause() {
+        let inbox = tempfile::tempdir().unwrap();
+        let network = Network::new(3, Impairments::default());
+        let transport = network.bind("10.0.0.2:3000".parse().unwrap()).unwrap();
+        let cancel = CancellationToken::new();
+        let manager = Daemon::new(Arc::new(transport), inbox.path())
+            .cancel(cancel.clone())
+            .build();
+        let data = contents(7, 100_000);
+        let receiver = Receiver::new(Output::Memory)
+            .transport(Arc::new(network.bind("10.0.0.4:3000".parse().unwrap()).unwrap()))
+            .deadline(Some(Duration::from_secs(20)));
+
+        let received = runtime().block_on(async {
+            let transfers = async {
+                let id = manager.send(Sender::new(Source::Bytes(data.clone())).target("10.0.0.4:3000").mtu(1500));
+                assert_eq!(manager.pause(id).unwrap().state, TransferState::Paused);
+                let receiving = async {
+                    // Nothing goes out while paused
+                    let sent = network.stats().sent;
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    assert_eq!(network.stats().sent, sent);
+                    assert_eq!(manager.resume(id).unwrap().state, TransferState::Running);
+                };
+                let (received, ()) = tokio::join!(receiver.receive(), receiving);
+                while !manager.get(id).unwrap().state.is_finished() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                assert_eq!(manager.get(id).unwrap().state, TransferState::Complete);
+                assert!(matches!(manager.pause(id), Err(DaemonError::Finished(_))));
+                assert!(matches!(manager.pause(id + 1), Err(DaemonError::UnknownTransfer(_))));
+                cancel.cancel();
+                received
+            };
+            let (served, received) = tokio::join!(manager.serve(), transfers);
+            served.unwrap();
+            received.unwrap()
+        });
+        assert_eq!(received.data, Some(data));
+    }
+}
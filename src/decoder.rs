@@ -1,11 +1,14 @@
 //! Implementation of the Raptor decoder based on RFC 5053
 
 use crate::fountain::Block;
-use crate::linear_algebra::BinaryMatrix;
-use crate::distribution::DegreeGenerator;
+use crate::linear_algebra::{BinaryMatrix, xor_into};
+use crate::distribution::{lt_indices, DegreeGenerator};
 use crate::systematic::{LDPCParams, generate_gray_sequence};
 use std::collections::HashMap;
 use thiserror::Error;
+
+/// Bookkeeping per received block besides its equation, in bytes
+const BLOCK_STATE_SIZE: u64 = 32;
 #[derive(Error, Debug)]
 pub enum DecoderError {
     #[error("Not enough blocks received")]
@@ -36,7 +39,7 @@ pub struct Decoder {
     source_block_count: usize,
     /// Size of each block in bytes
     block_size: usize,
-    /// Map of received encoded blocks
+    /// Received encoded blocks not yet folded into the equation system
     received_blocks: HashMap<u32, Block>,
     /// State of each block in the decoding process
     block_states: HashMap<u32, BlockState>,
@@ -44,8 +47,10 @@ pub struct Decoder {
     decoded_blocks: Vec<Option<Vec<u8>>>,
     /// Current state of the equation system
     equation_matrix: BinaryMatrix,
-    /// Right-hand side of the equation system
-    equation_values: Vec<u8>,
+    /// Reduced received equations with their payloads, indexed by pivot column
+    pivots: Vec<Option<(Vec<u8>, Vec<u8>)>>,
+    /// Number of linearly independent equations received so far
+    rank: usize,
     /// Degree generator for block relationships
     degree_gen: DegreeGenerator,
     /// LDPC and Half symbol parameters
@@ -62,7 +67,7 @@ impl Decoder {
     /// * `block_size` - Size of each block in bytes, must be > 0
     pub fn new(source_block_count: usize, block_size: usize) -> Result<Self, DecoderError> {
         // Validate K range (RFC 5053 requirement)
-        if source_block_count < 4 || source_block_count > 256 {
+        if !(4..=256).contains(&source_block_count) {
             return Err(DecoderError::InvalidBlockCount(source_block_count));
         }
 
@@ -84,7 +89,8 @@ impl Decoder {
             block_states: HashMap::new(),
             decoded_blocks: vec![None; source_block_count],
             equation_matrix: BinaryMatrix::new(matrix_size, matrix_size),
-            equation_values: vec![0; matrix_size],
+            pivots: vec![None; source_block_count],
+            rank: 0,
             degree_gen: DegreeGenerator::new(source_block_count),
             ldpc_params,
             gray_sequence,
@@ -133,7 +139,7 @@ impl Decoder {
         // Add Half symbol constraints following Section 5.4.2.3
         for i in 0..h {
             let row = k + s + i;
-            let h_half = (h + 1) / 2;
+            let h_half = h.div_ceil(2);
             
             // Each Half constraint connects to ceil(h/2) source symbols
             for j in 0..h_half {
@@ -157,6 +163,43 @@ impl Decoder {
         Ok(())
     }
 
+    /// Whether a block with `sequence` has already been added
+    pub fn has_block(&self, sequence: u32) -> bool {
+        self.block_states.contains_key(&sequence)
+    }
+
+    /// Number of encoded blocks added so far
+    pub fn blocks_received(&self) -> usize {
+        self.block_states.len()
+    }
+
+    /// Bytes a decoder for `source_block_count` blocks of `block_size` bytes
+    /// takes, roughly, once `received` encoded blocks were added
+    ///
+    /// The equation matrix gains a row per block while the payloads are
+    /// only kept for the independent ones, which become the decoded blocks.
+    pub fn memory(source_block_count: usize, block_size: usize, received: usize) -> u64 {
+        let l = LDPCParams::new(source_block_count).l as u64;
+        let (k, block_size, received) = (source_block_count as u64, block_size as u64, received as u64);
+        l * (l + received) + k * (k + block_size) + received * BLOCK_STATE_SIZE
+    }
+
+    /// Add source block `index` as received intact, as a systematic code
+    /// delivers most of them
+    pub fn add_source_block(&mut self, index: usize, data: Vec<u8>) -> Result<(), DecoderError> {
+        if data.len() != self.block_size {
+            return Err(DecoderError::InvalidBlockSize(data.len()));
+        }
+        if index >= self.source_block_count {
+            return Err(DecoderError::DecodingFailed(format!("Source block {} out of range", index)));
+        }
+
+        let mut coefficients = vec![0u8; self.source_block_count];
+        coefficients[index] = 1;
+        self.eliminate(coefficients, data);
+        Ok(())
+    }
+
     /// Process blocks that are in pending state
     fn process_pending_blocks(&mut self) -> Result<(), DecoderError> {
         let pending_blocks: Vec<_> = self.block_states
@@ -166,25 +209,64 @@ impl Decoder {
             .collect();
 
         for sequence in pending_blocks {
-            let block = self.received_blocks.get(&sequence).unwrap();
+            let block = self.received_blocks.remove(&sequence).unwrap();
             
             // Update equation matrix based on block's relationships
             // This follows Section 5.5.2.2 of RFC 5053
             let row = self.equation_matrix.rows();
             
-            // Extend matrix and values for new equation
+            // Extend matrix for new equation
             self.equation_matrix.add_row();
-            self.equation_values.push(1);
             
             // Fill in matrix row based on block relationships
-            let (seed, degree) = (block.seed(), block.degree());
+            let (seed, degree, data) = (block.seed(), block.degree(), block.data().to_vec());
             self.update_equation_matrix(row, seed, degree)?;
+
+            // Fold the equation into the reduced system over the source blocks
+            let coefficients = self.equation_matrix[row][..self.source_block_count].to_vec();
+            self.eliminate(coefficients, data);
             
             self.block_states.insert(sequence, BlockState::Processed);
         }
         Ok(())
     }
 
+    /// Reduce an equation against the existing pivots, keeping it if it adds rank
+    ///
+    /// This is the forward pass of the Gaussian elimination in Section 5.5.2,
+    /// performed incrementally so each received block costs at most K row XORs.
+    fn eliminate(&mut self, mut coefficients: Vec<u8>, mut data: Vec<u8>) {
+        for col in 0..self.source_block_count {
+            if coefficients[col] == 0 {
+                continue;
+            }
+            if let Some((pivot_coefficients, pivot_data)) = &self.pivots[col] {
+                xor_into(&mut coefficients[col..], &pivot_coefficients[col..]);
+                xor_into(&mut data, pivot_data);
+                continue;
+            }
+            self.pivots[col] = Some((coefficients, data));
+            self.rank += 1;
+            return;
+        }
+        // Equation was linearly dependent on the ones already received
+    }
+
+    /// Recover the source blocks once the reduced system has full rank
+    fn back_substitute(&mut self) {
+        for col in (0..self.source_block_count).rev() {
+            let Some((coefficients, mut data)) = self.pivots[col].take() else {
+                continue;
+            };
+            for (later, _) in coefficients.iter().enumerate().skip(col + 1).filter(|(_, &c)| c == 1) {
+                if let Some(solved) = &self.decoded_blocks[later] {
+                    xor_into(&mut data, solved);
+                }
+            }
+            self.decoded_blocks[col] = Some(data);
+        }
+    }
+
     /// Update equation matrix for a new block following RFC 5053 Section 5.4.4.4
     fn update_equation_matrix(&mut self, row: usize, sequence: u32, degree: usize) -> Result<(), DecoderError> {
         // Generate triple (d, a, b) for this sequence number
@@ -192,15 +274,9 @@ impl Decoder {
             .ok_or_else(|| DecoderError::DecodingFailed("Invalid block count".to_string()))?;
         
         let (_, a, b) = triple;
-        let k = self.source_block_count;
-        
-        // First block
-        let mut index = (b as usize) % k;
-        self.equation_matrix[row][index] ^= 1;
 
-        // Subsequent blocks following the sequence defined in RFC 5053
-        for _ in 1..degree {
-            index = ((index + (a as usize)) % k) as usize;
+        // Blocks combined following the sequence defined in RFC 5053
+        for index in lt_indices(self.source_block_count, degree, a, b) {
             self.equation_matrix[row][index] ^= 1;
         }
         
@@ -208,42 +284,44 @@ impl Decoder {
     }
 
     /// Try to decode the original data
+    ///
+    /// Returns `Ok(true)` once every source block has been recovered.
     pub fn try_decode(&mut self) -> Result<bool, DecoderError> {
         // Process any new blocks first
         self.process_pending_blocks()?;
+
+        if self.is_decoded() {
+            return Ok(true);
+        }
         
-        // Check if we have enough equations
-        if self.equation_values.len() < self.source_block_count {
+        // Check if we have enough independent equations
+        if self.rank < self.source_block_count {
             return Ok(false);
         }
 
-        // Solve the system using Gaussian elimination
-        if let Some(solution) = self.equation_matrix.solve(&self.equation_values) {
-            // Convert solution to source blocks
-            for (i, value) in solution.into_iter().enumerate().take(self.source_block_count) {
-                if value == 1 {
-                    let block_data = self.received_blocks
-                        .values()
-                        .next()
-                        .map(|b| b.data().to_vec())
-                        .ok_or(DecoderError::NotEnoughBlocks)?;
-                    self.decoded_blocks[i] = Some(block_data);
-                }
-            }
-            Ok(true)
-        } else {
-            Err(DecoderError::SystemNotSolvable)
+        self.back_substitute();
+        for state in self.block_states.values_mut() {
+            *state = BlockState::Solved;
         }
+        Ok(true)
+    }
+
+    /// Whether every source block has been recovered
+    pub fn is_decoded(&self) -> bool {
+        self.decoded_blocks.iter().all(|block| block.is_some())
+    }
+
+    /// Number of linearly independent blocks received so far
+    pub fn rank(&self) -> usize {
+        self.rank
     }
 
     /// Get the decoded data if available
     pub fn get_decoded_data(&self) -> Option<Vec<u8>> {
-        if self.decoded_blocks.iter().all(|block| block.is_some()) {
+        if self.is_decoded() {
             let mut result = Vec::with_capacity(self.source_block_count * self.block_size);
-            for block in &self.decoded_blocks {
-                if let Some(data) = block {
-                    result.extend_from_slice(data);
-                }
+            for data in self.decoded_blocks.iter().flatten() {
+                result.extend_from_slice(data);
             }
             Some(result)
         } else {
@@ -316,7 +394,7 @@ mod tests {
             if row_ones > 0 {
                 half_rows_nonzero += 1;
             }
-            assert_eq!(row_ones, (params.h + 1) / 2); // Each Half row has ceil(h/2) ones
+            assert_eq!(row_ones, params.h.div_ceil(2)); // Each Half row has ceil(h/2) ones
         }
         assert_eq!(half_rows_nonzero, params.h);
     }
@@ -355,6 +433,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_decode_roundtrip() {
+        let data: Vec<u8> = (0..64 * 16).map(|i| (i * 7 + i / 16) as u8).collect();
+        let mut encoder = crate::fountain::Encoder::new(&data, 16).unwrap();
+        let mut decoder = Decoder::new(64, 16).unwrap();
+
+        let mut decoded = false;
+        for sequence in 0..256 {
+            let block = encoder.next_block().unwrap();
+            decoder.add_block(block, sequence).unwrap();
+            if decoder.try_decode().unwrap() {
+                decoded = true;
+                break;
+            }
+        }
+
+        assert!(decoded);
+        assert_eq!(decoder.rank(), 64);
+        assert_eq!(decoder.get_decoded_data().unwrap(), data);
+    }
+
     #[test]
     fn test_invalid_block_size() {
         let mut decoder = Decoder::new(100, 8).unwrap();
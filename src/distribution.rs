@@ -1,11 +1,11 @@
 //! Implementation of degree distributions for Raptor codes
 //! Based on RFC 5053 Section 5.4.4
 
-use rand::Rng;
-use std::collections::HashMap;
+use rand::{Rng, SeedableRng};
 use crate::tables::{self, Q};
 
 /// Parameters for the robust soliton distribution
+#[allow(dead_code)]
 pub struct DistributionParams {
     /// Number of source symbols
     k: usize,
@@ -24,12 +24,39 @@ impl DistributionParams {
     }
 }
 
+/// Smallest prime greater than or equal to `n`
+fn smallest_prime_at_least(n: usize) -> usize {
+    let is_prime = |m: usize| m >= 2 && (2..).take_while(|d| d * d <= m).all(|d| !m.is_multiple_of(d));
+    (n.max(2)..).find(|&m| is_prime(m)).unwrap()
+}
+
+/// Indices of the source symbols combined into an encoding symbol, following
+/// LTEnc in Section 5.4.4.3
+///
+/// The walk steps by `a` modulo the smallest prime `P >= k`, skipping indices
+/// of `k` or more, so the `min(degree, k)` indices are always distinct.
+pub fn lt_indices(k: usize, degree: usize, a: u32, b: u32) -> Vec<usize> {
+    let p = smallest_prime_at_least(k);
+    let a = (a as usize % p).max(1);
+    let mut b = b as usize % p;
+
+    let mut indices = Vec::with_capacity(degree.min(k));
+    for _ in 0..degree.min(k) {
+        while b >= k {
+            b = (b + a) % p;
+        }
+        indices.push(b);
+        b = (b + a) % p;
+    }
+    indices
+}
+
 /// Degree generator that implements the degree distribution from RFC 5053
 pub struct DegreeGenerator {
     /// Cached probability distribution
     distribution: Vec<(usize, f64)>, // (degree, cumulative probability)
-    /// Random number generator
-    rng: rand::rngs::ThreadRng,
+    /// Random number generator, owned so sessions can move between threads
+    rng: rand::rngs::StdRng,
 }
 
 impl DegreeGenerator {
@@ -40,7 +67,7 @@ impl DegreeGenerator {
         
         Self {
             distribution,
-            rng: rand::thread_rng(),
+            rng: rand::rngs::StdRng::from_entropy(),
         }
     }
 
@@ -96,7 +123,8 @@ pub fn generate_triple(&mut self, k: usize, x: u32) -> Option<(usize, u32, u32)>
     // Calculate parameters based on RFC 5053
     let a = (53591 + j_k * 997) % Q;
     let b = 10267 * (j_k + 1) % Q;
-    let y = (b + x * a) % Q;
+    // Widen before multiplying: x * a overflows u32 once x exceeds Q
+    let y = ((b as u64 + x as u64 * a as u64) % Q as u64) as u32;
     
     let v = self.rand(y, 0, 1048576); // 2^20
     let d = self.degree_from_v(v);
@@ -115,16 +143,12 @@ pub fn generate_triple(&mut self, k: usize, x: u32) -> Option<(usize, u32, u32)>
     fn degree_from_v(&self, v: u32) -> usize {
         tables::deg(v) as usize
     }
-
-    /// Get systematic index for a given K (Section 5.7)
-    fn systematic_index(&self, k: usize) -> u32 {
-        tables::systematic_index(k).unwrap_or(0)
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn test_degree_distribution() {
@@ -149,6 +173,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_lt_indices_are_distinct() {
+        // 255 = 3 * 5 * 17: stepping modulo K would revisit indices
+        for a in 1..255 {
+            let mut indices = lt_indices(255, 40, a, 7);
+            assert_eq!(indices.len(), 40);
+            assert!(indices.iter().all(|&i| i < 255));
+            indices.sort_unstable();
+            indices.dedup();
+            assert_eq!(indices.len(), 40);
+        }
+
+        // Degree is capped at K
+        assert_eq!(lt_indices(4, 40, 3, 1).len(), 4);
+        assert_eq!(smallest_prime_at_least(255), 257);
+        assert_eq!(smallest_prime_at_least(4), 5);
+    }
+
     #[test]
     fn test_triple_generation() {
         let mut gen = DegreeGenerator::new(100);
@@ -157,9 +199,9 @@ mod tests {
         let triple = gen.generate_triple(100, 0);
         assert!(triple.is_some());
         let (d, a, b) = triple.unwrap();
-        assert!(d >= 1 && d <= 40); // Valid degree range
-        assert!(a >= 1 && a < 100); // Valid a range
-        assert!(b >= 0 && b < 100); // Valid b range
+        assert!((1..=40).contains(&d)); // Valid degree range
+        assert!((1..100).contains(&a)); // Valid a range
+        assert!(b < 100); // Valid b range
 
         // Test deterministic generation
         let triple1 = gen.generate_triple(100, 42).unwrap();
@@ -181,9 +223,9 @@ mod tests {
         
         // The RFC doesn't provide complete test vectors, but we can verify
         // the values are within valid ranges and deterministic
-        assert!(d >= 1 && d <= 40);
-        assert!(a >= 1 && a < 100);
-        assert!(b >= 0 && b < 100);
+        assert!((1..=40).contains(&d));
+        assert!((1..100).contains(&a));
+        assert!(b < 100);
 
         // Verify deterministic generation with specific seed
         let triple2 = gen.generate_triple(100, 2).unwrap();
@@ -1,7 +1,9 @@
 //! Implementation of the systematic Raptor encoder based on RFC 5053
 //! This implements the encoding process described in Section 5.4
 
-use crate::distribution::DegreeGenerator;
+use crate::distribution::{lt_indices, DegreeGenerator};
+use crate::fountain::Block;
+use crate::linear_algebra::xor_into;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -36,7 +38,7 @@ impl Encoder {
         }
 
         // Calculate number of source symbols
-        let k = (data.len() + symbol_size - 1) / symbol_size;
+        let k = data.len().div_ceil(symbol_size);
         let mut source_symbols = Vec::with_capacity(k);
 
         // Split data into symbols
@@ -86,36 +88,41 @@ impl Encoder {
 
     /// Generate the next repair symbol
     pub fn next_repair_symbol(&mut self) -> Result<Vec<u8>, EncoderError> {
+        let symbol = self.repair_symbol(self.current_sequence)?;
+        self.current_sequence += 1;
+        Ok(symbol.data().to_vec())
+    }
+
+    /// Generate repair symbol `sequence`, in the form the decoder takes
+    pub fn repair_symbol(&mut self, sequence: u32) -> Result<Block, EncoderError> {
+        self.encode_symbol(sequence, None)
+    }
+
+    /// Generate repair symbol `sequence` combining `degree` source symbols
+    /// rather than the number drawn from the degree distribution
+    pub fn repair_symbol_with_degree(&mut self, sequence: u32, degree: usize) -> Result<Block, EncoderError> {
+        self.encode_symbol(sequence, Some(degree))
+    }
+
+    fn encode_symbol(&mut self, sequence: u32, degree: Option<usize>) -> Result<Block, EncoderError> {
         // Ensure intermediate symbols are generated
         if self.intermediate_symbols.is_none() {
             self.generate_intermediate_symbols()?;
         }
 
-        let intermediates = self.intermediate_symbols.as_ref().unwrap();
-        let Some((degree, a, b)) = self.degree_generator.generate_triple(self.k, self.current_sequence) else {
+        let Some((drawn, a, b)) = self.degree_generator.generate_triple(self.k, sequence) else {
             return Err(EncoderError::InvalidSourceLength);
         };
-        self.current_sequence += 1;
-        
+        let degree = degree.unwrap_or(drawn);
+
         // Implement LT encoding as specified in Section 5.4.4.3
+        let intermediates = self.intermediate_symbols.as_ref().unwrap();
         let mut result = vec![0; self.symbol_size];
-        let mut b = b as usize;
-        
-        // First symbol
-        while b >= self.k {
-            b = (b + a as usize) % self.k;
+        for index in lt_indices(self.k, degree, a, b) {
+            xor_into(&mut result, &intermediates[index]);
         }
-        result.copy_from_slice(&intermediates[b]);
 
-        // XOR remaining symbols
-        for _ in 1..degree {
-            b = (b + a as usize) % self.k;
-            for i in 0..self.symbol_size {
-                result[i] ^= intermediates[b][i];
-            }
-        }
-
-        Ok(result)
+        Ok(Block::new(result, sequence, degree))
     }
 
     /// Get a source symbol
@@ -167,6 +174,28 @@ mod tests {
         assert_eq!(repair.unwrap().len(), 8); // Should match the symbol_size
     }
 
+    #[test]
+    fn test_repair_symbol_decodes() {
+        use crate::decoder::Decoder;
+
+        let data: Vec<u8> = (0..64).collect();
+        let mut encoder = Encoder::new(&data, 8).unwrap();
+        let mut decoder = Decoder::new(8, 8).unwrap();
+
+        // Source symbols 2 and 5 lost; repair symbols covering them restore them
+        for index in (0..8).filter(|&i| i != 2 && i != 5) {
+            decoder.add_source_block(index, encoder.source_symbol(index).unwrap().to_vec()).unwrap();
+        }
+        for sequence in 0..200 {
+            let symbol = encoder.repair_symbol(sequence).unwrap();
+            decoder.add_block(symbol, sequence).unwrap();
+            if decoder.try_decode().unwrap() {
+                break;
+            }
+        }
+        assert_eq!(decoder.get_decoded_data().unwrap(), data);
+    }
+
     #[test]
     fn test_source_symbol_access() {
         let data = vec![1, 2, 3, 4, 5, 6, 7, 8];
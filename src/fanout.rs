@@ -0,0 +1,324 @@
+//! Distribution of one symbol stream to several destinations
+//!
+//! Symbols are generated once and offered to every destination that has not
+//! yet decoded. Each destination has its own pacer and congestion
+//! controller; one whose pacer is not ready skips the symbols on offer,
+//! which costs nothing since any symbols of a fountain code serve equally
+//! well. Packets are renumbered per destination so that loss reports count
+//! only what was actually sent there.
+
+use crate::congestion::{clock_us, CongestionController};
+use crate::pacer::{Pacer, PacerConfig};
+use crate::protocol::{DataPacket, Packet, ProgressReport, DATA_HEADER_SIZE};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// One receiver, or multicast group of receivers, of a transfer
+#[derive(Debug)]
+pub struct Destination {
+    addr: SocketAddr,
+    pacer: Pacer,
+    /// Byte rate limit from the pacer configuration
+    max_rate: Option<f64>,
+    controller: Option<CongestionController>,
+    next_seq: u32,
+    epoch: Instant,
+    /// Receivers that confirmed completion
+    confirmed: HashSet<SocketAddr>,
+    /// Confirmations needed before the destination is done
+    receivers: usize,
+    /// Whether the receiver abandoned the transfer
+    aborted: bool,
+    sent: u64,
+}
+
+impl Destination {
+    /// Destination at `addr`, done once `receivers` distinct addresses have
+    /// confirmed completion
+    ///
+    /// With congestion control, the rate starts low and follows the
+    /// destination's progress reports up to the configured limit.
+    pub fn new(addr: SocketAddr, pacing: PacerConfig, congestion_control: bool, receivers: usize) -> Self {
+        let max_rate = pacing.bytes_per_second;
+        let controller =
+            congestion_control.then(|| CongestionController::new(max_rate.unwrap_or(f64::INFINITY)));
+        let mut pacer = Pacer::new(pacing);
+        if let Some(controller) = &controller {
+            pacer.set_rate(controller.rate());
+        }
+        Self {
+            addr,
+            pacer,
+            max_rate,
+            controller,
+            next_seq: 0,
+            epoch: Instant::now(),
+            confirmed: HashSet::new(),
+            receivers: receivers.max(1),
+            aborted: false,
+            sent: 0,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Current byte rate in bytes per second, `None` if unlimited
+    pub fn rate(&self) -> Option<f64> {
+        self.pacer.rate()
+    }
+
+    /// Data packets sent so far
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Receivers that confirmed completion so far
+    pub fn confirmed(&self) -> usize {
+        self.confirmed.len()
+    }
+
+    /// Confirmations needed before the destination is done
+    pub fn receivers(&self) -> usize {
+        self.receivers
+    }
+
+    /// Whether every expected receiver confirmed completion, or the
+    /// receiver abandoned the transfer
+    pub fn is_done(&self) -> bool {
+        self.aborted || self.confirmed.len() >= self.receivers
+    }
+
+    /// Whether the receiver abandoned the transfer
+    pub fn is_aborted(&self) -> bool {
+        self.aborted
+    }
+
+    fn set_rate(&mut self, bytes_per_second: f64) {
+        let rate = self.max_rate.map_or(bytes_per_second, |max| bytes_per_second.min(max));
+        self.pacer.set_rate(rate);
+    }
+
+    /// Copies of `batch` numbered and timestamped for this destination, with
+    /// their bytes charged to its pacer
+    pub fn stamp(&mut self, batch: &[DataPacket]) -> Vec<Packet> {
+        batch
+            .iter()
+            .map(|packet| {
+                let seq = self.next_seq;
+                self.next_seq = self.next_seq.wrapping_add(1);
+                self.sent += 1;
+                self.pacer.consume(DATA_HEADER_SIZE + packet.payload.len());
+                Packet::Data(DataPacket {
+                    seq,
+                    timestamp_us: clock_us(self.epoch),
+                    ..packet.clone()
+                })
+            })
+            .collect()
+    }
+}
+
+/// The destinations of a transfer
+#[derive(Debug)]
+pub struct Fanout {
+    destinations: Vec<Destination>,
+}
+
+impl Fanout {
+    pub fn new(destinations: Vec<Destination>) -> Self {
+        Self { destinations }
+    }
+
+    pub fn destinations(&self) -> &[Destination] {
+        &self.destinations
+    }
+
+    /// Whether every destination is done
+    pub fn is_done(&self) -> bool {
+        self.destinations.iter().all(Destination::is_done)
+    }
+
+    /// Destination that feedback from `from` belongs to: the one with that
+    /// address, or else a multicast group whose members answer from their
+    /// own addresses
+    fn find(&mut self, from: SocketAddr) -> Option<&mut Destination> {
+        let index = self
+            .destinations
+            .iter()
+            .position(|d| d.addr == from)
+            .or_else(|| self.destinations.iter().position(|d| d.addr.ip().is_multicast()))?;
+        Some(&mut self.destinations[index])
+    }
+
+    /// Adjust the rate of the destination reporting from `from`
+    pub fn on_report(&mut self, from: SocketAddr, report: &ProgressReport, now: Instant) {
+        let Some(destination) = self.find(from) else { return };
+        if let Some(rate) = destination.controller.as_mut().map(|c| c.on_report(report, now)) {
+            destination.set_rate(rate);
+        }
+    }
+
+    /// Record a completion acknowledgement from `from`, returning the
+    /// destination it completes, if any
+    pub fn on_complete(&mut self, from: SocketAddr) -> Option<&Destination> {
+        let destination = self.find(from)?;
+        let was_done = destination.is_done();
+        destination.confirmed.insert(from);
+        (!was_done && destination.is_done()).then_some(&*destination)
+    }
+
+    /// Stop sending to the destination at `from` as its receiver abandoned
+    /// the transfer, returning it unless already done
+    ///
+    /// Members of a multicast group come and go without affecting it.
+    pub fn on_abort(&mut self, from: SocketAddr) -> Option<&Destination> {
+        let destination = self.destinations.iter_mut().find(|d| d.addr == from)?;
+        if destination.is_done() {
+            return None;
+        }
+        destination.aborted = true;
+        Some(&*destination)
+    }
+
+    /// Back off destinations that stopped reporting
+    pub fn on_tick(&mut self, now: Instant) {
+        for destination in &mut self.destinations {
+            if let Some(rate) = destination.controller.as_mut().and_then(|c| c.on_tick(now)) {
+                destination.set_rate(rate);
+            }
+        }
+    }
+
+    /// Time until the next destination still decoding may be sent to
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        self.destinations
+            .iter_mut()
+            .filter(|d| !d.is_done())
+            .map(|d| d.pacer.delay(now))
+            .min()
+            .unwrap_or(Duration::ZERO)
+    }
+
+    /// Destinations still decoding whose pacer admits a packet at `now`
+    pub fn ready(&mut self, now: Instant) -> impl Iterator<Item = &mut Destination> {
+        self.destinations
+            .iter_mut()
+            .filter_map(move |d| (!d.is_done() && d.pacer.delay(now).is_zero()).then_some(d))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn packet(esi: u32) -> DataPacket {
+        DataPacket {
+            session_id: 1,
+            nonce: 0,
+            transfer_length: 100,
+            symbol_size: 100,
+            flags: 0,
+            sbn: 0,
+            esi,
+            degree: 1,
+            seq: 0,
+            timestamp_us: 0,
+            payload: Bytes::from(vec![0u8; 100]),
+        }
+    }
+
+    fn limited(bytes_per_second: f64) -> PacerConfig {
+        PacerConfig {
+            bytes_per_second: Some(bytes_per_second),
+            packets_per_second: None,
+            burst: 1000,
+        }
+    }
+
+    #[test]
+    fn test_per_destination_pacing() {
+        let fast: SocketAddr = "10.0.0.1:3000".parse().unwrap();
+        let slow: SocketAddr = "10.0.0.2:3000".parse().unwrap();
+        let mut fanout = Fanout::new(vec![
+            Destination::new(fast, limited(1e9), false, 1),
+            Destination::new(slow, limited(1e3), false, 1),
+        ]);
+
+        // Both start with a full burst, then only the fast one keeps up
+        let now = Instant::now();
+        let batch: Vec<_> = (0..20).map(packet).collect();
+        for destination in fanout.ready(now) {
+            destination.stamp(&batch);
+        }
+        let ready: Vec<_> = fanout.ready(now + Duration::from_millis(10)).map(|d| d.addr()).collect();
+        assert_eq!(ready, vec![fast]);
+        assert_eq!(fanout.delay(now + Duration::from_millis(10)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_sequence_per_destination() {
+        let addr: SocketAddr = "10.0.0.1:3000".parse().unwrap();
+        let mut destination = Destination::new(addr, PacerConfig::from_mbps(0), false, 1);
+        let batch: Vec<_> = (7..10).map(packet).collect();
+        destination.stamp(&batch);
+        let seqs: Vec<_> = destination
+            .stamp(&batch)
+            .into_iter()
+            .map(|p| match p {
+                Packet::Data(p) => (p.esi, p.seq),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(seqs, vec![(7, 3), (8, 4), (9, 5)]);
+        assert_eq!(destination.sent(), 6);
+    }
+
+    #[test]
+    fn test_completion() {
+        let a: SocketAddr = "10.0.0.1:3000".parse().unwrap();
+        let group: SocketAddr = "239.1.2.3:3000".parse().unwrap();
+        let mut fanout = Fanout::new(vec![
+            Destination::new(a, PacerConfig::from_mbps(0), true, 1),
+            Destination::new(group, PacerConfig::from_mbps(0), false, 2),
+        ]);
+
+        assert_eq!(fanout.on_complete(a).map(Destination::addr), Some(a));
+        assert!(fanout.on_complete(a).is_none());
+        assert_eq!(fanout.ready(Instant::now()).count(), 1);
+
+        // Group members acknowledge from their own addresses
+        assert!(fanout.on_complete("10.0.0.7:4000".parse().unwrap()).is_none());
+        assert!(fanout.on_complete("10.0.0.7:4000".parse().unwrap()).is_none());
+        assert!(!fanout.is_done());
+        assert_eq!(fanout.on_complete("10.0.0.8:4000".parse().unwrap()).map(Destination::addr), Some(group));
+        assert!(fanout.is_done());
+        assert_eq!(fanout.ready(Instant::now()).count(), 0);
+    }
+
+    #[test]
+    fn test_abort() {
+        let a: SocketAddr = "10.0.0.1:3000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:3000".parse().unwrap();
+        let group: SocketAddr = "239.1.2.3:3000".parse().unwrap();
+        let mut fanout = Fanout::new(vec![
+            Destination::new(a, PacerConfig::from_mbps(0), false, 1),
+            Destination::new(b, PacerConfig::from_mbps(0), false, 1),
+            Destination::new(group, PacerConfig::from_mbps(0), false, 1),
+        ]);
+
+        assert!(fanout.on_abort(a).is_some_and(Destination::is_aborted));
+        assert!(fanout.on_abort(a).is_none());
+        assert!(fanout.on_abort("10.0.0.7:4000".parse().unwrap()).is_none());
+        let ready: Vec<_> = fanout.ready(Instant::now()).map(|d| d.addr()).collect();
+        assert_eq!(ready, vec![b, group]);
+
+        // A receiver that already confirmed cannot take it back
+        fanout.on_complete(b);
+        assert!(fanout.on_abort(b).is_none());
+        assert!(!fanout.destinations()[1].is_aborted());
+    }
+}
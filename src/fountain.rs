@@ -1,7 +1,7 @@
 //! Implementation of the fountain code algorithm based on RFC 5053 (Raptor codes)
 
 use thiserror::Error;
-use crate::distribution::DegreeGenerator;
+use crate::distribution::{lt_indices, DegreeGenerator};
 
 #[derive(Error, Debug)]
 pub enum FountainError {
@@ -52,6 +52,8 @@ pub struct Encoder {
     degree_gen: DegreeGenerator,
     /// Current block sequence number
     sequence: u32,
+    /// Increment between sequence numbers
+    stride: u32,
 }
 
 impl Encoder {
@@ -80,7 +82,7 @@ impl Encoder {
 
         // RFC 5053 requires K (number of source blocks) to be in range 4..=256
         let k = blocks.len();
-        if k < 4 || k > 256 {
+        if !(4..=256).contains(&k) {
             return Err(FountainError::InvalidBlockSize(block_size));
         }
 
@@ -89,9 +91,19 @@ impl Encoder {
             block_size,
             degree_gen: DegreeGenerator::new(k),
             sequence: 0,
+            stride: 1,
         })
     }
 
+    /// Generate sequence numbers `offset`, `offset + stride`, ... from now on
+    ///
+    /// Encoders given the same stride and distinct offsets below it never
+    /// produce the same block, so several senders can serve one receiver.
+    pub fn set_schedule(&mut self, offset: u32, stride: u32) {
+        self.sequence = offset;
+        self.stride = stride.max(1);
+    }
+
     /// Generate the next encoded block following RFC 5053 Section 5.4.4.4
     pub fn next_block(&mut self) -> Result<Block, FountainError> {
         // Generate triple (d, a, b) for current sequence number
@@ -113,27 +125,17 @@ impl Encoder {
 
         // Create block and increment sequence
         let block = Block::new(data, self.sequence, degree);
-        self.sequence += 1;
+        self.sequence = self.sequence.wrapping_add(self.stride);
         
         Ok(block)
     }
 
     /// Select source blocks based on triple values from RFC 5053 Section 5.4.4.4
     fn select_blocks(&self, degree: usize, a: u32, b: u32) -> Vec<&Vec<u8>> {
-        let mut result = Vec::with_capacity(degree);
-        let k = self.blocks.len();
-        
-        // First block
-        let mut index = (b as usize) % k;
-        result.push(&self.blocks[index]);
-
-        // Subsequent blocks
-        for _ in 1..degree {
-            index = ((index + (a as usize)) % k) as usize;
-            result.push(&self.blocks[index]);
-        }
-        
-        result
+        lt_indices(self.blocks.len(), degree, a, b)
+            .into_iter()
+            .map(|index| &self.blocks[index])
+            .collect()
     }
 }
 
@@ -209,4 +211,19 @@ mod tests {
         assert_eq!(block1.degree(), block2.degree());
         assert_eq!(block1.seed(), block2.seed());
     }
+
+    #[test]
+    fn test_schedule() {
+        let data = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let mut reference = Encoder::new(&data, 2).unwrap();
+        let mut encoder = Encoder::new(&data, 2).unwrap();
+        encoder.set_schedule(1, 3);
+
+        let all: Vec<Block> = (0..8).map(|_| reference.next_block().unwrap()).collect();
+        for expected in [1, 4, 7] {
+            let block = encoder.next_block().unwrap();
+            assert_eq!(block.seed(), expected);
+            assert_eq!(block.data(), all[expected as usize].data());
+        }
+    }
 }
@@ -0,0 +1,255 @@
+//! Newline-delimited JSON records of transfers
+//!
+//! Each record is one JSON object on a line of its own, tagged with its
+//! kind under `event`: the transfer `Event`s as they happen, then either a
+//! `complete` record with the hash and statistics or an `error` record
+//! with a stable code. A daemon tags the events of each of its transfers
+//! with the transfer's number and adds `paused`, `resumed`, `finished` and
+//! `refused` records.
+
+use crate::config::ConfigError;
+use crate::daemon::{DaemonEvent, TransferInfo};
+use crate::progress::{decoded_from, goodput, overhead};
+use crate::simulator::NetworkStats;
+use crate::transfer::{Event, ReceiveReport, SendReport, TransferError};
+use serde_json::json;
+use std::net::SocketAddr;
+
+/// Code of errors that are not transfer errors
+pub const GENERIC_ERROR_CODE: &str = "failed";
+
+/// Record of a transfer event
+pub fn event(event: &Event) -> String {
+    serde_json::to_string(event).expect("events serialize")
+}
+
+/// Record of a daemon event
+pub fn daemon_event(event: &DaemonEvent) -> String {
+    let record = match event {
+        DaemonEvent::Transfer(id, event) => {
+            let mut record = serde_json::to_value(event).expect("events serialize");
+            record["transfer"] = json!(id);
+            record
+        }
+        DaemonEvent::Paused(id) => json!({"event": "paused", "transfer": id}),
+        DaemonEvent::Resumed(id) => json!({"event": "resumed", "transfer": id}),
+        DaemonEvent::Finished(info) => {
+            let mut record = serde_json::to_value(info).expect("transfer infos serialize");
+            let fields = record.as_object_mut().expect("transfer infos are objects");
+            let id = fields.remove("id").expect("transfer infos have an id");
+            fields.insert("event".into(), json!("finished"));
+            fields.insert("transfer".into(), id);
+            record
+        }
+        DaemonEvent::Refused {
+            session_id,
+            from,
+            reason,
+        } => json!({
+            "event": "refused",
+            "session_id": format!("{:016x}", session_id),
+            "from": from.to_string(),
+            "reason": reason,
+        }),
+    };
+    record.to_string()
+}
+
+/// Record of a transfer of a daemon, as `ctl` shows it
+pub fn transfer_info(info: &TransferInfo) -> String {
+    serde_json::to_string(info).expect("transfer infos serialize")
+}
+
+/// Record of a transfer submitted to a daemon
+pub fn submitted(transfer: u64) -> String {
+    json!({ "transfer": transfer }).to_string()
+}
+
+fn addrs(addrs: &[SocketAddr]) -> Vec<String> {
+    addrs.iter().map(SocketAddr::to_string).collect()
+}
+
+/// Record of a finished send
+pub fn send_complete(report: &SendReport) -> String {
+    let record = json!({
+        "event": "complete",
+        "role": "sender",
+        "session_id": format!("{:016x}", report.session_id),
+        "hash": report.hash.to_hex().as_str(),
+        "bytes": report.bytes,
+        "symbol_size": report.symbol_size,
+        "num_blocks": report.num_blocks,
+        "source_symbols": report.source_symbols,
+        "packets": report.packets,
+        "confirmed": addrs(&report.confirmed),
+        "elapsed_ms": report.elapsed.as_secs_f64() * 1e3,
+        "goodput_mbps": goodput(report.bytes, report.elapsed),
+        "overhead": overhead(report.packets, report.source_symbols) / 100.0,
+    });
+    record.to_string()
+}
+
+/// Record of a finished receive
+pub fn receive_complete(report: &ReceiveReport) -> String {
+    let decoded_from = decoded_from(report);
+    let record = json!({
+        "event": "complete",
+        "role": "receiver",
+        "session_id": format!("{:016x}", report.session_id),
+        "hash": report.hash.to_hex().as_str(),
+        "bytes": report.bytes,
+        "num_blocks": report.num_blocks,
+        "senders": addrs(&report.senders),
+        "symbols_received": report.symbols_received,
+        "source_symbols": report.source_symbols,
+        "duplicates": report.duplicates,
+        "redundant": report.redundant,
+        "loss": report.loss,
+        "decode_time_ms": report.decode_time.as_secs_f64() * 1e3,
+        "elapsed_ms": report.elapsed.as_secs_f64() * 1e3,
+        "goodput_mbps": goodput(report.bytes, report.elapsed),
+        "overhead": overhead(decoded_from, report.source_symbols) / 100.0,
+        "path": report.path,
+        "files": report.files,
+    });
+    record.to_string()
+}
+
+/// Record of what the simulated network did to the datagrams
+pub fn network(stats: &NetworkStats) -> String {
+    let record = json!({
+        "event": "network",
+        "sent": stats.sent,
+        "lost": stats.lost,
+        "duplicated": stats.duplicated,
+        "reordered": stats.reordered,
+        "corrupted": stats.corrupted,
+        "delivered": stats.delivered,
+    });
+    record.to_string()
+}
+
+/// Code of errors in the configuration files
+pub const CONFIG_ERROR_CODE: &str = "config";
+
+/// Record of an error, with the code of a transfer error if it is one
+pub fn error(error: &anyhow::Error) -> String {
+    let code = match error.downcast_ref::<TransferError>() {
+        Some(error) => error.code(),
+        None if error.is::<ConfigError>() => CONFIG_ERROR_CODE,
+        None => GENERIC_ERROR_CODE,
+    };
+    let record = json!({
+        "event": "error",
+        "code": code,
+        "message": error.to_string(),
+    });
+    record.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::{Direction, TransferInfo, TransferState};
+    use serde_json::Value;
+    use std::time::Duration;
+
+    #[test]
+    fn test_event_records() {
+        let record: Value = serde_json::from_str(&event(&Event::Receiving {
+            session_id: 0xab,
+            bytes: 100,
+            num_blocks: 1,
+            from: SocketAddr::from(([10, 0, 0, 1], 3000)),
+        }))
+        .unwrap();
+        assert_eq!(record["event"], "receiving");
+        assert_eq!(record["session_id"], "00000000000000ab");
+        assert_eq!(record["from"], "10.0.0.1:3000");
+
+        let record: Value = serde_json::from_str(&event(&Event::BlockDecoded {
+            sbn: 2,
+            blocks_decoded: 3,
+            num_blocks: 4,
+        }))
+        .unwrap();
+        assert_eq!(record["event"], "block_decoded");
+        assert_eq!(record["sbn"], 2);
+
+        let record: Value = serde_json::from_str(&event(&Event::InputEnded {
+            bytes: 5000,
+            num_blocks: 1,
+        }))
+        .unwrap();
+        assert_eq!(record["event"], "input_ended");
+        assert_eq!(record["bytes"], 5000);
+    }
+
+    #[test]
+    fn test_daemon_records() {
+        let record: Value = serde_json::from_str(&daemon_event(&DaemonEvent::Transfer(
+            3,
+            Event::InputEnded {
+                bytes: 5000,
+                num_blocks: 1,
+            },
+        )))
+        .unwrap();
+        assert_eq!(record["event"], "input_ended");
+        assert_eq!(record["transfer"], 3);
+
+        let info = TransferInfo {
+            id: 4,
+            direction: Direction::Incoming,
+            session_id: Some(0xab),
+            peers: vec!["10.0.0.1:3000".into()],
+            state: TransferState::Failed,
+            bytes: Some(100),
+            done: 50,
+            path: None,
+            error: Some("No packets received for 30s".into()),
+        };
+        let record: Value = serde_json::from_str(&daemon_event(&DaemonEvent::Finished(info))).unwrap();
+        assert_eq!(record["event"], "finished");
+        assert_eq!(record["transfer"], 4);
+        assert_eq!(record["direction"], "incoming");
+        assert_eq!(record["session_id"], "00000000000000ab");
+        assert_eq!(record["state"], "failed");
+        assert!(record.get("id").is_none());
+    }
+
+    #[test]
+    fn test_complete_record() {
+        let report = SendReport {
+            session_id: 1,
+            hash: blake3::hash(b"data"),
+            bytes: 1_000_000,
+            symbol_size: 1000,
+            num_blocks: 1,
+            source_symbols: 1000,
+            packets: 1100,
+            confirmed: vec![SocketAddr::from(([10, 0, 0, 2], 3000))],
+            elapsed: Duration::from_secs(2),
+        };
+        let record: Value = serde_json::from_str(&send_complete(&report)).unwrap();
+        assert_eq!(record["event"], "complete");
+        assert_eq!(record["hash"], blake3::hash(b"data").to_hex().as_str());
+        assert_eq!(record["confirmed"][0], "10.0.0.2:3000");
+        assert_eq!(record["goodput_mbps"], 4.0);
+        assert!((record["overhead"].as_f64().unwrap() - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let record: Value = serde_json::from_str(&error(&TransferError::Timeout.into())).unwrap();
+        assert_eq!(record["event"], "error");
+        assert_eq!(record["code"], "timeout");
+        assert_eq!(record["message"], "Failed to decode file within timeout");
+
+        let record: Value = serde_json::from_str(&error(&anyhow::anyhow!("something else"))).unwrap();
+        assert_eq!(record["code"], GENERIC_ERROR_CODE);
+
+        let record: Value = serde_json::from_str(&error(&ConfigError::UnknownProfile("lab".into()).into())).unwrap();
+        assert_eq!(record["code"], CONFIG_ERROR_CODE);
+    }
+}
@@ -1,18 +1,42 @@
 //! Fountainflow: High-performance file transfer using fountain codes
 //! Based on RFC 5053 (Raptor Forward Error Correction)
 
+pub mod addr;
+pub mod archive;
 pub mod block;
+pub mod checkpoint;
 pub mod cli;
+pub mod config;
+pub mod congestion;
+#[cfg(unix)]
+pub mod control;
+pub mod daemon;
 pub mod decoder;
 pub mod distribution;
 pub mod encoder;
+pub mod fanout;
 pub mod fountain;
+pub mod json;
 pub mod linear_algebra;
+#[cfg(target_os = "linux")]
+mod linux;
+pub mod offline;
+pub mod pacer;
+pub mod parity;
+pub mod pmtu;
+pub mod progress;
+pub mod protocol;
+pub mod session;
+pub mod simulator;
 pub mod systematic;
 pub mod tables;
+pub mod transfer;
 pub mod transport;
 
 pub use crate::cli::Cli;
 pub use crate::fountain::Encoder;
 pub use crate::decoder::Decoder;
-pub use crate::transport::UdpTransport;
\ No newline at end of file
+pub use crate::protocol::Packet;
+pub use crate::session::{ReceiveSession, SendSession};
+pub use crate::transfer::{Receiver, Sender};
+pub use crate::transport::{PacketTransport, UdpTransport};
\ No newline at end of file
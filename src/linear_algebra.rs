@@ -98,8 +98,8 @@ impl BinaryMatrix {
 
         // Augment matrix with b
         let mut augmented = self.clone();
-        for i in 0..self.rows {
-            augmented.data[i].push(b[i]);
+        for (row, &value) in augmented.data.iter_mut().zip(b) {
+            row.push(value);
         }
 
         // Perform Gaussian elimination
@@ -121,6 +121,13 @@ impl BinaryMatrix {
     }
 }
 
+/// XOR `src` into `dst` element-wise (addition over GF(2))
+pub fn xor_into(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
 impl Index<usize> for BinaryMatrix {
     type Output = Vec<u8>;
 
@@ -0,0 +1,317 @@
+//! Linux socket fast paths
+//!
+//! `sendmmsg`/`recvmmsg` move many datagrams per system call. With UDP GSO
+//! (`UDP_SEGMENT`) the kernel, or the NIC, splits one large buffer into
+//! equal-sized datagrams; with UDP GRO (`UDP_GRO`) it hands over runs of
+//! datagrams from the same sender coalesced into one buffer.
+
+use socket2::SockAddr;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::RawFd;
+use std::ptr;
+
+// Not exported by libc for glibc targets; part of the kernel ABI since
+// Linux 4.18 and 5.0 respectively
+const UDP_SEGMENT: libc::c_int = 103;
+const UDP_GRO: libc::c_int = 104;
+
+/// Most datagrams the kernel accepts in one GSO send
+pub const MAX_GSO_SEGMENTS: usize = 64;
+
+/// Room for one integer control message, as `u64`s for alignment
+type ControlBuffer = [u64; 4];
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn setsockopt_int(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    // SAFETY: `value` outlives the call and its size is passed along
+    check(unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    })?;
+    Ok(())
+}
+
+fn getsockopt_int(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `len` are valid for writes of the advertised size
+    check(unsafe {
+        libc::getsockopt(fd, level, name, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+    })?;
+    Ok(value)
+}
+
+/// Set the don't-fragment bit and ignore the kernel's cached path MTU
+pub fn set_dont_fragment(fd: RawFd, ipv4: bool) -> io::Result<()> {
+    if !ipv4 {
+        setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_PROBE)?;
+    }
+    // Also covers IPv4 traffic of dual-stack IPv6 sockets
+    match setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE) {
+        Err(e) if ipv4 => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the kernel supports UDP segmentation offload on this socket
+pub fn gso_supported(fd: RawFd) -> bool {
+    getsockopt_int(fd, libc::SOL_UDP, UDP_SEGMENT).is_ok()
+}
+
+/// Ask the kernel to coalesce received datagrams, returning whether it will
+pub fn enable_gro(fd: RawFd) -> bool {
+    setsockopt_int(fd, libc::SOL_UDP, UDP_GRO, 1).is_ok()
+}
+
+/// Send the concatenation of `buffers` to `target` as datagrams of
+/// `segment_size` bytes, the last of which may be shorter, in one system call
+pub fn send_segmented(fd: RawFd, target: &SockAddr, buffers: &[&[u8]], segment_size: u16) -> io::Result<()> {
+    let mut iovs: Vec<libc::iovec> = buffers
+        .iter()
+        .map(|buffer| libc::iovec {
+            iov_base: buffer.as_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        })
+        .collect();
+    let mut control: ControlBuffer = [0; 4];
+
+    // SAFETY: every pointer in `msg` refers to a live local or to `buffers`
+    // and `target`, and the control buffer has room for one `u16` message
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = target.as_ptr() as *mut libc::c_void;
+        msg.msg_namelen = target.len();
+        msg.msg_iov = iovs.as_mut_ptr();
+        msg.msg_iovlen = iovs.len();
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as usize;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as usize;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size);
+
+        if libc::sendmsg(fd, &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Send `datagrams` to `target` with as few system calls as the kernel
+/// allows, returning how many were sent
+pub fn send_batch(fd: RawFd, target: &SockAddr, datagrams: &[&[u8]]) -> io::Result<usize> {
+    let mut iovs: Vec<libc::iovec> = datagrams
+        .iter()
+        .map(|datagram| libc::iovec {
+            iov_base: datagram.as_ptr() as *mut libc::c_void,
+            iov_len: datagram.len(),
+        })
+        .collect();
+
+    // SAFETY: all-zero `mmsghdr`s are valid and the pointers stored in them
+    // refer to `iovs` and `target`, which outlive the call
+    let mut messages: Vec<libc::mmsghdr> = iovs
+        .iter_mut()
+        .map(|iov| unsafe {
+            let mut message: libc::mmsghdr = mem::zeroed();
+            message.msg_hdr.msg_name = target.as_ptr() as *mut libc::c_void;
+            message.msg_hdr.msg_namelen = target.len();
+            message.msg_hdr.msg_iov = iov;
+            message.msg_hdr.msg_iovlen = 1;
+            message
+        })
+        .collect();
+
+    let sent = check(unsafe { libc::sendmmsg(fd, messages.as_mut_ptr(), messages.len() as libc::c_uint, 0) })?;
+    Ok(sent as usize)
+}
+
+/// A datagram, or run of coalesced datagrams, received by `recv_batch`
+#[derive(Debug, Clone, Copy)]
+pub struct Received {
+    /// Buffer the data was written to
+    pub index: usize,
+    /// Bytes written to the buffer
+    pub len: usize,
+    pub addr: SocketAddr,
+    /// Size of the coalesced datagrams if the kernel merged several
+    pub segment_size: Option<usize>,
+}
+
+/// Reusable buffers for `recv_batch`
+pub struct RecvBuffers {
+    buffers: Vec<Vec<u8>>,
+    addrs: Vec<libc::sockaddr_storage>,
+    controls: Vec<ControlBuffer>,
+}
+
+impl RecvBuffers {
+    /// Room for `count` datagrams of up to `size` bytes each
+    pub fn new(count: usize, size: usize) -> Self {
+        Self {
+            buffers: vec![vec![0; size]; count],
+            // SAFETY: all-zero `sockaddr_storage` is valid
+            addrs: vec![unsafe { mem::zeroed() }; count],
+            controls: vec![[0; 4]; count],
+        }
+    }
+
+    /// Contents of buffer `index` after a `recv_batch`
+    pub fn get(&self, index: usize, len: usize) -> &[u8] {
+        &self.buffers[index][..len]
+    }
+}
+
+/// Receive as many waiting datagrams as fit in `buffers` with one system
+/// call, describing them in `out`
+pub fn recv_batch(fd: RawFd, buffers: &mut RecvBuffers, out: &mut Vec<Received>) -> io::Result<()> {
+    let count = buffers.buffers.len();
+    let mut iovs: Vec<libc::iovec> = buffers
+        .buffers
+        .iter_mut()
+        .map(|buffer| libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        })
+        .collect();
+
+    // SAFETY: all-zero `mmsghdr`s are valid and the pointers stored in them
+    // refer to buffers owned by `iovs` and `buffers`, which outlive the call
+    let mut messages: Vec<libc::mmsghdr> = (0..count)
+        .map(|i| unsafe {
+            let mut message: libc::mmsghdr = mem::zeroed();
+            message.msg_hdr.msg_name = &mut buffers.addrs[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+            message.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            message.msg_hdr.msg_iov = &mut iovs[i];
+            message.msg_hdr.msg_iovlen = 1;
+            message.msg_hdr.msg_control = buffers.controls[i].as_mut_ptr() as *mut libc::c_void;
+            message.msg_hdr.msg_controllen = mem::size_of::<ControlBuffer>();
+            message
+        })
+        .collect();
+
+    let received = check(unsafe {
+        libc::recvmmsg(fd, messages.as_mut_ptr(), count as libc::c_uint, libc::MSG_WAITFORONE, ptr::null_mut())
+    })?;
+
+    out.clear();
+    for (i, message) in messages.iter().take(received as usize).enumerate() {
+        let Some(addr) = socket_addr(&buffers.addrs[i]) else {
+            continue;
+        };
+        out.push(Received {
+            index: i,
+            len: message.msg_len as usize,
+            addr,
+            // SAFETY: the kernel filled in the control messages of `msg_hdr`
+            segment_size: unsafe { gro_segment_size(&message.msg_hdr) },
+        });
+    }
+    Ok(())
+}
+
+/// Segment size from a `UDP_GRO` control message, if any
+unsafe fn gro_segment_size(msg: &libc::msghdr) -> Option<usize> {
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == UDP_GRO {
+            let size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+            return Some(size as usize);
+        }
+        cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+    None
+}
+
+fn socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family says the storage holds a `sockaddr_in`
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family says the storage holds a `sockaddr_in6`
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::os::fd::AsRawFd;
+
+    #[test]
+    fn test_batched_roundtrip() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = SockAddr::from(receiver.local_addr().unwrap());
+
+        let datagrams: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 100 + i as usize]).collect();
+        let slices: Vec<&[u8]> = datagrams.iter().map(|d| d.as_slice()).collect();
+        assert_eq!(send_batch(sender.as_raw_fd(), &target, &slices).unwrap(), 5);
+
+        let mut buffers = RecvBuffers::new(8, 2048);
+        let mut out = Vec::new();
+        let mut received = Vec::new();
+        while received.len() < 5 {
+            recv_batch(receiver.as_raw_fd(), &mut buffers, &mut out).unwrap();
+            for datagram in &out {
+                assert_eq!(datagram.addr, sender.local_addr().unwrap());
+                received.push(buffers.get(datagram.index, datagram.len).to_vec());
+            }
+        }
+        assert_eq!(received, datagrams);
+    }
+
+    #[test]
+    fn test_segmented_send() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        if !gso_supported(sender.as_raw_fd()) {
+            return;
+        }
+        let target = SockAddr::from(receiver.local_addr().unwrap());
+
+        // Three 100-byte datagrams and a 40-byte one, without GRO on the
+        // receiving side so they arrive separately
+        let buffer: Vec<u8> = (0..340u32).map(|i| (i / 100) as u8).collect();
+        let (head, tail) = buffer.split_at(150);
+        send_segmented(sender.as_raw_fd(), &target, &[head, tail], 100).unwrap();
+
+        let mut datagram = [0u8; 2048];
+        for i in 0..4u8 {
+            let len = receiver.recv(&mut datagram).unwrap();
+            assert_eq!(len, if i < 3 { 100 } else { 40 });
+            assert!(datagram[..len].iter().all(|&b| b == i));
+        }
+    }
+}
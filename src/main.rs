@@ -1,125 +1,756 @@
 use anyhow::Result;
-use clap::Parser;
-use fountainflow::{Cli, Encoder, fountain::Block, Decoder};
-use std::path::Path;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use fountainflow::addr;
+use fountainflow::archive;
+use fountainflow::block::{symbol_size_for_payload, TransferLayout};
+use fountainflow::checkpoint;
+use fountainflow::config::{Config, ConfigError};
+#[cfg(unix)]
+use fountainflow::control::{ControlClient, ControlServer, Submission};
+use fountainflow::daemon::{Daemon, SessionManager};
+use fountainflow::cli::{
+    self, BenchArgs, Command, CreateArgs, CtlArgs, CtlCommand, DecodeFromDirArgs, EncodeToDirArgs, InspectArgs,
+    ParityArgs, ReceiveArgs, SendArgs, ServeArgs, SimulateArgs,
+};
+use fountainflow::offline;
+use fountainflow::pacer::PacerConfig;
+use fountainflow::parity;
+use fountainflow::json;
+use fountainflow::pmtu;
+use fountainflow::progress::{self, Display};
+use fountainflow::session::{session_id_for, ReceiveLimits};
+use fountainflow::simulator::Network;
+use fountainflow::transfer::{
+    self, CancellationToken, Event, Output, OverheadPolicy, PartialOutput, ReceiveReport, Source, TransferError,
+    DEFAULT_PORT,
+};
+use fountainflow::{Cli, PacketTransport, ReceiveSession, Receiver, SendSession, Sender, UdpTransport};
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Exit status of a cancelled command, as of a shell job stopped by Ctrl-C
+const EXIT_CANCELLED: i32 = 130;
+
+/// Exit status of a transfer that ran out of time, as from timeout(1)
+const EXIT_TIMED_OUT: i32 = 124;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize logging
     env_logger::init();
 
-    // Parse command line arguments
-    let cli = Cli::parse();
+    // Parse command line arguments, filling in the rest from the configuration
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let ui = Ui {
+        verbose: cli.verbose,
+        json: cli.json,
+        // Data received to standard output must not mix with the report
+        stderr: matches!(&cli.command, Command::Receive(args) if args.file == Path::new(cli::STDIO)),
+    };
 
-    match cli.mode.as_str() {
-        "send" => send_file(&cli).await?,
-        "receive" => receive_file(&cli).await?,
-        _ => {
-            anyhow::bail!("Invalid mode. Use 'send' or 'receive'");
+    let result = match configure(&mut cli, &matches) {
+        Ok(()) => run(&cli.command, ui).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = &result {
+        match ui.json {
+            true => ui.println(json::error(e)),
+            false => eprintln!("Error: {:?}", e),
         }
+        std::process::exit(exit_code(e));
+    }
+    Ok(())
+}
+
+/// Exit status telling scripts whether a command was cancelled, timed out
+/// or failed otherwise
+fn exit_code(error: &anyhow::Error) -> i32 {
+    match error.downcast_ref::<TransferError>() {
+        Some(TransferError::Cancelled) => EXIT_CANCELLED,
+        Some(
+            TransferError::Timeout
+            | TransferError::NoTransfer(_)
+            | TransferError::Idle(_)
+            | TransferError::Unconfirmed { .. },
+        ) => EXIT_TIMED_OUT,
+        _ => 1,
+    }
+}
+
+/// Token cancelled by the first Ctrl-C or SIGTERM; a second one exits at
+/// once
+fn cancel_on_signal() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        eprintln!("Cancelling, press Ctrl-C again to exit immediately");
+        token.cancel();
+        shutdown_signal().await;
+        std::process::exit(EXIT_CANCELLED);
+    });
+    cancel
+}
+
+/// Wait for Ctrl-C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler can be installed");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Apply the selected profile of the configuration files to the options
+/// not given on the command line
+fn configure(cli: &mut Cli, matches: &ArgMatches) -> Result<(), ConfigError> {
+    let config = Config::load(cli.config.as_deref())?;
+    let profile = config.profile(cli.profile.as_deref())?;
+    if let Some((_, matches)) = matches.subcommand() {
+        profile.apply(&mut cli.command, matches);
+    }
+    Ok(())
+}
+
+async fn run(command: &Command, ui: Ui) -> Result<()> {
+    match command {
+        Command::Send(args) => send_file(args, ui, cancel_on_signal()).await?,
+        Command::Receive(args) => receive_file(args, ui, cancel_on_signal()).await?,
+        Command::Serve(args) => serve(args, ui, cancel_on_signal()).await?,
+        Command::Ctl(args) => ctl(args, ui).await?,
+        Command::Inspect(args) => inspect(args)?,
+        Command::Simulate(args) => simulate(args, ui, cancel_on_signal()).await?,
+        Command::Bench(args) => bench(args)?,
+        Command::EncodeToDir(args) => encode_to_dir(args).await?,
+        Command::DecodeFromDir(args) => decode_from_dir(args).await?,
+        Command::Create(args) => create_parity(args)?,
+        Command::Verify(args) => verify_parity(args)?,
+        Command::Repair(args) => repair_parity(args)?,
     }
 
     Ok(())
 }
 
-async fn send_file(cli: &Cli) -> Result<()> {
-    // Read the file
-    let path = Path::new(&cli.file);
-    let mut file = File::open(path).await?;
-    let mut contents = Vec::new();
-    file.read_to_end(&mut contents).await?;
-
-    // Calculate optimal block size based on MTU
-    let block_size = 1400; // MTU (1500) - UDP header (28) - Our header (72)
-
-    // Create encoder
-    let mut encoder = Encoder::new(&contents, block_size)?;
-    let source_blocks = (contents.len() + block_size - 1) / block_size;
-    
-    // Create transport
-    let transport = fountainflow::transport::UdpTransport::new("0.0.0.0:0", cli.rate_limit).await?;
-
-    // Send approximately 1.5x the number of source blocks for reliable decoding
-    let target_blocks = source_blocks + (source_blocks / 2);
-    let mut sequence = 0u32;
-    
-    println!("Sending {} blocks ({} bytes) to {}", target_blocks, contents.len(), cli.target);
-    
-    for _ in 0..target_blocks {
-        let block = encoder.next_block()?;
-        transport
-            .send_block(&cli.target, block.data(), block.seed(), block.degree(), sequence)
-            .await?;
-        sequence = sequence.wrapping_add(1);
-
-        if cli.verbose {
-            println!(
-                "Sent block {} of {} (degree: {}, size: {})",
-                sequence,
-                target_blocks,
-                block.degree(),
-                block.data().len()
-            );
-        }
-    }
-    
-    println!("Finished sending {} blocks", target_blocks);
+/// How transfers report what happens to them
+#[derive(Debug, Clone, Copy, Default)]
+struct Ui {
+    /// Print every receiver report and decoded source block
+    verbose: bool,
+    /// Print newline-delimited JSON records instead of text and bars
+    json: bool,
+    /// Print to stderr, keeping stdout for data
+    stderr: bool,
+}
+
+impl Ui {
+    /// Print a line of output
+    fn println(&self, line: impl std::fmt::Display) {
+        match self.stderr {
+            true => eprintln!("{}", line),
+            false => println!("{}", line),
+        }
+    }
+
+    /// Print a single event
+    fn print(&self, event: &Event) {
+        match self.json {
+            true => self.println(json::event(event)),
+            false => self.println(progress::describe(event)),
+        }
+    }
+}
+
+/// Read the file, or pack a directory or glob into one archive object, and
+/// tell which it was
+async fn read_input(spec: &str, ui: Ui) -> Result<(Vec<u8>, bool)> {
+    let (object, manifest) = transfer::read_source(spec).await?;
+    if let Some(manifest) = &manifest {
+        ui.print(&Event::Packed {
+            files: manifest.files(),
+            bytes: manifest.total_size(),
+        });
+    }
+    Ok((object, manifest.is_some()))
+}
+
+/// Recreate an archived tree below the destination, or write the file
+async fn write_output(destination: &Path, data: Vec<u8>, archive: bool, ui: Ui) -> Result<()> {
+    let bytes = data.len() as u64;
+    let manifest = transfer::save(data, destination, archive).await?;
+    ui.print(&Event::Saved {
+        path: Some(destination.to_path_buf()),
+        bytes,
+        files: manifest.map(|manifest| manifest.files()),
+    });
     Ok(())
 }
 
-async fn receive_file(cli: &Cli) -> Result<()> {
-    use tokio::fs::File;
-    use tokio::io::AsyncWriteExt;
-    use std::time::Duration;
-    
-    // Create transport
-    let transport = fountainflow::transport::UdpTransport::new(&format!("0.0.0.0:{}", cli.target), cli.rate_limit).await?;
-    
-    println!("Listening on port {}", cli.target);
-    
-    // We'll determine block size and count from the first received block
-    let mut decoder = None;
-    let mut received_count = 0;
-    let start_time = std::time::Instant::now();
-    
-    // Receive blocks for up to 30 seconds
-    while start_time.elapsed() < Duration::from_secs(30) {
-        let (data, seed, degree, sequence, _addr) = transport.receive_block().await?;
-        received_count += 1;
-        
-        // Initialize decoder from first block
-        if decoder.is_none() {
-            let block_size = data.len();
-            // Estimate source blocks based on block size (assuming typical file sizes)
-            let estimated_blocks = 100; // Conservative estimate
-            decoder = Some(Decoder::new(estimated_blocks, block_size)?);
-            println!("Initialized decoder with block size {}", block_size);
-        }
-        
-        if let Some(decoder) = decoder.as_mut() {
-            let block = Block::new(data.to_vec(), seed, degree);
-            decoder.add_block(block, sequence)?;
-            
-            // Try decoding periodically
-            if received_count % 10 == 0 {
-                if decoder.try_decode()? {
-                    if let Some(decoded_data) = decoder.get_decoded_data() {
-                        // Write decoded data to file
-                        let mut file = File::create(&cli.file).await?;
-                        file.write_all(&decoded_data).await?;
-                        println!("Successfully decoded and saved {} bytes to {}", decoded_data.len(), cli.file);
-                        return Ok(());
+/// Run a transfer, showing its progress as it happens
+async fn with_events<F, T>(ui: Ui, transfer: impl FnOnce(mpsc::UnboundedSender<Event>) -> F) -> T
+where
+    F: Future<Output = T>,
+{
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let printer = async {
+        if ui.json {
+            while let Some(event) = events_rx.recv().await {
+                ui.println(json::event(&event));
+            }
+            return;
+        }
+        let mut display = Display::new(ui.verbose);
+        if ui.stderr {
+            display = display.to_stderr();
+        }
+        while let Some(event) = events_rx.recv().await {
+            display.handle(&event);
+        }
+        display.finish();
+    };
+    // The channel closes once the transfer is done with its sender
+    let (result, ()) = tokio::join!(transfer(events_tx), printer);
+    result
+}
+
+async fn send_file(args: &SendArgs, ui: Ui, cancel: CancellationToken) -> Result<()> {
+    let source = match args.file.as_str() {
+        cli::STDIO => Source::Stream(Box::new(tokio::io::stdin())),
+        spec => Source::Path(spec.to_string()),
+    };
+    let mut sender = Sender::new(source)
+        .family(args.family.family())
+        .pacing(args.pacing.pacing())
+        .congestion_control(!args.no_congestion_control)
+        .multicast(args.ttl, args.interface.multicast_interface()?)
+        .receivers(args.receivers)
+        .esi_schedule(args.esi_offset, args.esi_stride)
+        .max_mtu(args.max_mtu)
+        .deadline(args.deadline)
+        .verify_hash(!args.no_checksum)
+        .key(args.key.ack_key())
+        .cancel(cancel);
+    for target in &args.target {
+        sender = sender.target(target);
+    }
+    if let Some(mtu) = args.mtu {
+        sender = sender.mtu(mtu);
+    }
+    if let Some(overhead) = args.overhead {
+        sender = sender.overhead(OverheadPolicy::Fixed(f64::from(overhead) / 100.0));
+    }
+
+    let report = with_events(ui, |events| sender.events(events).send()).await?;
+    if ui.json {
+        println!("{}", json::send_complete(&report));
+        return Ok(());
+    }
+    if report.confirmed.is_empty() {
+        println!("Sent {} packets of {} bytes unconfirmed", report.packets, report.bytes);
+    }
+    println!("{}", progress::send_summary(&report));
+    Ok(())
+}
+
+async fn receive_file(args: &ReceiveArgs, ui: Ui, cancel: CancellationToken) -> Result<()> {
+    // Listen on both address families unless restricted
+    let family = args.listen.family.family();
+    let bind = addr::listen_address(args.listen.bind, args.listen.port, family)?;
+    let output = match args.file == Path::new(cli::STDIO) {
+        true => Output::Writer(Box::new(tokio::io::stdout())),
+        false => Output::Path(args.file.clone()),
+    };
+    let mut receiver = Receiver::new(output)
+        .listen(bind, family == addr::AddressFamily::Any)
+        .checkpoint(!args.no_checkpoint)
+        .partial(partial_output(args.discard_partial))
+        .deadline(args.timeouts.deadline)
+        .limits(args.limits.limits())
+        .key(args.key.ack_key())
+        .cancel(cancel);
+    receiver = match args.wait {
+        true => receiver.first_packet_timeout(None).idle_timeout(None),
+        false => receiver
+            .first_packet_timeout(Some(args.first_packet_timeout))
+            .idle_timeout(Some(args.timeouts.idle_timeout)),
+    };
+    if let Some(group) = args.group {
+        receiver = receiver.group(group, args.interface.multicast_interface()?);
+    }
+
+    let report = with_events(ui, |events| receiver.events(events).receive()).await?;
+    print_receive_report(&report, ui);
+    Ok(())
+}
+
+fn partial_output(discard: bool) -> PartialOutput {
+    match discard {
+        true => PartialOutput::Discard,
+        false => PartialOutput::Keep,
+    }
+}
+
+fn print_receive_report(report: &ReceiveReport, ui: Ui) {
+    match ui.json {
+        true => ui.println(json::receive_complete(report)),
+        false => ui.println(progress::receive_summary(report)),
+    }
+}
+
+/// Receive transfers one after another, each into a file or directory of
+/// the inbox named after its session
+async fn serve(args: &ServeArgs, ui: Ui, cancel: CancellationToken) -> Result<()> {
+    let family = args.listen.family.family();
+    let bind = addr::listen_address(args.listen.bind, args.listen.port, family)?;
+    let transport: Arc<dyn PacketTransport> =
+        Arc::new(UdpTransport::bind(bind, family == addr::AddressFamily::Any, PacerConfig::from_mbps(0)).await?);
+
+    ui.print(&Event::Listening {
+        addr: transport.local_addr()?,
+        group: None,
+    });
+    let daemon = Daemon::new(transport, args.inbox.clone())
+        .max_sessions(args.max_sessions)
+        .max_session_memory(args.max_session_memory)
+        .limits(args.limits.limits())
+        .idle_timeout(Some(args.timeouts.idle_timeout))
+        .deadline(args.timeouts.deadline)
+        .partial(partial_output(args.discard_partial))
+        .key(args.key.ack_key())
+        .cancel(cancel.clone());
+
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let manager = daemon.events(events_tx).build();
+    let control = control_socket(args, &manager, ui, cancel.clone()).await?;
+    let printer = async {
+        while let Some(event) = events_rx.recv().await {
+            match ui.json {
+                true => ui.println(json::daemon_event(&event)),
+                false => {
+                    let line = progress::describe_daemon(&event, ui.verbose);
+                    if !line.is_empty() {
+                        ui.println(line);
                     }
                 }
             }
-            
-            if cli.verbose {
-                println!("Received block {} (degree: {}, size: {})", sequence, degree, data.len());
+        }
+    };
+    // The events channel closes once the manager is dropped
+    let serving = async move {
+        let (served, controlled) = tokio::join!(manager.serve(), control);
+        served?;
+        controlled
+    };
+    let (served, ()) = tokio::join!(serving, printer);
+    served?;
+    // Serving only stops once cancelled
+    Err(TransferError::Cancelled.into())
+}
+
+/// Open the control socket if asked to, returning what answers requests on
+/// it until cancelled
+#[cfg(unix)]
+async fn control_socket(
+    args: &ServeArgs,
+    manager: &SessionManager,
+    ui: Ui,
+    cancel: CancellationToken,
+) -> Result<impl Future<Output = Result<()>>> {
+    let server = match args.control {
+        true => {
+            let server = ControlServer::bind(&args.socket.socket, manager.clone()).await?;
+            if !ui.json {
+                ui.println(format!("Accepting control requests on {}", args.socket.socket.display()));
+            }
+            Some(server.key(args.key.ack_key()))
+        }
+        false => None,
+    };
+    Ok(async move {
+        if let Some(server) = server {
+            server.serve(&cancel).await?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(not(unix))]
+async fn control_socket(
+    args: &ServeArgs,
+    _manager: &SessionManager,
+    _ui: Ui,
+    _cancel: CancellationToken,
+) -> Result<impl Future<Output = Result<()>>> {
+    if args.control {
+        anyhow::bail!("The control interface needs Unix domain sockets");
+    }
+    Ok(async { Ok(()) })
+}
+
+/// Send a request to a running daemon and print what it answers
+#[cfg(unix)]
+async fn ctl(args: &CtlArgs, ui: Ui) -> Result<()> {
+    let socket = &args.socket.socket;
+    let mut client = ControlClient::connect(socket)
+        .await
+        .map_err(|e| anyhow::anyhow!("No daemon is listening on {}: {}", socket.display(), e))?;
+    let transfers = match &args.command {
+        CtlCommand::Submit(submit) => {
+            if submit.file == cli::STDIO {
+                anyhow::bail!("The daemon cannot read the standard input of ctl");
+            }
+            // The daemon resolves paths from its own working directory
+            let file = std::path::absolute(&submit.file)?;
+            let transfer = client
+                .submit(&Submission {
+                    file: file.display().to_string(),
+                    targets: submit.target.clone(),
+                    rate_limit: submit.rate_limit,
+                    mtu: submit.mtu,
+                })
+                .await?;
+            match ui.json {
+                true => ui.println(json::submitted(transfer)),
+                false => ui.println(format!("Submitted transfer {}", transfer)),
             }
+            return Ok(());
+        }
+        CtlCommand::List => client.list().await?,
+        CtlCommand::Status { transfer } => vec![client.status(*transfer).await?],
+        CtlCommand::Pause { transfer } => vec![client.pause(*transfer).await?],
+        CtlCommand::Resume { transfer } => vec![client.resume(*transfer).await?],
+        CtlCommand::Cancel { transfer } => vec![client.cancel(*transfer).await?],
+    };
+    for info in &transfers {
+        match ui.json {
+            true => ui.println(json::transfer_info(info)),
+            false => ui.println(progress::describe_transfer(info)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn ctl(_args: &CtlArgs, _ui: Ui) -> Result<()> {
+    anyhow::bail!("The control interface needs Unix domain sockets")
+}
+
+/// Write the encoded packet stream of the input to chunk files
+async fn encode_to_dir(args: &EncodeToDirArgs) -> Result<()> {
+    let (contents, archive) = read_input(&args.file, Ui::default()).await?;
+    let written = offline::encode_to_dir(
+        &contents,
+        archive,
+        &args.dir,
+        args.chunks as usize,
+        f64::from(args.overhead) / 100.0,
+    )?;
+    println!(
+        "Wrote {} bytes as {} packets in {} source blocks to {} chunk files in {}",
+        contents.len(),
+        written.packets,
+        written.num_blocks,
+        written.files.len(),
+        args.dir.display()
+    );
+    Ok(())
+}
+
+/// Rebuild the input from whatever chunk files survive
+async fn decode_from_dir(args: &DecodeFromDirArgs) -> Result<()> {
+    let decoded = offline::decode_from_dir(&args.dir)?;
+    write_output(&args.file, decoded.data, decoded.archive, Ui::default()).await
+}
+
+/// Protect the file with parity volumes beside it
+fn create_parity(args: &CreateArgs) -> Result<()> {
+    let created = parity::create(&args.file, args.volumes as usize, f64::from(args.redundancy) / 100.0)?;
+    println!(
+        "Wrote {} repair symbols for {} source symbols of {} to {} parity volumes",
+        created.repair_symbols,
+        created.symbols,
+        args.file.display(),
+        created.files.len()
+    );
+    Ok(())
+}
+
+fn print_parity_report(file: &Path, report: &parity::Report) {
+    if report.is_intact() {
+        println!("{}: all {} source symbols intact", file.display(), report.symbols);
+        return;
+    }
+    println!(
+        "{}: {} of {} source symbols damaged{}, {} repair symbols available",
+        file.display(),
+        report.damaged.len(),
+        report.symbols,
+        if report.length_matches { "" } else { " (wrong length)" },
+        report.repair_symbols
+    );
+}
+
+/// Check the file against its parity volumes
+fn verify_parity(args: &ParityArgs) -> Result<()> {
+    let report = parity::verify(&args.file)?;
+    print_parity_report(&args.file, &report);
+    match (report.is_intact(), report.is_repairable()) {
+        (true, _) => Ok(()),
+        (false, true) => anyhow::bail!("{} is damaged but repairable", args.file.display()),
+        (false, false) => anyhow::bail!(
+            "{} is damaged beyond repair in source blocks {:?}",
+            args.file.display(),
+            report.unrepairable
+        ),
+    }
+}
+
+/// Rebuild damaged parts of the file from its parity volumes
+fn repair_parity(args: &ParityArgs) -> Result<()> {
+    let report = parity::repair(&args.file)?;
+    print_parity_report(&args.file, &report);
+    if !report.is_intact() {
+        println!("Repaired {}", args.file.display());
+    }
+    Ok(())
+}
+
+/// Describe what `path` holds: one of the formats written here, or else
+/// an object to send
+fn inspect(args: &InspectArgs) -> Result<()> {
+    let path = &args.path;
+    if path.is_dir() {
+        let chunks = offline::chunk_files(path)?;
+        if !chunks.is_empty() {
+            return inspect_chunks(&chunks);
         }
+        let spec = path.to_str().ok_or_else(|| anyhow::anyhow!("{} is not valid UTF-8", path.display()))?;
+        let (manifest, object) = archive::pack(spec)?;
+        println!(
+            "{}: directory of {} files ({} bytes)",
+            path.display(),
+            manifest.files(),
+            manifest.total_size()
+        );
+        return print_object(&object, args.mtu);
+    }
+
+    if let Ok(Some(saved)) = checkpoint::load(path) {
+        println!("{}: receive checkpoint", path.display());
+        println!("  Session ID:  {:016x}", saved.session_id);
+        println!("  Length:      {} bytes in {}-byte symbols", saved.transfer_length, saved.symbol_size);
+        println!("  Recovered:   {} source blocks", saved.blocks.len());
+        println!("  Saved:       {} symbols of incomplete blocks", saved.symbols.len());
+        return Ok(());
+    }
+    match offline::read_chunk(path) {
+        Ok(_) => return inspect_chunks(std::slice::from_ref(path)),
+        Err(offline::OfflineError::BadHeader(_)) => {}
+        Err(e) => return Err(e.into()),
+    }
+    if let Some(volume) = parity::describe(path)? {
+        println!("{}: parity volume", path.display());
+        println!("  Protects:    {} bytes, BLAKE3 {}", volume.transfer_length, blake3::Hash::from(volume.hash));
+        println!("  Symbols:     {} of {} bytes", volume.symbols, volume.symbol_size);
+        println!("  Repair:      {} intact repair symbols", volume.repair_symbols);
+        return Ok(());
     }
-    
-    anyhow::bail!("Failed to decode file within timeout")
+
+    let data = std::fs::read(path)?;
+    match archive::parse(&data) {
+        Some((manifest, _)) => println!(
+            "{}: archive of {} files ({} bytes)",
+            path.display(),
+            manifest.files(),
+            manifest.total_size()
+        ),
+        None => println!("{}: {} bytes", path.display(), data.len()),
+    }
+    print_object(&data, args.mtu)
+}
+
+/// Describe the sessions stored in chunk files
+fn inspect_chunks(files: &[PathBuf]) -> Result<()> {
+    let mut sessions: HashMap<u64, (u64, u16, usize)> = HashMap::new();
+    for path in files {
+        let packets = offline::read_chunk(path)?;
+        println!("{}: chunk file of {} packets", path.display(), packets.len());
+        for packet in packets {
+            let entry = sessions
+                .entry(packet.session_id)
+                .or_insert((packet.transfer_length, packet.symbol_size, 0));
+            entry.2 += 1;
+        }
+    }
+    for (session_id, (transfer_length, symbol_size, packets)) in sessions {
+        let layout = TransferLayout::new(transfer_length, symbol_size as usize)?;
+        println!(
+            "  Session {:016x}: {} bytes, {} packets for {} source symbols",
+            session_id,
+            transfer_length,
+            packets,
+            layout.source_symbols()
+        );
+    }
+    Ok(())
+}
+
+/// Print the hash, session ID and layout `data` would be sent with
+fn print_object(data: &[u8], mtu: usize) -> Result<()> {
+    let hash = blake3::hash(data);
+    let target = SocketAddr::from(([0, 0, 0, 0], 0));
+    let symbol_size = symbol_size_for_payload(pmtu::max_payload_size(mtu, &target))
+        .map_err(|_| anyhow::anyhow!("MTU of {} bytes leaves no room for data", mtu))?;
+    println!("  BLAKE3:      {}", hash);
+    println!("  Session ID:  {:016x}", session_id_for(&hash));
+    if data.is_empty() {
+        return Ok(());
+    }
+    let layout = TransferLayout::new(data.len() as u64, symbol_size)?;
+    println!(
+        "  Layout:      {} source symbols of {} bytes in {} source blocks (IPv4, MTU {})",
+        layout.source_symbols(),
+        layout.symbol_size,
+        layout.num_blocks,
+        mtu
+    );
+    Ok(())
+}
+
+/// Send the input across an in-process network with the requested
+/// impairments and report what happened to it
+async fn simulate(args: &SimulateArgs, ui: Ui, cancel: CancellationToken) -> Result<()> {
+    let contents = match &args.file {
+        Some(spec) => read_input(spec, ui).await?.0,
+        None => {
+            let mut contents = vec![0; args.size as usize];
+            rand::rngs::StdRng::seed_from_u64(args.seed).fill(&mut contents[..]);
+            contents
+        }
+    };
+
+    let network = Network::new(args.seed, args.impairments());
+    let sender = network.bind(SocketAddr::from(([10, 0, 0, 1], 0)))?;
+    let receiver = network.bind(SocketAddr::from(([10, 0, 0, 2], DEFAULT_PORT)))?;
+    let target = receiver.local_addr()?;
+
+    let (sent, received) = with_events(ui, |events| {
+        let sending = Sender::new(Source::Bytes(contents.clone()))
+            .target(target.to_string())
+            .transport(Arc::new(sender))
+            .pacing(args.pacing.pacing())
+            .congestion_control(!args.no_congestion_control)
+            .mtu(args.mtu)
+            .deadline(args.deadline)
+            .events(events.clone())
+            .cancel(cancel.clone())
+            .send();
+        let receiving = Receiver::new(Output::Memory)
+            .transport(Arc::new(receiver))
+            .deadline(Some(args.deadline))
+            .events(events)
+            .cancel(cancel)
+            .receive();
+        async { tokio::join!(sending, receiving) }
+    })
+    .await;
+    let sent = sent?;
+    let received = received?;
+    if received.data.as_deref() != Some(&contents[..]) {
+        anyhow::bail!("Received data differs from the data sent");
+    }
+
+    let stats = network.stats();
+    if ui.json {
+        println!("{}", json::send_complete(&sent));
+        println!("{}", json::receive_complete(&received));
+        println!("{}", json::network(&stats));
+        return Ok(());
+    }
+    println!(
+        "Transferred {} bytes in {:.2?} ({:.1} Mbps), {} packets sent",
+        received.bytes,
+        received.elapsed,
+        received.bytes as f64 * 8.0 / received.elapsed.as_secs_f64().max(1e-9) / 1e6,
+        sent.packets
+    );
+    println!("{}", progress::receive_summary(&received));
+    println!(
+        "Network: {} datagrams sent, {} lost, {} duplicated, {} reordered, {} corrupted, {} delivered",
+        stats.sent, stats.lost, stats.duplicated, stats.reordered, stats.corrupted, stats.delivered
+    );
+    Ok(())
+}
+
+/// Measure encoding and decoding throughput on random data
+fn bench(args: &BenchArgs) -> Result<()> {
+    if args.loss >= 1.0 {
+        anyhow::bail!("Nothing can be decoded with every symbol lost");
+    }
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+    let mut data = vec![0; args.size as usize];
+    rng.fill(&mut data[..]);
+
+    let mut sender = SendSession::new(&data, args.symbol_size as usize)?;
+    let symbols = sender.layout().source_symbols();
+    let from = SocketAddr::from(([127, 0, 0, 1], 0));
+    let mut receiver: Option<ReceiveSession> = None;
+    let (mut encode_time, mut decode_time) = (Duration::ZERO, Duration::ZERO);
+    let (mut sent, mut received) = (0usize, 0usize);
+    loop {
+        let start = Instant::now();
+        let packet = sender.next_packet()?;
+        encode_time += start.elapsed();
+        sent += 1;
+        if rng.gen_bool(args.loss) {
+            continue;
+        }
+
+        let start = Instant::now();
+        let active = match &mut receiver {
+            Some(active) => active,
+            None => receiver.insert(ReceiveSession::with_limits(&packet, &ReceiveLimits::unlimited())?),
+        };
+        // Symbols for blocks already recovered cost nothing to decode
+        if !active.is_block_decoded(packet.sbn as usize) {
+            received += 1;
+        }
+        active.add_packet(from, &packet)?;
+        let complete = active.is_complete();
+        decode_time += start.elapsed();
+        if complete {
+            break;
+        }
+    }
+    if receiver.and_then(ReceiveSession::into_data).as_deref() != Some(&data[..]) {
+        anyhow::bail!("Decoded data differs from the data encoded");
+    }
+
+    let throughput = |elapsed: Duration| data.len() as f64 * 8.0 / elapsed.as_secs_f64().max(1e-9) / 1e6;
+    println!(
+        "{} bytes in {} source symbols of {} bytes ({} source blocks)",
+        data.len(),
+        symbols,
+        sender.layout().symbol_size,
+        sender.layout().num_blocks
+    );
+    println!("Encoded {} symbols in {:.2?} ({:.1} Mbps)", sent, encode_time, throughput(encode_time));
+    println!("Decoded from {} symbols in {:.2?} ({:.1} Mbps)", received, decode_time, throughput(decode_time));
+    println!(
+        "Reception overhead: {:.1}%",
+        (received as f64 / symbols as f64 - 1.0) * 100.0
+    );
+    Ok(())
 }
@@ -0,0 +1,395 @@
+//! Encoded packet streams stored in chunk files
+//!
+//! For carrying a transfer on removable media, the data packets a sender
+//! would put on the wire are written, headers and all, to a directory of
+//! chunk files. Packets are dealt out to the chunks in turn while the
+//! session cycles through source blocks, so every chunk holds symbols of
+//! every block and any subset of chunks holding enough symbols rebuilds
+//! the object.
+//!
+//! Each chunk file is laid out as follows:
+//!
+//! ```text
+//! magic "FFCK" (4) | version (1) | records...
+//! record: marker "FFRC" (4) | packet length (4) | tag (8) | packet
+//! ```
+//!
+//! The tag is the start of the BLAKE3 hash of the length and packet, so a
+//! damaged record is dropped rather than fed to the decoder. Reading then
+//! resumes at the next marker, and a chunk cut short keeps the packets
+//! before the damage.
+
+use crate::protocol::{DataPacket, Packet};
+use crate::session::{session_id_for, ReceiveLimits, ReceiveSession, SendSession, SessionError};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Symbol size of chunked transfers, which are not limited by a path MTU
+pub const SYMBOL_SIZE: usize = 8192;
+
+/// Extension of chunk files
+pub const CHUNK_EXTENSION: &str = "ffc";
+
+const MAGIC: [u8; 4] = *b"FFCK";
+const VERSION: u8 = 2;
+
+/// Marker starting every record
+const RECORD_MARKER: [u8; 4] = *b"FFRC";
+/// Marker, packet length and tag
+const RECORD_HEADER_SIZE: usize = 16;
+
+/// Source recorded for packets read from chunks
+const CHUNK_SOURCE: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+#[derive(Debug, Error)]
+pub enum OfflineError {
+    #[error("{0}: {1}")]
+    Io(PathBuf, #[source] io::Error),
+    #[error("{0} is not a chunk file")]
+    BadHeader(PathBuf),
+    #[error("No chunk files in {0}")]
+    NoChunks(PathBuf),
+    #[error("Session error: {0}")]
+    Session(#[from] SessionError),
+    #[error("Chunks hold too few symbols: {decoded} of {num_blocks} source blocks recovered")]
+    Insufficient { decoded: usize, num_blocks: usize },
+    #[error("Decoded data does not match session {0:016x}")]
+    Mismatch(u64),
+}
+
+/// Outcome of writing a transfer to chunk files
+#[derive(Debug)]
+pub struct Written {
+    pub files: Vec<PathBuf>,
+    pub packets: usize,
+    pub num_blocks: usize,
+}
+
+/// Path of chunk file `index` in `dir`
+pub fn chunk_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("chunk-{:05}.{}", index, CHUNK_EXTENSION))
+}
+
+/// Object rebuilt from chunk files
+#[derive(Debug)]
+pub struct Decoded {
+    pub data: Vec<u8>,
+    /// Whether the object was marked as an archive to unpack
+    pub archive: bool,
+}
+
+/// Encode `data`, marked as an `archive` or not, into `chunks` chunk files
+/// in `dir`, writing `overhead` times as many repair symbols as there are
+/// source symbols on top of them
+pub fn encode_to_dir(
+    data: &[u8],
+    archive: bool,
+    dir: &Path,
+    chunks: usize,
+    overhead: f64,
+) -> Result<Written, OfflineError> {
+    let mut session = SendSession::new(data, SYMBOL_SIZE)?;
+    session.set_archive(archive);
+    encode_session(session, dir, chunks, overhead)
+}
+
+/// Write the packets of `session` to chunk files as `encode_to_dir` does
+fn encode_session(
+    mut session: SendSession,
+    dir: &Path,
+    chunks: usize,
+    overhead: f64,
+) -> Result<Written, OfflineError> {
+    let layout = session.layout().clone();
+    let per_block = (layout.large_block_symbols as f64 * (1.0 + overhead.max(0.0))).ceil() as usize;
+    let packets = per_block * layout.num_blocks;
+    let chunks = chunks.clamp(1, packets);
+
+    fs::create_dir_all(dir).map_err(|e| OfflineError::Io(dir.to_path_buf(), e))?;
+    let files: Vec<PathBuf> = (0..chunks).map(|index| chunk_path(dir, index)).collect();
+    let mut writers = Vec::with_capacity(chunks);
+    for path in &files {
+        let mut writer = BufWriter::new(File::create(path).map_err(|e| OfflineError::Io(path.clone(), e))?);
+        writer.write_all(&MAGIC).map_err(|e| OfflineError::Io(path.clone(), e))?;
+        writer.write_all(&[VERSION]).map_err(|e| OfflineError::Io(path.clone(), e))?;
+        writers.push(writer);
+    }
+
+    for index in 0..packets {
+        let packet = Packet::Data(session.next_packet()?).encode();
+        let chunk = index % chunks;
+        writers[chunk]
+            .write_all(&record(&packet))
+            .map_err(|e| OfflineError::Io(files[chunk].clone(), e))?;
+    }
+    for (writer, path) in writers.into_iter().zip(&files) {
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .map_err(|e| OfflineError::Io(path.clone(), e))?;
+    }
+
+    Ok(Written {
+        files,
+        packets,
+        num_blocks: layout.num_blocks,
+    })
+}
+
+/// Chunk file record holding `packet`
+fn record(packet: &[u8]) -> Vec<u8> {
+    let len = (packet.len() as u32).to_be_bytes();
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + packet.len());
+    record.extend_from_slice(&RECORD_MARKER);
+    record.extend_from_slice(&len);
+    record.extend_from_slice(&record_tag(&len, packet));
+    record.extend_from_slice(packet);
+    record
+}
+
+/// Tag of a record with length field `len` and `packet`
+fn record_tag(len: &[u8], packet: &[u8]) -> [u8; 8] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(len);
+    hasher.update(packet);
+    hasher.finalize().as_bytes()[..8].try_into().unwrap()
+}
+
+/// Packet of the intact record at the start of `buf` and the length of
+/// the record, or `None` if it is damaged or cut short
+fn parse_record(buf: &[u8]) -> Option<(&[u8], usize)> {
+    let header = buf.get(..RECORD_HEADER_SIZE)?;
+    if header[..4] != RECORD_MARKER {
+        return None;
+    }
+    let len = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+    let packet = buf.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE.checked_add(len)?)?;
+    (record_tag(&header[4..8], packet) == header[8..]).then_some((packet, RECORD_HEADER_SIZE + len))
+}
+
+/// Chunk files in `dir`, in name order
+pub fn chunk_files(dir: &Path) -> Result<Vec<PathBuf>, OfflineError> {
+    let entries = fs::read_dir(dir).map_err(|e| OfflineError::Io(dir.to_path_buf(), e))?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| OfflineError::Io(dir.to_path_buf(), e))?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == CHUNK_EXTENSION) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Data packets stored in the chunk file at `path`
+pub fn read_chunk(path: &Path) -> Result<Vec<DataPacket>, OfflineError> {
+    let mut contents = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut contents))
+        .map_err(|e| OfflineError::Io(path.to_path_buf(), e))?;
+    if contents.len() < MAGIC.len() + 1 || contents[..MAGIC.len()] != MAGIC || contents[MAGIC.len()] != VERSION {
+        return Err(OfflineError::BadHeader(path.to_path_buf()));
+    }
+
+    let mut packets = Vec::new();
+    let mut rest = &contents[MAGIC.len() + 1..];
+    while !rest.is_empty() {
+        let Some((record, len)) = parse_record(rest) else {
+            // Resume at the next record that may be intact
+            let next = rest[1..].windows(RECORD_MARKER.len()).position(|w| w == RECORD_MARKER);
+            log::warn!("Skipping damaged record in {} after {} packets", path.display(), packets.len());
+            match next {
+                Some(next) => rest = &rest[1 + next..],
+                None => break,
+            }
+            continue;
+        };
+        match Packet::decode(record) {
+            Ok(Packet::Data(packet)) => packets.push(packet),
+            Ok(_) => {}
+            Err(e) => log::warn!("Skipping malformed packet in {}: {}", path.display(), e),
+        }
+        rest = &rest[len..];
+    }
+    Ok(packets)
+}
+
+/// Rebuild the object stored in the chunk files in `dir`
+///
+/// Reading stops as soon as every source block of a session is recovered.
+/// The object is not bounded by the limits a network receiver applies, as
+/// the chunks were written locally. Unreadable chunks and packets that do
+/// not fit their session are skipped, and packets of stray sessions only
+/// decode alongside the object.
+pub fn decode_from_dir(dir: &Path) -> Result<Decoded, OfflineError> {
+    let files = chunk_files(dir)?;
+    if files.is_empty() {
+        return Err(OfflineError::NoChunks(dir.to_path_buf()));
+    }
+
+    let mut sessions: HashMap<u64, ReceiveSession> = HashMap::new();
+    let mut complete = None;
+    'chunks: for path in &files {
+        let packets = match read_chunk(path) {
+            Ok(packets) => packets,
+            Err(e) => {
+                log::warn!("Skipping chunk: {}", e);
+                continue;
+            }
+        };
+        for packet in packets {
+            let session = match sessions.entry(packet.session_id) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    match ReceiveSession::with_limits(&packet, &ReceiveLimits::unlimited()) {
+                        Ok(session) => entry.insert(session),
+                        Err(e) => {
+                            log::debug!("Skipping packet in {}: {}", path.display(), e);
+                            continue;
+                        }
+                    }
+                }
+            };
+            match session.add_packet(CHUNK_SOURCE, &packet) {
+                Ok(_) if session.is_complete() => {
+                    complete = Some(packet.session_id);
+                    break 'chunks;
+                }
+                Ok(_) => {}
+                Err(e) => log::debug!("Skipping packet in {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    // Without a complete session, report on the one that got furthest
+    let chosen = complete.or_else(|| {
+        sessions
+            .values()
+            .max_by_key(|session| session.blocks_decoded())
+            .map(ReceiveSession::session_id)
+    });
+    let session = chosen
+        .and_then(|session_id| sessions.remove(&session_id))
+        .ok_or_else(|| OfflineError::NoChunks(dir.to_path_buf()))?;
+    let (session_id, archive) = (session.session_id(), session.is_archive());
+    let (decoded, num_blocks) = (session.blocks_decoded(), session.layout().num_blocks);
+    let data = session.into_data().ok_or(OfflineError::Insufficient { decoded, num_blocks })?;
+    if session_id_for(&blake3::hash(&data)) != session_id {
+        return Err(OfflineError::Mismatch(session_id));
+    }
+    Ok(Decoded { data, archive })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    fn contents(len: usize) -> Vec<u8> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(9);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = contents(3_000_000);
+        let written = encode_to_dir(&data, false, dir.path(), 8, 1.5).unwrap();
+        assert_eq!(written.files.len(), 8);
+        assert_eq!(chunk_files(dir.path()).unwrap(), written.files);
+        let decoded = decode_from_dir(dir.path()).unwrap();
+        assert_eq!(decoded.data, data);
+        assert!(!decoded.archive);
+    }
+
+    #[test]
+    fn test_small_symbols_and_stray_packets() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = contents(200_000);
+
+        // Symbols below what a network receiver accepts, over many blocks
+        let session = SendSession::new(&data, 64).unwrap();
+        assert!(session.layout().num_blocks > 1);
+        let written = encode_session(session, dir.path(), 3, 3.0).unwrap();
+
+        // A stray packet of another session leads the first chunk
+        let stray = SendSession::new(&contents(1000), 64).unwrap().next_packet().unwrap();
+        let first = fs::read(&written.files[0]).unwrap();
+        let packet = Packet::Data(stray).encode();
+        let mut chunk = first[..MAGIC.len() + 1].to_vec();
+        chunk.extend_from_slice(&record(&packet));
+        chunk.extend_from_slice(&first[MAGIC.len() + 1..]);
+        fs::write(&written.files[0], chunk).unwrap();
+
+        assert_eq!(decode_from_dir(dir.path()).unwrap().data, data);
+    }
+
+    #[test]
+    fn test_damaged_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = contents(300_000);
+        let written = encode_to_dir(&data, false, dir.path(), 1, 1.5).unwrap();
+        let path = &written.files[0];
+        let intact = read_chunk(path).unwrap().len();
+        let mut chunk = fs::read(path).unwrap();
+        let start = MAGIC.len() + 1;
+        let size = parse_record(&chunk[start..]).unwrap().1;
+
+        // A flipped bit in a packet, a garbled length and a cut-short tail
+        // each cost only their own record
+        chunk[start + size + RECORD_HEADER_SIZE + 40] ^= 0x10;
+        chunk[start + 3 * size + 5] ^= 0xff;
+        chunk.truncate(chunk.len() - 100);
+        fs::write(path, &chunk).unwrap();
+        assert_eq!(read_chunk(path).unwrap().len(), intact - 3);
+
+        assert_eq!(decode_from_dir(dir.path()).unwrap().data, data);
+    }
+
+    #[test]
+    fn test_archive_flag() {
+        let dir = tempfile::tempdir().unwrap();
+        encode_to_dir(&contents(100_000), true, dir.path(), 2, 0.5).unwrap();
+        assert!(decode_from_dir(dir.path()).unwrap().archive);
+    }
+
+    #[test]
+    fn test_subset_of_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = contents(1_000_000);
+        encode_to_dir(&data, false, dir.path(), 10, 3.0).unwrap();
+
+        // Half the chunks lost, another cut short
+        for index in [0, 2, 4, 6, 8] {
+            fs::remove_file(chunk_path(dir.path(), index)).unwrap();
+        }
+        let cut = chunk_path(dir.path(), 5);
+        let len = fs::metadata(&cut).unwrap().len();
+        File::options().write(true).open(&cut).unwrap().set_len(len / 2).unwrap();
+        assert_eq!(decode_from_dir(dir.path()).unwrap().data, data);
+
+        // One chunk is not enough
+        for index in [1, 3, 5, 7] {
+            fs::remove_file(chunk_path(dir.path(), index)).unwrap();
+        }
+        assert!(matches!(
+            decode_from_dir(dir.path()),
+            Err(OfflineError::Insufficient { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bad_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(decode_from_dir(dir.path()), Err(OfflineError::NoChunks(_))));
+
+        let path = chunk_path(dir.path(), 0);
+        fs::write(&path, b"not a chunk").unwrap();
+        assert!(matches!(read_chunk(&path), Err(OfflineError::BadHeader(_))));
+    }
+}
@@ -0,0 +1,265 @@
+//! Token-bucket pacing for outgoing packets
+//!
+//! Tokens accrue continuously at the configured rate up to the burst size.
+//! A packet may leave as soon as the bucket is non-negative, after which its
+//! size is deducted, so the long-run rate is exact even for packets larger
+//! than the burst. Long gaps are slept through on the tokio timer; the last
+//! stretch below the timer's millisecond granularity is spent yielding or
+//! spinning so packets leave on time instead of in clumps.
+
+use std::time::{Duration, Instant};
+
+/// Default burst size in bytes: ten full-size Ethernet frames
+pub const DEFAULT_BURST: usize = 15_000;
+
+/// Largest packet assumed when converting the byte burst to a packet burst
+const PACKET_BURST_UNIT: usize = 1500;
+
+/// Gaps shorter than this are not handed to the tokio timer
+const SLEEP_THRESHOLD: Duration = Duration::from_millis(2);
+
+/// Gaps shorter than this are busy-waited instead of yielding to the runtime
+const SPIN_THRESHOLD: Duration = Duration::from_micros(50);
+
+/// Convert a rate in megabits per second (SI, 10^6 bits) to bytes per second
+pub fn mbps_to_bytes(mbps: f64) -> f64 {
+    mbps * 1_000_000.0 / 8.0
+}
+
+/// Pacer settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PacerConfig {
+    /// Byte rate limit in bytes per second, `None` for unlimited
+    pub bytes_per_second: Option<f64>,
+    /// Packet rate limit in packets per second, `None` for unlimited
+    pub packets_per_second: Option<f64>,
+    /// Bucket capacity in bytes; the packet bucket holds
+    /// `burst / 1500` packets (at least one)
+    pub burst: usize,
+}
+
+impl PacerConfig {
+    /// Byte rate limit given in Mbps, with 0 meaning unlimited
+    pub fn from_mbps(mbps: u32) -> Self {
+        Self {
+            bytes_per_second: (mbps > 0).then(|| mbps_to_bytes(mbps as f64)),
+            packets_per_second: None,
+            burst: DEFAULT_BURST,
+        }
+    }
+}
+
+/// One token bucket
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+        }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+    }
+
+    /// Time until the bucket is non-negative again
+    fn delay(&self) -> Duration {
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Token-bucket pacer limiting bytes and/or packets per second
+#[derive(Debug)]
+pub struct Pacer {
+    bytes: Option<Bucket>,
+    packets: Option<Bucket>,
+    burst: usize,
+    last_refill: Instant,
+}
+
+impl Pacer {
+    pub fn new(config: PacerConfig) -> Self {
+        let packet_burst = (config.burst / PACKET_BURST_UNIT).max(1) as f64;
+        Self {
+            bytes: config
+                .bytes_per_second
+                .filter(|rate| rate.is_finite() && *rate > 0.0)
+                .map(|rate| Bucket::new(rate, config.burst as f64)),
+            packets: config
+                .packets_per_second
+                .filter(|rate| rate.is_finite() && *rate > 0.0)
+                .map(|rate| Bucket::new(rate, packet_burst)),
+            burst: config.burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Current byte rate in bytes per second, `None` if unlimited
+    pub fn rate(&self) -> Option<f64> {
+        self.bytes.as_ref().map(|bucket| bucket.rate)
+    }
+
+    /// Change the byte rate, keeping the tokens already accumulated
+    ///
+    /// A non-finite or non-positive rate removes the byte limit.
+    pub fn set_rate(&mut self, bytes_per_second: f64) {
+        self.refill(Instant::now());
+        if !bytes_per_second.is_finite() || bytes_per_second <= 0.0 {
+            self.bytes = None;
+            return;
+        }
+        match self.bytes.as_mut() {
+            Some(bucket) => bucket.rate = bytes_per_second,
+            None => self.bytes = Some(Bucket::new(bytes_per_second, self.burst as f64)),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        for bucket in self.bytes.iter_mut().chain(self.packets.iter_mut()) {
+            bucket.refill(elapsed);
+        }
+    }
+
+    /// How long to wait at `now` before the next packet may be sent
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        self.bytes
+            .iter()
+            .chain(self.packets.iter())
+            .map(Bucket::delay)
+            .max()
+            .unwrap_or(Duration::ZERO)
+    }
+
+    /// Account for a packet of `bytes` having been sent
+    pub fn consume(&mut self, bytes: usize) {
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.tokens -= bytes as f64;
+        }
+        if let Some(bucket) = self.packets.as_mut() {
+            bucket.tokens -= 1.0;
+        }
+    }
+
+    /// Wait until a packet of `bytes` may be sent, then account for it
+    pub async fn wait(&mut self, bytes: usize) {
+        sleep(self.delay(Instant::now())).await;
+        self.consume(bytes);
+    }
+}
+
+/// Sleep for `delay` with better than timer resolution
+pub async fn sleep(delay: Duration) {
+    if delay.is_zero() {
+        return;
+    }
+    let deadline = Instant::now() + delay;
+    if delay > SLEEP_THRESHOLD {
+        tokio::time::sleep(delay - SLEEP_THRESHOLD).await;
+    }
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        if remaining > SPIN_THRESHOLD {
+            tokio::task::yield_now().await;
+        } else {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_si_units() {
+        assert_eq!(mbps_to_bytes(8.0), 1_000_000.0);
+        assert_eq!(PacerConfig::from_mbps(100).bytes_per_second, Some(12_500_000.0));
+        assert_eq!(PacerConfig::from_mbps(0).bytes_per_second, None);
+    }
+
+    #[test]
+    fn test_burst_then_steady_rate() {
+        let mut pacer = Pacer::new(PacerConfig {
+            bytes_per_second: Some(1_000_000.0),
+            packets_per_second: None,
+            burst: 3000,
+        });
+        let start = pacer.last_refill;
+
+        // The full burst goes out immediately
+        assert_eq!(pacer.delay(start), Duration::ZERO);
+        pacer.consume(1500);
+        assert_eq!(pacer.delay(start), Duration::ZERO);
+        pacer.consume(1500);
+        assert_eq!(pacer.delay(start), Duration::ZERO);
+        pacer.consume(1500);
+
+        // Then one packet every 1.5 ms
+        let delay = pacer.delay(start);
+        assert!((delay.as_secs_f64() - 0.0015).abs() < 1e-9);
+        assert_eq!(pacer.delay(start + delay), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_packet_rate_limit() {
+        let mut pacer = Pacer::new(PacerConfig {
+            bytes_per_second: None,
+            packets_per_second: Some(1000.0),
+            burst: 1500,
+        });
+        let start = pacer.last_refill;
+
+        pacer.consume(64);
+        assert_eq!(pacer.delay(start), Duration::ZERO);
+        pacer.consume(64);
+        let delay = pacer.delay(start);
+        assert!((delay.as_secs_f64() - 0.001).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_set_rate() {
+        let mut pacer = Pacer::new(PacerConfig::from_mbps(0));
+        assert_eq!(pacer.rate(), None);
+        pacer.set_rate(5000.0);
+        assert_eq!(pacer.rate(), Some(5000.0));
+        pacer.set_rate(f64::INFINITY);
+        assert_eq!(pacer.rate(), None);
+    }
+
+    #[test]
+    fn test_paced_throughput() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // 100 Mbps with the default burst
+            let mut pacer = Pacer::new(PacerConfig::from_mbps(100));
+            let start = Instant::now();
+
+            // 2.5 MB in 1250-byte packets should take about 0.2 seconds
+            for _ in 0..2000 {
+                pacer.wait(1250).await;
+            }
+
+            let elapsed = start.elapsed().as_secs_f64();
+            assert!((0.15..=0.5).contains(&elapsed), "took {}s", elapsed);
+        });
+    }
+}
@@ -0,0 +1,553 @@
+//! Parity volumes protecting a stored file against damage
+//!
+//! Like par2 recovery files, parity volumes sit beside the file they
+//! protect. The file itself supplies the source symbols of the systematic
+//! encoder; the volumes hold a hash of every source symbol, to tell which
+//! ones are damaged, and repair symbols to rebuild them from the rest.
+//! Every volume carries the full symbol index, so any one of them is
+//! enough to verify the file, and repair symbols are dealt out across the
+//! volumes so each one lost takes a share of every source block's.
+//!
+//! A repair symbol only helps if it covers a damaged source symbol, so
+//! rather than following the degree distribution, which mostly combines
+//! two or three, each one combines half the source symbols of its block.
+//! A few more repair symbols than damaged source symbols then usually
+//! suffice.
+//!
+//! Each volume is laid out as follows:
+//!
+//! ```text
+//! magic "FFPV" (4) | version (1) | transfer length (8) | symbol size (4) |
+//! file hash (32) | symbol count (4) | symbol hashes (32 each) | index hash (32) |
+//! records...
+//! record: sbn (4) | esi (4) | hash (32) | symbol
+//! ```
+
+use crate::block::TransferLayout;
+use crate::decoder::{Decoder, DecoderError};
+use crate::encoder::{Encoder, EncoderError};
+use crate::fountain::Block;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Size of the source symbols hashes are kept for, and so the granularity
+/// at which damage is found and repaired
+pub const SYMBOL_SIZE: usize = 4096;
+
+/// Extension of parity volumes
+pub const EXTENSION: &str = "ffpar";
+
+const MAGIC: [u8; 4] = *b"FFPV";
+const VERSION: u8 = 1;
+const HASH_SIZE: usize = 32;
+const RECORD_HEADER_SIZE: usize = 4 + 4 + HASH_SIZE;
+
+#[derive(Debug, Error)]
+pub enum ParityError {
+    #[error("{0}: {1}")]
+    Io(PathBuf, #[source] io::Error),
+    #[error("No parity volumes found for {0}")]
+    NoVolumes(PathBuf),
+    #[error("No parity volume of {0} has an intact index")]
+    NoIndex(PathBuf),
+    #[error("Encoder error: {0}")]
+    Encoder(#[from] EncoderError),
+    #[error("Decoder error: {0}")]
+    Decoder(#[from] DecoderError),
+    #[error("Too few repair symbols for source blocks {0:?}")]
+    Unrepairable(Vec<usize>),
+    #[error("Repaired data does not match the protected file")]
+    HashMismatch,
+}
+
+/// Symbol hashes and layout of a protected file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Index {
+    transfer_length: u64,
+    symbol_size: usize,
+    hash: [u8; HASH_SIZE],
+    symbol_hashes: Vec<[u8; HASH_SIZE]>,
+}
+
+impl Index {
+    fn layout(&self) -> TransferLayout {
+        TransferLayout::new(self.transfer_length, self.symbol_size).expect("layout of a protected file")
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut index = Vec::with_capacity(4 + 1 + 8 + 4 + HASH_SIZE + 4 + self.symbol_hashes.len() * HASH_SIZE);
+        index.extend_from_slice(&MAGIC);
+        index.push(VERSION);
+        index.extend_from_slice(&self.transfer_length.to_be_bytes());
+        index.extend_from_slice(&(self.symbol_size as u32).to_be_bytes());
+        index.extend_from_slice(&self.hash);
+        index.extend_from_slice(&(self.symbol_hashes.len() as u32).to_be_bytes());
+        for hash in &self.symbol_hashes {
+            index.extend_from_slice(hash);
+        }
+        let index_hash = *blake3::hash(&index).as_bytes();
+        index.extend_from_slice(&index_hash);
+        index
+    }
+
+    /// Parse the index at the start of a volume, returning it and the
+    /// records following it, or `None` if it is damaged
+    fn decode(volume: &[u8]) -> Option<(Self, &[u8])> {
+        let fixed = 4 + 1 + 8 + 4 + HASH_SIZE + 4;
+        if volume.len() < fixed || volume[..4] != MAGIC || volume[4] != VERSION {
+            return None;
+        }
+        let transfer_length = u64::from_be_bytes(volume[5..13].try_into().unwrap());
+        let symbol_size = u32::from_be_bytes(volume[13..17].try_into().unwrap()) as usize;
+        let hash: [u8; HASH_SIZE] = volume[17..17 + HASH_SIZE].try_into().unwrap();
+        let count = u32::from_be_bytes(volume[fixed - 4..fixed].try_into().unwrap()) as usize;
+
+        let end = fixed.checked_add(count.checked_mul(HASH_SIZE)?)?;
+        let index_hash = volume.get(end..end + HASH_SIZE)?;
+        if blake3::hash(&volume[..end]).as_bytes() != index_hash {
+            return None;
+        }
+        let symbol_hashes = volume[fixed..end]
+            .chunks_exact(HASH_SIZE)
+            .map(|hash| hash.try_into().unwrap())
+            .collect();
+        let index = Self {
+            transfer_length,
+            symbol_size,
+            hash,
+            symbol_hashes,
+        };
+        // The layout must be one a protected file could have had
+        let layout = TransferLayout::new(transfer_length, symbol_size).ok()?;
+        (layout.source_symbols() == count).then_some((index, &volume[end + HASH_SIZE..]))
+    }
+}
+
+/// A repair symbol read back from a volume
+struct RepairSymbol {
+    sbn: usize,
+    esi: u32,
+    data: Vec<u8>,
+}
+
+/// State of a protected file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Source symbols of the file
+    pub symbols: usize,
+    /// Indices of source symbols that are damaged or missing
+    pub damaged: Vec<usize>,
+    /// Whether the file has the protected length
+    pub length_matches: bool,
+    /// Intact repair symbols found in the volumes
+    pub repair_symbols: usize,
+    /// Source blocks whose damage the repair symbols cannot undo
+    pub unrepairable: Vec<usize>,
+}
+
+impl Report {
+    pub fn is_intact(&self) -> bool {
+        self.damaged.is_empty() && self.length_matches
+    }
+
+    pub fn is_repairable(&self) -> bool {
+        self.unrepairable.is_empty()
+    }
+}
+
+/// Outcome of creating parity volumes
+#[derive(Debug)]
+pub struct Created {
+    pub files: Vec<PathBuf>,
+    pub symbols: usize,
+    pub repair_symbols: usize,
+}
+
+/// Contents of one parity volume
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Volume {
+    /// Length of the protected file
+    pub transfer_length: u64,
+    pub symbol_size: usize,
+    /// BLAKE3 hash of the protected file
+    pub hash: [u8; HASH_SIZE],
+    /// Source symbols of the protected file
+    pub symbols: usize,
+    /// Intact repair symbols held by this volume
+    pub repair_symbols: usize,
+}
+
+/// Path of parity volume `volume` of `file`
+pub fn volume_path(file: &Path, volume: usize) -> PathBuf {
+    let mut name = file.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".vol{:02}.{}", volume, EXTENSION));
+    file.with_file_name(name)
+}
+
+/// Parity volumes present beside `file`, in name order
+pub fn volume_files(file: &Path) -> Result<Vec<PathBuf>, ParityError> {
+    let dir = match file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Some(name) = file.file_name().and_then(|name| name.to_str()) else {
+        return Ok(Vec::new());
+    };
+    let prefix = format!("{}.vol", name);
+    let suffix = format!(".{}", EXTENSION);
+
+    let entries = fs::read_dir(dir).map_err(|e| ParityError::Io(dir.to_path_buf(), e))?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| ParityError::Io(dir.to_path_buf(), e))?;
+        let entry_name = entry.file_name();
+        let Some(entry_name) = entry_name.to_str() else { continue };
+        let Some(number) = entry_name.strip_prefix(&prefix).and_then(|rest| rest.strip_suffix(&suffix)) else {
+            continue;
+        };
+        if !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()) {
+            files.push(file.with_file_name(entry_name));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Source symbols of `data` laid out in source block `sbn`, zero padded
+/// past the end of the data
+fn block_symbols(data: &[u8], layout: &TransferLayout, sbn: usize) -> Vec<Vec<u8>> {
+    let start = layout.block_range(sbn).start as usize;
+    (0..layout.block_symbols(sbn))
+        .map(|i| {
+            let offset = (start + i * layout.symbol_size).min(data.len());
+            let end = (offset + layout.symbol_size).min(data.len());
+            let mut symbol = data[offset..end].to_vec();
+            symbol.resize(layout.symbol_size, 0);
+            symbol
+        })
+        .collect()
+}
+
+/// Source symbols combined into each repair symbol of a block of `k`
+fn repair_degree(k: usize) -> usize {
+    k.div_ceil(2)
+}
+
+/// Write `volumes` parity volumes beside `file` holding `redundancy` times
+/// as many repair symbols as the file has source symbols
+pub fn create(file: &Path, volumes: usize, redundancy: f64) -> Result<Created, ParityError> {
+    let data = fs::read(file).map_err(|e| ParityError::Io(file.to_path_buf(), e))?;
+    let layout = TransferLayout::new(data.len() as u64, SYMBOL_SIZE).expect("layout of a file in memory");
+
+    let blocks: Vec<Vec<Vec<u8>>> = (0..layout.num_blocks).map(|sbn| block_symbols(&data, &layout, sbn)).collect();
+    let index = Index {
+        transfer_length: data.len() as u64,
+        symbol_size: layout.symbol_size,
+        hash: *blake3::hash(&data).as_bytes(),
+        symbol_hashes: blocks.iter().flatten().map(|symbol| *blake3::hash(symbol).as_bytes()).collect(),
+    }
+    .encode();
+
+    let volumes = volumes.max(1);
+    let files: Vec<PathBuf> = (0..volumes).map(|volume| volume_path(file, volume)).collect();
+    let mut writers = Vec::with_capacity(volumes);
+    for path in &files {
+        let mut writer = BufWriter::new(File::create(path).map_err(|e| ParityError::Io(path.clone(), e))?);
+        writer.write_all(&index).map_err(|e| ParityError::Io(path.clone(), e))?;
+        writers.push(writer);
+    }
+
+    // Deal each block's repair symbols out across the volumes in turn
+    let mut repair_symbols = 0;
+    for (sbn, symbols) in blocks.iter().enumerate() {
+        let k = symbols.len();
+        let count = ((k as f64 * redundancy.max(0.0)).ceil() as usize).max(1);
+        let mut encoder = Encoder::new(&symbols.concat(), layout.symbol_size)?;
+        for esi in 0..count as u32 {
+            let symbol = encoder.repair_symbol_with_degree(esi, repair_degree(k))?;
+            let volume = repair_symbols % volumes;
+            let writer = &mut writers[volume];
+            let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + symbol.data().len());
+            record.extend_from_slice(&(sbn as u32).to_be_bytes());
+            record.extend_from_slice(&esi.to_be_bytes());
+            record.extend_from_slice(blake3::hash(symbol.data()).as_bytes());
+            record.extend_from_slice(symbol.data());
+            writer.write_all(&record).map_err(|e| ParityError::Io(files[volume].clone(), e))?;
+            repair_symbols += 1;
+        }
+    }
+    for (writer, path) in writers.into_iter().zip(&files) {
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_all())
+            .map_err(|e| ParityError::Io(path.clone(), e))?;
+    }
+
+    Ok(Created {
+        files,
+        symbols: layout.source_symbols(),
+        repair_symbols,
+    })
+}
+
+/// Repair symbols of the records following a volume's index whose hash
+/// checks out
+fn intact_records<'a>(layout: &'a TransferLayout, records: &'a [u8]) -> impl Iterator<Item = RepairSymbol> + 'a {
+    // Records have a fixed size, so a damaged one does not hide the rest
+    records
+        .chunks_exact(RECORD_HEADER_SIZE + layout.symbol_size)
+        .filter_map(|record| {
+            let sbn = u32::from_be_bytes(record[0..4].try_into().unwrap()) as usize;
+            let esi = u32::from_be_bytes(record[4..8].try_into().unwrap());
+            let data = &record[RECORD_HEADER_SIZE..];
+            if sbn >= layout.num_blocks || blake3::hash(data).as_bytes() != &record[8..RECORD_HEADER_SIZE] {
+                return None;
+            }
+            Some(RepairSymbol {
+                sbn,
+                esi,
+                data: data.to_vec(),
+            })
+        })
+}
+
+/// Describe the parity volume at `path`, `None` if it is not one or its
+/// index is damaged
+pub fn describe(path: &Path) -> Result<Option<Volume>, ParityError> {
+    let volume = fs::read(path).map_err(|e| ParityError::Io(path.to_path_buf(), e))?;
+    let Some((index, records)) = Index::decode(&volume) else {
+        return Ok(None);
+    };
+    let layout = index.layout();
+    Ok(Some(Volume {
+        transfer_length: index.transfer_length,
+        symbol_size: index.symbol_size,
+        hash: index.hash,
+        symbols: index.symbol_hashes.len(),
+        repair_symbols: intact_records(&layout, records).count(),
+    }))
+}
+
+/// Read the index and the intact repair symbols of the volumes of `file`
+fn load(file: &Path) -> Result<(Index, Vec<RepairSymbol>), ParityError> {
+    let files = volume_files(file)?;
+    if files.is_empty() {
+        return Err(ParityError::NoVolumes(file.to_path_buf()));
+    }
+
+    let mut index: Option<Index> = None;
+    let mut repair = Vec::new();
+    for path in &files {
+        let volume = match fs::read(path) {
+            Ok(volume) => volume,
+            Err(e) => {
+                log::warn!("Skipping parity volume {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let Some((volume_index, records)) = Index::decode(&volume) else {
+            log::warn!("Skipping parity volume {} with a damaged index", path.display());
+            continue;
+        };
+        match &index {
+            Some(index) if *index != volume_index => {
+                log::warn!("Skipping parity volume {} of another file", path.display());
+                continue;
+            }
+            Some(_) => {}
+            None => index = Some(volume_index.clone()),
+        }
+
+        repair.extend(intact_records(&volume_index.layout(), records));
+    }
+
+    let index = index.ok_or_else(|| ParityError::NoIndex(file.to_path_buf()))?;
+    Ok((index, repair))
+}
+
+/// Check `file` against its parity volumes, rebuilding damaged source
+/// symbols in memory
+fn recover(file: &Path) -> Result<(Report, Option<Vec<u8>>), ParityError> {
+    let (index, repair) = load(file)?;
+    let layout = index.layout();
+    let mut data = match fs::read(file) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(ParityError::Io(file.to_path_buf(), e)),
+    };
+    let length_matches = data.len() as u64 == index.transfer_length;
+    data.resize(index.transfer_length as usize, 0);
+
+    let mut report = Report {
+        symbols: index.symbol_hashes.len(),
+        damaged: Vec::new(),
+        length_matches,
+        repair_symbols: repair.len(),
+        unrepairable: Vec::new(),
+    };
+
+    let mut first = 0;
+    for sbn in 0..layout.num_blocks {
+        let symbols = block_symbols(&data, &layout, sbn);
+        let damaged: Vec<usize> = (0..symbols.len())
+            .filter(|&i| blake3::hash(&symbols[i]).as_bytes() != &index.symbol_hashes[first + i])
+            .collect();
+        report.damaged.extend(damaged.iter().map(|i| first + i));
+        if damaged.is_empty() {
+            first += symbols.len();
+            continue;
+        }
+
+        let mut decoder = Decoder::new(symbols.len(), layout.symbol_size)?;
+        for (i, symbol) in symbols.iter().enumerate() {
+            if !damaged.contains(&i) {
+                decoder.add_source_block(i, symbol.clone())?;
+            }
+        }
+        // Repair symbols go in one at a time, so a lightly damaged block
+        // does not pay for every repair symbol the volumes hold
+        let degree = repair_degree(symbols.len());
+        let mut decoded = false;
+        for symbol in repair.iter().filter(|symbol| symbol.sbn == sbn) {
+            decoder.add_block(Block::new(symbol.data.clone(), symbol.esi, degree), symbol.esi)?;
+            if decoder.try_decode()? {
+                decoded = true;
+                break;
+            }
+        }
+        match decoded {
+            true => {
+                let decoded = decoder.get_decoded_data().expect("decoded block");
+                let start = layout.block_range(sbn).start as usize;
+                for i in damaged {
+                    let offset = start + i * layout.symbol_size;
+                    let end = (offset + layout.symbol_size).min(data.len());
+                    data[offset..end].copy_from_slice(&decoded[i * layout.symbol_size..][..end - offset]);
+                }
+            }
+            false => report.unrepairable.push(sbn),
+        }
+        first += symbols.len();
+    }
+
+    if !report.is_repairable() {
+        return Ok((report, None));
+    }
+    if blake3::hash(&data).as_bytes() != &index.hash {
+        return Err(ParityError::HashMismatch);
+    }
+    Ok((report, Some(data)))
+}
+
+/// Check `file` against its parity volumes
+pub fn verify(file: &Path) -> Result<Report, ParityError> {
+    recover(file).map(|(report, _)| report)
+}
+
+/// Rebuild the damaged parts of `file` from its parity volumes
+pub fn repair(file: &Path) -> Result<Report, ParityError> {
+    let (report, data) = recover(file)?;
+    if report.is_intact() {
+        return Ok(report);
+    }
+    let data = data.ok_or_else(|| ParityError::Unrepairable(report.unrepairable.clone()))?;
+
+    let mut name = file.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let temporary = file.with_file_name(name);
+    fs::write(&temporary, &data)
+        .and_then(|_| fs::rename(&temporary, file))
+        .map_err(|e| ParityError::Io(file.to_path_buf(), e))?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    fn protected(len: usize) -> (tempfile::TempDir, PathBuf, Vec<u8>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.tar");
+        let mut rng = rand::rngs::StdRng::seed_from_u64(4);
+        let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+        fs::write(&path, &data).unwrap();
+        (dir, path, data)
+    }
+
+    #[test]
+    fn test_intact() {
+        let (_dir, path, _) = protected(100_000);
+        let created = create(&path, 3, 0.1).unwrap();
+        assert_eq!(created.files.len(), 3);
+        assert_eq!(volume_files(&path).unwrap(), created.files);
+        assert_eq!(created.symbols, 25);
+
+        let report = verify(&path).unwrap();
+        assert!(report.is_intact());
+        assert_eq!(report.repair_symbols, created.repair_symbols);
+
+        let volumes: Vec<Volume> = created.files.iter().map(|file| describe(file).unwrap().unwrap()).collect();
+        assert_eq!(volumes.iter().map(|volume| volume.repair_symbols).sum::<usize>(), created.repair_symbols);
+        assert_eq!((volumes[0].transfer_length, volumes[0].symbols), (100_000, 25));
+        assert_eq!(describe(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn test_repair() {
+        let (_dir, path, data) = protected(3_000_000);
+        create(&path, 4, 0.05).unwrap();
+
+        // Bit rot in two symbols, a zeroed sector and a lost volume
+        let mut damaged = data.clone();
+        damaged[10] ^= 1;
+        damaged[1_000_000] ^= 0x80;
+        damaged[2_000_000..2_000_512].fill(0);
+        fs::write(&path, &damaged).unwrap();
+        fs::remove_file(volume_path(&path, 1)).unwrap();
+
+        let report = verify(&path).unwrap();
+        assert_eq!(report.damaged.len(), 3);
+        assert!(!report.is_intact() && report.is_repairable());
+        assert_eq!(fs::read(&path).unwrap(), damaged);
+
+        repair(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert!(verify(&path).unwrap().is_intact());
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let (_dir, path, data) = protected(50_000);
+        create(&path, 1, 0.5).unwrap();
+        fs::write(&path, &data[..45_000]).unwrap();
+
+        let report = verify(&path).unwrap();
+        assert!(!report.length_matches);
+        repair(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn test_unrepairable() {
+        let (_dir, path, data) = protected(200_000);
+        create(&path, 2, 0.05).unwrap();
+
+        // A damaged volume index leaves the other volume to verify with
+        let volume = volume_path(&path, 0);
+        let mut contents = fs::read(&volume).unwrap();
+        contents[20] ^= 1;
+        fs::write(&volume, contents).unwrap();
+
+        let mut damaged = data.clone();
+        damaged[..100_000].fill(0);
+        fs::write(&path, &damaged).unwrap();
+        let report = verify(&path).unwrap();
+        assert!(!report.is_repairable());
+        assert!(matches!(repair(&path), Err(ParityError::Unrepairable(_))));
+        assert_eq!(fs::read(&path).unwrap(), damaged);
+    }
+}
@@ -0,0 +1,300 @@
+//! Packetization layer path MTU discovery in the style of DPLPMTUD (RFC 8899)
+//!
+//! Before a transfer the sender sends probe packets padded to candidate
+//! sizes and the receiver echoes each one it gets. Every round probes a
+//! handful of sizes spread between the largest confirmed MTU and the
+//! smallest one known to fail, so a jumbo-frame LAN or a VPN tunnel is
+//! found in a few round trips. Routers must drop rather than fragment the
+//! probes, which the transport arranges by setting the don't-fragment bit.
+
+use crate::protocol::{Packet, PathProbe, DATA_HEADER_SIZE};
+use crate::transport::PacketTransport;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// MTU every path is assumed to carry (IPv6 minimum link MTU)
+pub const BASE_MTU: usize = 1280;
+
+/// MTU assumed where the path cannot be probed, as for multicast
+pub const DEFAULT_MTU: usize = 1500;
+
+/// Largest MTU probed unless configured otherwise (jumbo frames)
+pub const MAX_PROBE_MTU: usize = 9000;
+
+/// Unanswered rounds before the probed sizes are considered too large
+/// (RFC 8899 Section 5.1.2)
+const MAX_PROBES: u32 = 3;
+
+/// Sizes probed per round
+const PROBES_PER_ROUND: usize = 8;
+
+/// Search stops once the confirmed and failed sizes are this close
+const SEARCH_RESOLUTION: usize = 16;
+
+/// Probe timeout before any round trip has been measured
+const INITIAL_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bounds on the probe timeout derived from the measured round trip
+const MIN_PROBE_TIMEOUT: Duration = Duration::from_millis(50);
+const MAX_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const UDP_HEADER_SIZE: usize = 8;
+
+/// IP and UDP header bytes per datagram sent to `target`
+pub fn datagram_overhead(target: &SocketAddr) -> usize {
+    let ip_header = if target.is_ipv4() { 20 } else { 40 };
+    ip_header + UDP_HEADER_SIZE
+}
+
+/// Largest encoding symbol that fits in a data packet of `mtu` bytes
+pub fn max_payload_size(mtu: usize, target: &SocketAddr) -> usize {
+    mtu.saturating_sub(datagram_overhead(target) + DATA_HEADER_SIZE)
+}
+
+/// Search state of path MTU discovery
+///
+/// Starts by confirming `BASE_MTU`, then narrows the range between the
+/// largest acknowledged and the smallest failed size.
+#[derive(Debug)]
+pub struct ProbeSearch {
+    /// Largest MTU known to work
+    low: usize,
+    /// Largest MTU that might still work
+    high: usize,
+    /// Whether `BASE_MTU` itself has been acknowledged
+    confirmed: bool,
+    /// Unanswered attempts of the current round
+    attempts: u32,
+    done: bool,
+}
+
+impl ProbeSearch {
+    /// Search for an MTU of at most `max_mtu` bytes
+    pub fn new(max_mtu: usize) -> Self {
+        Self {
+            low: BASE_MTU,
+            high: max_mtu.max(BASE_MTU),
+            confirmed: false,
+            attempts: 0,
+            done: false,
+        }
+    }
+
+    /// MTUs to probe in the next round, empty once the search is over
+    pub fn next_round(&self) -> Vec<usize> {
+        if self.done {
+            return Vec::new();
+        }
+        if !self.confirmed {
+            return vec![BASE_MTU];
+        }
+        if self.high - self.low < SEARCH_RESOLUTION {
+            return Vec::new();
+        }
+
+        let span = self.high - self.low;
+        let mut sizes: Vec<usize> = (1..=PROBES_PER_ROUND)
+            .map(|i| self.low + span * i / PROBES_PER_ROUND)
+            .collect();
+        sizes.dedup();
+        sizes
+    }
+
+    /// Record which of the sizes of the current round were acknowledged
+    pub fn on_round(&mut self, acked: &[usize]) {
+        let sizes = self.next_round();
+        let best = acked.iter().copied().filter(|size| sizes.contains(size)).max();
+
+        match best {
+            Some(best) => {
+                self.confirmed = true;
+                self.low = self.low.max(best);
+                // Sizes above the best one in the same round went unanswered;
+                // as with a round without answers, they are only ruled out
+                // once that happened `MAX_PROBES` times, not for one loss
+                match sizes.iter().copied().filter(|&size| size > best).min() {
+                    Some(failed) => {
+                        self.attempts += 1;
+                        if self.attempts >= MAX_PROBES {
+                            self.high = failed - 1;
+                            self.attempts = 0;
+                        }
+                    }
+                    None => self.attempts = 0,
+                }
+            }
+            None => {
+                self.attempts += 1;
+                if self.attempts < MAX_PROBES {
+                    return;
+                }
+                self.attempts = 0;
+                match sizes.iter().min() {
+                    Some(&smallest) if self.confirmed => self.high = smallest - 1,
+                    _ => self.done = true,
+                }
+            }
+        }
+    }
+
+    /// Whether the path answered probes at all
+    pub fn confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    /// Largest MTU confirmed so far, or `BASE_MTU` if none was
+    pub fn mtu(&self) -> usize {
+        self.low
+    }
+}
+
+/// Probe the path to `target` and return the largest MTU of at most
+/// `max_mtu` bytes acknowledged by the receiver
+///
+/// Falls back to `BASE_MTU` if the receiver does not answer probes.
+pub async fn discover(
+    transport: &dyn PacketTransport,
+    target: SocketAddr,
+    session_id: u64,
+    max_mtu: usize,
+) -> Result<usize> {
+    let overhead = datagram_overhead(&target);
+    let max_mtu = max_mtu.min(u16::MAX as usize + overhead);
+    let mut search = ProbeSearch::new(max_mtu);
+    let mut timeout = INITIAL_PROBE_TIMEOUT;
+    let mut probe_id = 0u32;
+
+    loop {
+        let sizes = search.next_round();
+        if sizes.is_empty() {
+            break;
+        }
+
+        let start = Instant::now();
+        let mut pending = HashMap::new();
+        for &mtu in &sizes {
+            let probe = PathProbe {
+                session_id,
+                probe_id,
+                size: (mtu - overhead) as u16,
+            };
+            // Sizes above the interface MTU fail locally with EMSGSIZE
+            match transport.send_packet(target, &Packet::Probe(probe)).await {
+                Ok(()) => {
+                    pending.insert(probe_id, mtu);
+                }
+                Err(e) => log::debug!("Probe of {} bytes not sent: {}", mtu, e),
+            }
+            probe_id = probe_id.wrapping_add(1);
+        }
+
+        let mut acked = Vec::new();
+        let deadline = tokio::time::Instant::from_std(start + timeout);
+        while !pending.is_empty() {
+            let Ok(received) = tokio::time::timeout_at(deadline, transport.receive_packet()).await else {
+                break;
+            };
+            let (packet, addr) = received?;
+            let Packet::ProbeAck(ack) = packet else {
+                continue;
+            };
+            if addr != target || ack.session_id != session_id {
+                continue;
+            }
+            if let Some(mtu) = pending.remove(&ack.probe_id) {
+                if acked.is_empty() {
+                    timeout = (start.elapsed() * 3).clamp(MIN_PROBE_TIMEOUT, MAX_PROBE_TIMEOUT);
+                }
+                acked.push(mtu);
+            }
+        }
+
+        log::debug!("Probed MTUs {:?}, acknowledged {:?}", sizes, acked);
+        search.on_round(&acked);
+    }
+
+    if !search.confirmed() {
+        log::warn!("{} did not answer path MTU probes, assuming {} bytes", target, BASE_MTU);
+    }
+    Ok(search.mtu())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a search against a path carrying `path_mtu`, dropping probes
+    /// for which `lose` returns true
+    fn simulate(max_mtu: usize, path_mtu: usize, mut lose: impl FnMut(usize) -> bool) -> (usize, usize) {
+        let mut search = ProbeSearch::new(max_mtu);
+        let mut rounds = 0;
+        loop {
+            let sizes = search.next_round();
+            if sizes.is_empty() {
+                return (search.mtu(), rounds);
+            }
+            let acked: Vec<usize> = sizes.into_iter().filter(|&s| s <= path_mtu && !lose(s)).collect();
+            search.on_round(&acked);
+            rounds += 1;
+            assert!(rounds < 100, "search did not terminate");
+        }
+    }
+
+    #[test]
+    fn test_finds_path_mtu() {
+        for path_mtu in [1280, 1420, 1500, 4000, 9000] {
+            let (mtu, rounds) = simulate(MAX_PROBE_MTU, path_mtu, |_| false);
+            assert!(mtu <= path_mtu);
+            assert!(path_mtu - mtu < SEARCH_RESOLUTION, "found {} for {}", mtu, path_mtu);
+            assert!(rounds <= 12, "{} rounds for {}", rounds, path_mtu);
+        }
+
+        // Never above the configured maximum
+        let (mtu, _) = simulate(1400, 9000, |_| false);
+        assert_eq!(mtu, 1400);
+    }
+
+    #[test]
+    fn test_silent_receiver() {
+        let (mtu, rounds) = simulate(MAX_PROBE_MTU, 9000, |_| true);
+        assert_eq!(mtu, BASE_MTU);
+        assert_eq!(rounds, MAX_PROBES as usize);
+    }
+
+    #[test]
+    fn test_survives_probe_loss() {
+        // Every other probe is lost
+        let mut n = 0;
+        let (mtu, _) = simulate(MAX_PROBE_MTU, 1500, |_| {
+            n += 1;
+            n % 2 == 0
+        });
+        assert!(mtu <= 1500);
+        assert!(mtu >= 1400);
+    }
+
+    #[test]
+    fn test_single_probe_loss() {
+        // A probe just below the path MTU is lost once, while a smaller one
+        // of the same round is answered
+        let mut lost = false;
+        let (mtu, _) = simulate(MAX_PROBE_MTU, 8500, |size| {
+            let lose = (8300..=8500).contains(&size) && !lost;
+            lost |= lose;
+            lose
+        });
+        assert!(lost);
+        assert!(8500 - mtu < SEARCH_RESOLUTION, "found {}", mtu);
+    }
+
+    #[test]
+    fn test_payload_size() {
+        let v4: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let v6: SocketAddr = "[::1]:1".parse().unwrap();
+        assert_eq!(max_payload_size(1500, &v4), 1500 - 28 - DATA_HEADER_SIZE);
+        assert_eq!(max_payload_size(1500, &v6), 1500 - 48 - DATA_HEADER_SIZE);
+        assert_eq!(max_payload_size(10, &v4), 0);
+    }
+}
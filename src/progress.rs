@@ -0,0 +1,523 @@
+//! Terminal progress bars and end-of-transfer reports
+//!
+//! `Display` turns transfer events into one bar per destination when
+//! sending and one bar when receiving, printing the other events above
+//! them. Streams, whose length is unknown, get a spinner instead. Bars are
+//! hidden when stderr is not a terminal.
+
+use crate::daemon::{DaemonEvent, Direction, TransferInfo, TransferState};
+use crate::transfer::{Event, ReceiveReport, SendReport};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const BAR_TEMPLATE: &str = "{prefix:>21} [{bar:30.cyan/blue}] {bytes}/{total_bytes} {bytes_per_sec} ETA {eta} {msg}";
+const SPINNER_TEMPLATE: &str = "{prefix:>21} {spinner} {bytes} {bytes_per_sec} {msg}";
+
+/// How often bars are redrawn
+const REFRESH_RATE: u8 = 4;
+
+/// Progress of a transfer on the terminal
+pub struct Display {
+    verbose: bool,
+    bars: MultiProgress,
+    /// One bar per destination, filled as its receiver reports symbols
+    destinations: HashMap<SocketAddr, ProgressBar>,
+    /// Bytes in each symbol of the object being sent
+    symbol_size: u64,
+    /// Bytes of symbols encoded so far
+    sent: u64,
+    receiving: Option<ProgressBar>,
+    /// Bytes of a stream sent or written so far
+    stream: Option<ProgressBar>,
+    /// Print lines to stderr instead of stdout
+    stderr: bool,
+}
+
+impl Display {
+    /// Display that also prints per-report and per-block lines if `verbose`
+    pub fn new(verbose: bool) -> Self {
+        Self {
+            verbose,
+            bars: MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::stderr_with_hz(REFRESH_RATE)),
+            destinations: HashMap::new(),
+            symbol_size: 0,
+            sent: 0,
+            receiving: None,
+            stream: None,
+            stderr: false,
+        }
+    }
+
+    /// Print lines to stderr, keeping stdout for data
+    pub fn to_stderr(mut self) -> Self {
+        self.stderr = true;
+        self
+    }
+
+    fn bar(&self, bytes: u64, prefix: String) -> ProgressBar {
+        let style = ProgressStyle::with_template(BAR_TEMPLATE)
+            .expect("valid template")
+            .progress_chars("=> ");
+        self.bars
+            .add(ProgressBar::new(bytes).with_style(style).with_prefix(prefix))
+    }
+
+    fn spinner(&self, prefix: &str) -> ProgressBar {
+        let style = ProgressStyle::with_template(SPINNER_TEMPLATE).expect("valid template");
+        self.bars
+            .add(ProgressBar::new_spinner().with_style(style).with_prefix(prefix.to_string()))
+    }
+
+    /// Print a line above the bars
+    fn println(&self, line: String) {
+        if line.is_empty() {
+            return;
+        }
+        match self.stderr {
+            true => self.bars.suspend(|| eprintln!("{}", line)),
+            false => self.bars.suspend(|| println!("{}", line)),
+        }
+    }
+
+    /// Update the bars for `event`, or print it
+    pub fn handle(&mut self, event: &Event) {
+        match event {
+            Event::Sending {
+                bytes,
+                symbol_size,
+                targets,
+                ..
+            } => {
+                self.println(describe(event));
+                self.symbol_size = *symbol_size as u64;
+                for &target in targets {
+                    let bar = self.bar(*bytes, target.to_string());
+                    self.destinations.insert(target, bar);
+                }
+            }
+            Event::Streaming { .. } => {
+                self.println(describe(event));
+                self.stream = Some(self.spinner("sent"));
+            }
+            Event::Sent { bytes, .. } => {
+                self.sent = *bytes;
+                if let Some(stream) = &self.stream {
+                    stream.set_position(self.sent);
+                }
+                for (addr, bar) in &self.destinations {
+                    // Nobody in a multicast group reports back, so its bar
+                    // shows what was sent
+                    if addr.ip().is_multicast() {
+                        bar.set_position(self.sent.min(bar.length().unwrap_or(0)));
+                    }
+                    bar.set_message(format!("{} sent", HumanBytes(self.sent)));
+                }
+            }
+            Event::Progress {
+                addr,
+                symbols_received,
+                loss,
+                ..
+            } => {
+                if let Some(bar) = self.destinations.get(addr) {
+                    bar.set_position((symbols_received * self.symbol_size).min(bar.length().unwrap_or(0)));
+                    bar.set_message(format!("{} sent, {:.1}% loss", HumanBytes(self.sent), loss * 100.0));
+                }
+                if self.verbose {
+                    self.println(describe(event));
+                }
+            }
+            Event::Confirmed { addr, .. } => {
+                if let Some(bar) = self.destinations.get(addr) {
+                    bar.finish_with_message("confirmed");
+                }
+                self.println(describe(event));
+            }
+            Event::Aborted { addr } => {
+                if let Some(bar) = self.destinations.get(addr) {
+                    bar.abandon_with_message("abandoned");
+                }
+                self.println(describe(event));
+            }
+            Event::Receiving { bytes, .. } => {
+                self.println(describe(event));
+                self.receiving = Some(self.bar(*bytes, "received".to_string()));
+            }
+            Event::Received {
+                symbols_received,
+                symbols_useful,
+                symbols_needed,
+                loss,
+                ..
+            } => {
+                if let Some(bar) = &self.receiving {
+                    let needed = (*symbols_needed).max(1) as u64;
+                    bar.set_position(bar.length().unwrap_or(0) * (*symbols_useful as u64).min(needed) / needed);
+                    bar.set_message(format!(
+                        "{}/{} symbols, {:.1}% loss",
+                        symbols_received,
+                        symbols_needed,
+                        loss * 100.0
+                    ));
+                }
+            }
+            Event::ReceivingStream { .. } => {
+                self.println(describe(event));
+                self.stream = Some(self.spinner("written"));
+            }
+            Event::Written { bytes, blocks } => {
+                if let Some(stream) = &self.stream {
+                    stream.set_position(*bytes);
+                    stream.set_message(format!("{} source blocks", blocks));
+                }
+            }
+            Event::BlockDecoded { .. } => {
+                if self.verbose {
+                    self.println(describe(event));
+                }
+            }
+            Event::Saved { .. } => {
+                for bar in self.receiving.take().into_iter().chain(self.stream.take()) {
+                    bar.finish_and_clear();
+                }
+                self.println(describe(event));
+            }
+            _ => self.println(describe(event)),
+        }
+    }
+
+    /// Remove the bars once the transfer is over
+    pub fn finish(&mut self) {
+        for (_, bar) in self.destinations.drain() {
+            bar.finish_and_clear();
+        }
+        for bar in self.receiving.take().into_iter().chain(self.stream.take()) {
+            bar.finish_and_clear();
+        }
+        let _ = self.bars.clear();
+    }
+}
+
+/// One line describing `event`, empty for events only shown as progress
+pub fn describe(event: &Event) -> String {
+    match event {
+        Event::Packed { files, bytes } => format!("Packed {} files ({} bytes) into an archive", files, bytes),
+        Event::Sending {
+            bytes,
+            num_blocks,
+            symbol_size,
+            targets,
+            mtu,
+            ..
+        } => {
+            let targets: Vec<String> = targets.iter().map(SocketAddr::to_string).collect();
+            format!(
+                "Sending {} bytes in {} source blocks of {}-byte symbols to {} (MTU {})",
+                bytes,
+                num_blocks,
+                symbol_size,
+                targets.join(", "),
+                mtu
+            )
+        }
+        Event::Streaming {
+            symbol_size,
+            block_symbols,
+            targets,
+            mtu,
+            ..
+        } => {
+            let targets: Vec<String> = targets.iter().map(SocketAddr::to_string).collect();
+            format!(
+                "Streaming in source blocks of {} {}-byte symbols to {} (MTU {})",
+                block_symbols,
+                symbol_size,
+                targets.join(", "),
+                mtu
+            )
+        }
+        Event::InputEnded { bytes, num_blocks } => {
+            format!("Input ended after {} bytes in {} source blocks", bytes, num_blocks)
+        }
+        Event::Progress {
+            addr,
+            symbols_received,
+            blocks_decoded,
+            num_blocks,
+            rate,
+            loss,
+        } => format!(
+            "Receiver {}: {} symbols received, {}/{} source blocks decoded, {:.1}% loss (rate: {:.1} Mbps)",
+            addr,
+            symbols_received,
+            blocks_decoded,
+            num_blocks,
+            loss * 100.0,
+            rate.unwrap_or(f64::INFINITY) * 8.0 / 1e6
+        ),
+        Event::Confirmed {
+            addr,
+            confirmed,
+            receivers: 1,
+            packets_sent,
+        } if *confirmed == 1 => format!("Receiver {} confirmed after {} blocks sent", addr, packets_sent),
+        Event::Confirmed {
+            addr,
+            confirmed,
+            packets_sent,
+            ..
+        } => format!(
+            "{} receivers of {} confirmed after {} blocks sent",
+            confirmed, addr, packets_sent
+        ),
+        Event::Aborted { addr } => format!("Receiver {} abandoned the transfer", addr),
+        Event::Listening {
+            addr,
+            group: Some(group),
+        } => format!("Listening on {} for group {}", addr, group),
+        Event::Listening { addr, group: None } => format!("Listening on {}", addr),
+        Event::Receiving {
+            bytes,
+            num_blocks,
+            from,
+            ..
+        } => format!(
+            "Receiving {} bytes in {} source blocks from {}",
+            bytes, num_blocks, from
+        ),
+        Event::ReceivingStream { from, .. } => format!("Receiving a stream from {}", from),
+        Event::Resumed { path, symbols, blocks } => format!(
+            "Resuming from {}: {} source blocks recovered, {} symbols saved",
+            path.display(),
+            blocks,
+            symbols
+        ),
+        Event::NewSender { addr } => format!("Also receiving from {}", addr),
+        Event::BlockDecoded {
+            sbn,
+            blocks_decoded,
+            num_blocks,
+        } => format!("Decoded source block {} ({}/{})", sbn, blocks_decoded, num_blocks),
+        Event::Saved {
+            path: Some(path),
+            bytes,
+            files: Some(files),
+        } => format!(
+            "Successfully decoded and saved {} files ({} bytes) to {}",
+            files,
+            bytes,
+            path.display()
+        ),
+        Event::Saved {
+            path: Some(path),
+            bytes,
+            files: None,
+        } => format!("Successfully decoded and saved {} bytes to {}", bytes, path.display()),
+        Event::Kept { path } => format!("Kept what was received in {}", path.display()),
+        Event::Sent { .. } | Event::Received { .. } | Event::Written { .. } | Event::Saved { path: None, .. } => {
+            String::new()
+        }
+    }
+}
+
+/// One line describing `event` of a daemon, empty for events only shown
+/// as progress and, unless `verbose`, per-report and per-block events
+pub fn describe_daemon(event: &DaemonEvent, verbose: bool) -> String {
+    match event {
+        DaemonEvent::Transfer(_, Event::Progress { .. } | Event::BlockDecoded { .. }) if !verbose => String::new(),
+        DaemonEvent::Transfer(id, event) => match describe(event) {
+            line if line.is_empty() => line,
+            line => format!("[{}] {}", id, line),
+        },
+        DaemonEvent::Paused(id) => format!("[{}] Paused", id),
+        DaemonEvent::Resumed(id) => format!("[{}] Resumed", id),
+        DaemonEvent::Finished(info) => match (info.state, &info.error) {
+            (TransferState::Failed, Some(error)) => format!("[{}] Failed: {}", info.id, error),
+            (state, _) => format!("[{}] {:?}", info.id, state),
+        },
+        DaemonEvent::Refused {
+            session_id,
+            from,
+            reason,
+        } => format!("Refused session {:016x} from {}: {}", session_id, from, reason),
+    }
+}
+
+/// One line describing a transfer of a daemon and how far it got
+pub fn describe_transfer(info: &TransferInfo) -> String {
+    let mut line = match info.direction {
+        Direction::Incoming => format!("[{}] Incoming from {}", info.id, info.peers.join(", ")),
+        Direction::Outgoing => format!("[{}] Outgoing to {}", info.id, info.peers.join(", ")),
+    };
+    line += &format!(", {:?}", info.state).to_lowercase();
+    line += &match info.bytes {
+        Some(bytes) => format!(
+            ": {} of {} ({:.0}%)",
+            HumanBytes(info.done),
+            HumanBytes(bytes),
+            info.done as f64 * 100.0 / bytes.max(1) as f64
+        ),
+        None => format!(": {}", HumanBytes(info.done)),
+    };
+    if let Some(session_id) = info.session_id {
+        line += &format!(", session {:016x}", session_id);
+    }
+    if let Some(path) = &info.path {
+        line += &format!(", saved to {}", path.display());
+    }
+    if let Some(error) = &info.error {
+        line += &format!(" ({})", error);
+    }
+    line
+}
+
+/// Payload bytes per second over `elapsed`, in Mbps
+pub(crate) fn goodput(bytes: u64, elapsed: Duration) -> f64 {
+    bytes as f64 * 8.0 / elapsed.as_secs_f64().max(1e-9) / 1e6
+}
+
+/// Symbols beyond the source symbols, as a percentage of them
+pub(crate) fn overhead(symbols: u64, source_symbols: usize) -> f64 {
+    (symbols as f64 / source_symbols.max(1) as f64 - 1.0) * 100.0
+}
+
+/// Summary of a finished send
+pub fn send_summary(report: &SendReport) -> String {
+    format!(
+        "  Goodput:     {:.1} Mbps ({} in {:.2?})\n  \
+         Overhead:    {:.1}% ({} packets for {} source symbols)",
+        goodput(report.bytes, report.elapsed),
+        HumanBytes(report.bytes),
+        report.elapsed,
+        overhead(report.packets, report.source_symbols),
+        report.packets,
+        report.source_symbols
+    )
+}
+
+/// Symbols decoding took, leaving out duplicates and symbols for source
+/// blocks already recovered
+pub(crate) fn decoded_from(report: &ReceiveReport) -> u64 {
+    report.symbols_received.saturating_sub(report.duplicates + report.redundant)
+}
+
+/// Summary of a finished receive
+pub fn receive_summary(report: &ReceiveReport) -> String {
+    let decoded_from = decoded_from(report);
+    format!(
+        "  Goodput:     {:.1} Mbps ({} in {:.2?})\n  \
+         Overhead:    {:.1}% ({} symbols for {} source symbols)\n  \
+         Redundant:   {} symbols for source blocks already decoded\n  \
+         Duplicates:  {}\n  \
+         Loss:        {:.1}%\n  \
+         Decode time: {:.2?}",
+        goodput(report.bytes, report.elapsed),
+        HumanBytes(report.bytes),
+        report.elapsed,
+        overhead(decoded_from, report.source_symbols),
+        decoded_from,
+        report.source_symbols,
+        report.redundant,
+        report.duplicates,
+        report.loss * 100.0,
+        report.decode_time
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let addr = SocketAddr::from(([10, 0, 0, 2], 3000));
+        let event = Event::Confirmed {
+            addr,
+            confirmed: 1,
+            receivers: 1,
+            packets_sent: 42,
+        };
+        assert_eq!(
+            describe(&event),
+            "Receiver 10.0.0.2:3000 confirmed after 42 blocks sent"
+        );
+        assert!(describe(&Event::Sent {
+            packets: 1,
+            bytes: 1400
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn test_describe_daemon() {
+        let event = DaemonEvent::Transfer(
+            2,
+            Event::NewSender {
+                addr: SocketAddr::from(([10, 0, 0, 1], 3000)),
+            },
+        );
+        assert_eq!(describe_daemon(&event, false), "[2] Also receiving from 10.0.0.1:3000");
+        let event = DaemonEvent::Transfer(
+            2,
+            Event::BlockDecoded {
+                sbn: 0,
+                blocks_decoded: 1,
+                num_blocks: 2,
+            },
+        );
+        assert!(describe_daemon(&event, false).is_empty());
+        assert_eq!(describe_daemon(&event, true), "[2] Decoded source block 0 (1/2)");
+    }
+
+    #[test]
+    fn test_describe_transfer() {
+        let mut info = TransferInfo {
+            id: 3,
+            direction: Direction::Outgoing,
+            session_id: Some(0xab),
+            peers: vec!["10.0.0.4:3000".into()],
+            state: TransferState::Paused,
+            bytes: Some(4096),
+            done: 1024,
+            path: None,
+            error: None,
+        };
+        assert_eq!(
+            describe_transfer(&info),
+            "[3] Outgoing to 10.0.0.4:3000, paused: 1.00 KiB of 4.00 KiB (25%), session 00000000000000ab"
+        );
+        info.state = TransferState::Failed;
+        info.error = Some("10.0.0.4:3000 abandoned the transfer".into());
+        assert!(describe_transfer(&info).ends_with("(10.0.0.4:3000 abandoned the transfer)"));
+    }
+
+    #[test]
+    fn test_receive_summary() {
+        let report = ReceiveReport {
+            session_id: 1,
+            hash: blake3::hash(b""),
+            bytes: 1_000_000,
+            num_blocks: 1,
+            senders: Vec::new(),
+            symbols_received: 120,
+            source_symbols: 100,
+            duplicates: 3,
+            redundant: 7,
+            loss: 0.05,
+            decode_time: Duration::from_millis(20),
+            path: None,
+            files: None,
+            data: None,
+            elapsed: Duration::from_secs(1),
+        };
+        let summary = receive_summary(&report);
+        assert!(summary.contains("Goodput:     8.0 Mbps"));
+        assert!(summary.contains("Overhead:    10.0% (110 symbols for 100 source symbols)"));
+        assert!(summary.contains("Redundant:   7 symbols"));
+        assert!(summary.contains("Duplicates:  3"));
+        assert!(summary.contains("Loss:        5.0%"));
+        assert!(summary.contains("Decode time: 20.00ms"));
+    }
+}
@@ -0,0 +1,555 @@
+//! Wire format for packets exchanged between sender and receiver
+//!
+//! Every packet starts with a common header:
+//!
+//! ```text
+//! magic (2) | version (1) | kind (1) | session id (8)
+//! ```
+//!
+//! Data packets flow from sender to receiver; progress reports and
+//! completion acknowledgements flow back over the same socket. Path MTU
+//! probes are padded to the size being tested and echoed by the receiver.
+//! Either end abandoning a session tells the other with an abort notice,
+//! signed like completion acknowledgements.
+//!
+//! Session IDs are derived from the content, so every data packet also
+//! carries a nonce the sender picks at random for each run. The tags of
+//! acknowledgements and abort notices cover it, so one captured from an
+//! earlier send of the same object is not accepted by a later one.
+//!
+//! Data packets of a stream, whose length is unknown until its input ends,
+//! have the top bit of the transfer length set. Those of an object that
+//! packs several files have `FLAG_ARCHIVE` set, for the receiver to unpack
+//! it. For a stream, progress
+//! reports count the source blocks decoded in order from the first.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+/// Magic bytes identifying a Fountainflow packet
+pub const MAGIC: [u8; 2] = *b"FF";
+/// Current protocol version
+pub const VERSION: u8 = 1;
+/// Size of the header shared by all packet kinds
+pub const COMMON_HEADER_SIZE: usize = 12;
+/// Size of the data packet header, including the common header
+pub const DATA_HEADER_SIZE: usize = COMMON_HEADER_SIZE + DATA_BODY_SIZE;
+
+/// Fixed-size part of each packet kind following the common header
+const DATA_BODY_SIZE: usize = 37;
+const PROGRESS_BODY_SIZE: usize = 24;
+const COMPLETE_BODY_SIZE: usize = 64;
+const PROBE_BODY_SIZE: usize = 6;
+const ABORT_BODY_SIZE: usize = 32;
+
+/// Top bit of the transfer length, set in every data packet of a stream
+pub const STREAM_FLAG: u64 = 1 << 63;
+/// Transfer length of a stream whose input has not ended yet
+pub const STREAM_LENGTH_UNKNOWN: u64 = u64::MAX;
+/// Data packet flag set for an object that is an archive of several files
+pub const FLAG_ARCHIVE: u8 = 1;
+
+/// Context string for deriving the completion acknowledgement key
+const ACK_KEY_CONTEXT: &str = "fountainflow 2025-01 completion acknowledgement";
+/// Context string for deriving the abort notice key
+const ABORT_KEY_CONTEXT: &str = "fountainflow 2025-01 abort notice";
+
+/// Delay field of a progress report without a delay sample
+const NO_DELAY_SAMPLE: u32 = u32::MAX;
+
+const KIND_DATA: u8 = 0;
+const KIND_PROGRESS: u8 = 1;
+const KIND_COMPLETE: u8 = 2;
+const KIND_PROBE: u8 = 3;
+const KIND_PROBE_ACK: u8 = 4;
+const KIND_ABORT: u8 = 5;
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("Packet too short: {0} bytes")]
+    TooShort(usize),
+    #[error("Bad magic bytes")]
+    BadMagic,
+    #[error("Unsupported protocol version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unknown packet kind: {0}")]
+    UnknownKind(u8),
+}
+
+/// An encoding symbol of one source block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataPacket {
+    /// Session the symbol belongs to
+    pub session_id: u64,
+    /// Random value picked by the sender for this run of the session
+    pub nonce: u64,
+    /// Transfer length in bytes, used by the receiver to derive the layout
+    pub transfer_length: u64,
+    /// Symbol size in bytes
+    pub symbol_size: u16,
+    /// Properties of the object, such as `FLAG_ARCHIVE`
+    pub flags: u8,
+    /// Source block number
+    pub sbn: u32,
+    /// Encoding symbol ID, also the seed of the symbol's triple
+    pub esi: u32,
+    /// Number of source symbols combined into this symbol
+    pub degree: u16,
+    /// Packet sequence number across the whole session, for loss estimation
+    pub seq: u32,
+    /// Sender clock in microseconds (wrapping), for one-way delay estimation
+    pub timestamp_us: u32,
+    /// Encoded symbol
+    pub payload: Bytes,
+}
+
+impl DataPacket {
+    /// Whether the packet belongs to a stream rather than an object of
+    /// known length
+    pub fn is_stream(&self) -> bool {
+        self.transfer_length & STREAM_FLAG != 0
+    }
+
+    /// Whether the object is an archive to unpack
+    pub fn is_archive(&self) -> bool {
+        self.flags & FLAG_ARCHIVE != 0
+    }
+
+    /// Length of the stream the packet belongs to, once its input has ended
+    pub fn stream_length(&self) -> Option<u64> {
+        (self.is_stream() && self.transfer_length != STREAM_LENGTH_UNKNOWN)
+            .then_some(self.transfer_length & !STREAM_FLAG)
+    }
+}
+
+/// Periodic decode progress reported by the receiver
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressReport {
+    /// Session being reported on
+    pub session_id: u64,
+    /// Data packets received so far, including duplicates
+    pub symbols_received: u64,
+    /// Source blocks fully decoded so far
+    pub blocks_decoded: u32,
+    /// Total source blocks in the transfer
+    pub num_blocks: u32,
+    /// Highest data packet sequence number seen so far
+    pub highest_seq: u32,
+    /// Smallest one-way delay sample since the previous report, in
+    /// microseconds, if any packet arrived in between; only differences
+    /// between samples are meaningful since the two clocks are not
+    /// synchronised
+    pub delay_us: Option<u32>,
+}
+
+/// Final acknowledgement sent once the receiver has decoded the whole object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompletionAck {
+    /// Session being acknowledged
+    pub session_id: u64,
+    /// BLAKE3 hash of the decoded object
+    pub hash: [u8; 32],
+    /// Keyed BLAKE3 tag over the session ID, the sender's nonce and hash
+    pub tag: [u8; 32],
+}
+
+impl CompletionAck {
+    /// Create an acknowledgement for the run of the sender that picked
+    /// `nonce`, signed with `key`
+    ///
+    /// Without a pre-shared key (`key` empty) the tag still detects corruption,
+    /// but anyone who can see the data packets can forge it.
+    pub fn new(session_id: u64, nonce: u64, hash: [u8; 32], key: &[u8]) -> Self {
+        let tag = Self::compute_tag(session_id, nonce, &hash, key);
+        Self { session_id, hash, tag }
+    }
+
+    /// Check the tag against `key` and the `nonce` of this run
+    pub fn verify(&self, nonce: u64, key: &[u8]) -> bool {
+        // blake3::Hash comparison is constant time
+        blake3::Hash::from(self.tag) == blake3::Hash::from(Self::compute_tag(self.session_id, nonce, &self.hash, key))
+    }
+
+    fn compute_tag(session_id: u64, nonce: u64, hash: &[u8; 32], key: &[u8]) -> [u8; 32] {
+        let key = blake3::derive_key(ACK_KEY_CONTEXT, key);
+        let mut hasher = blake3::Hasher::new_keyed(&key);
+        hasher.update(&session_id.to_be_bytes());
+        hasher.update(&nonce.to_be_bytes());
+        hasher.update(hash);
+        *hasher.finalize().as_bytes()
+    }
+}
+
+/// Notice that the sending end gave up on the session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AbortNotice {
+    /// Session being abandoned
+    pub session_id: u64,
+    /// Keyed BLAKE3 tag over the session ID and the sender's nonce
+    pub tag: [u8; 32],
+}
+
+impl AbortNotice {
+    /// Create a notice for the run of the sender that picked `nonce`,
+    /// signed with `key`
+    pub fn new(session_id: u64, nonce: u64, key: &[u8]) -> Self {
+        Self {
+            session_id,
+            tag: Self::compute_tag(session_id, nonce, key),
+        }
+    }
+
+    /// Check the tag against `key` and the `nonce` of this run
+    pub fn verify(&self, nonce: u64, key: &[u8]) -> bool {
+        blake3::Hash::from(self.tag) == blake3::Hash::from(Self::compute_tag(self.session_id, nonce, key))
+    }
+
+    fn compute_tag(session_id: u64, nonce: u64, key: &[u8]) -> [u8; 32] {
+        let key = blake3::derive_key(ABORT_KEY_CONTEXT, key);
+        let mut hasher = blake3::Hasher::new_keyed(&key);
+        hasher.update(&session_id.to_be_bytes());
+        hasher.update(&nonce.to_be_bytes());
+        *hasher.finalize().as_bytes()
+    }
+}
+
+/// Path MTU probe, padded with zeros to `size` bytes on the wire
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathProbe {
+    /// Session the probe is sent for
+    pub session_id: u64,
+    /// Identifier echoed in the acknowledgement
+    pub probe_id: u32,
+    /// Encoded size of the probe, i.e. the UDP payload size being tested
+    pub size: u16,
+}
+
+/// Acknowledgement of a path MTU probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeAck {
+    /// Session of the acknowledged probe
+    pub session_id: u64,
+    /// Identifier of the acknowledged probe
+    pub probe_id: u32,
+    /// Size of the acknowledged probe
+    pub size: u16,
+}
+
+impl PathProbe {
+    /// Acknowledgement for this probe
+    pub fn ack(&self) -> ProbeAck {
+        ProbeAck {
+            session_id: self.session_id,
+            probe_id: self.probe_id,
+            size: self.size,
+        }
+    }
+}
+
+/// A packet of any kind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Data(DataPacket),
+    Progress(ProgressReport),
+    Complete(CompletionAck),
+    Probe(PathProbe),
+    ProbeAck(ProbeAck),
+    Abort(AbortNotice),
+}
+
+impl Packet {
+    /// Session the packet belongs to
+    pub fn session_id(&self) -> u64 {
+        match self {
+            Packet::Data(p) => p.session_id,
+            Packet::Progress(p) => p.session_id,
+            Packet::Complete(p) => p.session_id,
+            Packet::Probe(p) => p.session_id,
+            Packet::ProbeAck(p) => p.session_id,
+            Packet::Abort(p) => p.session_id,
+        }
+    }
+
+    /// Serialize the packet into its wire format
+    pub fn encode(&self) -> BytesMut {
+        let (kind, body_len) = match self {
+            Packet::Data(p) => (KIND_DATA, DATA_BODY_SIZE + p.payload.len()),
+            Packet::Progress(_) => (KIND_PROGRESS, PROGRESS_BODY_SIZE),
+            Packet::Complete(_) => (KIND_COMPLETE, COMPLETE_BODY_SIZE),
+            Packet::Probe(p) => (
+                KIND_PROBE,
+                (p.size as usize).saturating_sub(COMMON_HEADER_SIZE).max(PROBE_BODY_SIZE),
+            ),
+            Packet::ProbeAck(_) => (KIND_PROBE_ACK, PROBE_BODY_SIZE),
+            Packet::Abort(_) => (KIND_ABORT, ABORT_BODY_SIZE),
+        };
+
+        let mut buffer = BytesMut::with_capacity(COMMON_HEADER_SIZE + body_len);
+        buffer.put_slice(&MAGIC);
+        buffer.put_u8(VERSION);
+        buffer.put_u8(kind);
+        buffer.put_u64(self.session_id());
+
+        match self {
+            Packet::Data(p) => {
+                buffer.put_u64(p.nonce);
+                buffer.put_u64(p.transfer_length);
+                buffer.put_u16(p.symbol_size);
+                buffer.put_u8(p.flags);
+                buffer.put_u32(p.sbn);
+                buffer.put_u32(p.esi);
+                buffer.put_u16(p.degree);
+                buffer.put_u32(p.seq);
+                buffer.put_u32(p.timestamp_us);
+                buffer.put_slice(&p.payload);
+            }
+            Packet::Progress(p) => {
+                buffer.put_u64(p.symbols_received);
+                buffer.put_u32(p.blocks_decoded);
+                buffer.put_u32(p.num_blocks);
+                buffer.put_u32(p.highest_seq);
+                // A sample that happens to equal the sentinel is off by a microsecond
+                buffer.put_u32(p.delay_us.map_or(NO_DELAY_SAMPLE, |delay| delay.min(NO_DELAY_SAMPLE - 1)));
+            }
+            Packet::Complete(p) => {
+                buffer.put_slice(&p.hash);
+                buffer.put_slice(&p.tag);
+            }
+            Packet::Probe(p) => {
+                buffer.put_u32(p.probe_id);
+                buffer.put_u16(p.size);
+                buffer.resize(COMMON_HEADER_SIZE + body_len, 0);
+            }
+            Packet::ProbeAck(p) => {
+                buffer.put_u32(p.probe_id);
+                buffer.put_u16(p.size);
+            }
+            Packet::Abort(p) => buffer.put_slice(&p.tag),
+        }
+
+        buffer
+    }
+
+    /// Parse a packet from its wire format
+    pub fn decode(packet: &[u8]) -> Result<Self, ProtocolError> {
+        if packet.len() < COMMON_HEADER_SIZE {
+            return Err(ProtocolError::TooShort(packet.len()));
+        }
+        if packet[0..2] != MAGIC {
+            return Err(ProtocolError::BadMagic);
+        }
+        if packet[2] != VERSION {
+            return Err(ProtocolError::UnsupportedVersion(packet[2]));
+        }
+
+        let kind = packet[3];
+        let mut buf = &packet[4..];
+        let session_id = buf.get_u64();
+
+        let body_len = match kind {
+            KIND_DATA => DATA_BODY_SIZE,
+            KIND_PROGRESS => PROGRESS_BODY_SIZE,
+            KIND_COMPLETE => COMPLETE_BODY_SIZE,
+            KIND_PROBE | KIND_PROBE_ACK => PROBE_BODY_SIZE,
+            KIND_ABORT => ABORT_BODY_SIZE,
+            other => return Err(ProtocolError::UnknownKind(other)),
+        };
+        if buf.remaining() < body_len {
+            return Err(ProtocolError::TooShort(packet.len()));
+        }
+
+        Ok(match kind {
+            KIND_DATA => Packet::Data(DataPacket {
+                session_id,
+                nonce: buf.get_u64(),
+                transfer_length: buf.get_u64(),
+                symbol_size: buf.get_u16(),
+                flags: buf.get_u8(),
+                sbn: buf.get_u32(),
+                esi: buf.get_u32(),
+                degree: buf.get_u16(),
+                seq: buf.get_u32(),
+                timestamp_us: buf.get_u32(),
+                payload: Bytes::copy_from_slice(buf),
+            }),
+            KIND_PROGRESS => Packet::Progress(ProgressReport {
+                session_id,
+                symbols_received: buf.get_u64(),
+                blocks_decoded: buf.get_u32(),
+                num_blocks: buf.get_u32(),
+                highest_seq: buf.get_u32(),
+                delay_us: Some(buf.get_u32()).filter(|&delay| delay != NO_DELAY_SAMPLE),
+            }),
+            KIND_PROBE => {
+                let probe = PathProbe {
+                    session_id,
+                    probe_id: buf.get_u32(),
+                    size: buf.get_u16(),
+                };
+                // A truncated probe must not be acknowledged as the full size
+                if packet.len() < probe.size as usize {
+                    return Err(ProtocolError::TooShort(packet.len()));
+                }
+                Packet::Probe(probe)
+            }
+            KIND_PROBE_ACK => Packet::ProbeAck(ProbeAck {
+                session_id,
+                probe_id: buf.get_u32(),
+                size: buf.get_u16(),
+            }),
+            KIND_ABORT => {
+                let mut tag = [0u8; 32];
+                buf.copy_to_slice(&mut tag);
+                Packet::Abort(AbortNotice { session_id, tag })
+            }
+            _ => {
+                let mut hash = [0u8; 32];
+                let mut tag = [0u8; 32];
+                buf.copy_to_slice(&mut hash);
+                buf.copy_to_slice(&mut tag);
+                Packet::Complete(CompletionAck { session_id, hash, tag })
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_roundtrip() {
+        let packet = Packet::Data(DataPacket {
+            session_id: 0x0102_0304_0506_0708,
+            nonce: 0x1112_1314_1516_1718,
+            transfer_length: 1_000_000,
+            symbol_size: 1400,
+            flags: FLAG_ARCHIVE,
+            sbn: 2,
+            esi: 77,
+            degree: 3,
+            seq: 230,
+            timestamp_us: 123_456,
+            payload: Bytes::from_static(&[1, 2, 3, 4]),
+        });
+
+        let encoded = packet.encode();
+        assert_eq!(encoded.len(), DATA_HEADER_SIZE + 4);
+        assert_eq!(Packet::decode(&encoded).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_stream_length() {
+        let mut packet = DataPacket {
+            session_id: 1,
+            nonce: 2,
+            transfer_length: 1000,
+            symbol_size: 100,
+            flags: 0,
+            sbn: 0,
+            esi: 0,
+            degree: 1,
+            seq: 0,
+            timestamp_us: 0,
+            payload: Bytes::new(),
+        };
+        assert!(!packet.is_stream());
+        assert!(!packet.is_archive());
+        assert_eq!(packet.stream_length(), None);
+
+        packet.transfer_length = STREAM_LENGTH_UNKNOWN;
+        assert!(packet.is_stream());
+        assert_eq!(packet.stream_length(), None);
+
+        packet.transfer_length = STREAM_FLAG | 1000;
+        assert!(packet.is_stream());
+        assert_eq!(packet.stream_length(), Some(1000));
+    }
+
+    #[test]
+    fn test_feedback_roundtrip() {
+        let progress = Packet::Progress(ProgressReport {
+            session_id: 9,
+            symbols_received: 1234,
+            blocks_decoded: 1,
+            num_blocks: 3,
+            highest_seq: 1300,
+            delay_us: Some(2500),
+        });
+        assert_eq!(Packet::decode(&progress.encode()).unwrap(), progress);
+        let Packet::Progress(mut report) = progress else { unreachable!() };
+        report.delay_us = None;
+        let idle = Packet::Progress(report);
+        assert_eq!(Packet::decode(&idle.encode()).unwrap(), idle);
+
+        let ack = Packet::Complete(CompletionAck::new(9, 5, [7u8; 32], b"secret"));
+        assert_eq!(Packet::decode(&ack.encode()).unwrap(), ack);
+    }
+
+    #[test]
+    fn test_probe_roundtrip() {
+        let probe = PathProbe {
+            session_id: 3,
+            probe_id: 17,
+            size: 1472,
+        };
+        let encoded = Packet::Probe(probe.clone()).encode();
+        assert_eq!(encoded.len(), 1472);
+        assert_eq!(Packet::decode(&encoded).unwrap(), Packet::Probe(probe.clone()));
+        assert!(matches!(Packet::decode(&encoded[..1000]), Err(ProtocolError::TooShort(1000))));
+
+        let ack = Packet::ProbeAck(probe.ack());
+        assert_eq!(Packet::decode(&ack.encode()).unwrap(), ack);
+    }
+
+    #[test]
+    fn test_ack_signature() {
+        let ack = CompletionAck::new(42, 7, [1u8; 32], b"secret");
+        assert!(ack.verify(7, b"secret"));
+        assert!(!ack.verify(7, b"other"));
+        // An acknowledgement of an earlier run of the same session
+        assert!(!ack.verify(8, b"secret"));
+
+        let mut forged = ack.clone();
+        forged.hash[0] ^= 1;
+        assert!(!forged.verify(7, b"secret"));
+    }
+
+    #[test]
+    fn test_abort_notice() {
+        let abort = Packet::Abort(AbortNotice::new(42, 7, b"secret"));
+        assert_eq!(Packet::decode(&abort.encode()).unwrap(), abort);
+
+        let notice = AbortNotice::new(42, 7, b"secret");
+        assert!(notice.verify(7, b"secret"));
+        assert!(!notice.verify(7, b""));
+        assert!(!notice.verify(8, b"secret"));
+        // Acknowledgement tags are not valid abort tags
+        let ack = CompletionAck::new(42, 7, [0u8; 32], b"secret");
+        assert!(!AbortNotice { session_id: 42, tag: ack.tag }.verify(7, b"secret"));
+    }
+
+    #[test]
+    fn test_malformed_packets() {
+        assert!(matches!(Packet::decode(&[0u8; 4]), Err(ProtocolError::TooShort(4))));
+
+        let mut packet = Packet::Progress(ProgressReport {
+            session_id: 1,
+            symbols_received: 0,
+            blocks_decoded: 0,
+            num_blocks: 1,
+            highest_seq: 0,
+            delay_us: None,
+        })
+        .encode();
+        packet[0] = b'X';
+        assert!(matches!(Packet::decode(&packet), Err(ProtocolError::BadMagic)));
+
+        packet[0] = MAGIC[0];
+        packet[3] = 99;
+        assert!(matches!(Packet::decode(&packet), Err(ProtocolError::UnknownKind(99))));
+
+        packet[3] = KIND_DATA;
+        assert!(matches!(Packet::decode(&packet), Err(ProtocolError::TooShort(_))));
+    }
+}
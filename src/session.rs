@@ -0,0 +1,1364 @@
+//! Transfer sessions tying the source block layout to per-block coders
+//!
+//! A `SendSession` produces data packets round-robin across source blocks;
+//! a `ReceiveSession` feeds them into one `Decoder` per source block until
+//! the whole object is recovered. Decoders are opened on the first symbol
+//! of their block and dropped once it is recovered.
+//!
+//! Streams of unknown length have sessions of their own: a
+//! `StreamSendSession` opens an encoder for each source block as its input
+//! fills it, and a `StreamReceiveSession` hands recovered blocks back
+//! strictly in order. Both keep only a window of blocks in memory.
+
+use crate::block::{BlockError, TransferLayout, MIN_BLOCK_SYMBOLS};
+use crate::congestion::{clock_us, DelaySampler};
+use crate::decoder::{Decoder, DecoderError};
+use crate::fountain::{Block, Encoder, FountainError};
+use crate::protocol::{DataPacket, ProgressReport, FLAG_ARCHIVE, STREAM_FLAG, STREAM_LENGTH_UNKNOWN};
+use crate::systematic::KMAX;
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("Block layout error: {0}")]
+    Layout(#[from] BlockError),
+    #[error("Encoder error: {0}")]
+    Encoder(#[from] FountainError),
+    #[error("Decoder error: {0}")]
+    Decoder(#[from] DecoderError),
+    #[error("Symbol size {0} does not fit in a packet")]
+    SymbolTooLarge(usize),
+    #[error("Packet does not match the session layout")]
+    LayoutMismatch,
+    #[error("Stream input already ended")]
+    StreamEnded,
+    #[error("Transfer of {0} bytes exceeds the size limit")]
+    TooLarge(u64),
+    #[error("Symbol size {0} is below the minimum accepted")]
+    SymbolTooSmall(usize),
+}
+
+/// Source symbols in every block of a stream but the last
+pub const STREAM_BLOCK_SYMBOLS: usize = KMAX;
+
+/// Most source blocks of a stream open at once, on either side
+pub const STREAM_WINDOW: usize = 64;
+
+/// Most symbols the decoder of a source block takes, per source symbol of
+/// the block; a decoder that fills up without decoding starts over, so a
+/// block fed bad symbols holds bounded memory and can still recover
+pub const DECODER_SYMBOL_LIMIT: usize = 4;
+
+/// Largest object a receiver accepts by default, in bytes
+pub const DEFAULT_MAX_TRANSFER_LENGTH: u64 = 4 << 30;
+
+/// Smallest symbol size a receiver accepts by default for objects of more
+/// than one source block, in bytes
+pub const DEFAULT_MIN_SYMBOL_SIZE: usize = 256;
+
+/// Bounds a receiver puts on the layout announced by a session's first
+/// packet, which nothing authenticates
+///
+/// Each source block costs a decoder, so tiny symbols inflate the memory a
+/// transfer of a given length needs. Objects that fit in one source block
+/// may use any symbol size, as small ones get small symbols.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReceiveLimits {
+    /// Largest transfer length accepted, in bytes
+    pub max_transfer_length: u64,
+    /// Smallest symbol size accepted for objects of more than one source
+    /// block, in bytes
+    pub min_symbol_size: usize,
+}
+
+impl Default for ReceiveLimits {
+    fn default() -> Self {
+        Self {
+            max_transfer_length: DEFAULT_MAX_TRANSFER_LENGTH,
+            min_symbol_size: DEFAULT_MIN_SYMBOL_SIZE,
+        }
+    }
+}
+
+impl ReceiveLimits {
+    /// No bounds beyond what the wire format allows
+    pub fn unlimited() -> Self {
+        Self {
+            max_transfer_length: u64::MAX,
+            min_symbol_size: 1,
+        }
+    }
+
+    /// Check `layout` against the limits
+    pub fn check(&self, layout: &TransferLayout) -> Result<(), SessionError> {
+        if layout.transfer_length > self.max_transfer_length {
+            return Err(SessionError::TooLarge(layout.transfer_length));
+        }
+        if layout.num_blocks > 1 && layout.symbol_size < self.min_symbol_size {
+            return Err(SessionError::SymbolTooSmall(layout.symbol_size));
+        }
+        Ok(())
+    }
+}
+
+/// Derive the session ID for an object from its BLAKE3 hash
+///
+/// Content-derived IDs let a receiver recognise the same object across
+/// sender restarts.
+pub fn session_id_for(hash: &blake3::Hash) -> u64 {
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&hash.as_bytes()[..8]);
+    u64::from_be_bytes(id)
+}
+
+/// Zero-pad source block `sbn` of `data` to whole symbols
+fn source_block_data(data: &[u8], layout: &TransferLayout, sbn: usize) -> Vec<u8> {
+    let range = layout.block_range(sbn);
+    let mut block = data[range.start as usize..range.end as usize].to_vec();
+    block.resize(layout.block_symbols(sbn) * layout.symbol_size, 0);
+    block
+}
+
+/// Sending side of a transfer
+pub struct SendSession {
+    session_id: u64,
+    /// Random value telling this run apart from other sends of the object
+    nonce: u64,
+    hash: blake3::Hash,
+    layout: TransferLayout,
+    /// Flags of every data packet
+    flags: u8,
+    encoders: Vec<Encoder>,
+    /// Source block the next packet is generated for
+    next_sbn: usize,
+    /// Sequence number of the next packet
+    next_seq: u32,
+    /// Reference point for packet timestamps
+    epoch: Instant,
+}
+
+impl SendSession {
+    /// Prepare `data` for sending with symbols of at most `max_symbol_size` bytes
+    pub fn new(data: &[u8], max_symbol_size: usize) -> Result<Self, SessionError> {
+        if max_symbol_size > u16::MAX as usize {
+            return Err(SessionError::SymbolTooLarge(max_symbol_size));
+        }
+
+        let hash = blake3::hash(data);
+        let layout = TransferLayout::new(data.len() as u64, max_symbol_size)?;
+        let encoders = (0..layout.num_blocks)
+            .map(|sbn| Encoder::new(&source_block_data(data, &layout, sbn), layout.symbol_size))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            session_id: session_id_for(&hash),
+            nonce: rand::random(),
+            hash,
+            layout,
+            flags: 0,
+            encoders,
+            next_sbn: 0,
+            next_seq: 0,
+            epoch: Instant::now(),
+        })
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    /// Nonce acknowledgements and abort notices for this run are signed with
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// BLAKE3 hash of the object being sent
+    pub fn hash(&self) -> &blake3::Hash {
+        &self.hash
+    }
+
+    pub fn layout(&self) -> &TransferLayout {
+        &self.layout
+    }
+
+    /// Mark the object as an archive for receivers to unpack
+    pub fn set_archive(&mut self, archive: bool) {
+        self.flags = if archive { FLAG_ARCHIVE } else { 0 };
+    }
+
+    /// Send encoding symbol IDs `offset`, `offset + stride`, ... of every
+    /// source block, so that mirrors with distinct offsets never duplicate
+    /// each other's symbols
+    pub fn set_esi_schedule(&mut self, offset: u32, stride: u32) {
+        for encoder in &mut self.encoders {
+            encoder.set_schedule(offset, stride);
+        }
+    }
+
+    /// Generate the next encoding symbol, cycling through the source blocks
+    pub fn next_packet(&mut self) -> Result<DataPacket, SessionError> {
+        let sbn = self.next_sbn;
+        self.next_sbn = (self.next_sbn + 1) % self.encoders.len();
+
+        let block = self.encoders[sbn].next_block()?;
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        Ok(DataPacket {
+            session_id: self.session_id,
+            nonce: self.nonce,
+            transfer_length: self.layout.transfer_length,
+            symbol_size: self.layout.symbol_size as u16,
+            flags: self.flags,
+            sbn: sbn as u32,
+            esi: block.seed(),
+            degree: block.degree() as u16,
+            seq,
+            timestamp_us: clock_us(self.epoch),
+            payload: Bytes::from(block.data().to_vec()),
+        })
+    }
+}
+
+/// Packet statistics of one sender, reported back to it
+struct SourceStats {
+    /// Nonce of the sender's latest packet
+    nonce: u64,
+    symbols_received: u64,
+    /// Sequence number of the first packet received from this sender
+    first_seq: u32,
+    highest_seq: u32,
+    delays: DelaySampler,
+}
+
+/// Packet statistics of every sender of a session
+#[derive(Default)]
+struct Sources(HashMap<SocketAddr, SourceStats>);
+
+impl Sources {
+    fn observe(&mut self, from: SocketAddr, packet: &DataPacket) {
+        let source = self.0.entry(from).or_insert_with(|| SourceStats {
+            nonce: packet.nonce,
+            symbols_received: 0,
+            first_seq: packet.seq,
+            highest_seq: packet.seq,
+            delays: DelaySampler::new(),
+        });
+        source.nonce = packet.nonce;
+        source.symbols_received += 1;
+        if (packet.seq.wrapping_sub(source.highest_seq) as i32) > 0 {
+            source.highest_seq = packet.seq;
+        }
+        source.delays.observe(packet.timestamp_us);
+    }
+
+    fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.0.keys().copied()
+    }
+
+    fn nonce(&self, source: SocketAddr) -> Option<u64> {
+        self.0.get(&source).map(|stats| stats.nonce)
+    }
+
+    /// Fraction of packets lost, estimated from gaps in sequence numbers
+    fn loss_rate(&self) -> f64 {
+        let (received, sent) = self.0.values().fold((0u64, 0u64), |(received, sent), source| {
+            let span = source.highest_seq.wrapping_sub(source.first_seq) as u64 + 1;
+            (received + source.symbols_received, sent + span)
+        });
+        if sent == 0 {
+            return 0.0;
+        }
+        (1.0 - received as f64 / sent as f64).max(0.0)
+    }
+
+    /// Progress report for `source`, starting a new delay sampling
+    /// interval, or `None` if nothing arrived from it since the last one
+    fn report(
+        &mut self,
+        source: SocketAddr,
+        session_id: u64,
+        blocks_decoded: usize,
+        num_blocks: usize,
+    ) -> Option<ProgressReport> {
+        let stats = self.0.get_mut(&source)?;
+        let delay_us = stats.delays.take()?;
+        Some(ProgressReport {
+            session_id,
+            symbols_received: stats.symbols_received,
+            blocks_decoded: blocks_decoded as u32,
+            num_blocks: num_blocks as u32,
+            highest_seq: stats.highest_seq,
+            delay_us: Some(delay_us),
+        })
+    }
+}
+
+/// Receiving side of a transfer
+///
+/// Symbols of the session are accepted from any number of senders, each
+/// of which gets progress reports about its own packets.
+pub struct ReceiveSession {
+    session_id: u64,
+    layout: TransferLayout,
+    flags: u8,
+    /// Decoders for source blocks that got symbols but are still incomplete
+    decoders: BTreeMap<usize, Decoder>,
+    /// Recovered source blocks, padded to whole symbols
+    blocks: Vec<Option<Vec<u8>>>,
+    blocks_decoded: usize,
+    symbols_received: u64,
+    /// Symbols received again for a source block still being decoded
+    duplicates: u64,
+    /// Symbols received for source blocks already recovered
+    redundant: u64,
+    sources: Sources,
+    /// When the first packet arrived
+    started: Instant,
+    /// Time spent in the decoders
+    decode_time: Duration,
+}
+
+impl ReceiveSession {
+    /// Start a session from the first data packet received for it, within
+    /// the default limits
+    pub fn new(first: &DataPacket) -> Result<Self, SessionError> {
+        Self::with_limits(first, &ReceiveLimits::default())
+    }
+
+    /// Start a session from the first data packet received for it, refusing
+    /// layouts beyond `limits`
+    pub fn with_limits(first: &DataPacket, limits: &ReceiveLimits) -> Result<Self, SessionError> {
+        let layout = TransferLayout::new(first.transfer_length, first.symbol_size as usize)?;
+        if layout.symbol_size != first.symbol_size as usize {
+            return Err(SessionError::LayoutMismatch);
+        }
+        limits.check(&layout)?;
+
+        Ok(Self {
+            session_id: first.session_id,
+            blocks: vec![None; layout.num_blocks],
+            layout,
+            flags: first.flags,
+            decoders: BTreeMap::new(),
+            blocks_decoded: 0,
+            symbols_received: 0,
+            duplicates: 0,
+            redundant: 0,
+            sources: Sources::default(),
+            started: Instant::now(),
+            decode_time: Duration::ZERO,
+        })
+    }
+
+    /// Bytes receiving an object laid out as `layout` takes at most,
+    /// roughly: the object itself and a decoder for each source block
+    /// holding as many symbols as it takes
+    pub fn memory(layout: &TransferLayout) -> u64 {
+        let decoder = |symbols| Decoder::memory(symbols, layout.symbol_size, symbols * DECODER_SYMBOL_LIMIT);
+        let small_blocks = (layout.num_blocks - layout.large_blocks) as u64;
+        layout.transfer_length
+            + layout.large_blocks as u64 * decoder(layout.large_block_symbols)
+            + small_blocks * decoder(layout.small_block_symbols)
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    pub fn layout(&self) -> &TransferLayout {
+        &self.layout
+    }
+
+    /// Whether the sender marked the object as an archive to unpack
+    pub fn is_archive(&self) -> bool {
+        self.flags & FLAG_ARCHIVE != 0
+    }
+
+    /// Add a symbol received from `from`, returning `true` if it completed
+    /// its source block
+    pub fn add_packet(&mut self, from: SocketAddr, packet: &DataPacket) -> Result<bool, SessionError> {
+        if packet.session_id != self.session_id
+            || packet.transfer_length != self.layout.transfer_length
+            || packet.symbol_size as usize != self.layout.symbol_size
+            || packet.flags != self.flags
+        {
+            return Err(SessionError::LayoutMismatch);
+        }
+        let sbn = packet.sbn as usize;
+        if sbn >= self.layout.num_blocks {
+            return Err(SessionError::LayoutMismatch);
+        }
+
+        self.symbols_received += 1;
+        self.sources.observe(from, packet);
+
+        self.add_symbol(sbn, packet.esi, packet.degree, &packet.payload)
+    }
+
+    fn add_symbol(&mut self, sbn: usize, esi: u32, degree: u16, payload: &[u8]) -> Result<bool, SessionError> {
+        if self.blocks[sbn].is_some() {
+            // Source block already recovered
+            self.redundant += 1;
+            return Ok(false);
+        }
+        let (symbols, symbol_size) = (self.layout.block_symbols(sbn), self.layout.symbol_size);
+        let decoder = match self.decoders.entry(sbn) {
+            std::collections::btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::btree_map::Entry::Vacant(entry) => entry.insert(Decoder::new(symbols, symbol_size)?),
+        };
+        if decoder.has_block(esi) {
+            self.duplicates += 1;
+            return Ok(false);
+        }
+        if decoder.blocks_received() >= symbols * DECODER_SYMBOL_LIMIT {
+            log::warn!("Source block {} did not decode from {} symbols, starting over", sbn, decoder.blocks_received());
+            *decoder = Decoder::new(symbols, symbol_size)?;
+        }
+
+        let start = Instant::now();
+        let block = Block::new(payload.to_vec(), esi, degree as usize);
+        decoder.add_block(block, esi)?;
+        let decoded = decoder.try_decode()?;
+        self.decode_time += start.elapsed();
+        if !decoded {
+            return Ok(false);
+        }
+
+        self.blocks[sbn] = decoder.get_decoded_data();
+        self.decoders.remove(&sbn);
+        self.blocks_decoded += 1;
+        Ok(true)
+    }
+
+    /// Feed a symbol saved by an earlier run, returning `true` if it
+    /// completed its source block
+    pub fn restore_symbol(&mut self, sbn: usize, esi: u32, degree: u16, payload: &[u8]) -> Result<bool, SessionError> {
+        if sbn >= self.layout.num_blocks || payload.len() != self.layout.symbol_size {
+            return Err(SessionError::LayoutMismatch);
+        }
+        self.add_symbol(sbn, esi, degree, payload)
+    }
+
+    /// Insert source block `sbn` recovered by an earlier run, padded to
+    /// whole symbols
+    pub fn restore_block(&mut self, sbn: usize, data: Vec<u8>) -> Result<(), SessionError> {
+        if sbn >= self.layout.num_blocks || data.len() != self.layout.block_symbols(sbn) * self.layout.symbol_size {
+            return Err(SessionError::LayoutMismatch);
+        }
+        if self.blocks[sbn].is_none() {
+            self.decoders.remove(&sbn);
+            self.blocks[sbn] = Some(data);
+            self.blocks_decoded += 1;
+        }
+        Ok(())
+    }
+
+    /// Whether source block `sbn` has been recovered
+    pub fn is_block_decoded(&self, sbn: usize) -> bool {
+        self.blocks.get(sbn).is_some_and(Option::is_some)
+    }
+
+    /// Recovered source block `sbn`, padded to whole symbols
+    pub fn block(&self, sbn: usize) -> Option<&[u8]> {
+        self.blocks.get(sbn)?.as_deref()
+    }
+
+    /// Whether every source block has been recovered
+    pub fn is_complete(&self) -> bool {
+        self.blocks_decoded == self.layout.num_blocks
+    }
+
+    /// Data packets received so far, including duplicates and redundant symbols
+    pub fn symbols_received(&self) -> u64 {
+        self.symbols_received
+    }
+
+    pub fn blocks_decoded(&self) -> usize {
+        self.blocks_decoded
+    }
+
+    /// Symbols received more than once for a source block still being decoded
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Symbols received for source blocks already recovered
+    pub fn redundant(&self) -> u64 {
+        self.redundant
+    }
+
+    /// Time spent decoding so far
+    pub fn decode_time(&self) -> Duration {
+        self.decode_time
+    }
+
+    /// Time since the first packet of the session arrived
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Linearly independent symbols held so far, counting recovered source
+    /// blocks as complete
+    ///
+    /// The object is recovered once this reaches `layout().source_symbols()`.
+    pub fn symbols_useful(&self) -> usize {
+        let recovered: usize = (0..self.layout.num_blocks)
+            .filter(|&sbn| self.blocks[sbn].is_some())
+            .map(|sbn| self.layout.block_symbols(sbn))
+            .sum();
+        recovered + self.decoders.values().map(Decoder::rank).sum::<usize>()
+    }
+
+    /// Fraction of packets lost on the way from all senders, estimated from
+    /// gaps in their sequence numbers
+    pub fn loss_rate(&self) -> f64 {
+        self.sources.loss_rate()
+    }
+
+    /// Senders that symbols were received from
+    pub fn sources(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.sources.addrs()
+    }
+
+    /// Nonce of the latest packet from `source`, which acknowledgements and
+    /// abort notices exchanged with it are signed with
+    pub fn nonce(&self, source: SocketAddr) -> Option<u64> {
+        self.sources.nonce(source)
+    }
+
+    /// Senders that symbols were received from, with the nonce of their
+    /// latest packet
+    pub fn senders(&self) -> impl Iterator<Item = (SocketAddr, u64)> + '_ {
+        self.sources.0.iter().map(|(&addr, stats)| (addr, stats.nonce))
+    }
+
+    /// Progress report to send back to the sender at `source`, counting
+    /// only its own packets, unless nothing arrived from it since the last
+    ///
+    /// Starts a new delay sampling interval for that sender.
+    pub fn progress(&mut self, source: SocketAddr) -> Option<ProgressReport> {
+        self.sources
+            .report(source, self.session_id, self.blocks_decoded, self.layout.num_blocks)
+    }
+
+    /// Reassemble the object once complete, trimming the symbol padding
+    pub fn into_data(self) -> Option<Vec<u8>> {
+        if !self.is_complete() {
+            return None;
+        }
+
+        let mut data = Vec::with_capacity(self.layout.transfer_length as usize);
+        for (sbn, block) in self.blocks.into_iter().enumerate() {
+            let range = self.layout.block_range(sbn);
+            data.extend_from_slice(&block?[..(range.end - range.start) as usize]);
+        }
+        Some(data)
+    }
+}
+
+/// Sending side of a stream, whose length is unknown until its input ends
+///
+/// Input is cut into source blocks of `STREAM_BLOCK_SYMBOLS` symbols as it
+/// arrives. Each block stays open, getting symbols round-robin with the
+/// others, until it is retired once its receivers have it or it has had
+/// its share of symbols.
+pub struct StreamSendSession {
+    session_id: u64,
+    nonce: u64,
+    symbol_size: usize,
+    hasher: blake3::Hasher,
+    /// Input not filling a source block yet
+    pending: Vec<u8>,
+    /// Bytes of input so far
+    length: u64,
+    /// Hash of the whole input, once it ended
+    hash: Option<blake3::Hash>,
+    /// Encoders of the open source blocks, from `first_open` on
+    encoders: VecDeque<Encoder>,
+    /// Symbols generated for each open source block, and its source symbols
+    sent: VecDeque<(u64, usize)>,
+    /// Repair symbols each block gets, as a multiple of its source symbols
+    overhead: Option<f64>,
+    first_open: usize,
+    /// Encoder of the source block retired last, reopened to announce the
+    /// length if the input ends without filling another block
+    last_retired: Option<Encoder>,
+    /// Index in `encoders` of the block the next packet is generated for
+    next: usize,
+    esi_offset: u32,
+    esi_stride: u32,
+    next_seq: u32,
+    epoch: Instant,
+}
+
+impl StreamSendSession {
+    /// Start a stream in symbols of `symbol_size` bytes, under a random
+    /// session ID since the content is not known yet
+    pub fn new(symbol_size: usize) -> Result<Self, SessionError> {
+        if symbol_size > u16::MAX as usize {
+            return Err(SessionError::SymbolTooLarge(symbol_size));
+        }
+        if symbol_size == 0 {
+            return Err(BlockError::InvalidParameters.into());
+        }
+
+        Ok(Self {
+            session_id: rand::random(),
+            nonce: rand::random(),
+            symbol_size,
+            hasher: blake3::Hasher::new(),
+            pending: Vec::new(),
+            length: 0,
+            hash: None,
+            encoders: VecDeque::new(),
+            sent: VecDeque::new(),
+            overhead: None,
+            first_open: 0,
+            last_retired: None,
+            next: 0,
+            esi_offset: 0,
+            esi_stride: 1,
+            next_seq: 0,
+            epoch: Instant::now(),
+        })
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    /// Nonce acknowledgements and abort notices for this run are signed with
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn symbol_size(&self) -> usize {
+        self.symbol_size
+    }
+
+    /// Bytes of input so far
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// BLAKE3 hash of the whole input, once it ended
+    pub fn hash(&self) -> Option<&blake3::Hash> {
+        self.hash.as_ref()
+    }
+
+    /// Layout of the stream, once its input ended
+    pub fn layout(&self) -> Option<TransferLayout> {
+        self.hash?;
+        TransferLayout::stream(self.length, self.symbol_size, STREAM_BLOCK_SYMBOLS).ok()
+    }
+
+    /// Source blocks being sent
+    pub fn open_blocks(&self) -> usize {
+        self.encoders.len()
+    }
+
+    /// Source blocks retired, all of which come before the open ones
+    pub fn blocks_retired(&self) -> usize {
+        self.first_open
+    }
+
+    /// Whether the input ended and every source block has been retired
+    pub fn is_done(&self) -> bool {
+        self.hash.is_some() && self.encoders.is_empty()
+    }
+
+    /// Send encoding symbol IDs `offset`, `offset + stride`, ... of every
+    /// source block
+    pub fn set_esi_schedule(&mut self, offset: u32, stride: u32) {
+        self.esi_offset = offset;
+        self.esi_stride = stride;
+        for encoder in &mut self.encoders {
+            encoder.set_schedule(offset, stride);
+        }
+    }
+
+    /// Generate `overhead` times as many repair symbols as each source
+    /// block has source symbols, then retire it, as on a link without a
+    /// return path
+    pub fn set_overhead(&mut self, overhead: f64) {
+        self.overhead = Some(overhead.max(0.0));
+    }
+
+    /// Whether the open source block at `index` had its share of symbols
+    fn is_exhausted(&self, index: usize) -> bool {
+        let (sent, symbols) = self.sent[index];
+        self.overhead
+            .is_some_and(|overhead| sent >= (symbols as f64 * (1.0 + overhead)).ceil() as u64)
+    }
+
+    fn open(&mut self, block: &[u8]) -> Result<(), SessionError> {
+        let mut encoder = Encoder::new(block, self.symbol_size)?;
+        encoder.set_schedule(self.esi_offset, self.esi_stride);
+        self.encoders.push_back(encoder);
+        self.sent.push_back((0, block.len() / self.symbol_size));
+        Ok(())
+    }
+
+    /// Append input, opening a source block whenever one fills up
+    pub fn push(&mut self, data: &[u8]) -> Result<(), SessionError> {
+        if self.hash.is_some() {
+            return Err(SessionError::StreamEnded);
+        }
+        self.hasher.update(data);
+        self.length += data.len() as u64;
+        self.pending.extend_from_slice(data);
+
+        let block_bytes = STREAM_BLOCK_SYMBOLS * self.symbol_size;
+        while self.pending.len() >= block_bytes {
+            let rest = self.pending.split_off(block_bytes);
+            let block = std::mem::replace(&mut self.pending, rest);
+            self.open(&block)?;
+        }
+        Ok(())
+    }
+
+    /// End the input, opening the last source block with what is left
+    ///
+    /// Packets announce the length from now on, so some block is kept or
+    /// put back open to carry it.
+    pub fn finish(&mut self) -> Result<(), SessionError> {
+        if self.hash.is_some() {
+            return Err(SessionError::StreamEnded);
+        }
+        if !self.pending.is_empty() || self.first_open + self.encoders.len() == 0 {
+            let mut block = std::mem::take(&mut self.pending);
+            let symbols = block.len().div_ceil(self.symbol_size).max(MIN_BLOCK_SYMBOLS);
+            block.resize(symbols * self.symbol_size, 0);
+            self.open(&block)?;
+        } else if self.encoders.is_empty() {
+            let encoder = self.last_retired.take().expect("a block was retired");
+            self.encoders.push_back(encoder);
+            self.sent.push_back((0, STREAM_BLOCK_SYMBOLS));
+            self.first_open -= 1;
+        }
+        self.hash = Some(self.hasher.finalize());
+        Ok(())
+    }
+
+    fn retire_first(&mut self) {
+        self.last_retired = self.encoders.pop_front();
+        self.sent.pop_front();
+        self.first_open += 1;
+        self.next = self.next.saturating_sub(1);
+    }
+
+    /// Retire the source blocks before `blocks`, which every receiver has
+    ///
+    /// Once the input ended the last block stays open until the transfer
+    /// is confirmed, as receivers may not know the length yet.
+    pub fn retire(&mut self, blocks: usize) {
+        let keep = usize::from(self.hash.is_some());
+        while self.first_open < blocks && self.encoders.len() > keep {
+            self.retire_first();
+        }
+    }
+
+    /// Generate the next encoding symbol, cycling through the open source
+    /// blocks that did not have their share yet, or `None` if there are none
+    pub fn next_packet(&mut self) -> Result<Option<DataPacket>, SessionError> {
+        while !self.encoders.is_empty() && self.is_exhausted(0) {
+            self.retire_first();
+        }
+        let Some(index) = (0..self.encoders.len())
+            .map(|step| (self.next + step) % self.encoders.len())
+            .find(|&index| !self.is_exhausted(index))
+        else {
+            return Ok(None);
+        };
+        self.next = (index + 1) % self.encoders.len();
+
+        let block = self.encoders[index].next_block()?;
+        self.sent[index].0 += 1;
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        Ok(Some(DataPacket {
+            session_id: self.session_id,
+            nonce: self.nonce,
+            transfer_length: match self.hash {
+                Some(_) => STREAM_FLAG | self.length,
+                None => STREAM_LENGTH_UNKNOWN,
+            },
+            symbol_size: self.symbol_size as u16,
+            flags: 0,
+            sbn: (self.first_open + index) as u32,
+            esi: block.seed(),
+            degree: block.degree() as u16,
+            seq,
+            timestamp_us: clock_us(self.epoch),
+            payload: Bytes::from(block.data().to_vec()),
+        }))
+    }
+}
+
+/// Receiving side of a stream
+///
+/// Source blocks are decoded within a window from the first one not handed
+/// out yet, and handed out strictly in order.
+pub struct StreamReceiveSession {
+    session_id: u64,
+    symbol_size: usize,
+    /// Length of the stream, once the sender's input ended
+    length: Option<u64>,
+    /// Next source block to hand out
+    next_block: usize,
+    /// Decoders for source blocks of the window that are still incomplete
+    decoders: BTreeMap<usize, Decoder>,
+    /// Recovered source blocks not handed out yet
+    blocks: BTreeMap<usize, Vec<u8>>,
+    blocks_decoded: usize,
+    symbols_received: u64,
+    duplicates: u64,
+    /// Symbols received for source blocks recovered or outside the window
+    redundant: u64,
+    sources: Sources,
+    started: Instant,
+    decode_time: Duration,
+}
+
+impl StreamReceiveSession {
+    /// Start a stream from the first data packet received for it
+    pub fn new(first: &DataPacket) -> Result<Self, SessionError> {
+        if !first.is_stream() || first.symbol_size == 0 {
+            return Err(SessionError::LayoutMismatch);
+        }
+
+        Ok(Self {
+            session_id: first.session_id,
+            symbol_size: first.symbol_size as usize,
+            length: None,
+            next_block: 0,
+            decoders: BTreeMap::new(),
+            blocks: BTreeMap::new(),
+            blocks_decoded: 0,
+            symbols_received: 0,
+            duplicates: 0,
+            redundant: 0,
+            sources: Sources::default(),
+            started: Instant::now(),
+            decode_time: Duration::ZERO,
+        })
+    }
+
+    /// Bytes receiving a stream in symbols of `symbol_size` bytes takes at
+    /// most, roughly: a window of source blocks, each with a decoder
+    /// holding as many symbols as it takes
+    pub fn memory(symbol_size: usize) -> u64 {
+        let symbols = STREAM_BLOCK_SYMBOLS;
+        let decoder = Decoder::memory(symbols, symbol_size, symbols * DECODER_SYMBOL_LIMIT);
+        STREAM_WINDOW as u64 * ((symbols * symbol_size) as u64 + decoder)
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    /// Length of the stream, once the sender's input ended
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    /// Layout of the stream, once its length is known
+    pub fn layout(&self) -> Option<TransferLayout> {
+        TransferLayout::stream(self.length?, self.symbol_size, STREAM_BLOCK_SYMBOLS).ok()
+    }
+
+    /// Add a symbol received from `from`, returning `true` if it completed
+    /// its source block
+    pub fn add_packet(&mut self, from: SocketAddr, packet: &DataPacket) -> Result<bool, SessionError> {
+        if packet.session_id != self.session_id
+            || !packet.is_stream()
+            || packet.symbol_size as usize != self.symbol_size
+        {
+            return Err(SessionError::LayoutMismatch);
+        }
+        if let Some(length) = packet.stream_length() {
+            if self.length.is_some_and(|known| known != length) {
+                return Err(SessionError::LayoutMismatch);
+            }
+            self.length = Some(length);
+        }
+        let sbn = packet.sbn as usize;
+        if self.layout().is_some_and(|layout| sbn >= layout.num_blocks) {
+            return Err(SessionError::LayoutMismatch);
+        }
+
+        self.symbols_received += 1;
+        self.sources.observe(from, packet);
+        if sbn < self.next_block || sbn >= self.next_block + STREAM_WINDOW || self.blocks.contains_key(&sbn) {
+            self.redundant += 1;
+            return Ok(false);
+        }
+
+        // Packets of the last block, the only one that may be shorter, all
+        // carry the length
+        let symbols = self
+            .layout()
+            .map_or(STREAM_BLOCK_SYMBOLS, |layout| layout.block_symbols(sbn));
+        let decoder = match self.decoders.entry(sbn) {
+            std::collections::btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::btree_map::Entry::Vacant(entry) => entry.insert(Decoder::new(symbols, self.symbol_size)?),
+        };
+        if decoder.has_block(packet.esi) {
+            self.duplicates += 1;
+            return Ok(false);
+        }
+        if decoder.blocks_received() >= symbols * DECODER_SYMBOL_LIMIT {
+            log::warn!("Source block {} did not decode from {} symbols, starting over", sbn, decoder.blocks_received());
+            *decoder = Decoder::new(symbols, self.symbol_size)?;
+        }
+
+        let start = Instant::now();
+        let block = Block::new(packet.payload.to_vec(), packet.esi, packet.degree as usize);
+        decoder.add_block(block, packet.esi)?;
+        let decoded = decoder.try_decode()?;
+        self.decode_time += start.elapsed();
+        if !decoded {
+            return Ok(false);
+        }
+
+        if let Some(data) = self.decoders.remove(&sbn).and_then(|decoder| decoder.get_decoded_data()) {
+            self.blocks.insert(sbn, data);
+            self.blocks_decoded += 1;
+        }
+        Ok(true)
+    }
+
+    /// Take the next source block if it has been recovered, without the
+    /// padding of the last one
+    pub fn pop_block(&mut self) -> Option<Vec<u8>> {
+        let mut block = self.blocks.remove(&self.next_block)?;
+        if let Some(layout) = self.layout() {
+            let range = layout.block_range(self.next_block);
+            block.truncate((range.end - range.start) as usize);
+        }
+        self.next_block += 1;
+        Some(block)
+    }
+
+    /// Source blocks handed out so far
+    pub fn blocks_written(&self) -> usize {
+        self.next_block
+    }
+
+    /// Whether every source block of the stream has been handed out
+    pub fn is_complete(&self) -> bool {
+        self.layout().is_some_and(|layout| self.next_block >= layout.num_blocks)
+    }
+
+    /// Data packets received so far, including duplicates and redundant symbols
+    pub fn symbols_received(&self) -> u64 {
+        self.symbols_received
+    }
+
+    /// Source blocks recovered so far, handed out or not
+    pub fn blocks_decoded(&self) -> usize {
+        self.blocks_decoded
+    }
+
+    /// Symbols received more than once for a source block still being decoded
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Symbols received for source blocks already recovered or beyond the
+    /// window
+    pub fn redundant(&self) -> u64 {
+        self.redundant
+    }
+
+    /// Time spent decoding so far
+    pub fn decode_time(&self) -> Duration {
+        self.decode_time
+    }
+
+    /// Time since the first packet of the stream arrived
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Fraction of packets lost on the way from all senders
+    pub fn loss_rate(&self) -> f64 {
+        self.sources.loss_rate()
+    }
+
+    /// Senders that symbols were received from
+    pub fn sources(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.sources.addrs()
+    }
+
+    /// Nonce of the latest packet from `source`, which acknowledgements and
+    /// abort notices exchanged with it are signed with
+    pub fn nonce(&self, source: SocketAddr) -> Option<u64> {
+        self.sources.nonce(source)
+    }
+
+    /// Senders that symbols were received from, with the nonce of their
+    /// latest packet
+    pub fn senders(&self) -> impl Iterator<Item = (SocketAddr, u64)> + '_ {
+        self.sources.0.iter().map(|(&addr, stats)| (addr, stats.nonce))
+    }
+
+    /// Progress report to send back to the sender at `source`, unless
+    /// nothing arrived from it since the last
+    ///
+    /// Counts the source blocks recovered in order from the first, which
+    /// the sender no longer needs to send, and no blocks in all until the
+    /// length is known.
+    pub fn progress(&mut self, source: SocketAddr) -> Option<ProgressReport> {
+        let prefix = (self.next_block..)
+            .take_while(|sbn| self.blocks.contains_key(sbn))
+            .last()
+            .map_or(self.next_block, |sbn| sbn + 1);
+        let num_blocks = self.layout().map_or(0, |layout| layout.num_blocks);
+        self.sources.report(source, self.session_id, prefix, num_blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 3000))
+    }
+
+    fn roundtrip(data: &[u8], max_symbol_size: usize) -> (Vec<u8>, usize) {
+        let mut sender = SendSession::new(data, max_symbol_size).unwrap();
+        let first = sender.next_packet().unwrap();
+        let mut receiver = ReceiveSession::with_limits(&first, &ReceiveLimits::unlimited()).unwrap();
+        receiver.add_packet(mirror(1), &first).unwrap();
+
+        let mut sent = 1;
+        while !receiver.is_complete() {
+            receiver.add_packet(mirror(1), &sender.next_packet().unwrap()).unwrap();
+            sent += 1;
+            assert!(sent < 10_000, "transfer did not converge");
+        }
+        (receiver.into_data().unwrap(), sent)
+    }
+
+    #[test]
+    fn test_single_block_roundtrip() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let (decoded, _) = roundtrip(&data, 100);
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn test_multi_block_roundtrip() {
+        // 600 symbols split across three source blocks
+        let data: Vec<u8> = (0..600 * 16u32).map(|i| (i * 31 % 256) as u8).collect();
+        let (decoded, sent) = roundtrip(&data, 16);
+        assert_eq!(decoded, data);
+        assert!(sent >= 600);
+    }
+
+    #[test]
+    fn test_decoders_opened_lazily() {
+        let data: Vec<u8> = (0..600 * 16u32).map(|i| (i * 31 % 256) as u8).collect();
+        let mut sender = SendSession::new(&data, 16).unwrap();
+        let first = sender.next_packet().unwrap();
+        let mut receiver = ReceiveSession::with_limits(&first, &ReceiveLimits::unlimited()).unwrap();
+        assert!(receiver.decoders.is_empty());
+
+        receiver.add_packet(mirror(1), &first).unwrap();
+        assert_eq!(receiver.decoders.len(), 1);
+        while !receiver.is_complete() {
+            receiver.add_packet(mirror(1), &sender.next_packet().unwrap()).unwrap();
+            assert!(receiver.decoders.len() + receiver.blocks_decoded() <= receiver.layout().num_blocks);
+        }
+        // Every decoder is dropped once its block is recovered
+        assert!(receiver.decoders.is_empty());
+        assert_eq!(receiver.into_data().unwrap(), data);
+    }
+
+    #[test]
+    fn test_receive_limits() {
+        let mut sender = SendSession::new(&vec![3u8; 600 * 16], 16).unwrap();
+        let first = sender.next_packet().unwrap();
+        assert!(matches!(ReceiveSession::new(&first), Err(SessionError::SymbolTooSmall(16))));
+        let limits = ReceiveLimits {
+            max_transfer_length: 1000,
+            min_symbol_size: 1,
+        };
+        assert!(matches!(ReceiveSession::with_limits(&first, &limits), Err(SessionError::TooLarge(9600))));
+
+        // Objects in one source block may use small symbols
+        let mut sender = SendSession::new(&[3u8; 1000], 10).unwrap();
+        assert!(ReceiveSession::new(&sender.next_packet().unwrap()).is_ok());
+
+        // A forged length is refused before anything is allocated for it
+        let mut forged = first.clone();
+        forged.transfer_length = 1 << 40;
+        forged.symbol_size = 1400;
+        assert!(matches!(ReceiveSession::new(&forged), Err(SessionError::TooLarge(_))));
+    }
+
+    #[test]
+    fn test_memory_estimate() {
+        // Decoders cost more per byte of the object the smaller its symbols
+        let large = ReceiveSession::memory(&TransferLayout::new(1 << 20, 1400).unwrap());
+        let small = ReceiveSession::memory(&TransferLayout::new(1 << 20, 16).unwrap());
+        assert!(large > 1 << 20);
+        assert!(small > 10 * large);
+        assert!(StreamReceiveSession::memory(1400) > (STREAM_WINDOW * STREAM_BLOCK_SYMBOLS * 1400) as u64);
+    }
+
+    #[test]
+    fn test_tiny_objects() {
+        for len in [0usize, 1, 3, 7] {
+            let data = vec![0xAB; len];
+            let (decoded, _) = roundtrip(&data, 1400);
+            assert_eq!(decoded, data);
+        }
+    }
+
+    #[test]
+    fn test_progress_tracks_sequence() {
+        let mut sender = SendSession::new(&[7u8; 1000], 10).unwrap();
+        let packets: Vec<_> = (0..5).map(|_| sender.next_packet().unwrap()).collect();
+        assert_eq!(packets.iter().map(|p| p.seq).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+
+        // Out of order and lost packets
+        let mut receiver = ReceiveSession::new(&packets[0]).unwrap();
+        for i in [0, 3, 1] {
+            receiver.add_packet(mirror(1), &packets[i]).unwrap();
+        }
+
+        let report = receiver.progress(mirror(1)).unwrap();
+        assert_eq!(report.symbols_received, 3);
+        assert_eq!(report.highest_seq, 3);
+        assert!(report.delay_us.is_some());
+
+        // Nothing new from the sender, nothing to report
+        assert_eq!(receiver.progress(mirror(1)), None);
+        assert_eq!(receiver.progress(mirror(9)), None);
+    }
+
+    #[test]
+    fn test_multiple_sources() {
+        let data: Vec<u8> = (0..300 * 16u32).map(|i| (i * 7 % 256) as u8).collect();
+        let mut mirrors: Vec<SendSession> = (0..3)
+            .map(|offset| {
+                let mut session = SendSession::new(&data, 16).unwrap();
+                session.set_esi_schedule(offset, 3);
+                session
+            })
+            .collect();
+
+        let first = mirrors[0].next_packet().unwrap();
+        let mut receiver = ReceiveSession::with_limits(&first, &ReceiveLimits::unlimited()).unwrap();
+        receiver.add_packet(mirror(0), &first).unwrap();
+
+        // Mirrors interleave disjoint symbols, so none is ever a duplicate
+        let mut seen = std::collections::HashSet::new();
+        seen.insert((first.sbn, first.esi));
+        let mut turn = 1;
+        while !receiver.is_complete() {
+            let packet = mirrors[turn % 3].next_packet().unwrap();
+            assert!(seen.insert((packet.sbn, packet.esi)));
+            receiver.add_packet(mirror((turn % 3) as u8), &packet).unwrap();
+            turn += 1;
+            assert!(turn < 10_000, "transfer did not converge");
+        }
+        assert_eq!(receiver.into_data().unwrap(), data);
+    }
+
+    #[test]
+    fn test_progress_per_source() {
+        let mut a = SendSession::new(&[7u8; 1000], 10).unwrap();
+        let mut b = SendSession::new(&[7u8; 1000], 10).unwrap();
+        let first = a.next_packet().unwrap();
+        let mut receiver = ReceiveSession::new(&first).unwrap();
+        receiver.add_packet(mirror(1), &first).unwrap();
+        for _ in 0..4 {
+            receiver.add_packet(mirror(2), &b.next_packet().unwrap()).unwrap();
+        }
+
+        let mut sources: Vec<_> = receiver.sources().collect();
+        sources.sort();
+        assert_eq!(sources, vec![mirror(1), mirror(2)]);
+        assert_eq!(receiver.progress(mirror(1)).unwrap().symbols_received, 1);
+        let report = receiver.progress(mirror(2)).unwrap();
+        assert_eq!(report.symbols_received, 4);
+        assert_eq!(report.highest_seq, 3);
+        assert_eq!(receiver.symbols_received(), 5);
+    }
+
+    #[test]
+    fn test_duplicates_and_loss() {
+        let mut sender = SendSession::new(&[7u8; 1000], 10).unwrap();
+        let packets: Vec<_> = (0..10).map(|_| sender.next_packet().unwrap()).collect();
+        let mut receiver = ReceiveSession::new(&packets[0]).unwrap();
+        for i in [0, 1, 1, 4, 9, 4] {
+            receiver.add_packet(mirror(1), &packets[i]).unwrap();
+        }
+
+        assert_eq!(receiver.symbols_received(), 6);
+        assert_eq!(receiver.duplicates(), 2);
+        assert!(receiver.symbols_useful() <= 4);
+        // Four distinct packets out of ten sent, six counting duplicates
+        assert!((receiver.loss_rate() - 0.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_decoder_starts_over() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let mut sender = SendSession::new(&data, 10).unwrap();
+        let first = sender.next_packet().unwrap();
+        let mut receiver = ReceiveSession::new(&first).unwrap();
+
+        // Symbols combining no source symbols fill the decoder without
+        // ever decoding the block
+        let symbols = receiver.layout().block_symbols(0);
+        for i in 0..symbols * DECODER_SYMBOL_LIMIT {
+            let mut junk = first.clone();
+            junk.esi = 1_000_000 + i as u32;
+            junk.degree = 0;
+            receiver.add_packet(mirror(1), &junk).unwrap();
+        }
+        assert!(!receiver.is_complete());
+
+        // Genuine symbols still get through and recover the block
+        receiver.add_packet(mirror(1), &first).unwrap();
+        for _ in 0..symbols * 2 {
+            if receiver.is_complete() {
+                break;
+            }
+            receiver.add_packet(mirror(1), &sender.next_packet().unwrap()).unwrap();
+        }
+        assert_eq!(receiver.into_data().unwrap(), data);
+    }
+
+    fn stream(data: &[u8], chunk: usize, symbol_size: usize) -> StreamSendSession {
+        let mut sender = StreamSendSession::new(symbol_size).unwrap();
+        for piece in data.chunks(chunk) {
+            sender.push(piece).unwrap();
+        }
+        sender
+    }
+
+    /// Feed every packet of `sender` to `receiver` until it completes,
+    /// retiring source blocks as it reports them recovered
+    fn drain_stream(sender: &mut StreamSendSession, receiver: &mut Option<StreamReceiveSession>, output: &mut Vec<u8>) {
+        for _ in 0..100_000 {
+            let Some(packet) = sender.next_packet().unwrap() else {
+                return;
+            };
+            let active = receiver.get_or_insert_with(|| StreamReceiveSession::new(&packet).unwrap());
+            active.add_packet(mirror(1), &packet).unwrap();
+            while let Some(block) = active.pop_block() {
+                output.extend_from_slice(&block);
+            }
+            if active.is_complete() {
+                return;
+            }
+            if let Some(report) = active.progress(mirror(1)) {
+                sender.retire(report.blocks_decoded as usize);
+            }
+        }
+        panic!("stream did not converge");
+    }
+
+    #[test]
+    fn test_stream_roundtrip() {
+        let data: Vec<u8> = (0..2500 * 16u32).map(|i| (i * 13 % 256) as u8).collect();
+        let mut sender = stream(&data, 1000, 16);
+        // Nine full blocks are sent before the input ends
+        assert_eq!(sender.open_blocks(), 9);
+        assert!(sender.next_packet().unwrap().unwrap().stream_length().is_none());
+
+        let mut receiver = None;
+        let mut output = Vec::new();
+        drain_stream(&mut sender, &mut receiver, &mut output);
+        assert_eq!(output.len(), 9 * STREAM_BLOCK_SYMBOLS * 16);
+
+        sender.finish().unwrap();
+        assert!(matches!(sender.push(b"more"), Err(SessionError::StreamEnded)));
+        drain_stream(&mut sender, &mut receiver, &mut output);
+        let receiver = receiver.unwrap();
+        assert!(receiver.is_complete());
+        assert_eq!(receiver.layout().unwrap().num_blocks, 10);
+        assert_eq!(output, data);
+        assert_eq!(sender.hash(), Some(&blake3::hash(&data)));
+    }
+
+    #[test]
+    fn test_stream_length_announced() {
+        // Input ending exactly at a block boundary after every block was
+        // retired reopens the last one to carry the length
+        let data = vec![9u8; 2 * STREAM_BLOCK_SYMBOLS * 8];
+        let mut sender = stream(&data, 100, 8);
+        let mut receiver = None;
+        let mut output = Vec::new();
+        drain_stream(&mut sender, &mut receiver, &mut output);
+        assert_eq!(sender.open_blocks(), 0);
+        assert!(!receiver.as_ref().unwrap().is_complete());
+
+        sender.finish().unwrap();
+        assert_eq!(sender.open_blocks(), 1);
+        drain_stream(&mut sender, &mut receiver, &mut output);
+        assert!(receiver.unwrap().is_complete());
+        assert_eq!(output, data);
+
+        // Even an empty stream has a block to announce its length
+        let mut sender = stream(&[], 1, 8);
+        sender.finish().unwrap();
+        let mut receiver = None;
+        let mut output = Vec::new();
+        drain_stream(&mut sender, &mut receiver, &mut output);
+        assert!(receiver.unwrap().is_complete());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_stream_overhead() {
+        let mut sender = stream(&vec![1u8; 2 * STREAM_BLOCK_SYMBOLS * 4 + 10], 4096, 4);
+        sender.set_overhead(0.5);
+        sender.finish().unwrap();
+
+        // The short last block gets its share in proportion
+        let mut sent = HashMap::new();
+        while let Some(packet) = sender.next_packet().unwrap() {
+            *sent.entry(packet.sbn).or_insert(0) += 1;
+        }
+        assert!(sender.is_done());
+        assert_eq!(sent[&0], STREAM_BLOCK_SYMBOLS * 3 / 2);
+        assert_eq!(sent[&1], STREAM_BLOCK_SYMBOLS * 3 / 2);
+        assert_eq!(sent[&2], MIN_BLOCK_SYMBOLS * 3 / 2);
+    }
+
+    #[test]
+    fn test_session_id_is_content_derived() {
+        let a = SendSession::new(b"same content here", 4).unwrap();
+        let b = SendSession::new(b"same content here", 4).unwrap();
+        let c = SendSession::new(b"other content", 4).unwrap();
+        assert_eq!(a.session_id(), b.session_id());
+        assert_ne!(a.session_id(), c.session_id());
+    }
+
+    #[test]
+    fn test_foreign_packets_rejected() {
+        let mut sender = SendSession::new(&[1u8; 100], 10).unwrap();
+        let first = sender.next_packet().unwrap();
+        let mut receiver = ReceiveSession::new(&first).unwrap();
+
+        let mut other = first.clone();
+        other.sbn = 5;
+        assert!(matches!(receiver.add_packet(mirror(1), &other), Err(SessionError::LayoutMismatch)));
+        other = first.clone();
+        other.transfer_length += 1;
+        assert!(matches!(receiver.add_packet(mirror(1), &other), Err(SessionError::LayoutMismatch)));
+    }
+}
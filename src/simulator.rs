@@ -0,0 +1,649 @@
+//! In-process network with configurable impairments
+//!
+//! A `Network` connects any number of endpoints, each a `PacketTransport`
+//! bound to a socket address of its own. Datagrams between endpoints pass
+//! through the impairments of their link: loss, either independent or in
+//! Gilbert-Elliott bursts, duplication, delay with jitter, reordering and
+//! bit corruption. All random choices come from one generator seeded at
+//! creation and delivery times follow the tokio clock, so a transfer run
+//! on a paused clock plays out identically for the same seed.
+//!
+//! Corrupted datagrams are delivered as they are, as if a link checksum
+//! had missed the damage; those that no longer parse are dropped on
+//! receipt, like garbage arriving on a UDP socket.
+
+use crate::protocol::Packet;
+use crate::transport::PacketTransport;
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// First port handed out to endpoints bound to port 0
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+#[derive(Debug, Error)]
+pub enum SimulatorError {
+    #[error("Address {0} is already in use")]
+    AddressInUse(SocketAddr),
+    #[error("No ephemeral ports left on {0}")]
+    NoPortsLeft(SocketAddr),
+}
+
+/// How datagrams on a link are lost
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LossModel {
+    /// Nothing is lost
+    #[default]
+    None,
+    /// Each datagram is lost independently with probability `rate`
+    Bernoulli { rate: f64 },
+    /// Two-state Markov chain alternating between a good and a bad state,
+    /// each losing datagrams at its own rate
+    GilbertElliott {
+        /// Probability of moving from the good to the bad state per datagram
+        to_bad: f64,
+        /// Probability of moving from the bad to the good state per datagram
+        to_good: f64,
+        /// Loss rate in the good state
+        good_loss: f64,
+        /// Loss rate in the bad state
+        bad_loss: f64,
+    },
+}
+
+impl LossModel {
+    /// Independent loss at `rate`
+    pub fn bernoulli(rate: f64) -> Self {
+        LossModel::Bernoulli { rate }
+    }
+
+    /// Bursts of loss averaging `1 / to_good` datagrams, entered with
+    /// probability `to_bad` per datagram, that drop everything
+    pub fn bursts(to_bad: f64, to_good: f64) -> Self {
+        LossModel::GilbertElliott {
+            to_bad,
+            to_good,
+            good_loss: 0.0,
+            bad_loss: 1.0,
+        }
+    }
+
+    /// Long-run fraction of datagrams lost
+    pub fn mean_loss(&self) -> f64 {
+        match *self {
+            LossModel::None => 0.0,
+            LossModel::Bernoulli { rate } => rate,
+            LossModel::GilbertElliott {
+                to_bad,
+                to_good,
+                good_loss,
+                bad_loss,
+            } => {
+                let bad = match to_bad + to_good {
+                    total if total > 0.0 => to_bad / total,
+                    _ => 0.0,
+                };
+                (1.0 - bad) * good_loss + bad * bad_loss
+            }
+        }
+    }
+}
+
+/// What happens to datagrams on their way across a link
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Impairments {
+    pub loss: LossModel,
+    /// Delay every datagram takes
+    pub delay: Duration,
+    /// Most additional delay, drawn uniformly per datagram
+    pub jitter: Duration,
+    /// Probability of holding a datagram back behind later ones
+    pub reorder: f64,
+    /// Extra delay of datagrams held back
+    pub reorder_delay: Duration,
+    /// Probability of delivering a datagram twice
+    pub duplicate: f64,
+    /// Probability of flipping one bit of a datagram
+    pub corrupt: f64,
+}
+
+/// What the network did to the datagrams sent across it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NetworkStats {
+    pub sent: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub corrupted: u64,
+    pub delivered: u64,
+}
+
+/// A datagram in flight, ordered by delivery time and then by send order
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Datagram {
+    deliver_at: Instant,
+    order: u64,
+    from: SocketAddr,
+    bytes: Bytes,
+}
+
+/// Impairments of one direction between two addresses and the state of
+/// its loss model
+#[derive(Debug)]
+struct Link {
+    impairments: Impairments,
+    bad: bool,
+}
+
+impl Link {
+    fn new(impairments: Impairments) -> Self {
+        Self {
+            impairments,
+            bad: false,
+        }
+    }
+
+    fn lose(&mut self, rng: &mut StdRng) -> bool {
+        match self.impairments.loss {
+            LossModel::None => false,
+            LossModel::Bernoulli { rate } => rng.gen_bool(rate.clamp(0.0, 1.0)),
+            LossModel::GilbertElliott {
+                to_bad,
+                to_good,
+                good_loss,
+                bad_loss,
+            } => {
+                let switch = if self.bad { to_good } else { to_bad };
+                if rng.gen_bool(switch.clamp(0.0, 1.0)) {
+                    self.bad = !self.bad;
+                }
+                let rate = if self.bad { bad_loss } else { good_loss };
+                rng.gen_bool(rate.clamp(0.0, 1.0))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    rng: StdRng,
+    impairments: Impairments,
+    links: HashMap<(SocketAddr, SocketAddr), Link>,
+    endpoints: HashMap<SocketAddr, mpsc::UnboundedSender<Datagram>>,
+    next_port: u16,
+    next_order: u64,
+    stats: NetworkStats,
+}
+
+impl State {
+    fn bind(&mut self, addr: SocketAddr) -> Result<SocketAddr, SimulatorError> {
+        let addr = match addr.port() {
+            0 => {
+                let port = (self.next_port..=u16::MAX)
+                    .find(|&port| !self.endpoints.contains_key(&SocketAddr::new(addr.ip(), port)))
+                    .ok_or(SimulatorError::NoPortsLeft(addr))?;
+                self.next_port = port.saturating_add(1);
+                SocketAddr::new(addr.ip(), port)
+            }
+            _ if self.endpoints.contains_key(&addr) => return Err(SimulatorError::AddressInUse(addr)),
+            _ => addr,
+        };
+        Ok(addr)
+    }
+
+    /// Put `bytes` on the link from `from` to `to`, deciding its fate
+    fn transmit(&mut self, from: SocketAddr, to: SocketAddr, bytes: Bytes) {
+        self.stats.sent += 1;
+        let default = &self.impairments;
+        let link = self
+            .links
+            .entry((from, to))
+            .or_insert_with(|| Link::new(default.clone()));
+        if link.lose(&mut self.rng) {
+            self.stats.lost += 1;
+            return;
+        }
+        let impairments = &link.impairments;
+
+        let copies = match self.rng.gen_bool(impairments.duplicate.clamp(0.0, 1.0)) {
+            true => {
+                self.stats.duplicated += 1;
+                2
+            }
+            false => 1,
+        };
+        for _ in 0..copies {
+            let mut delay = impairments.delay;
+            if !impairments.jitter.is_zero() {
+                delay += impairments.jitter.mul_f64(self.rng.gen::<f64>());
+            }
+            if self.rng.gen_bool(impairments.reorder.clamp(0.0, 1.0)) {
+                self.stats.reordered += 1;
+                delay += impairments.reorder_delay;
+            }
+
+            let bytes = match self.rng.gen_bool(impairments.corrupt.clamp(0.0, 1.0)) && !bytes.is_empty() {
+                true => {
+                    self.stats.corrupted += 1;
+                    let mut damaged = bytes.to_vec();
+                    let bit = self.rng.gen_range(0..damaged.len() * 8);
+                    damaged[bit / 8] ^= 1 << (bit % 8);
+                    Bytes::from(damaged)
+                }
+                false => bytes.clone(),
+            };
+
+            let order = self.next_order;
+            self.next_order += 1;
+            // Datagrams to addresses nobody is bound to vanish, as with UDP
+            if let Some(endpoint) = self.endpoints.get(&to) {
+                let datagram = Datagram {
+                    deliver_at: Instant::now() + delay,
+                    order,
+                    from,
+                    bytes,
+                };
+                if endpoint.send(datagram).is_ok() {
+                    self.stats.delivered += 1;
+                }
+            }
+        }
+    }
+}
+
+/// An in-process network of endpoints
+///
+/// Clones share the same network.
+#[derive(Debug, Clone)]
+pub struct Network {
+    state: Arc<Mutex<State>>,
+}
+
+impl Network {
+    /// Network whose links all start with `impairments`, making its random
+    /// choices from `seed`
+    pub fn new(seed: u64, impairments: Impairments) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                rng: StdRng::seed_from_u64(seed),
+                impairments,
+                links: HashMap::new(),
+                endpoints: HashMap::new(),
+                next_port: FIRST_EPHEMERAL_PORT,
+                next_order: 0,
+                stats: NetworkStats::default(),
+            })),
+        }
+    }
+
+    /// Attach an endpoint at `addr`, picking a free port if it is 0
+    pub fn bind(&self, addr: SocketAddr) -> Result<Endpoint, SimulatorError> {
+        let mut state = self.state.lock().unwrap();
+        let addr = state.bind(addr)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        state.endpoints.insert(addr, tx);
+        Ok(Endpoint {
+            addr,
+            network: self.clone(),
+            inbox: tokio::sync::Mutex::new(Inbox {
+                rx,
+                queue: BinaryHeap::new(),
+            }),
+        })
+    }
+
+    /// Give the link from `from` to `to` impairments of its own
+    pub fn set_link(&self, from: SocketAddr, to: SocketAddr, impairments: Impairments) {
+        let mut state = self.state.lock().unwrap();
+        state.links.insert((from, to), Link::new(impairments));
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+#[derive(Debug)]
+struct Inbox {
+    rx: mpsc::UnboundedReceiver<Datagram>,
+    /// Datagrams received but not yet due
+    queue: BinaryHeap<Reverse<Datagram>>,
+}
+
+/// An address on a `Network`
+#[derive(Debug)]
+pub struct Endpoint {
+    addr: SocketAddr,
+    network: Network,
+    inbox: tokio::sync::Mutex<Inbox>,
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.network.state.lock() {
+            state.endpoints.remove(&self.addr);
+        }
+    }
+}
+
+#[async_trait]
+impl PacketTransport for Endpoint {
+    async fn send_packet(&self, peer: SocketAddr, packet: &Packet) -> Result<()> {
+        let bytes = packet.encode().freeze();
+        self.network.state.lock().unwrap().transmit(self.addr, peer, bytes);
+        Ok(())
+    }
+
+    async fn receive_packet(&self) -> Result<(Packet, SocketAddr)> {
+        let mut inbox = self.inbox.lock().await;
+        let Inbox { rx, queue } = &mut *inbox;
+        loop {
+            while let Ok(datagram) = rx.try_recv() {
+                queue.push(Reverse(datagram));
+            }
+
+            let next = queue.peek().map(|Reverse(datagram)| datagram.deliver_at);
+            if next.is_some_and(|due| due <= Instant::now()) {
+                let Reverse(datagram) = queue.pop().unwrap();
+                match Packet::decode(&datagram.bytes) {
+                    Ok(packet) => return Ok((packet, datagram.from)),
+                    Err(e) => {
+                        log::debug!("Dropping datagram from {}: {}", datagram.from, e);
+                        continue;
+                    }
+                }
+            }
+
+            tokio::select! {
+                received = rx.recv() => match received {
+                    Some(datagram) => queue.push(Reverse(datagram)),
+                    None => anyhow::bail!("Endpoint {} was detached from the network", self.addr),
+                },
+                _ = tokio::time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {}
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{CompletionAck, DataPacket};
+    use crate::session::{ReceiveSession, SendSession};
+    use tokio::runtime::{Builder, Runtime};
+
+    /// Runtime on a paused clock, which jumps ahead whenever all tasks wait
+    fn paused() -> Runtime {
+        Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .unwrap()
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn data(esi: u32) -> Packet {
+        Packet::Data(DataPacket {
+            session_id: 1,
+            nonce: 0,
+            transfer_length: 1000,
+            symbol_size: 100,
+            flags: 0,
+            sbn: 0,
+            esi,
+            degree: 1,
+            seq: esi,
+            timestamp_us: 0,
+            payload: Bytes::from(vec![esi as u8; 100]),
+        })
+    }
+
+    /// Send `count` packets across `network` and collect what arrives
+    async fn deliver(network: &Network, count: u32) -> Vec<Packet> {
+        let a = network.bind(addr("10.0.0.1:0")).unwrap();
+        let b = network.bind(addr("10.0.0.2:3000")).unwrap();
+        for esi in 0..count {
+            a.send_packet(b.local_addr().unwrap(), &data(esi)).await.unwrap();
+        }
+        let mut received = Vec::new();
+        while let Ok(packet) = tokio::time::timeout(Duration::from_secs(1), b.receive_packet()).await {
+            received.push(packet.unwrap().0);
+        }
+        received
+    }
+
+    /// Send `contents` from one endpoint to another until the receiver
+    /// acknowledges, returning what it decoded and the symbols sent
+    async fn transfer(network: &Network, contents: &[u8]) -> (Vec<u8>, u64) {
+        let sender = network.bind(addr("10.0.0.1:0")).unwrap();
+        let receiver = network.bind(addr("10.0.0.2:3000")).unwrap();
+        let target = receiver.local_addr().unwrap();
+        // The single acknowledgement must get through
+        network.set_link(target, sender.local_addr().unwrap(), Impairments::default());
+
+        let receiving = async {
+            let mut session: Option<ReceiveSession> = None;
+            loop {
+                let (packet, from) = receiver.receive_packet().await.unwrap();
+                let Packet::Data(packet) = packet else {
+                    continue;
+                };
+                let active = match &mut session {
+                    Some(active) => active,
+                    None => session.insert(ReceiveSession::new(&packet).unwrap()),
+                };
+                if active.add_packet(from, &packet).is_err() || !active.is_complete() {
+                    continue;
+                }
+                let session_id = active.session_id();
+                let decoded = session.take().unwrap().into_data().unwrap();
+                let hash = blake3::hash(&decoded);
+                let ack = Packet::Complete(CompletionAck::new(session_id, packet.nonce, *hash.as_bytes(), b""));
+                receiver.send_packet(from, &ack).await.unwrap();
+                return decoded;
+            }
+        };
+
+        let sending = async {
+            let mut session = SendSession::new(contents, 1000).unwrap();
+            let mut sent = 0u64;
+            loop {
+                for _ in 0..16 {
+                    let packet = Packet::Data(session.next_packet().unwrap());
+                    sender.send_packet(target, &packet).await.unwrap();
+                    sent += 1;
+                }
+                if let Ok(received) = tokio::time::timeout(Duration::from_millis(1), sender.receive_packet()).await {
+                    if matches!(received.unwrap().0, Packet::Complete(_)) {
+                        return sent;
+                    }
+                }
+            }
+        };
+
+        tokio::join!(receiving, sending)
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        let mut rng = StdRng::seed_from_u64(7);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn test_clean_delivery() {
+        paused().block_on(async {
+            let network = Network::new(1, Impairments::default());
+            let received = deliver(&network, 50).await;
+            assert_eq!(received, (0..50).map(data).collect::<Vec<_>>());
+            assert_eq!(network.stats().delivered, 50);
+        });
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let impairments = Impairments {
+            loss: LossModel::bursts(0.05, 0.3),
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(5),
+            reorder: 0.1,
+            reorder_delay: Duration::from_millis(10),
+            duplicate: 0.05,
+            corrupt: 0.05,
+        };
+        let run = |seed| {
+            paused().block_on(async {
+                let network = Network::new(seed, impairments.clone());
+                (deliver(&network, 500).await, network.stats())
+            })
+        };
+
+        let (first, stats) = run(42);
+        assert_eq!(run(42), (first.clone(), stats));
+        assert_ne!(run(43).0, first);
+        assert!(stats.lost > 0 && stats.duplicated > 0 && stats.reordered > 0 && stats.corrupted > 0);
+
+        // Jitter and held-back datagrams overtake one another
+        let esis: Vec<u32> = first
+            .iter()
+            .filter_map(|p| match p {
+                Packet::Data(p) => Some(p.seq),
+                _ => None,
+            })
+            .collect();
+        assert!(esis.windows(2).any(|w| w[0] > w[1]));
+    }
+
+    #[test]
+    fn test_burst_loss() {
+        paused().block_on(async {
+            let loss = LossModel::bursts(0.02, 0.2);
+            let network = Network::new(
+                3,
+                Impairments {
+                    loss,
+                    ..Impairments::default()
+                },
+            );
+            let received = deliver(&network, 5000).await;
+            let lost = 1.0 - received.len() as f64 / 5000.0;
+            assert!((lost - loss.mean_loss()).abs() < 0.04, "lost {}", lost);
+
+            // Losses come in runs rather than alone
+            let seqs: Vec<u32> = received
+                .iter()
+                .map(|p| match p {
+                    Packet::Data(p) => p.seq,
+                    _ => unreachable!(),
+                })
+                .collect();
+            let gaps: Vec<u32> = seqs.windows(2).map(|w| w[1] - w[0] - 1).filter(|&g| g > 0).collect();
+            let mean_gap = gaps.iter().sum::<u32>() as f64 / gaps.len() as f64;
+            assert!(mean_gap > 3.0, "mean gap {}", mean_gap);
+        });
+    }
+
+    #[test]
+    fn test_corruption() {
+        paused().block_on(async {
+            let impairments = Impairments {
+                corrupt: 1.0,
+                ..Impairments::default()
+            };
+            let network = Network::new(5, impairments);
+            let received = deliver(&network, 100).await;
+            assert!(received.iter().all(|p| !(0..100).map(data).any(|sent| sent == *p)));
+            assert_eq!(network.stats().corrupted, 100);
+        });
+    }
+
+    #[test]
+    fn test_addresses() {
+        let network = Network::new(0, Impairments::default());
+        let a = network.bind(addr("10.0.0.1:0")).unwrap();
+        let b = network.bind(addr("10.0.0.1:0")).unwrap();
+        assert_ne!(a.local_addr().unwrap(), b.local_addr().unwrap());
+        assert!(network.bind(a.local_addr().unwrap()).is_err());
+
+        // Addresses are released when their endpoint goes away
+        let released = a.local_addr().unwrap();
+        drop(a);
+        assert!(network.bind(released).is_ok());
+    }
+
+    #[test]
+    fn test_round_trip_bernoulli() {
+        paused().block_on(async {
+            let network = Network::new(
+                11,
+                Impairments {
+                    loss: LossModel::bernoulli(0.2),
+                    delay: Duration::from_millis(30),
+                    ..Impairments::default()
+                },
+            );
+            let contents = contents(200_000);
+            let (decoded, sent) = transfer(&network, &contents).await;
+            assert_eq!(decoded, contents);
+            assert!(sent >= 200, "sent {}", sent);
+        });
+    }
+
+    #[test]
+    fn test_round_trip_impaired() {
+        paused().block_on(async {
+            let network = Network::new(
+                12,
+                Impairments {
+                    loss: LossModel::bursts(0.02, 0.25),
+                    delay: Duration::from_millis(40),
+                    jitter: Duration::from_millis(10),
+                    reorder: 0.05,
+                    reorder_delay: Duration::from_millis(25),
+                    duplicate: 0.02,
+                    corrupt: 0.0,
+                },
+            );
+            let contents = contents(300_000);
+            let (decoded, _) = transfer(&network, &contents).await;
+            assert_eq!(decoded, contents);
+        });
+    }
+
+    #[test]
+    fn test_asymmetric_link() {
+        paused().block_on(async {
+            let network = Network::new(13, Impairments::default());
+            let a = network.bind(addr("10.0.0.1:1000")).unwrap();
+            let b = network.bind(addr("10.0.0.2:2000")).unwrap();
+            let lossy = Impairments {
+                loss: LossModel::bernoulli(1.0),
+                ..Impairments::default()
+            };
+            network.set_link(a.local_addr().unwrap(), b.local_addr().unwrap(), lossy);
+
+            a.send_packet(b.local_addr().unwrap(), &data(1)).await.unwrap();
+            b.send_packet(a.local_addr().unwrap(), &data(2)).await.unwrap();
+            assert_eq!(a.receive_packet().await.unwrap(), (data(2), b.local_addr().unwrap()));
+            assert!(tokio::time::timeout(Duration::from_secs(1), b.receive_packet())
+                .await
+                .is_err());
+        });
+    }
+}
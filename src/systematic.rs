@@ -30,7 +30,7 @@ lazy_static! {
 
 /// Get systematic index J(K) for a given K value
 pub fn get_systematic_index(k: usize) -> Option<usize> {
-    if k < 4 || k > KMAX {
+    if !(4..=KMAX).contains(&k) {
         None
     } else {
         SYSTEMATIC_INDEX_TABLE.get(&k).copied()
@@ -61,7 +61,7 @@ impl LDPCParams {
         // H is the smallest positive integer such that choose(H,ceil(H/2)) >= K + S
         let h = {
             let mut h = 1;
-            while combinations(h, (h + 1) / 2) < k + s {
+            while combinations(h, h.div_ceil(2)) < k + s {
                 h += 1;
             }
             h
@@ -0,0 +1,2416 @@
+//! Whole transfers for embedding in other programs
+//!
+//! `Sender` and `Receiver` tie sessions, transports, pacing, checkpoints
+//! and acknowledgements together. Each is configured with chained setters
+//! and consumed by a future that resolves to a report of the finished
+//! transfer; what happens along the way is reported as `Event`s on an
+//! optional channel.
+//!
+//! A `Source::Stream` is sent as it is read, in source blocks opened as the
+//! input fills them, and the receiver writes recovered blocks out in order
+//! instead of saving the object at the end.
+//!
+//! Either end stops once its cancellation token is cancelled, telling the
+//! other with an abort notice. Files are written under a `.part` name and
+//! renamed once complete, so an output file that exists is never
+//! truncated; what a cancelled receive leaves behind follows its
+//! `PartialOutput` policy.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), fountainflow::transfer::TransferError> {
+//! use fountainflow::transfer::{Receiver, Sender, Source, Output};
+//!
+//! let report = Sender::new(Source::Path("backup.tar".into()))
+//!     .target("192.168.1.100:3000")
+//!     .rate_limit(500)
+//!     .send()
+//!     .await?;
+//! println!("Sent {} bytes", report.bytes);
+//!
+//! let report = Receiver::new(Output::Path("backup.tar".into())).receive().await?;
+//! println!("Received {} bytes", report.bytes);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::addr::{self, AddressError, AddressFamily, Interface};
+use crate::archive::{self, part_path, ArchiveError, Manifest};
+use crate::block::symbol_size_for_payload;
+use crate::checkpoint::{self, Checkpoint, CheckpointError};
+use crate::fanout::{Destination, Fanout};
+use crate::pacer::{self, PacerConfig};
+use crate::pmtu;
+use crate::protocol::{AbortNotice, CompletionAck, DataPacket, Packet, DATA_HEADER_SIZE};
+use crate::session::{
+    session_id_for, ReceiveLimits, ReceiveSession, SendSession, SessionError, StreamReceiveSession, StreamSendSession,
+    STREAM_BLOCK_SYMBOLS, STREAM_WINDOW,
+};
+use crate::transport::{PacketTransport, UdpTransport};
+use serde::{Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{mpsc, watch};
+
+pub use tokio_util::sync::CancellationToken;
+
+/// Most data packets handed to the transport at once
+const MAX_SEND_BATCH: usize = 64;
+
+/// Bytes read from a stream's input at a time
+const INPUT_CHUNK: usize = 64 * 1024;
+
+/// Chunks of a stream's input read ahead of the send loop
+const INPUT_QUEUE: usize = 16;
+
+/// How often a stream sender with nothing to send checks for input and
+/// feedback
+const IDLE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Interval between progress reports sent by the receiver
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Minimum interval between repeated completion acknowledgements
+const ACK_REPEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval between flushes of the receive checkpoint
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the receiver keeps acknowledging after the last data packet
+const ACK_LINGER: Duration = Duration::from_secs(2);
+
+/// Port receivers listen on unless told otherwise
+pub const DEFAULT_PORT: u16 = 3000;
+
+/// How long a sender waits for acknowledgements unless told otherwise
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(120);
+
+/// How long a receiver waits for the first packet unless told otherwise
+pub const DEFAULT_FIRST_PACKET_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a receiver goes without packets of its transfer unless told
+/// otherwise
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum TransferError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Address(#[from] AddressError),
+    #[error(transparent)]
+    Archive(#[from] ArchiveError),
+    #[error(transparent)]
+    Checkpoint(#[from] CheckpointError),
+    #[error("Session error: {0}")]
+    Session(#[from] SessionError),
+    #[error("Transport error: {0:#}")]
+    Transport(#[from] anyhow::Error),
+    #[error("No targets to send to")]
+    NoTargets,
+    #[error("Targets must all be IPv4 or all IPv6")]
+    MixedFamilies,
+    #[error("At most one multicast target is supported")]
+    MultipleGroups,
+    #[error("Streaming to a multicast group needs a fixed overhead")]
+    MulticastStream,
+    #[error("MTU of {0} bytes leaves no room for data")]
+    MtuTooSmall(usize),
+    #[error("Receiver {addr} decoded a file with hash {reported}, expected {expected}")]
+    HashMismatch {
+        addr: SocketAddr,
+        reported: blake3::Hash,
+        expected: blake3::Hash,
+    },
+    #[error("No completion acknowledgement from {} within {deadline:?}", pending.join(", "))]
+    Unconfirmed { pending: Vec<String>, deadline: Duration },
+    #[error("Failed to decode file within timeout")]
+    Timeout,
+    #[error("No transfer arrived within {0:?}")]
+    NoTransfer(Duration),
+    #[error("No packets received for {0:?}")]
+    Idle(Duration),
+    #[error("Decoded data does not match session {0:016x}")]
+    Corrupt(u64),
+    #[error("Transfer cancelled")]
+    Cancelled,
+    #[error("{0} abandoned the transfer")]
+    Aborted(SocketAddr),
+}
+
+impl TransferError {
+    /// Stable name of the kind of error, for scripts to act on
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransferError::Io(_) => "io",
+            TransferError::Address(_) => "address",
+            TransferError::Archive(_) => "archive",
+            TransferError::Checkpoint(_) => "checkpoint",
+            TransferError::Session(_) => "session",
+            TransferError::Transport(_) => "transport",
+            TransferError::NoTargets => "no_targets",
+            TransferError::MixedFamilies => "mixed_families",
+            TransferError::MultipleGroups => "multiple_groups",
+            TransferError::MulticastStream => "multicast_stream",
+            TransferError::MtuTooSmall(_) => "mtu_too_small",
+            TransferError::HashMismatch { .. } => "hash_mismatch",
+            TransferError::Unconfirmed { .. } => "unconfirmed",
+            TransferError::Timeout => "timeout",
+            TransferError::NoTransfer(_) => "no_transfer",
+            TransferError::Idle(_) => "idle",
+            TransferError::Corrupt(_) => "corrupt",
+            TransferError::Cancelled => "cancelled",
+            TransferError::Aborted(_) => "aborted",
+        }
+    }
+}
+
+/// What a `Sender` sends
+pub enum Source {
+    /// A file, or a directory or glob pattern packed into an archive
+    Path(String),
+    /// Data already in memory
+    Bytes(Vec<u8>),
+    /// Everything a reader yields until it ends
+    Reader(Box<dyn AsyncRead + Send + Unpin>),
+    /// Everything a reader yields, sent as it is read
+    Stream(Box<dyn AsyncRead + Send + Unpin>),
+}
+
+/// Where a `Receiver` puts what it receives
+pub enum Output {
+    /// A file, or the directory an archive is unpacked below
+    Path(PathBuf),
+    /// A directory in which each transfer is saved, named after its session
+    Inbox(PathBuf),
+    /// A writer that takes the received object as is
+    Writer(Box<dyn AsyncWrite + Send + Unpin>),
+    /// The `data` of the report
+    Memory,
+}
+
+/// What a cancelled or failed `Receiver` does with what it received
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PartialOutput {
+    /// Flush the checkpoint, or the `.part` file of a stream, to resume or
+    /// salvage from
+    #[default]
+    Keep,
+    /// Remove the checkpoint or `.part` file
+    Discard,
+}
+
+/// How much a `Sender` sends
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverheadPolicy {
+    /// Keep generating repair symbols until every destination acknowledges
+    /// completion or the deadline passes
+    #[default]
+    UntilAcknowledged,
+    /// Send this many times as many repair symbols as there are source
+    /// symbols, then stop, as on a link without a return path; earlier
+    /// acknowledgements still end the transfer
+    Fixed(f64),
+}
+
+/// Something that happened during a transfer
+///
+/// Events serialize as objects tagged with their snake-case name under
+/// `event`, with session IDs in hex as printed elsewhere.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The files of a directory or glob were packed into one archive
+    Packed { files: usize, bytes: u64 },
+    /// Sending started
+    Sending {
+        #[serde(serialize_with = "serialize_session_id")]
+        session_id: u64,
+        bytes: u64,
+        num_blocks: usize,
+        symbol_size: usize,
+        targets: Vec<SocketAddr>,
+        mtu: usize,
+    },
+    /// Streaming started; the length is announced once the input ends
+    Streaming {
+        #[serde(serialize_with = "serialize_session_id")]
+        session_id: u64,
+        symbol_size: usize,
+        block_symbols: usize,
+        targets: Vec<SocketAddr>,
+        mtu: usize,
+    },
+    /// The input of a stream ended
+    InputEnded { bytes: u64, num_blocks: usize },
+    /// Symbols encoded so far, each sent to every destination still going
+    Sent { packets: u64, bytes: u64 },
+    /// A receiver reported its progress; `rate` is the rate it is sent at
+    /// in bytes per second, `None` if unlimited, and `loss` the fraction of
+    /// packets sent to it that went missing
+    Progress {
+        addr: SocketAddr,
+        symbols_received: u64,
+        blocks_decoded: u32,
+        num_blocks: u32,
+        rate: Option<f64>,
+        loss: f64,
+    },
+    /// A destination confirmed completion; a multicast group has
+    /// `confirmed` of its `receivers` done
+    Confirmed {
+        addr: SocketAddr,
+        confirmed: usize,
+        receivers: usize,
+        packets_sent: u64,
+    },
+    /// A receiver abandoned the transfer
+    Aborted { addr: SocketAddr },
+    /// The receiver is waiting for a transfer
+    Listening { addr: SocketAddr, group: Option<IpAddr> },
+    /// The first packet of a transfer arrived
+    Receiving {
+        #[serde(serialize_with = "serialize_session_id")]
+        session_id: u64,
+        bytes: u64,
+        num_blocks: usize,
+        from: SocketAddr,
+    },
+    /// The first packet of a stream arrived
+    ReceivingStream {
+        #[serde(serialize_with = "serialize_session_id")]
+        session_id: u64,
+        from: SocketAddr,
+    },
+    /// Periodic stream progress: bytes and source blocks written so far
+    Written { bytes: u64, blocks: usize },
+    /// Decoding state was restored from a checkpoint
+    Resumed {
+        path: PathBuf,
+        symbols: usize,
+        blocks: usize,
+    },
+    /// Symbols started arriving from another sender
+    NewSender { addr: SocketAddr },
+    /// Periodic receive progress; the object is recovered once
+    /// `symbols_useful` reaches `symbols_needed`
+    Received {
+        symbols_received: u64,
+        symbols_useful: usize,
+        symbols_needed: usize,
+        duplicates: u64,
+        blocks_decoded: usize,
+        num_blocks: usize,
+        loss: f64,
+    },
+    /// A source block was recovered
+    BlockDecoded {
+        sbn: u32,
+        blocks_decoded: usize,
+        num_blocks: usize,
+    },
+    /// The received object was saved; `files` is set for an archive
+    Saved {
+        path: Option<PathBuf>,
+        bytes: u64,
+        files: Option<usize>,
+    },
+    /// What an abandoned receive got so far was left at `path`
+    Kept { path: PathBuf },
+}
+
+fn serialize_session_id<S: Serializer>(session_id: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:016x}", session_id))
+}
+
+/// Outcome of a send
+#[derive(Debug, Clone, PartialEq)]
+pub struct SendReport {
+    pub session_id: u64,
+    pub hash: blake3::Hash,
+    pub bytes: u64,
+    pub symbol_size: usize,
+    pub num_blocks: usize,
+    /// Source symbols of the object, the least any receiver needs
+    pub source_symbols: usize,
+    /// Data packets encoded, each sent to every destination still going
+    pub packets: u64,
+    /// Destinations that acknowledged completion
+    pub confirmed: Vec<SocketAddr>,
+    pub elapsed: Duration,
+}
+
+/// Outcome of a receive
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceiveReport {
+    pub session_id: u64,
+    pub hash: blake3::Hash,
+    pub bytes: u64,
+    pub num_blocks: usize,
+    pub senders: Vec<SocketAddr>,
+    /// Data packets received, including duplicates
+    pub symbols_received: u64,
+    /// Source symbols of the object, the least that recovers it
+    pub source_symbols: usize,
+    /// Symbols received more than once
+    pub duplicates: u64,
+    /// Symbols received for source blocks already recovered
+    pub redundant: u64,
+    /// Fraction of packets lost on the way, from gaps in their sequence
+    pub loss: f64,
+    /// Time spent decoding
+    pub decode_time: Duration,
+    /// Where the object was saved, if in a file or directory
+    pub path: Option<PathBuf>,
+    /// Files unpacked from an archive
+    pub files: Option<usize>,
+    /// Received object, for `Output::Memory`
+    pub data: Option<Vec<u8>>,
+    /// Time from the first packet until the object was saved
+    pub elapsed: Duration,
+}
+
+/// Optional channel events are reported on
+#[derive(Debug, Clone, Default)]
+struct Events(Option<mpsc::UnboundedSender<Event>>);
+
+impl Events {
+    fn emit(&self, event: Event) {
+        if let Some(tx) = &self.0 {
+            let _ = tx.send(event);
+        }
+    }
+}
+
+/// Read the file at `spec`, or pack the directory or glob it names into
+/// an archive, returning the object and the archive's manifest
+pub async fn read_source(spec: &str) -> Result<(Vec<u8>, Option<Manifest>), TransferError> {
+    if archive::is_multi_file(spec) {
+        let (manifest, object) = archive::pack(spec)?;
+        return Ok((object, Some(manifest)));
+    }
+    Ok((tokio::fs::read(spec).await?, None))
+}
+
+/// Save a received object at `destination`, unpacking it below it if the
+/// sender marked it as an `archive`, and return the archive's manifest
+///
+/// Only marked objects are unpacked, so a plain file that happens to be a
+/// valid archive is saved as it is. Either way files are written under
+/// their `.part` names and renamed once complete; unpacking runs on the
+/// blocking pool.
+pub async fn save(data: Vec<u8>, destination: &Path, archive: bool) -> Result<Option<Manifest>, TransferError> {
+    match archive {
+        true => {
+            let destination = destination.to_path_buf();
+            let unpacking = tokio::task::spawn_blocking(move || {
+                let (manifest, contents) = archive::parse(&data).ok_or(ArchiveError::Malformed)?;
+                archive::unpack(&manifest, contents, &destination)?;
+                Ok::<_, ArchiveError>(manifest)
+            });
+            Ok(Some(unpacking.await.map_err(std::io::Error::from)??))
+        }
+        false => {
+            let part = part_path(destination);
+            let mut file = tokio::fs::File::create(&part).await?;
+            file.write_all(&data).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&part, destination).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Run `future` unless `cancel` is cancelled first
+async fn cancellable<T>(
+    cancel: &CancellationToken,
+    future: impl std::future::Future<Output = Result<T, TransferError>>,
+) -> Result<T, TransferError> {
+    tokio::select! {
+        result = future => result,
+        _ = cancel.cancelled() => Err(TransferError::Cancelled),
+    }
+}
+
+/// Sends one object to one or more receivers
+pub struct Sender {
+    source: Source,
+    options: SendOptions,
+}
+
+/// Everything about a send but its source
+struct SendOptions {
+    targets: Vec<String>,
+    family: AddressFamily,
+    transport: Option<Arc<dyn PacketTransport>>,
+    pacing: PacerConfig,
+    congestion_control: bool,
+    ttl: u32,
+    interface: Interface,
+    receivers: usize,
+    esi_offset: u32,
+    esi_stride: u32,
+    mtu: Option<usize>,
+    max_mtu: usize,
+    overhead: OverheadPolicy,
+    deadline: Duration,
+    verify_hash: bool,
+    key: Vec<u8>,
+    events: Events,
+    cancel: CancellationToken,
+    paused: Option<watch::Receiver<bool>>,
+}
+
+impl Sender {
+    /// Sender of `source` at 1000 Mbps with congestion control, waiting up
+    /// to two minutes for acknowledgements
+    pub fn new(source: Source) -> Self {
+        Self {
+            source,
+            options: SendOptions {
+                targets: Vec::new(),
+                family: AddressFamily::Any,
+                transport: None,
+                pacing: PacerConfig::from_mbps(1000),
+                congestion_control: true,
+                ttl: 1,
+                interface: Interface::Default,
+                receivers: 1,
+                esi_offset: 0,
+                esi_stride: 1,
+                mtu: None,
+                max_mtu: pmtu::MAX_PROBE_MTU,
+                overhead: OverheadPolicy::default(),
+                deadline: DEFAULT_DEADLINE,
+                verify_hash: true,
+                key: Vec::new(),
+                events: Events::default(),
+                cancel: CancellationToken::new(),
+                paused: None,
+            },
+        }
+    }
+
+    /// Add a destination, a literal address or `host:port`
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.options.targets.push(target.into());
+        self
+    }
+
+    /// Destinations added so far
+    pub fn targets(&self) -> &[String] {
+        &self.options.targets
+    }
+
+    /// Resolve targets to addresses of this family only
+    pub fn family(mut self, family: AddressFamily) -> Self {
+        self.options.family = family;
+        self
+    }
+
+    /// Send through `transport` instead of a UDP socket of its own
+    pub fn transport(mut self, transport: Arc<dyn PacketTransport>) -> Self {
+        self.options.transport = Some(transport);
+        self
+    }
+
+    /// Rate limits applied to each destination
+    pub fn pacing(mut self, pacing: PacerConfig) -> Self {
+        self.options.pacing = pacing;
+        self
+    }
+
+    /// Byte rate limit in Mbps, 0 for unlimited
+    pub fn rate_limit(mut self, mbps: u32) -> Self {
+        self.options.pacing.bytes_per_second = PacerConfig::from_mbps(mbps).bytes_per_second;
+        self
+    }
+
+    /// Whether to adapt the rate to receiver feedback
+    pub fn congestion_control(mut self, enabled: bool) -> Self {
+        self.options.congestion_control = enabled;
+        self
+    }
+
+    /// TTL and interface for a multicast target
+    pub fn multicast(mut self, ttl: u32, interface: Interface) -> Self {
+        self.options.ttl = ttl;
+        self.options.interface = interface;
+        self
+    }
+
+    /// Completion acknowledgements to wait for from a multicast target
+    pub fn receivers(mut self, receivers: usize) -> Self {
+        self.options.receivers = receivers.max(1);
+        self
+    }
+
+    /// Encoding symbol IDs sent, for mirrors of the same object
+    pub fn esi_schedule(mut self, offset: u32, stride: u32) -> Self {
+        self.options.esi_offset = offset;
+        self.options.esi_stride = stride;
+        self
+    }
+
+    /// Path MTU, skipping path MTU discovery
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.options.mtu = Some(mtu);
+        self
+    }
+
+    /// Largest MTU tried by path MTU discovery
+    pub fn max_mtu(mut self, max_mtu: usize) -> Self {
+        self.options.max_mtu = max_mtu;
+        self
+    }
+
+    pub fn overhead(mut self, overhead: OverheadPolicy) -> Self {
+        self.options.overhead = overhead;
+        self
+    }
+
+    /// How long to send while waiting for acknowledgements, counted from
+    /// the end of the input for a stream
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.options.deadline = deadline;
+        self
+    }
+
+    /// Whether acknowledgements must report the hash of the object sent
+    pub fn verify_hash(mut self, verify: bool) -> Self {
+        self.options.verify_hash = verify;
+        self
+    }
+
+    /// Pre-shared key completion acknowledgements are signed with
+    pub fn key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.options.key = key.into();
+        self
+    }
+
+    /// Report events on `tx`
+    pub fn events(mut self, tx: mpsc::UnboundedSender<Event>) -> Self {
+        self.options.events = Events(Some(tx));
+        self
+    }
+
+    /// Stop, telling the receivers, once `token` is cancelled
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.options.cancel = token;
+        self
+    }
+
+    /// Hold off sending while `paused` is true, extending the deadline by
+    /// the time spent paused; receivers give up on a pause longer than their
+    /// idle timeout
+    pub fn pause(mut self, paused: watch::Receiver<bool>) -> Self {
+        self.options.paused = Some(paused);
+        self
+    }
+
+    /// Send until every destination confirms completion, or as the
+    /// overhead policy says
+    pub async fn send(mut self) -> Result<SendReport, TransferError> {
+        let cancel = self.options.cancel.clone();
+        let (contents, archive) = match std::mem::replace(&mut self.source, Source::Bytes(Vec::new())) {
+            Source::Path(spec) => {
+                let (contents, manifest) = cancellable(&cancel, read_source(&spec)).await?;
+                if let Some(manifest) = &manifest {
+                    self.options.events.emit(Event::Packed {
+                        files: manifest.files(),
+                        bytes: manifest.total_size(),
+                    });
+                }
+                (contents, manifest.is_some())
+            }
+            Source::Bytes(bytes) => (bytes, false),
+            Source::Reader(mut reader) => {
+                let mut contents = Vec::new();
+                let reading = async { Ok(reader.read_to_end(&mut contents).await?) };
+                cancellable(&cancel, reading).await?;
+                (contents, false)
+            }
+            Source::Stream(reader) => {
+                let (transport, targets, mtu) = cancellable(&cancel, self.options.connect()).await?;
+                return send_stream(&self.options, transport, &targets, reader, mtu).await;
+            }
+        };
+
+        let (transport, targets, mtu) = cancellable(&cancel, self.options.connect()).await?;
+        send_object(&self.options, transport, &targets, &contents, archive, mtu).await
+    }
+}
+
+impl SendOptions {
+    fn is_paused(&self) -> bool {
+        self.paused.as_ref().is_some_and(|paused| *paused.borrow())
+    }
+
+    /// Wait until the send is no longer paused or is cancelled, returning
+    /// how long that took
+    async fn wait_resumed(&self) -> Duration {
+        let Some(mut paused) = self.paused.clone() else {
+            return Duration::ZERO;
+        };
+        let start = Instant::now();
+        tokio::select! {
+            _ = paused.wait_for(|paused| !paused) => {}
+            _ = self.cancel.cancelled() => {}
+        }
+        start.elapsed()
+    }
+
+    /// Resolve the targets and set up a transport for them, returning it
+    /// along with the targets and the MTU of the narrowest path to them
+    async fn connect(&self) -> Result<(Arc<dyn PacketTransport>, Vec<SocketAddr>, usize), TransferError> {
+        let mut targets = Vec::with_capacity(self.targets.len());
+        for target in &self.targets {
+            targets.push(addr::resolve(target, self.family).await?);
+        }
+        let Some(&first) = targets.first() else {
+            return Err(TransferError::NoTargets);
+        };
+        if targets.iter().any(|target| target.is_ipv4() != first.is_ipv4()) {
+            return Err(TransferError::MixedFamilies);
+        }
+        // Acknowledgements from group members cannot be told apart by group
+        if targets.iter().filter(|target| target.ip().is_multicast()).count() > 1 {
+            return Err(TransferError::MultipleGroups);
+        }
+
+        // Create transport in the family of the targets; rate limits apply
+        // per destination, so the socket itself is not paced
+        let transport: Arc<dyn PacketTransport> = match &self.transport {
+            Some(transport) => transport.clone(),
+            None => {
+                let transport =
+                    UdpTransport::bind(addr::unspecified_for(&first), false, PacerConfig::from_mbps(0)).await?;
+                if targets.iter().any(|target| target.ip().is_multicast()) {
+                    transport.set_multicast(self.ttl, self.interface)?;
+                }
+                Arc::new(transport)
+            }
+        };
+
+        // Size symbols to fill the largest packet every path carries; probes
+        // go out before the session exists, so they carry session ID 0.
+        // Paths to a multicast group cannot be probed as one.
+        let mut mtu = usize::MAX;
+        for &target in &targets {
+            let path_mtu = match self.mtu {
+                Some(mtu) => mtu,
+                None if target.ip().is_multicast() => pmtu::DEFAULT_MTU,
+                None => pmtu::discover(transport.as_ref(), target, 0, self.max_mtu).await?,
+            };
+            mtu = mtu.min(path_mtu);
+        }
+
+        Ok((transport, targets, mtu))
+    }
+}
+
+/// Send `contents`, marked as an `archive` or not, through `transport` to
+/// every target until each confirms completion, in symbols sized for
+/// packets of at most `mtu` bytes
+async fn send_object(
+    options: &SendOptions,
+    transport: Arc<dyn PacketTransport>,
+    targets: &[SocketAddr],
+    contents: &[u8],
+    archive: bool,
+    mtu: usize,
+) -> Result<SendReport, TransferError> {
+    let start = Instant::now();
+    let first = targets[0];
+    let symbol_size =
+        symbol_size_for_payload(pmtu::max_payload_size(mtu, &first)).map_err(|_| TransferError::MtuTooSmall(mtu))?;
+
+    // Split into source blocks and set up one encoder per block
+    let mut session = SendSession::new(contents, symbol_size)?;
+    session.set_esi_schedule(options.esi_offset, options.esi_stride);
+    session.set_archive(archive);
+    let layout = session.layout().clone();
+
+    // Listen for receiver feedback on the same socket while sending
+    let (feedback_tx, mut feedback_rx) = mpsc::unbounded_channel();
+    let _feedback = Task(tokio::spawn(receive_feedback(
+        transport.clone(),
+        session.session_id(),
+        session.nonce(),
+        options.key.clone(),
+        feedback_tx,
+    )));
+
+    let mut fanout = options.fanout(targets);
+
+    options.events.emit(Event::Sending {
+        session_id: session.session_id(),
+        bytes: contents.len() as u64,
+        num_blocks: layout.num_blocks,
+        symbol_size: layout.symbol_size,
+        targets: targets.to_vec(),
+        mtu,
+    });
+
+    // A fixed overhead stops after a budget of packets
+    let budget = match options.overhead {
+        OverheadPolicy::UntilAcknowledged => u64::MAX,
+        OverheadPolicy::Fixed(overhead) => (layout.source_symbols() as f64 * (1.0 + overhead.max(0.0))).ceil() as u64,
+    };
+
+    // Batches stay within the pacer's burst so they do not add burstiness
+    let packet_size = DATA_HEADER_SIZE + layout.symbol_size;
+    let batch_size = (options.pacing.burst / packet_size).clamp(1, MAX_SEND_BATCH);
+
+    // Keep generating repair symbols until every destination confirms
+    // completion, encoding each symbol once for all of them
+    let mut deadline = Instant::now() + options.deadline;
+    let mut packets = 0u64;
+    let mut confirmed = Vec::new();
+    let mut last_progress = Instant::now();
+    while !fanout.is_done() {
+        while let Ok((packet, addr)) = feedback_rx.try_recv() {
+            options.on_feedback(&mut fanout, &mut confirmed, packet, addr, Some(session.hash()))?;
+        }
+        if options.cancel.is_cancelled() {
+            return Err(options.abandon(transport.as_ref(), &fanout, session.session_id(), session.nonce()).await);
+        }
+        if options.is_paused() {
+            deadline += options.wait_resumed().await;
+            continue;
+        }
+
+        let now = Instant::now();
+        if now >= deadline || fanout.is_done() || packets >= budget {
+            break;
+        }
+        if now - last_progress >= PROGRESS_INTERVAL {
+            options.events.emit(Event::Sent {
+                packets,
+                bytes: packets * layout.symbol_size as u64,
+            });
+            last_progress = now;
+        }
+        fanout.on_tick(now);
+
+        let delay = fanout.delay(now);
+        if !delay.is_zero() {
+            pacer::sleep(delay).await;
+            continue;
+        }
+
+        let batch_size = batch_size.min((budget - packets) as usize);
+        let batch = (0..batch_size)
+            .map(|_| session.next_packet())
+            .collect::<Result<Vec<_>, _>>()?;
+        packets += batch.len() as u64;
+        for destination in fanout.ready(Instant::now()) {
+            let stamped = destination.stamp(&batch);
+            transport.send_packets(destination.addr(), &stamped).await?;
+
+            for packet in &batch {
+                log::trace!(
+                    "Sent symbol {} of source block {} to {} (degree: {}, size: {})",
+                    packet.esi,
+                    packet.sbn,
+                    destination.addr(),
+                    packet.degree,
+                    packet.payload.len()
+                );
+            }
+        }
+    }
+    options.check_confirmed(&fanout)?;
+
+    Ok(SendReport {
+        session_id: session.session_id(),
+        hash: *session.hash(),
+        bytes: contents.len() as u64,
+        symbol_size: layout.symbol_size,
+        num_blocks: layout.num_blocks,
+        source_symbols: layout.source_symbols(),
+        packets,
+        confirmed,
+        elapsed: start.elapsed(),
+    })
+}
+
+impl SendOptions {
+    /// Destinations for `targets`
+    ///
+    /// Each destination starts below its ceiling and follows its own
+    /// receiver reports. Multicast receivers each see a different path, so
+    /// a group is sent to at the configured rate.
+    fn fanout(&self, targets: &[SocketAddr]) -> Fanout {
+        Fanout::new(
+            targets
+                .iter()
+                .map(|&target| match target.ip().is_multicast() {
+                    true => Destination::new(target, self.pacing, false, self.receivers),
+                    false => Destination::new(target, self.pacing, self.congestion_control, 1),
+                })
+                .collect(),
+        )
+    }
+
+    /// Apply feedback from `addr` to the destinations and report it
+    ///
+    /// Completion acknowledgements are checked against `hash`, and passed
+    /// over while it is not known yet.
+    fn on_feedback(
+        &self,
+        fanout: &mut Fanout,
+        confirmed: &mut Vec<SocketAddr>,
+        packet: Packet,
+        addr: SocketAddr,
+        hash: Option<&blake3::Hash>,
+    ) -> Result<(), TransferError> {
+        match packet {
+            Packet::Complete(ack) => {
+                let Some(&expected) = hash else {
+                    return Ok(());
+                };
+                let reported = blake3::Hash::from(ack.hash);
+                if self.verify_hash && reported != expected {
+                    return Err(TransferError::HashMismatch {
+                        addr,
+                        reported,
+                        expected,
+                    });
+                }
+                if let Some(destination) = fanout.on_complete(addr) {
+                    if !confirmed.contains(&destination.addr()) {
+                        confirmed.push(destination.addr());
+                    }
+                    self.events.emit(Event::Confirmed {
+                        addr: destination.addr(),
+                        confirmed: destination.confirmed(),
+                        receivers: destination.receivers(),
+                        packets_sent: destination.sent(),
+                    });
+                }
+            }
+            Packet::Progress(report) => {
+                fanout.on_report(addr, &report, Instant::now());
+                let rate = fanout
+                    .destinations()
+                    .iter()
+                    .find(|destination| destination.addr() == addr)
+                    .and_then(Destination::rate);
+                // Sequence numbers to each destination start at zero
+                let sent = u64::from(report.highest_seq) + 1;
+                self.events.emit(Event::Progress {
+                    addr,
+                    symbols_received: report.symbols_received,
+                    blocks_decoded: report.blocks_decoded,
+                    num_blocks: report.num_blocks,
+                    rate,
+                    loss: (1.0 - report.symbols_received as f64 / sent as f64).max(0.0),
+                });
+            }
+            Packet::Abort(_) => {
+                if let Some(destination) = fanout.on_abort(addr) {
+                    self.events.emit(Event::Aborted {
+                        addr: destination.addr(),
+                    });
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Tell every destination still going that the run of `session_id`
+    /// with `nonce` is abandoned, returning the error to end the send with
+    async fn abandon(
+        &self,
+        transport: &dyn PacketTransport,
+        fanout: &Fanout,
+        session_id: u64,
+        nonce: u64,
+    ) -> TransferError {
+        let abort = Packet::Abort(AbortNotice::new(session_id, nonce, &self.key));
+        for destination in fanout.destinations().iter().filter(|destination| !destination.is_done()) {
+            if let Err(e) = transport.send_packet(destination.addr(), &abort).await {
+                log::debug!("Failed to send abort notice to {}: {:#}", destination.addr(), e);
+            }
+        }
+        TransferError::Cancelled
+    }
+
+    /// Fail if a receiver abandoned the transfer, or unless every
+    /// destination confirmed completion or the overhead policy does not
+    /// wait for them to
+    fn check_confirmed(&self, fanout: &Fanout) -> Result<(), TransferError> {
+        if let Some(destination) = fanout.destinations().iter().find(|destination| destination.is_aborted()) {
+            return Err(TransferError::Aborted(destination.addr()));
+        }
+        let pending: Vec<String> = fanout
+            .destinations()
+            .iter()
+            .filter(|destination| !destination.is_done())
+            .map(|destination| match destination.receivers() {
+                1 => destination.addr().to_string(),
+                n => format!(
+                    "{} ({} of {} receivers)",
+                    destination.addr(),
+                    destination.confirmed(),
+                    n
+                ),
+            })
+            .collect();
+        if !pending.is_empty() && self.overhead == OverheadPolicy::UntilAcknowledged {
+            return Err(TransferError::Unconfirmed {
+                pending,
+                deadline: self.deadline,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Task aborted once dropped, so that an early return does not leave it
+/// running
+struct Task<T>(tokio::task::JoinHandle<T>);
+
+impl<T> Drop for Task<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Send what `reader` yields through `transport` to every target as it is
+/// read, in symbols sized for packets of at most `mtu` bytes
+///
+/// Only a window of source blocks is held at a time. A block is retired
+/// once every unicast destination reports it recovered or, under a fixed
+/// overhead, once it has had its share of symbols.
+async fn send_stream(
+    options: &SendOptions,
+    transport: Arc<dyn PacketTransport>,
+    targets: &[SocketAddr],
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    mtu: usize,
+) -> Result<SendReport, TransferError> {
+    let start = Instant::now();
+    let first = targets[0];
+    // Receivers in a group do not report which blocks they have
+    if options.overhead == OverheadPolicy::UntilAcknowledged && targets.iter().any(|target| target.ip().is_multicast())
+    {
+        return Err(TransferError::MulticastStream);
+    }
+    let symbol_size =
+        symbol_size_for_payload(pmtu::max_payload_size(mtu, &first)).map_err(|_| TransferError::MtuTooSmall(mtu))?;
+
+    let mut session = StreamSendSession::new(symbol_size)?;
+    session.set_esi_schedule(options.esi_offset, options.esi_stride);
+    // A fixed overhead retires each block after its share of symbols
+    if let OverheadPolicy::Fixed(overhead) = options.overhead {
+        session.set_overhead(overhead);
+    }
+
+    // Read ahead in a task of its own so that sending goes on while the
+    // reader blocks
+    let (input_tx, mut input_rx) = mpsc::channel(INPUT_QUEUE);
+    let _reading = Task(tokio::spawn(read_stream(reader, input_tx)));
+    let (feedback_tx, mut feedback_rx) = mpsc::unbounded_channel();
+    let _feedback = Task(tokio::spawn(receive_feedback(
+        transport.clone(),
+        session.session_id(),
+        session.nonce(),
+        options.key.clone(),
+        feedback_tx,
+    )));
+
+    let mut fanout = options.fanout(targets);
+    options.events.emit(Event::Streaming {
+        session_id: session.session_id(),
+        symbol_size,
+        block_symbols: STREAM_BLOCK_SYMBOLS,
+        targets: targets.to_vec(),
+        mtu,
+    });
+
+    let packet_size = DATA_HEADER_SIZE + symbol_size;
+    let batch_size = (options.pacing.burst / packet_size).clamp(1, MAX_SEND_BATCH);
+
+    // Source blocks each unicast destination recovered in order
+    let mut recovered: HashMap<SocketAddr, usize> = HashMap::new();
+    let mut ended = false;
+    // Receivers cannot confirm before the input ends, so neither can they
+    // be waited for
+    let mut deadline = None;
+    let mut packets = 0u64;
+    let mut confirmed = Vec::new();
+    let mut last_progress = Instant::now();
+    loop {
+        while let Ok((packet, addr)) = feedback_rx.try_recv() {
+            if let Packet::Progress(report) = &packet {
+                let blocks = recovered.entry(addr).or_default();
+                *blocks = (*blocks).max(report.blocks_decoded as usize);
+            }
+            options.on_feedback(&mut fanout, &mut confirmed, packet, addr, session.hash())?;
+        }
+        if options.cancel.is_cancelled() {
+            return Err(options.abandon(transport.as_ref(), &fanout, session.session_id(), session.nonce()).await);
+        }
+        if options.is_paused() {
+            let paused = options.wait_resumed().await;
+            if let Some(deadline) = &mut deadline {
+                *deadline += paused;
+            }
+            continue;
+        }
+
+        // Take input while the window has room
+        while !ended && session.open_blocks() < STREAM_WINDOW {
+            let chunk = match input_rx.try_recv() {
+                Ok(chunk) => Some(chunk),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => None,
+            };
+            ended = take_input(&mut session, chunk)?;
+        }
+        if ended && deadline.is_none() {
+            deadline = Some(Instant::now() + options.deadline);
+            options.events.emit(Event::InputEnded {
+                bytes: session.length(),
+                num_blocks: session.layout().map_or(0, |layout| layout.num_blocks),
+            });
+        }
+
+        // Retire the blocks every unicast destination still going has
+        let least = fanout
+            .destinations()
+            .iter()
+            .filter(|destination| !destination.is_done() && !destination.addr().ip().is_multicast())
+            .map(|destination| recovered.get(&destination.addr()).copied().unwrap_or(0))
+            .min();
+        if let Some(blocks) = least {
+            session.retire(blocks);
+        }
+
+        let now = Instant::now();
+        if fanout.is_done() || deadline.is_some_and(|deadline| now >= deadline) {
+            break;
+        }
+        if options.overhead != OverheadPolicy::UntilAcknowledged && session.is_done() {
+            break;
+        }
+        if now - last_progress >= PROGRESS_INTERVAL {
+            options.events.emit(Event::Sent {
+                packets,
+                bytes: packets * symbol_size as u64,
+            });
+            last_progress = now;
+        }
+        fanout.on_tick(now);
+
+        // Nothing to send until more input arrives or receivers catch up
+        if session.open_blocks() == 0 {
+            tokio::select! {
+                chunk = input_rx.recv(), if !ended => ended = take_input(&mut session, chunk)?,
+                _ = tokio::time::sleep(IDLE_INTERVAL) => {}
+            }
+            continue;
+        }
+
+        let delay = fanout.delay(now);
+        if !delay.is_zero() {
+            pacer::sleep(delay).await;
+            continue;
+        }
+
+        let mut batch = Vec::with_capacity(batch_size);
+        while batch.len() < batch_size {
+            match session.next_packet()? {
+                Some(packet) => batch.push(packet),
+                None => break,
+            }
+        }
+        packets += batch.len() as u64;
+        for destination in fanout.ready(Instant::now()) {
+            let stamped = destination.stamp(&batch);
+            transport.send_packets(destination.addr(), &stamped).await?;
+        }
+    }
+    options.check_confirmed(&fanout)?;
+
+    let layout = session.layout().expect("input ended");
+    Ok(SendReport {
+        session_id: session.session_id(),
+        hash: *session.hash().expect("input ended"),
+        bytes: session.length(),
+        symbol_size,
+        num_blocks: layout.num_blocks,
+        source_symbols: layout.source_symbols(),
+        packets,
+        confirmed,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Add a chunk of input to the stream, or end it once there are no more,
+/// returning whether it ended
+fn take_input(session: &mut StreamSendSession, chunk: Option<std::io::Result<Vec<u8>>>) -> Result<bool, TransferError> {
+    match chunk {
+        Some(chunk) => {
+            session.push(&chunk?)?;
+            Ok(false)
+        }
+        None => {
+            session.finish()?;
+            Ok(true)
+        }
+    }
+}
+
+/// Forward what `reader` yields to the send loop in chunks, up to the
+/// first error reading it
+async fn read_stream(mut reader: Box<dyn AsyncRead + Send + Unpin>, input_tx: mpsc::Sender<std::io::Result<Vec<u8>>>) {
+    loop {
+        let mut chunk = vec![0; INPUT_CHUNK];
+        let read = match reader.read(&mut chunk).await {
+            Ok(0) => return,
+            Ok(n) => {
+                chunk.truncate(n);
+                Ok(chunk)
+            }
+            Err(e) => Err(e),
+        };
+        let failed = read.is_err();
+        if input_tx.send(read).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Forward progress reports and completion acknowledgements and abort
+/// notices signed for the run of `session_id` with `nonce` to the send
+/// loop, along with their origin
+async fn receive_feedback(
+    transport: Arc<dyn PacketTransport>,
+    session_id: u64,
+    nonce: u64,
+    key: Vec<u8>,
+    feedback_tx: mpsc::UnboundedSender<(Packet, SocketAddr)>,
+) -> Result<(), TransferError> {
+    loop {
+        let (packet, addr) = transport.receive_packet().await?;
+        if packet.session_id() != session_id {
+            continue;
+        }
+        match &packet {
+            Packet::Complete(ack) if !ack.verify(nonce, &key) => {
+                log::warn!("Ignoring completion acknowledgement from {} with a bad signature", addr);
+                continue;
+            }
+            Packet::Abort(notice) if !notice.verify(nonce, &key) => {
+                log::warn!("Ignoring abort notice from {} with a bad signature", addr);
+                continue;
+            }
+            Packet::Progress(_) | Packet::Complete(_) | Packet::Abort(_) => {}
+            _ => continue,
+        }
+        if feedback_tx.send((packet, addr)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Receives one object
+pub struct Receiver {
+    output: Output,
+    options: ReceiveOptions,
+}
+
+/// Everything about a receive but its output
+struct ReceiveOptions {
+    listen: SocketAddr,
+    dual_stack: bool,
+    group: Option<IpAddr>,
+    interface: Interface,
+    transport: Option<Arc<dyn PacketTransport>>,
+    checkpoint: bool,
+    first_packet_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    deadline: Option<Duration>,
+    expected: Option<u64>,
+    ignored: HashSet<u64>,
+    key: Vec<u8>,
+    limits: ReceiveLimits,
+    events: Events,
+    cancel: CancellationToken,
+    partial: PartialOutput,
+}
+
+impl Receiver {
+    /// Receiver into `output` listening on port 3000 of every local
+    /// address, waiting 30 seconds for the first packet and at most as
+    /// long between packets, and keeping a checkpoint beside a file
+    pub fn new(output: Output) -> Self {
+        Self {
+            output,
+            options: ReceiveOptions {
+                listen: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), DEFAULT_PORT),
+                dual_stack: true,
+                group: None,
+                interface: Interface::Default,
+                transport: None,
+                checkpoint: true,
+                first_packet_timeout: Some(DEFAULT_FIRST_PACKET_TIMEOUT),
+                idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+                deadline: None,
+                expected: None,
+                ignored: HashSet::new(),
+                key: Vec::new(),
+                limits: ReceiveLimits::default(),
+                events: Events::default(),
+                cancel: CancellationToken::new(),
+                partial: PartialOutput::default(),
+            },
+        }
+    }
+
+    /// Listen on `addr`; an unspecified IPv6 address also accepts IPv4
+    /// unless `dual_stack` is off
+    pub fn listen(mut self, addr: SocketAddr, dual_stack: bool) -> Self {
+        self.options.listen = addr;
+        self.options.dual_stack = dual_stack;
+        self
+    }
+
+    /// Join multicast `group` on `interface`, at the port being listened on
+    pub fn group(mut self, group: IpAddr, interface: Interface) -> Self {
+        self.options.group = Some(group);
+        self.options.interface = interface;
+        self
+    }
+
+    /// Receive through `transport` instead of a UDP socket of its own
+    pub fn transport(mut self, transport: Arc<dyn PacketTransport>) -> Self {
+        self.options.transport = Some(transport);
+        self
+    }
+
+    /// Whether to journal progress beside an output file to resume from
+    pub fn checkpoint(mut self, enabled: bool) -> Self {
+        self.options.checkpoint = enabled;
+        self
+    }
+
+    /// How long to wait for the first packet, `None` to wait indefinitely
+    pub fn first_packet_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.first_packet_timeout = timeout;
+        self
+    }
+
+    /// How long the transfer may go without a packet once it started,
+    /// `None` for no limit
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.idle_timeout = timeout;
+        self
+    }
+
+    /// Time allowed for the whole transfer from its first packet, `None`
+    /// (the default) for no limit
+    pub fn deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.options.deadline = deadline;
+        self
+    }
+
+    /// Accept only the object with this hash
+    pub fn expect_hash(mut self, hash: blake3::Hash) -> Self {
+        self.options.expected = Some(session_id_for(&hash));
+        self
+    }
+
+    /// Pass over packets of these sessions, such as ones already received
+    pub fn ignore_sessions(mut self, sessions: impl IntoIterator<Item = u64>) -> Self {
+        self.options.ignored.extend(sessions);
+        self
+    }
+
+    /// Pre-shared key completion acknowledgements are signed with
+    pub fn key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.options.key = key.into();
+        self
+    }
+
+    /// Refuse objects whose first packet announces a layout beyond `limits`
+    pub fn limits(mut self, limits: ReceiveLimits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+
+    /// Report events on `tx`
+    pub fn events(mut self, tx: mpsc::UnboundedSender<Event>) -> Self {
+        self.options.events = Events(Some(tx));
+        self
+    }
+
+    /// Stop, telling the senders, once `token` is cancelled
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.options.cancel = token;
+        self
+    }
+
+    /// What to do with what was received if the transfer does not finish
+    pub fn partial(mut self, partial: PartialOutput) -> Self {
+        self.options.partial = partial;
+        self
+    }
+
+    /// Receive the first transfer to arrive, save it and acknowledge it
+    pub async fn receive(mut self) -> Result<ReceiveReport, TransferError> {
+        // Create transport, on both address families unless restricted
+        let transport: Arc<dyn PacketTransport> = match (&self.options.transport, self.options.group) {
+            (Some(transport), _) => transport.clone(),
+            (None, Some(group)) => Arc::new(
+                UdpTransport::bind_multicast(
+                    group,
+                    self.options.listen.port(),
+                    self.options.interface,
+                    PacerConfig::from_mbps(0),
+                )
+                .await?,
+            ),
+            (None, None) => Arc::new(
+                UdpTransport::bind(self.options.listen, self.options.dual_stack, PacerConfig::from_mbps(0)).await?,
+            ),
+        };
+        self.options.events.emit(Event::Listening {
+            addr: transport.local_addr()?,
+            group: self.options.group,
+        });
+
+        let waiting = self.options.first_packet_timeout;
+        let first = next_data(&self.options, transport.as_ref(), None, |_| None, waiting.map(|t| Instant::now() + t))
+            .await
+            .map_err(|e| match (e, waiting) {
+                (TransferError::Timeout, Some(waiting)) => TransferError::NoTransfer(waiting),
+                (e, _) => e,
+            })?;
+        let end = self.options.deadline.map(|deadline| Instant::now() + deadline);
+        if first.0.is_stream() {
+            return receive_stream(&self.options, &mut self.output, &transport, first, end).await;
+        }
+
+        let checkpoint_path = match &self.output {
+            Output::Path(path) if self.options.checkpoint => Some(path.clone()),
+            _ => None,
+        };
+        let mut checkpoint = None;
+        let received = receive_session(
+            &self.options,
+            transport.as_ref(),
+            checkpoint_path.as_deref(),
+            &mut checkpoint,
+            first,
+            end,
+        )
+        .await;
+        let (senders, session) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                if let (Some(journal), Some(output)) = (checkpoint, &checkpoint_path) {
+                    self.options.leave_checkpoint(journal, output);
+                }
+                return Err(e);
+            }
+        };
+        let session_id = session.session_id();
+        let archive = session.is_archive();
+        let num_blocks = session.layout().num_blocks;
+        let source_symbols = session.layout().source_symbols();
+        let symbols_received = session.symbols_received();
+        let duplicates = session.duplicates();
+        let redundant = session.redundant();
+        let loss = session.loss_rate();
+        let decode_time = session.decode_time();
+        let elapsed = session.elapsed();
+        let saving = Instant::now();
+
+        // Session IDs are derived from the content, which catches a
+        // corrupt checkpoint as well as any decoding error
+        let data = session.into_data().expect("session is complete");
+        let hash = blake3::hash(&data);
+        if session_id_for(&hash) != session_id {
+            if let Some(journal) = checkpoint.take() {
+                journal.remove()?;
+            }
+            return Err(TransferError::Corrupt(session_id));
+        }
+
+        let mut report = ReceiveReport {
+            session_id,
+            hash,
+            bytes: data.len() as u64,
+            num_blocks,
+            senders: senders.iter().map(|&(addr, _)| addr).collect(),
+            symbols_received,
+            source_symbols,
+            duplicates,
+            redundant,
+            loss,
+            decode_time,
+            path: None,
+            files: None,
+            data: None,
+            elapsed: Duration::ZERO,
+        };
+        let destination = match &self.output {
+            Output::Path(path) => Some(path.clone()),
+            Output::Inbox(dir) => {
+                tokio::fs::create_dir_all(dir).await?;
+                Some(dir.join(format!("{:016x}", session_id)))
+            }
+            Output::Writer(_) | Output::Memory => None,
+        };
+        match (&mut self.output, &destination) {
+            (_, Some(path)) => report.files = save(data, path, archive).await?.map(|manifest| manifest.files()),
+            (Output::Writer(writer), None) => {
+                writer.write_all(&data).await?;
+                writer.flush().await?;
+            }
+            _ => report.data = Some(data),
+        }
+        report.path = destination;
+        report.elapsed = elapsed + saving.elapsed();
+        self.options.events.emit(Event::Saved {
+            path: report.path.clone(),
+            bytes: report.bytes,
+            files: report.files,
+        });
+
+        if let Some(journal) = checkpoint {
+            journal.remove()?;
+        }
+
+        acknowledge_senders(&self.options, &transport, &senders, session_id, hash).await?;
+        Ok(report)
+    }
+}
+
+impl ReceiveOptions {
+    /// Tell `senders`, each with the nonce of its run, that the session of
+    /// `session_id` is abandoned, unless they send to a group and do not
+    /// hear from its members
+    async fn abandon(
+        &self,
+        transport: &dyn PacketTransport,
+        session_id: u64,
+        senders: impl Iterator<Item = (SocketAddr, u64)>,
+    ) {
+        if self.group.is_some() {
+            return;
+        }
+        for (sender, nonce) in senders {
+            let abort = Packet::Abort(AbortNotice::new(session_id, nonce, &self.key));
+            if let Err(e) = transport.send_packet(sender, &abort).await {
+                log::debug!("Failed to send abort notice to {}: {:#}", sender, e);
+            }
+        }
+    }
+
+    /// Flush or remove the checkpoint of an abandoned receive into
+    /// `output`, as the partial output policy says
+    fn leave_checkpoint(&self, mut journal: Checkpoint, output: &Path) {
+        let left = match self.partial {
+            PartialOutput::Keep => journal.flush(),
+            PartialOutput::Discard => journal.remove(),
+        };
+        match left {
+            Ok(()) if self.partial == PartialOutput::Keep => self.events.emit(Event::Kept {
+                path: checkpoint::sidecar_path(output),
+            }),
+            Ok(()) => {}
+            Err(e) => log::warn!("Failed to clean up the checkpoint of {}: {}", output.display(), e),
+        }
+    }
+
+    /// Keep or remove the `.part` file of an abandoned stream, as the
+    /// partial output policy says
+    async fn leave_part(&self, part: &Path) {
+        match self.partial {
+            PartialOutput::Keep => self.events.emit(Event::Kept { path: part.to_path_buf() }),
+            PartialOutput::Discard => {
+                if let Err(e) = tokio::fs::remove_file(part).await {
+                    log::warn!("Failed to remove {}: {}", part.display(), e);
+                }
+            }
+        }
+    }
+}
+
+/// Acknowledge completion to every sender
+///
+/// Receivers of a group share its port, so each acknowledges from a
+/// socket of its own for the sender to tell them apart.
+async fn acknowledge_senders(
+    options: &ReceiveOptions,
+    transport: &Arc<dyn PacketTransport>,
+    senders: &[(SocketAddr, u64)],
+    session_id: u64,
+    hash: blake3::Hash,
+) -> Result<(), TransferError> {
+    match (&options.transport, options.group) {
+        (None, Some(_)) => {
+            let local = addr::unspecified_for(&senders[0].0);
+            let reply = UdpTransport::bind(local, false, PacerConfig::from_mbps(0)).await?;
+            acknowledge(transport.as_ref(), &reply, senders, session_id, hash, &options.key).await
+        }
+        _ => acknowledge(transport.as_ref(), transport.as_ref(), senders, session_id, hash, &options.key).await,
+    }
+}
+
+/// Wait for the next data packet of a session not passed over, answering
+/// path MTU probes meanwhile, until `deadline` if there is one
+///
+/// An abort notice for the `active` session, signed for the nonce
+/// `nonce_of` its sender, ends the wait, as does cancellation.
+async fn next_data(
+    options: &ReceiveOptions,
+    transport: &dyn PacketTransport,
+    active: Option<u64>,
+    nonce_of: impl Fn(SocketAddr) -> Option<u64>,
+    deadline: Option<Instant>,
+) -> Result<(DataPacket, SocketAddr), TransferError> {
+    loop {
+        let received = async {
+            match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    tokio::time::timeout(remaining, transport.receive_packet())
+                        .await
+                        .map_err(|_| TransferError::Timeout)
+                }
+                None => Ok(transport.receive_packet().await),
+            }
+        };
+        let (packet, addr) = cancellable(&options.cancel, received).await??;
+        let packet = match packet {
+            Packet::Data(packet) => packet,
+            Packet::Probe(probe) => {
+                transport.send_packet(addr, &Packet::ProbeAck(probe.ack())).await?;
+                continue;
+            }
+            Packet::Abort(notice) if Some(notice.session_id) == active => {
+                if !nonce_of(addr).is_some_and(|nonce| notice.verify(nonce, &options.key)) {
+                    log::warn!("Ignoring abort notice from {} with a bad signature", addr);
+                    continue;
+                }
+                return Err(TransferError::Aborted(addr));
+            }
+            _ => continue,
+        };
+        if options.ignored.contains(&packet.session_id)
+            || options.expected.is_some_and(|expected| expected != packet.session_id)
+        {
+            continue;
+        }
+        return Ok((packet, addr));
+    }
+}
+
+/// Wait for the next data packet of the `active` session, or any before
+/// there is one, giving up once the idle timeout passes after the `last`
+/// one or the session's `end` passes
+async fn next_session_data(
+    options: &ReceiveOptions,
+    transport: &dyn PacketTransport,
+    active: Option<u64>,
+    nonce_of: impl Fn(SocketAddr) -> Option<u64>,
+    last: Instant,
+    end: Option<Instant>,
+) -> Result<(DataPacket, SocketAddr), TransferError> {
+    let idle_end = options.idle_timeout.map(|idle| last + idle);
+    let until = match (idle_end, end) {
+        (Some(idle_end), Some(end)) => Some(idle_end.min(end)),
+        (idle_end, end) => idle_end.or(end),
+    };
+    next_data(options, transport, active, nonce_of, until).await.map_err(|e| match e {
+        TransferError::Timeout if end.is_none_or(|end| Instant::now() < end) => {
+            options.idle_timeout.map_or(TransferError::Timeout, TransferError::Idle)
+        }
+        e => e,
+    })
+}
+
+/// Receive the session of the `first` packet on `transport` until it
+/// decodes, goes idle or its `end` passes, returning it along with its
+/// senders and the nonces of their runs
+///
+/// The session is journaled in `checkpoint` beside `output`, which is left
+/// to the caller whether or not the session completes.
+async fn receive_session(
+    options: &ReceiveOptions,
+    transport: &dyn PacketTransport,
+    output: Option<&Path>,
+    checkpoint: &mut Option<Checkpoint>,
+    first: (DataPacket, SocketAddr),
+    end: Option<Instant>,
+) -> Result<(Vec<(SocketAddr, u64)>, ReceiveSession), TransferError> {
+    // Block layout is determined from the first received packet
+    let mut session: Option<ReceiveSession> = None;
+    let mut last_packet = Instant::now();
+    let mut last_report = Instant::now();
+    let mut last_flush = Instant::now();
+    let mut next = Some(first);
+
+    let (senders, session) = loop {
+        let (packet, addr) = match next.take() {
+            Some(received) => received,
+            None => {
+                let active = session.as_ref().map(ReceiveSession::session_id);
+                let nonce_of = |addr| session.as_ref().and_then(|session| session.nonce(addr));
+                match next_session_data(options, transport, active, nonce_of, last_packet, end).await {
+                    Ok(received) => received,
+                    Err(e) => {
+                        if let Some(active) = &session {
+                            options.abandon(transport, active.session_id(), active.senders()).await;
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        };
+
+        // Lock on to the first session seen
+        let active = match &mut session {
+            Some(active) => active,
+            None => match ReceiveSession::with_limits(&packet, &options.limits) {
+                Ok(mut new) => {
+                    options.events.emit(Event::Receiving {
+                        session_id: packet.session_id,
+                        bytes: packet.transfer_length,
+                        num_blocks: new.layout().num_blocks,
+                        from: addr,
+                    });
+                    if let Some(output) = output {
+                        *checkpoint = open_checkpoint(output, &packet, &mut new, &options.events);
+                    }
+                    session.insert(new)
+                }
+                Err(e) => {
+                    log::debug!("Ignoring packet from {}: {}", addr, e);
+                    continue;
+                }
+            },
+        };
+        if packet.session_id != active.session_id() {
+            continue;
+        }
+        last_packet = Instant::now();
+
+        if active.sources().all(|source| source != addr) && active.symbols_received() > 0 {
+            options.events.emit(Event::NewSender { addr });
+        }
+        let fresh = !active.is_block_decoded(packet.sbn as usize);
+        let decoded = match active.add_packet(addr, &packet) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                log::debug!("Ignoring packet from {}: {}", addr, e);
+                continue;
+            }
+        };
+        if decoded {
+            options.events.emit(Event::BlockDecoded {
+                sbn: packet.sbn,
+                blocks_decoded: active.blocks_decoded(),
+                num_blocks: active.layout().num_blocks,
+            });
+        }
+
+        // Journal symbols until their block is recovered, then the block
+        if let Some(journal) = checkpoint.as_mut() {
+            let recorded = match active.block(packet.sbn as usize) {
+                Some(block) if decoded => journal.record_block(packet.sbn, block),
+                None if fresh => journal.record_symbol(&packet),
+                _ => Ok(()),
+            };
+            let flushed = match last_flush.elapsed() >= CHECKPOINT_INTERVAL {
+                true => {
+                    last_flush = Instant::now();
+                    journal.flush()
+                }
+                false => Ok(()),
+            };
+            if let Err(e) = recorded.and(flushed) {
+                log::warn!("Checkpointing stopped: {}", e);
+                *checkpoint = None;
+            }
+        }
+
+        log::trace!(
+            "Received symbol {} of source block {} (degree: {}, size: {})",
+            packet.esi,
+            packet.sbn,
+            packet.degree,
+            packet.payload.len()
+        );
+
+        if active.is_complete() {
+            let complete = session.take().unwrap();
+            break (complete.senders().collect::<Vec<_>>(), complete);
+        }
+
+        if last_report.elapsed() < PROGRESS_INTERVAL {
+            continue;
+        }
+        last_report = Instant::now();
+        options.events.emit(Event::Received {
+            symbols_received: active.symbols_received(),
+            symbols_useful: active.symbols_useful(),
+            symbols_needed: active.layout().source_symbols(),
+            duplicates: active.duplicates(),
+            blocks_decoded: active.blocks_decoded(),
+            num_blocks: active.layout().num_blocks,
+            loss: active.loss_rate(),
+        });
+
+        // Reports from every multicast receiver would swamp the sender,
+        // which does not adapt its rate to them anyway
+        if options.group.is_none() {
+            // Every sender learns about the packets it sent itself, if it
+            // sent any since the last report
+            let sources: Vec<SocketAddr> = active.sources().collect();
+            for source in sources {
+                if let Some(report) = active.progress(source) {
+                    transport.send_packet(source, &Packet::Progress(report)).await?;
+                }
+            }
+        }
+    };
+
+    Ok((senders, session))
+}
+
+/// Receive the stream of the `first` packet on `transport`, writing its
+/// source blocks to `output` in order as they are recovered
+///
+/// Streams are not saved as a whole, so they are never unpacked as
+/// archives nor checkpointed, and their random session IDs say nothing
+/// about the content; the sender checks the hash acknowledged instead. A
+/// file is written under its `.part` name until the stream is complete.
+async fn receive_stream(
+    options: &ReceiveOptions,
+    output: &mut Output,
+    transport: &Arc<dyn PacketTransport>,
+    first: (DataPacket, SocketAddr),
+    end: Option<Instant>,
+) -> Result<ReceiveReport, TransferError> {
+    let mut session = StreamReceiveSession::new(&first.0)?;
+    let session_id = session.session_id();
+    options.events.emit(Event::ReceivingStream {
+        session_id,
+        from: first.1,
+    });
+
+    let destination = match output {
+        Output::Path(path) => Some(path.clone()),
+        Output::Inbox(dir) => {
+            tokio::fs::create_dir_all(&dir).await?;
+            Some(dir.join(format!("{:016x}", session_id)))
+        }
+        Output::Writer(_) | Output::Memory => None,
+    };
+    let part = destination.as_deref().map(part_path);
+    let mut file = match &part {
+        Some(part) => Some(tokio::fs::File::create(part).await?),
+        None => None,
+    };
+    let mut data = Vec::new();
+    let mut hasher = blake3::Hasher::new();
+    let mut bytes = 0u64;
+    let mut last_packet = Instant::now();
+    let mut last_report = Instant::now();
+    let mut next = Some(first);
+    let received = async {
+        loop {
+            let (packet, addr) = match next.take() {
+                Some(received) => received,
+                None => {
+                    let nonce_of = |addr| session.nonce(addr);
+                    next_session_data(options, transport.as_ref(), Some(session_id), nonce_of, last_packet, end).await?
+                }
+            };
+            if packet.session_id != session_id {
+                continue;
+            }
+            last_packet = Instant::now();
+            if session.sources().all(|source| source != addr) && session.symbols_received() > 0 {
+                options.events.emit(Event::NewSender { addr });
+            }
+            if let Err(e) = session.add_packet(addr, &packet) {
+                log::debug!("Ignoring packet from {}: {}", addr, e);
+                continue;
+            }
+
+            let mut written = false;
+            while let Some(block) = session.pop_block() {
+                hasher.update(&block);
+                bytes += block.len() as u64;
+                match (&mut file, &mut *output) {
+                    (Some(file), _) => file.write_all(&block).await?,
+                    (None, Output::Writer(writer)) => writer.write_all(&block).await?,
+                    _ => data.extend_from_slice(&block),
+                }
+                written = true;
+            }
+            if written {
+                if let Output::Writer(writer) = output {
+                    writer.flush().await?;
+                }
+            }
+            if session.is_complete() {
+                break;
+            }
+
+            if last_report.elapsed() < PROGRESS_INTERVAL {
+                continue;
+            }
+            last_report = Instant::now();
+            options.events.emit(Event::Written {
+                bytes,
+                blocks: session.blocks_written(),
+            });
+            if options.group.is_none() {
+                let sources: Vec<SocketAddr> = session.sources().collect();
+                for source in sources {
+                    if let Some(report) = session.progress(source) {
+                        transport.send_packet(source, &Packet::Progress(report)).await?;
+                    }
+                }
+            }
+        }
+        Ok::<_, TransferError>(())
+    }
+    .await;
+    let flushed = match file.as_mut() {
+        Some(file) => async { file.flush().await.and(file.sync_all().await) }.await.map_err(TransferError::from),
+        None => Ok(()),
+    };
+    if let Err(e) = received.and(flushed) {
+        options.abandon(transport.as_ref(), session_id, session.senders()).await;
+        if let Some(part) = &part {
+            drop(file);
+            options.leave_part(part).await;
+        }
+        return Err(e);
+    }
+    if let (Some(part), Some(path)) = (&part, &destination) {
+        tokio::fs::rename(part, path).await?;
+    }
+
+    let layout = session.layout().expect("stream is complete");
+    let hash = hasher.finalize();
+    let senders: Vec<(SocketAddr, u64)> = session.senders().collect();
+    let report = ReceiveReport {
+        session_id,
+        hash,
+        bytes,
+        num_blocks: layout.num_blocks,
+        senders: session.sources().collect(),
+        symbols_received: session.symbols_received(),
+        source_symbols: layout.source_symbols(),
+        duplicates: session.duplicates(),
+        redundant: session.redundant(),
+        loss: session.loss_rate(),
+        decode_time: session.decode_time(),
+        path: destination,
+        files: None,
+        data: matches!(output, Output::Memory).then_some(data),
+        elapsed: session.elapsed(),
+    };
+    options.events.emit(Event::Saved {
+        path: report.path.clone(),
+        bytes,
+        files: None,
+    });
+
+    acknowledge_senders(options, transport, &senders, session_id, hash).await?;
+    Ok(report)
+}
+
+/// Start journaling the session of `packet` next to `output`, first
+/// restoring `session` from an earlier run of the same transfer
+///
+/// Problems with the checkpoint are logged; the transfer goes on without.
+fn open_checkpoint(
+    output: &Path,
+    packet: &DataPacket,
+    session: &mut ReceiveSession,
+    events: &Events,
+) -> Option<Checkpoint> {
+    let path = checkpoint::sidecar_path(output);
+    let saved = match checkpoint::load(&path) {
+        Ok(Some(saved)) if saved.matches(packet) => {
+            let (symbols, blocks) = saved.restore(session);
+            events.emit(Event::Resumed {
+                path: path.clone(),
+                symbols,
+                blocks,
+            });
+            Some(saved)
+        }
+        Ok(Some(_)) => {
+            log::warn!("Replacing checkpoint {} of another transfer", path.display());
+            None
+        }
+        Ok(None) => None,
+        Err(e) => {
+            log::warn!("Replacing unreadable checkpoint {}: {}", path.display(), e);
+            None
+        }
+    };
+
+    Checkpoint::create(&path, packet, saved.as_ref())
+        .inspect_err(|e| log::warn!("Not checkpointing to {}: {}", path.display(), e))
+        .ok()
+}
+
+/// Send the completion acknowledgement through `reply` to every sender,
+/// signed for the nonce of its run, repeating it to those whose data keeps
+/// arriving on `transport` in case it was lost
+async fn acknowledge(
+    transport: &dyn PacketTransport,
+    reply: &dyn PacketTransport,
+    senders: &[(SocketAddr, u64)],
+    session_id: u64,
+    hash: blake3::Hash,
+    key: &[u8],
+) -> Result<(), TransferError> {
+    let ack = |nonce| Packet::Complete(CompletionAck::new(session_id, nonce, *hash.as_bytes(), key));
+    let mut last_ack = HashMap::new();
+    for &(sender, nonce) in senders {
+        reply.send_packet(sender, &ack(nonce)).await?;
+        last_ack.insert(sender, Instant::now());
+    }
+
+    while let Ok(received) = tokio::time::timeout(ACK_LINGER, transport.receive_packet()).await {
+        let (packet, addr) = received?;
+        let Packet::Data(packet) = packet else {
+            continue;
+        };
+        if packet.session_id != session_id {
+            continue;
+        }
+        if last_ack
+            .get(&addr)
+            .is_none_or(|sent| sent.elapsed() >= ACK_REPEAT_INTERVAL)
+        {
+            reply.send_packet(addr, &ack(packet.nonce)).await?;
+            last_ack.insert(addr, Instant::now());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{Impairments, LossModel, Network};
+    use rand::{Rng, SeedableRng};
+
+    fn contents(len: usize) -> Vec<u8> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(5);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    /// Sender and receiver endpoints on `network`, and the receiver's address
+    fn endpoints(network: &Network) -> (Arc<dyn PacketTransport>, Arc<dyn PacketTransport>, String) {
+        let sender = network.bind("10.0.0.1:0".parse().unwrap()).unwrap();
+        let receiver = network.bind("10.0.0.2:3000".parse().unwrap()).unwrap();
+        let target = receiver.local_addr().unwrap().to_string();
+        (Arc::new(sender), Arc::new(receiver), target)
+    }
+
+    #[test]
+    fn test_futures_are_send() {
+        fn is_send<T: Send>(_: &T) {}
+        is_send(&Sender::new(Source::Bytes(Vec::new())).send());
+        is_send(&Receiver::new(Output::Memory).receive());
+    }
+
+    #[test]
+    fn test_send_receive() {
+        let data = contents(500_000);
+        let network = Network::new(
+            3,
+            Impairments {
+                loss: LossModel::bernoulli(0.05),
+                ..Impairments::default()
+            },
+        );
+        let (sender, receiver, target) = endpoints(&network);
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+
+        let sending = Sender::new(Source::Bytes(data.clone()))
+            .target(target)
+            .transport(sender)
+            .mtu(1500)
+            .rate_limit(0)
+            .deadline(Duration::from_secs(20))
+            .events(events_tx.clone())
+            .send();
+        let receiving = Receiver::new(Output::Memory)
+            .transport(receiver)
+            .deadline(Some(Duration::from_secs(20)))
+            .events(events_tx)
+            .receive();
+        let (sent, received) = runtime().block_on(async { tokio::join!(sending, receiving) });
+        let (sent, received) = (sent.unwrap(), received.unwrap());
+
+        assert_eq!(received.data.as_deref(), Some(&data[..]));
+        assert_eq!(sent.hash, received.hash);
+        assert_eq!(sent.session_id, received.session_id);
+        assert_eq!(sent.confirmed, vec!["10.0.0.2:3000".parse().unwrap()]);
+
+        let mut events = Vec::new();
+        while let Ok(event) = events_rx.try_recv() {
+            events.push(event);
+        }
+        assert!(events.iter().any(|event| matches!(event, Event::Listening { .. })));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::Sending { mtu: 1500, .. })));
+        assert!(events.iter().any(|event| matches!(event, Event::Receiving { .. })));
+        let decoded = events
+            .iter()
+            .filter(|event| matches!(event, Event::BlockDecoded { .. }))
+            .count();
+        assert_eq!(decoded, sent.num_blocks);
+        assert!(events.iter().any(|event| matches!(event, Event::Confirmed { .. })));
+    }
+
+    #[test]
+    fn test_stream() {
+        // Several source blocks, read in small pieces
+        let data = contents(3_000_000);
+        let network = Network::new(
+            8,
+            Impairments {
+                loss: LossModel::bernoulli(0.05),
+                ..Impairments::default()
+            },
+        );
+        let (sender, receiver, target) = endpoints(&network);
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let reader = tokio::io::BufReader::with_capacity(1000, std::io::Cursor::new(data.clone()));
+
+        let sending = Sender::new(Source::Stream(Box::new(reader)))
+            .target(target)
+            .transport(sender)
+            .mtu(1500)
+            .rate_limit(0)
+            .deadline(Duration::from_secs(20))
+            .events(events_tx.clone())
+            .send();
+        let receiving = Receiver::new(Output::Memory)
+            .transport(receiver)
+            .deadline(Some(Duration::from_secs(20)))
+            .events(events_tx)
+            .receive();
+        let (sent, received) = runtime().block_on(async { tokio::join!(sending, receiving) });
+        let (sent, received) = (sent.unwrap(), received.unwrap());
+
+        assert_eq!(received.data.as_deref(), Some(&data[..]));
+        assert_eq!(sent.hash, blake3::hash(&data));
+        assert_eq!(received.hash, sent.hash);
+        assert_eq!(sent.bytes, data.len() as u64);
+        assert!(sent.num_blocks > 1);
+        assert_eq!(sent.confirmed, vec!["10.0.0.2:3000".parse().unwrap()]);
+
+        let mut events = Vec::new();
+        while let Ok(event) = events_rx.try_recv() {
+            events.push(event);
+        }
+        assert!(events.iter().any(|event| matches!(event, Event::Streaming { .. })));
+        assert!(events.iter().any(|event| matches!(event, Event::ReceivingStream { .. })));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::InputEnded { num_blocks, .. } if *num_blocks == sent.num_blocks)));
+    }
+
+    #[test]
+    fn test_stream_fixed_overhead() {
+        let network = Network::new(9, Impairments::default());
+        let (sender, _receiver, target) = endpoints(&network);
+        let reader = std::io::Cursor::new(contents(1_000_000));
+        let report = runtime()
+            .block_on(
+                Sender::new(Source::Stream(Box::new(reader)))
+                    .target(target)
+                    .transport(sender)
+                    .mtu(1500)
+                    .overhead(OverheadPolicy::Fixed(0.5))
+                    .send(),
+            )
+            .unwrap();
+        // The last source block is shorter but gets as many symbols in
+        // proportion
+        let per_block = (STREAM_BLOCK_SYMBOLS as f64 * 1.5).ceil() as u64;
+        let last = report.source_symbols - (report.num_blocks - 1) * STREAM_BLOCK_SYMBOLS;
+        assert_eq!(
+            report.packets,
+            per_block * (report.num_blocks as u64 - 1) + (last as f64 * 1.5).ceil() as u64
+        );
+        assert!(report.confirmed.is_empty());
+
+        // Nobody in a group reports which blocks they have
+        let network = Network::new(10, Impairments::default());
+        let (sender, _receiver, _target) = endpoints(&network);
+        let result = runtime().block_on(
+            Sender::new(Source::Stream(Box::new(std::io::Cursor::new(Vec::new()))))
+                .target("239.1.2.3:3000")
+                .transport(sender)
+                .mtu(1500)
+                .send(),
+        );
+        assert!(matches!(result, Err(TransferError::MulticastStream)));
+    }
+
+    #[test]
+    fn test_fixed_overhead() {
+        // Nobody listens, so nothing is ever acknowledged
+        let network = Network::new(4, Impairments::default());
+        let (sender, _receiver, target) = endpoints(&network);
+        let report = runtime()
+            .block_on(
+                Sender::new(Source::Bytes(contents(100_000)))
+                    .target(target)
+                    .transport(sender)
+                    .mtu(1500)
+                    .overhead(OverheadPolicy::Fixed(0.5))
+                    .send(),
+            )
+            .unwrap();
+        let symbols = 100_000usize.div_ceil(report.symbol_size) as u64;
+        assert_eq!(report.packets, (symbols as f64 * 1.5).ceil() as u64);
+        assert!(report.confirmed.is_empty());
+    }
+
+    #[test]
+    fn test_save_to_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.bin");
+        let data = contents(200_000);
+        let network = Network::new(6, Impairments::default());
+        let (sender, receiver, target) = endpoints(&network);
+
+        let sending = Sender::new(Source::Reader(Box::new(std::io::Cursor::new(data.clone()))))
+            .target(target)
+            .transport(sender)
+            .mtu(1500)
+            .send();
+        let receiving = Receiver::new(Output::Path(output.clone()))
+            .transport(receiver)
+            .expect_hash(blake3::hash(&data))
+            .receive();
+        let (sent, received) = runtime().block_on(async { tokio::join!(sending, receiving) });
+        sent.unwrap();
+        let received = received.unwrap();
+
+        assert_eq!(received.path.as_deref(), Some(output.as_path()));
+        assert_eq!(received.data, None);
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        assert!(!checkpoint::sidecar_path(&output).exists());
+    }
+
+    #[test]
+    fn test_save_unmarked_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("tree");
+        std::fs::create_dir(&tree).unwrap();
+        std::fs::write(tree.join("a.txt"), b"hello").unwrap();
+        let (_, object) = archive::pack(tree.to_str().unwrap()).unwrap();
+
+        // A file that happens to hold an archive is saved as it is
+        let output = dir.path().join("object.ffar");
+        let manifest = runtime().block_on(save(object.clone(), &output, false)).unwrap();
+        assert!(manifest.is_none());
+        assert_eq!(std::fs::read(&output).unwrap(), object);
+
+        let unpacked = dir.path().join("unpacked");
+        let manifest = runtime().block_on(save(object, &unpacked, true)).unwrap();
+        assert_eq!(manifest.unwrap().files(), 1);
+        assert!(unpacked.is_dir());
+
+        // A marked object that is no archive is refused
+        let result = runtime().block_on(save(b"plain".to_vec(), &dir.path().join("bad"), true));
+        assert!(matches!(result, Err(TransferError::Archive(ArchiveError::Malformed))));
+    }
+
+    #[test]
+    fn test_cancel_sender() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.bin");
+        let network = Network::new(11, Impairments::default());
+        let (sender, receiver, target) = endpoints(&network);
+        let from = sender.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+
+        let sending = Sender::new(Source::Bytes(contents(1_000_000)))
+            .target(target)
+            .transport(sender)
+            .mtu(1500)
+            .rate_limit(1)
+            .cancel(cancel.clone())
+            .send();
+        let receiving = Receiver::new(Output::Path(output.clone()))
+            .transport(receiver)
+            .receive();
+        let (sent, received, ()) = runtime().block_on(async {
+            let cancelling = async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                cancel.cancel();
+            };
+            tokio::join!(sending, receiving, cancelling)
+        });
+
+        assert!(matches!(sent, Err(TransferError::Cancelled)));
+        assert!(matches!(received, Err(TransferError::Aborted(addr)) if addr == from));
+        // Nothing looks complete, but the checkpoint is kept to resume from
+        assert!(!output.exists());
+        assert!(checkpoint::sidecar_path(&output).exists());
+    }
+
+    #[test]
+    fn test_cancel_receiver() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.bin");
+        let network = Network::new(12, Impairments::default());
+        let (sender, receiver, target) = endpoints(&network);
+        let cancel = CancellationToken::new();
+
+        let sending = Sender::new(Source::Stream(Box::new(std::io::Cursor::new(contents(1_000_000)))))
+            .target(target.clone())
+            .transport(sender)
+            .mtu(1500)
+            .rate_limit(1)
+            .send();
+        let receiving = Receiver::new(Output::Path(output.clone()))
+            .transport(receiver)
+            .partial(PartialOutput::Discard)
+            .cancel(cancel.clone())
+            .receive();
+        let (sent, received, ()) = runtime().block_on(async {
+            let cancelling = async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                cancel.cancel();
+            };
+            tokio::join!(sending, receiving, cancelling)
+        });
+
+        assert!(matches!(received, Err(TransferError::Cancelled)));
+        assert!(matches!(sent, Err(TransferError::Aborted(addr)) if addr.to_string() == target));
+        assert!(!output.exists());
+        assert!(!part_path(&output).exists());
+    }
+
+    #[test]
+    fn test_replayed_feedback() {
+        // Acknowledgements and abort notices captured from an earlier send
+        // of the same object are signed for another nonce
+        let data = contents(10_000);
+        let network = Network::new(15, Impairments::default());
+        let (sender, receiver, target) = endpoints(&network);
+        let sending = Sender::new(Source::Bytes(data.clone()))
+            .target(target)
+            .transport(sender)
+            .mtu(1500)
+            .key(b"secret".to_vec())
+            .deadline(Duration::from_millis(500))
+            .send();
+        let replaying = async {
+            let (packet, from) = receiver.receive_packet().await.unwrap();
+            let Packet::Data(packet) = packet else {
+                panic!("expected a data packet");
+            };
+            let hash = blake3::hash(&data);
+            let (session_id, stale) = (packet.session_id, packet.nonce.wrapping_add(1));
+            let ack = CompletionAck::new(session_id, stale, *hash.as_bytes(), b"secret");
+            let abort = AbortNotice::new(session_id, stale, b"secret");
+            receiver.send_packet(from, &Packet::Complete(ack)).await.unwrap();
+            receiver.send_packet(from, &Packet::Abort(abort)).await.unwrap();
+        };
+        let (sent, ()) = runtime().block_on(async { tokio::join!(sending, replaying) });
+        assert!(matches!(sent, Err(TransferError::Unconfirmed { .. })));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let network = Network::new(7, Impairments::default());
+        let (_sender, receiver, _target) = endpoints(&network);
+        let result = runtime().block_on(
+            Receiver::new(Output::Memory)
+                .transport(receiver)
+                .first_packet_timeout(Some(Duration::from_millis(50)))
+                .receive(),
+        );
+        assert!(matches!(result, Err(TransferError::NoTransfer(_))));
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        // Half the packets are lost and the sender stops after sending as
+        // many as there are source symbols
+        let network = Network::new(
+            13,
+            Impairments {
+                loss: LossModel::bernoulli(0.5),
+                ..Impairments::default()
+            },
+        );
+        let (sender, receiver, target) = endpoints(&network);
+        let sending = Sender::new(Source::Bytes(contents(100_000)))
+            .target(target)
+            .transport(sender)
+            .mtu(1500)
+            .overhead(OverheadPolicy::Fixed(0.0))
+            .send();
+        let receiving = Receiver::new(Output::Memory)
+            .transport(receiver)
+            .idle_timeout(Some(Duration::from_millis(200)))
+            .receive();
+        let (sent, received) = runtime().block_on(async { tokio::join!(sending, receiving) });
+        sent.unwrap();
+        assert!(matches!(received, Err(TransferError::Idle(_))));
+    }
+
+    #[test]
+    fn test_deadline() {
+        // Packets keep arriving, but too slowly to finish in time
+        let network = Network::new(14, Impairments::default());
+        let (sender, receiver, target) = endpoints(&network);
+        let sending = Sender::new(Source::Bytes(contents(1_000_000)))
+            .target(target)
+            .transport(sender)
+            .mtu(1500)
+            .rate_limit(1)
+            .send();
+        let receiving = Receiver::new(Output::Memory)
+            .transport(receiver)
+            .idle_timeout(None)
+            .deadline(Some(Duration::from_millis(300)))
+            .receive();
+        let (sent, received) = runtime().block_on(async { tokio::join!(sending, receiving) });
+        assert!(matches!(received, Err(TransferError::Timeout)));
+        // The receiver gave up, so the sender stops at once
+        assert!(matches!(sent, Err(TransferError::Aborted(_))));
+    }
+}